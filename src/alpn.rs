@@ -0,0 +1,121 @@
+use crate::{ConnContext, HttpContext, HttpHandler};
+
+/// An HTTP handler that dispatches to other handlers based on the protocol the client negotiated
+/// with ALPN when its TLS connection was intercepted.
+///
+/// Each request is passed to the handler registered for [`HttpContext::client_alpn`], or to the
+/// default handler if there is none, such as for plain HTTP requests and `CONNECT` requests.
+/// Methods called without an [`HttpContext`] always use the default handler.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{AlpnRouter, NoopHandler};
+///
+/// let handler = AlpnRouter::new(NoopHandler::default()).route("h2", NoopHandler::default());
+/// ```
+#[derive(Clone, Debug)]
+pub struct AlpnRouter<H> {
+    default: H,
+    routes: Vec<(Vec<u8>, H)>,
+}
+
+impl<H> AlpnRouter<H> {
+    /// Create a new router, which passes every request to the default handler until routes are
+    /// added.
+    pub fn new(default: H) -> Self {
+        Self {
+            default,
+            routes: Vec::new(),
+        }
+    }
+
+    /// Pass requests from clients that negotiated the protocol to the handler. Adding a route for
+    /// a protocol that already has one replaces it.
+    pub fn route(mut self, protocol: impl AsRef<[u8]>, handler: H) -> Self {
+        let protocol = protocol.as_ref();
+        self.routes.retain(|(p, _)| p != protocol);
+        self.routes.push((protocol.to_vec(), handler));
+        self
+    }
+
+    fn handler(&self, ctx: &HttpContext) -> &H {
+        ctx.client_alpn
+            .as_deref()
+            .and_then(|alpn| self.routes.iter().find(|(p, _)| p == alpn))
+            .map_or(&self.default, |(_, handler)| handler)
+    }
+
+    fn handler_mut(&mut self, ctx: &HttpContext) -> &mut H {
+        let index = ctx
+            .client_alpn
+            .as_deref()
+            .and_then(|alpn| self.routes.iter().position(|(p, _)| p == alpn));
+
+        match index {
+            Some(index) => &mut self.routes[index].1,
+            None => &mut self.default,
+        }
+    }
+}
+
+delegate_http_handler! {
+    impl<H: HttpHandler> HttpHandler for AlpnRouter<H> => handler, handler_mut {
+        async fn on_connection_close(&mut self, ctx: &ConnContext) {
+            self.default.on_connection_close(ctx).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_ctx, RequestOrResponse};
+    use hyper::{Body, Request, Response};
+
+    #[derive(Clone)]
+    struct NamedHandler(&'static str);
+
+    #[async_trait::async_trait]
+    impl HttpHandler for NamedHandler {
+        async fn handle_request(
+            &mut self,
+            _ctx: &HttpContext,
+            _req: Request<Body>,
+        ) -> RequestOrResponse {
+            Response::new(Body::from(self.0)).into()
+        }
+    }
+
+    fn ctx(client_alpn: Option<&[u8]>) -> HttpContext {
+        HttpContext {
+            request_target_form: crate::TargetForm::Origin,
+            client_alpn: client_alpn.map(<[_]>::to_vec),
+            ..test_ctx()
+        }
+    }
+
+    async fn handled_by(router: &mut AlpnRouter<NamedHandler>, ctx: &HttpContext) -> String {
+        match router.handle_request(ctx, Request::default()).await {
+            RequestOrResponse::Response(res) => {
+                let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+                String::from_utf8(body.to_vec()).unwrap()
+            }
+            _ => panic!("Request was forwarded"),
+        }
+    }
+
+    #[tokio::test]
+    async fn routes_by_alpn() {
+        let mut router = AlpnRouter::new(NamedHandler("default"))
+            .route("h2", NamedHandler("first"))
+            .route("h2", NamedHandler("h2"));
+
+        assert_eq!(handled_by(&mut router, &ctx(Some(b"h2"))).await, "h2");
+        assert_eq!(
+            handled_by(&mut router, &ctx(Some(b"http/1.1"))).await,
+            "default"
+        );
+        assert_eq!(handled_by(&mut router, &ctx(None)).await, "default");
+    }
+}
@@ -0,0 +1,127 @@
+//! Helpers for reading bodies in handlers.
+
+use bytes::{Bytes, BytesMut};
+use futures::{stream, StreamExt};
+use hyper::Body;
+use thiserror::Error;
+
+/// Why a body could not be collected with [`collect_with_limit`].
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum BodyError {
+    /// The body is larger than the limit.
+    #[error("body is larger than {limit} bytes")]
+    TooLarge {
+        /// The limit that was exceeded.
+        limit: usize,
+        /// A body that yields everything read so far followed by the rest of the original body,
+        /// so that it can still be forwarded as is.
+        body: Body,
+    },
+    /// Reading the body failed.
+    #[error("failed to read body")]
+    Network(#[from] hyper::Error),
+}
+
+/// Read a body in full, unless it is larger than `max` bytes.
+///
+/// Unlike [`hyper::body::to_bytes`], this stops reading once the limit is exceeded, so a client
+/// can't make the proxy buffer an arbitrarily large body. The collected bytes can be forwarded by
+/// converting them back into a [`Body`] with [`Body::from`].
+///
+/// # Errors
+///
+/// This will return [`BodyError::TooLarge`] if the body is larger than `max` bytes, with a body
+/// that can be forwarded in its place, or [`BodyError::Network`] if reading the body fails.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{
+///     async_trait::async_trait,
+///     body::{collect_with_limit, BodyError},
+///     hyper::{Body, Request},
+///     HttpContext, HttpHandler, RequestOrResponse,
+/// };
+///
+/// #[derive(Clone)]
+/// pub struct MyHandler;
+///
+/// #[async_trait]
+/// impl HttpHandler for MyHandler {
+///     async fn handle_request(
+///         &mut self,
+///         _ctx: &HttpContext,
+///         req: Request<Body>,
+///     ) -> RequestOrResponse {
+///         let (parts, body) = req.into_parts();
+///
+///         let body = match collect_with_limit(body, 1024 * 1024).await {
+///             Ok(bytes) => {
+///                 // Do something with the body
+///
+///                 Body::from(bytes)
+///             }
+///             Err(BodyError::TooLarge { body, .. }) => body,
+///             Err(_) => Body::empty(),
+///         };
+///
+///         Request::from_parts(parts, body).into()
+///     }
+/// }
+/// ```
+pub async fn collect_with_limit(mut body: Body, max: usize) -> Result<Bytes, BodyError> {
+    let mut buffer = BytesMut::new();
+
+    while let Some(chunk) = body.next().await {
+        buffer.extend_from_slice(&chunk?);
+
+        if buffer.len() > max {
+            let buffered = stream::once(async move { Ok(buffer.freeze()) });
+            return Err(BodyError::TooLarge {
+                limit: max,
+                body: Body::wrap_stream(buffered.chain(body)),
+            });
+        }
+    }
+
+    Ok(buffer.freeze())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::body::to_bytes;
+    use std::convert::Infallible;
+
+    fn chunked(chunks: &'static [&'static str]) -> Body {
+        Body::wrap_stream(stream::iter(
+            chunks.iter().copied().map(Ok::<_, Infallible>),
+        ))
+    }
+
+    #[tokio::test]
+    async fn collects_body_within_limit() {
+        let bytes = collect_with_limit(chunked(&["hello, ", "world"]), 12)
+            .await
+            .unwrap();
+
+        assert_eq!(bytes, "hello, world");
+        assert_eq!(to_bytes(Body::from(bytes)).await.unwrap(), "hello, world");
+    }
+
+    #[tokio::test]
+    async fn rejects_body_over_limit() {
+        let err = collect_with_limit(chunked(&["hello, ", "world", "!"]), 11)
+            .await
+            .unwrap_err();
+
+        match err {
+            BodyError::TooLarge { limit, body } => {
+                assert_eq!(limit, 11);
+                assert_eq!(to_bytes(body).await.unwrap(), "hello, world!");
+            }
+            err => panic!("unexpected error: {}", err),
+        }
+    }
+}
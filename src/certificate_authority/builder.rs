@@ -0,0 +1,324 @@
+use crate::{
+    certificate_authority::{RcgenAuthority, NOT_BEFORE_OFFSET},
+    Error,
+};
+use rand::{thread_rng, Rng};
+use rcgen::{
+    BasicConstraints, DistinguishedName, DnType, IsCa, KeyPair, KeyUsagePurpose, SignatureAlgorithm,
+};
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    time::Duration,
+};
+use time::OffsetDateTime;
+use tokio_rustls::rustls;
+
+const DEFAULT_VALIDITY: Duration = Duration::from_secs(10 * 365 * 24 * 60 * 60);
+const KEY_FILE: &str = "ca.key";
+const CERT_FILE: &str = "ca.cer";
+
+/// Generates a new self-signed root certificate and key in memory, and creates an
+/// [`RcgenAuthority`] using them.
+///
+/// The root certificate can be exported with [`RcgenAuthority::ca_cert_pem`] or
+/// [`RcgenAuthority::ca_cert_der`] so that it can be installed in clients. The key is never
+/// written anywhere, so a new root is generated each time the authority is built.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::certificate_authority::CaBuilder;
+///
+/// let ca = CaBuilder::new()
+///     .with_common_name("My Proxy CA")
+///     .with_organization("My Company")
+///     .build()
+///     .unwrap();
+///
+/// // Install this in clients.
+/// let ca_cert = ca.ca_cert_pem();
+/// ```
+#[cfg_attr(docsrs, doc(cfg(feature = "rcgen-ca")))]
+#[derive(Clone, Debug)]
+pub struct CaBuilder {
+    common_name: String,
+    organization: String,
+    validity: Duration,
+    algorithm: &'static SignatureAlgorithm,
+    cache_size: u64,
+}
+
+impl CaBuilder {
+    /// Create a new builder with the default options.
+    pub fn new() -> Self {
+        Self {
+            common_name: "Hudsucker CA".to_owned(),
+            organization: "Hudsucker".to_owned(),
+            validity: DEFAULT_VALIDITY,
+            algorithm: &rcgen::PKCS_ECDSA_P256_SHA256,
+            cache_size: 1_000,
+        }
+    }
+
+    /// Set the common name of the root certificate. Defaults to `Hudsucker CA`.
+    pub fn with_common_name(mut self, common_name: impl Into<String>) -> Self {
+        self.common_name = common_name.into();
+        self
+    }
+
+    /// Set the organization of the root certificate. Defaults to `Hudsucker`.
+    pub fn with_organization(mut self, organization: impl Into<String>) -> Self {
+        self.organization = organization.into();
+        self
+    }
+
+    /// Set how long the root certificate is valid for. Defaults to 10 years.
+    pub fn with_validity(mut self, validity: Duration) -> Self {
+        self.validity = validity;
+        self
+    }
+
+    /// Set the algorithm of the generated key. Defaults to ECDSA using P-256 and SHA-256.
+    ///
+    /// RSA keys can't be generated, so RSA algorithms cause [`CaBuilder::build`] to fail.
+    pub fn with_key_algorithm(mut self, algorithm: &'static SignatureAlgorithm) -> Self {
+        self.algorithm = algorithm;
+        self
+    }
+
+    /// Set the number of certificates cached in memory by the authority. Defaults to 1000.
+    pub fn with_cache_size(mut self, cache_size: u64) -> Self {
+        self.cache_size = cache_size;
+        self
+    }
+
+    /// Attempts to generate the root certificate and create the authority.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if a key can't be generated for the algorithm.
+    pub fn build(self) -> Result<RcgenAuthority, Error> {
+        let cert = self.generate()?;
+        RcgenAuthority::new(
+            rustls::PrivateKey(cert.serialize_private_key_der()),
+            rustls::Certificate(cert.serialize_der()?),
+            self.cache_size,
+        )
+    }
+
+    /// Attempts to load the root certificate and key from `ca.cer` and `ca.key` in the directory,
+    /// or to generate them and write them there as PEM if neither file exists, and create the
+    /// authority.
+    ///
+    /// This keeps the same root across restarts, so that it only needs to be installed in clients
+    /// once. The directory is created if it doesn't exist, and on Unix the key is only readable by
+    /// its owner. The other options of the builder, other than the cache size, are only used when
+    /// generating a new root.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if the files can't be read or written, if only one of them
+    /// exists, or if they don't contain a valid key and matching certificate.
+    pub fn build_to_disk(self, dir: impl AsRef<Path>) -> Result<RcgenAuthority, Error> {
+        let dir = dir.as_ref();
+        let key_path = dir.join(KEY_FILE);
+        let cert_path = dir.join(CERT_FILE);
+
+        let (private_key, ca_cert) = match (key_path.exists(), cert_path.exists()) {
+            (true, true) => (
+                read_pem(&key_path, "PRIVATE KEY")?,
+                read_pem(&cert_path, "CERTIFICATE")?,
+            ),
+            (false, false) => {
+                // Each serialization signs the certificate again, so it is only serialized once.
+                let cert = self.generate()?;
+                let (private_key, ca_cert) =
+                    (cert.serialize_private_key_der(), cert.serialize_der()?);
+
+                fs::create_dir_all(dir).map_err(Error::CaFile)?;
+                write_pem(&key_path, "PRIVATE KEY", &private_key, 0o600)?;
+                write_pem(&cert_path, "CERTIFICATE", &ca_cert, 0o644)?;
+                (private_key, ca_cert)
+            }
+            _ => {
+                return Err(Error::CaFile(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "only one of the CA key and certificate exists",
+                )))
+            }
+        };
+
+        RcgenAuthority::new(
+            rustls::PrivateKey(private_key),
+            rustls::Certificate(ca_cert),
+            self.cache_size,
+        )
+    }
+
+    fn generate(&self) -> Result<rcgen::Certificate, Error> {
+        let mut params = rcgen::CertificateParams::default();
+        params.serial_number = Some(thread_rng().gen::<u64>().into());
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.key_usages = vec![
+            KeyUsagePurpose::KeyCertSign,
+            KeyUsagePurpose::CrlSign,
+            KeyUsagePurpose::DigitalSignature,
+        ];
+
+        let not_before = OffsetDateTime::now_utc() - time::Duration::seconds(NOT_BEFORE_OFFSET);
+        params.not_before = not_before;
+        params.not_after = time::Duration::try_from(self.validity)
+            .ok()
+            .and_then(|validity| not_before.checked_add(validity))
+            .unwrap_or_else(|| rcgen::date_time_ymd(9999, 12, 31));
+
+        let mut distinguished_name = DistinguishedName::new();
+        distinguished_name.push(DnType::CommonName, self.common_name.as_str());
+        distinguished_name.push(DnType::OrganizationName, self.organization.as_str());
+        params.distinguished_name = distinguished_name;
+
+        params.alg = self.algorithm;
+        params.key_pair = Some(KeyPair::generate(self.algorithm)?);
+
+        Ok(rcgen::Certificate::from_params(params)?)
+    }
+}
+
+/// Generate a root certificate and key with the given validity and key algorithm, and write them
+/// to `ca.cer` and `ca.key` in the directory, or load them if they already exist.
+///
+/// This is shorthand for [`CaBuilder::build_to_disk`], which can also set the names of the root.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use hudsucker::certificate_authority::generate_ca_to_disk;
+/// use std::time::Duration;
+///
+/// let ca = generate_ca_to_disk(
+///     "/var/lib/my-proxy",
+///     Duration::from_secs(365 * 24 * 60 * 60),
+///     &hudsucker::rcgen::PKCS_ECDSA_P256_SHA256,
+/// )
+/// .unwrap();
+/// ```
+///
+/// # Errors
+///
+/// This will return an error if the files can't be read or written, or if they are invalid.
+#[cfg_attr(docsrs, doc(cfg(feature = "rcgen-ca")))]
+pub fn generate_ca_to_disk(
+    dir: impl AsRef<Path>,
+    validity: Duration,
+    algorithm: &'static SignatureAlgorithm,
+) -> Result<RcgenAuthority, Error> {
+    CaBuilder::new()
+        .with_validity(validity)
+        .with_key_algorithm(algorithm)
+        .build_to_disk(dir)
+}
+
+fn read_pem(path: &Path, tag: &str) -> Result<Vec<u8>, Error> {
+    let contents = fs::read(path).map_err(Error::CaFile)?;
+    let pem = pem::parse(contents)
+        .map_err(|e| Error::CaFile(io::Error::new(io::ErrorKind::InvalidData, e)))?;
+
+    if pem.tag() != tag {
+        return Err(Error::CaFile(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("expected {} in {}", tag, path.display()),
+        )));
+    }
+
+    Ok(pem.into_contents())
+}
+
+fn write_pem(path: &Path, tag: &str, contents: &[u8], _mode: u32) -> Result<(), Error> {
+    let contents = pem::encode(&pem::Pem::new(tag, contents));
+
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, _mode);
+
+    options
+        .open(path)
+        .and_then(|mut file| file.write_all(contents.as_bytes()))
+        .map_err(Error::CaFile)
+}
+
+impl Default for CaBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x509_parser::prelude::*;
+
+    #[test]
+    fn generates_root_certificate() {
+        let ca = CaBuilder::new()
+            .with_common_name("Test CA")
+            .with_organization("Test Org")
+            .with_validity(Duration::from_secs(24 * 60 * 60))
+            .build()
+            .unwrap();
+
+        let (_, cert) = parse_x509_certificate(&ca.ca_cert_der().0).unwrap();
+        let subject = cert.subject();
+
+        assert!(cert.is_ca());
+        assert_eq!(
+            subject.iter_common_name().next().unwrap().as_str().unwrap(),
+            "Test CA"
+        );
+        assert_eq!(
+            subject
+                .iter_organization()
+                .next()
+                .unwrap()
+                .as_str()
+                .unwrap(),
+            "Test Org"
+        );
+        assert_eq!(
+            cert.validity().time_to_expiration().unwrap().whole_hours(),
+            23
+        );
+    }
+
+    #[test]
+    fn exports_pem() {
+        let ca = CaBuilder::new().build().unwrap();
+        let certs = rustls_pemfile::certs(&mut ca.ca_cert_pem().as_bytes()).unwrap();
+
+        assert_eq!(certs, vec![ca.ca_cert_der().0.clone()]);
+    }
+
+    #[test]
+    fn error_for_partial_files() {
+        let dir = std::env::temp_dir().join(format!("hudsucker-ca-partial-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(CERT_FILE), "").unwrap();
+
+        let result = CaBuilder::new().build_to_disk(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(matches!(result, Err(Error::CaFile(_))));
+    }
+
+    #[test]
+    fn error_for_rsa() {
+        let result = CaBuilder::new()
+            .with_key_algorithm(&rcgen::PKCS_RSA_SHA256)
+            .build();
+
+        assert!(result.is_err());
+    }
+}
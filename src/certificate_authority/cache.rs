@@ -0,0 +1,64 @@
+use crate::certificate_authority::{CertCache, CACHE_TTL};
+use async_trait::async_trait;
+use http::uri::Authority;
+use moka::future::Cache;
+use std::time::Duration;
+use tokio_rustls::rustls;
+
+/// An in-memory [`CertCache`].
+///
+/// Certificates are evicted once the cache reaches its max size, or once they have been cached for
+/// half of their validity period. This is the cache used by the built-in certificate authorities
+/// by default.
+#[cfg_attr(docsrs, doc(cfg(any(feature = "rcgen-ca", feature = "openssl-ca"))))]
+#[derive(Clone)]
+pub struct MemoryCertCache(Cache<Authority, rustls::Certificate>);
+
+impl MemoryCertCache {
+    /// Create a new cache holding up to `max_capacity` certificates.
+    pub fn new(max_capacity: u64) -> Self {
+        Self(
+            Cache::builder()
+                .max_capacity(max_capacity)
+                .time_to_live(Duration::from_secs(CACHE_TTL))
+                .build(),
+        )
+    }
+}
+
+#[async_trait]
+impl CertCache for MemoryCertCache {
+    async fn get(&self, authority: &Authority) -> Option<rustls::Certificate> {
+        self.0.get(authority)
+    }
+
+    async fn insert(&self, authority: Authority, cert: rustls::Certificate) {
+        self.0.insert(authority, cert).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn caches_certificates() {
+        let cache = MemoryCertCache::new(10);
+        let authority = Authority::from_static("example.com");
+
+        assert!(cache.get(&authority).await.is_none());
+
+        cache
+            .insert(authority.clone(), rustls::Certificate(vec![1, 2, 3]))
+            .await;
+
+        assert_eq!(
+            cache.get(&authority).await,
+            Some(rustls::Certificate(vec![1, 2, 3]))
+        );
+        assert!(cache
+            .get(&Authority::from_static("example2.com"))
+            .await
+            .is_none());
+    }
+}
@@ -0,0 +1,56 @@
+use std::{sync::Arc, time::SystemTime};
+use tokio_rustls::rustls::{
+    server::{ClientCertVerified, ClientCertVerifier},
+    Certificate, DistinguishedName, Error,
+};
+
+/// Whether generated server configs request a certificate from clients, set with
+/// [`RcgenAuthority::with_request_client_cert`](crate::certificate_authority::RcgenAuthority::with_request_client_cert).
+///
+/// Presented certificates are not checked against any trust anchors, only that the client holds
+/// the private key of the certificate. They are available to handlers in
+/// [`HttpContext::client_cert`](crate::HttpContext::client_cert).
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+pub enum ClientCertMode {
+    /// Don't request a certificate.
+    #[default]
+    None,
+    /// Request a certificate, but accept clients that don't present one.
+    Optional,
+    /// Request a certificate, and reject clients that don't present one.
+    Required,
+}
+
+impl ClientCertMode {
+    pub(crate) fn verifier(self) -> Option<Arc<dyn ClientCertVerifier>> {
+        match self {
+            Self::None => None,
+            Self::Optional => Some(Arc::new(CaptureClientCert { mandatory: false })),
+            Self::Required => Some(Arc::new(CaptureClientCert { mandatory: true })),
+        }
+    }
+}
+
+/// Accepts any certificate presented by a client, so that it can be captured.
+struct CaptureClientCert {
+    mandatory: bool,
+}
+
+impl ClientCertVerifier for CaptureClientCert {
+    fn client_auth_mandatory(&self) -> bool {
+        self.mandatory
+    }
+
+    fn client_auth_root_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _now: SystemTime,
+    ) -> Result<ClientCertVerified, Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+}
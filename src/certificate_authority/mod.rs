@@ -1,13 +1,28 @@
+#[cfg(feature = "rcgen-ca")]
+mod builder;
+#[cfg(any(feature = "rcgen-ca", feature = "openssl-ca"))]
+mod cache;
+#[cfg(feature = "rcgen-ca")]
+mod client_cert;
+#[cfg(feature = "rcgen-ca")]
+mod ocsp;
 #[cfg(feature = "openssl-ca")]
 mod openssl_authority;
 #[cfg(feature = "rcgen-ca")]
 mod rcgen_authority;
 
+use crate::TlsFault;
 use async_trait::async_trait;
 use http::uri::Authority;
-use std::sync::Arc;
-use tokio_rustls::rustls::ServerConfig;
+use std::{sync::Arc, time::Duration};
+use tokio_rustls::rustls::{self, ServerConfig, SignatureAlgorithm};
 
+#[cfg(feature = "rcgen-ca")]
+pub use builder::{generate_ca_to_disk, CaBuilder};
+#[cfg(any(feature = "rcgen-ca", feature = "openssl-ca"))]
+pub use cache::MemoryCertCache;
+#[cfg(feature = "rcgen-ca")]
+pub use client_cert::ClientCertMode;
 #[cfg(feature = "openssl-ca")]
 pub use openssl_authority::*;
 #[cfg(feature = "rcgen-ca")]
@@ -24,5 +39,85 @@ const NOT_BEFORE_OFFSET: i64 = 60;
 #[async_trait]
 pub trait CertificateAuthority: Send + Sync + 'static {
     /// Generate ServerConfig for use with rustls.
+    ///
+    /// The authority is the target of the CONNECT request, and may include a port. Certificates
+    /// should only be issued for its host, as ports are not part of a certificate's names.
     async fn gen_server_config(&self, authority: &Authority) -> Arc<ServerConfig>;
+
+    /// Generate ServerConfig for use with rustls, along with details of how its certificate was
+    /// obtained.
+    ///
+    /// The details are sent as [`ProxyEventKind::CertServed`] events to the channel created with
+    /// [`ProxyBuilder::with_event_channel`]. Defaults to calling
+    /// [`CertificateAuthority::gen_server_config`] without any details.
+    ///
+    /// [`ProxyEventKind::CertServed`]: crate::ProxyEventKind::CertServed
+    /// [`ProxyBuilder::with_event_channel`]: crate::ProxyBuilder::with_event_channel
+    async fn gen_server_config_with_info(
+        &self,
+        authority: &Authority,
+    ) -> (Arc<ServerConfig>, Option<CertInfo>) {
+        (self.gen_server_config(authority).await, None)
+    }
+
+    /// The root certificate of the authority, DER encoded, if it is known.
+    ///
+    /// This is served to clients by [`ProxyBuilder::with_ca_cert_endpoint`], so that they can
+    /// install it. Defaults to `None`.
+    ///
+    /// [`ProxyBuilder::with_ca_cert_endpoint`]: crate::ProxyBuilder::with_ca_cert_endpoint
+    fn ca_cert(&self) -> Option<rustls::Certificate> {
+        None
+    }
+
+    /// Generate a ServerConfig with an intentionally invalid certificate, for the faults injected
+    /// by [`ProxyBuilder::with_tls_faults`].
+    ///
+    /// Returns `None` if the fault isn't supported, in which case the handshake proceeds with a
+    /// valid certificate. Certificates generated for faults should not be cached. Defaults to
+    /// `None`.
+    ///
+    /// [`ProxyBuilder::with_tls_faults`]: crate::ProxyBuilder::with_tls_faults
+    async fn gen_faulty_server_config(
+        &self,
+        _authority: &Authority,
+        _fault: TlsFault,
+    ) -> Option<Arc<ServerConfig>> {
+        None
+    }
+}
+
+/// Details of how the certificate of a server config was obtained, returned by
+/// [`CertificateAuthority::gen_server_config_with_info`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CertInfo {
+    /// Whether the certificate was taken from the cache.
+    pub from_cache: bool,
+    /// How long it took to generate the certificate, if it wasn't taken from the cache.
+    pub gen_duration: Option<Duration>,
+    /// Algorithm of the key that the certificate was issued for.
+    pub key_algo: SignatureAlgorithm,
+}
+
+/// The signature algorithm of a private key.
+#[cfg(any(feature = "rcgen-ca", feature = "openssl-ca"))]
+fn key_algorithm(private_key: &rustls::PrivateKey) -> SignatureAlgorithm {
+    rustls::sign::any_supported_type(private_key)
+        .expect("Failed to parse private key")
+        .algorithm()
+}
+
+/// A cache for certificates generated by a certificate authority.
+///
+/// Implementing this allows certificates to be stored outside of the process, for example to share
+/// them between multiple instances of a proxy using the same CA. Certificates are generated using
+/// the CA's private key, so they can only be shared between instances using the same key.
+/// Certificates are valid for one year, and should be evicted before then.
+#[async_trait]
+pub trait CertCache: Send + Sync + 'static {
+    /// Get the cached certificate for the given authority.
+    async fn get(&self, authority: &Authority) -> Option<rustls::Certificate>;
+
+    /// Cache the certificate generated for the given authority.
+    async fn insert(&self, authority: Authority, cert: rustls::Certificate);
 }
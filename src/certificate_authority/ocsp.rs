@@ -0,0 +1,161 @@
+//! Minimal encoder for OCSP responses stating that a certificate is good, as defined in RFC 6960.
+
+use ring::digest::{digest, SHA1_FOR_LEGACY_USE_ONLY};
+use time::{format_description::FormatItem, macros::format_description, OffsetDateTime};
+use tokio_rustls::rustls::{self, sign, SignatureScheme};
+
+const SEQUENCE: u8 = 0x30;
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const NULL: u8 = 0x05;
+const ENUMERATED: u8 = 0x0a;
+const GENERALIZED_TIME: u8 = 0x18;
+
+const ID_PKIX_OCSP_BASIC: &[u8] = &[
+    0x06, 0x09, 0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x30, 0x01, 0x01,
+];
+const ID_SHA1: &[u8] = &[0x06, 0x05, 0x2b, 0x0e, 0x03, 0x02, 0x1a];
+const SHA256_WITH_RSA_ENCRYPTION: &[u8] = &[
+    0x06, 0x09, 0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b,
+];
+const ECDSA_WITH_SHA256: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x02];
+const ECDSA_WITH_SHA384: &[u8] = &[0x06, 0x08, 0x2a, 0x86, 0x48, 0xce, 0x3d, 0x04, 0x03, 0x03];
+const ED25519: &[u8] = &[0x06, 0x03, 0x2b, 0x65, 0x70];
+
+const GENERALIZED_TIME_FORMAT: &[FormatItem<'_>] =
+    format_description!("[year][month][day][hour][minute][second]Z");
+
+fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes = len.to_be_bytes();
+        let bytes = &bytes[bytes.iter().take_while(|&&b| b == 0).count()..];
+        out.push(0x80 | bytes.len() as u8);
+        out.extend_from_slice(bytes);
+    }
+
+    out.extend_from_slice(content);
+    out
+}
+
+fn sequence(items: &[&[u8]]) -> Vec<u8> {
+    der(SEQUENCE, &items.concat())
+}
+
+fn explicit(tag: u8, content: &[u8]) -> Vec<u8> {
+    der(0xa0 | tag, content)
+}
+
+fn generalized_time(time: OffsetDateTime) -> Vec<u8> {
+    let time = time
+        .format(GENERALIZED_TIME_FORMAT)
+        .expect("Failed to format time");
+    der(GENERALIZED_TIME, time.as_bytes())
+}
+
+fn sha1(data: &[u8]) -> Vec<u8> {
+    der(
+        OCTET_STRING,
+        digest(&SHA1_FOR_LEGACY_USE_ONLY, data).as_ref(),
+    )
+}
+
+fn signature_algorithm(scheme: SignatureScheme) -> Option<Vec<u8>> {
+    match scheme {
+        SignatureScheme::RSA_PKCS1_SHA256 => {
+            Some(sequence(&[SHA256_WITH_RSA_ENCRYPTION, &der(NULL, &[])]))
+        }
+        SignatureScheme::ECDSA_NISTP256_SHA256 => Some(sequence(&[ECDSA_WITH_SHA256])),
+        SignatureScheme::ECDSA_NISTP384_SHA384 => Some(sequence(&[ECDSA_WITH_SHA384])),
+        SignatureScheme::ED25519 => Some(sequence(&[ED25519])),
+        _ => None,
+    }
+}
+
+/// Build a DER encoded OCSP response stating that `cert` has not been revoked, signed by the CA.
+///
+/// Returns `None` if either certificate can't be parsed, or the key can't be used for signing.
+pub(crate) fn good_response(
+    cert: &rustls::Certificate,
+    ca_cert: &rustls::Certificate,
+    ca_key: &rustls::PrivateKey,
+    this_update: OffsetDateTime,
+    next_update: OffsetDateTime,
+) -> Option<Vec<u8>> {
+    let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).ok()?;
+    let (_, ca_cert) = x509_parser::parse_x509_certificate(&ca_cert.0).ok()?;
+
+    let signer = sign::any_supported_type(ca_key).ok()?.choose_scheme(&[
+        SignatureScheme::ECDSA_NISTP256_SHA256,
+        SignatureScheme::ECDSA_NISTP384_SHA384,
+        SignatureScheme::ED25519,
+        SignatureScheme::RSA_PKCS1_SHA256,
+    ])?;
+    let signature_algorithm = signature_algorithm(signer.scheme())?;
+
+    let issuer_key_hash = sha1(&ca_cert.public_key().subject_public_key.data);
+    let cert_id = sequence(&[
+        &sequence(&[ID_SHA1, &der(NULL, &[])]),
+        &sha1(ca_cert.subject().as_raw()),
+        &issuer_key_hash,
+        &der(INTEGER, cert.raw_serial()),
+    ]);
+
+    let single_response = sequence(&[
+        &cert_id,
+        // `good` is an implicitly tagged NULL.
+        &[0x80, 0x00],
+        &generalized_time(this_update),
+        &explicit(0, &generalized_time(next_update)),
+    ]);
+
+    let response_data = sequence(&[
+        // The responder is identified by the hash of the CA's public key.
+        &explicit(2, &issuer_key_hash),
+        &generalized_time(this_update),
+        &sequence(&[&single_response]),
+    ]);
+
+    let signature = signer.sign(&response_data).ok()?;
+    let mut signature_bits = vec![0];
+    signature_bits.extend_from_slice(&signature);
+
+    let basic_response = sequence(&[
+        &response_data,
+        &signature_algorithm,
+        &der(BIT_STRING, &signature_bits),
+    ]);
+
+    Some(sequence(&[
+        // `successful` response status.
+        &der(ENUMERATED, &[0]),
+        &explicit(
+            0,
+            &sequence(&[ID_PKIX_OCSP_BASIC, &der(OCTET_STRING, &basic_response)]),
+        ),
+    ]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_long_lengths() {
+        assert_eq!(der(OCTET_STRING, &[0; 3]), vec![0x04, 0x03, 0, 0, 0]);
+        assert_eq!(der(OCTET_STRING, &[0; 200])[..3], [0x04, 0x81, 200]);
+        assert_eq!(der(OCTET_STRING, &[0; 300])[..4], [0x04, 0x82, 0x01, 0x2c]);
+    }
+
+    #[test]
+    fn formats_generalized_time() {
+        let time = OffsetDateTime::from_unix_timestamp(1_700_000_000).unwrap();
+
+        assert_eq!(generalized_time(time)[2..], *b"20231114221320Z");
+    }
+}
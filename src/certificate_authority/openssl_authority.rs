@@ -1,7 +1,9 @@
-use crate::certificate_authority::{CertificateAuthority, CACHE_TTL, NOT_BEFORE_OFFSET, TTL_SECS};
+use crate::certificate_authority::{
+    key_algorithm, CertCache, CertInfo, CertificateAuthority, MemoryCertCache, NOT_BEFORE_OFFSET,
+    TTL_SECS,
+};
 use async_trait::async_trait;
 use http::uri::Authority;
-use moka::future::Cache;
 use openssl::{
     asn1::{Asn1Integer, Asn1Time},
     bn::BigNum,
@@ -13,7 +15,7 @@ use openssl::{
 };
 use std::{
     sync::Arc,
-    time::{Duration, SystemTime},
+    time::{Instant, SystemTime},
 };
 use tokio_rustls::rustls::{self, ServerConfig};
 use tracing::debug;
@@ -21,8 +23,8 @@ use tracing::debug;
 /// Issues certificates for use when communicating with clients.
 ///
 /// Issues certificates for communicating with clients over TLS. Certificates are cached in memory
-/// up to a max size that is provided when creating the authority. Certificates are generated using
-/// the `openssl` crate.
+/// up to a max size that is provided when creating the authority, unless a custom cache is set
+/// with [`OpensslAuthority::with_cache`]. Certificates are generated using the `openssl` crate.
 ///
 /// # Examples
 ///
@@ -46,7 +48,7 @@ pub struct OpensslAuthority {
     private_key: rustls::PrivateKey,
     ca_cert: X509,
     hash: MessageDigest,
-    cache: Cache<Authority, Arc<ServerConfig>>,
+    cache: Arc<dyn CertCache>,
 }
 
 impl OpensslAuthority {
@@ -62,14 +64,18 @@ impl OpensslAuthority {
             private_key,
             ca_cert,
             hash,
-            cache: Cache::builder()
-                .max_capacity(cache_size)
-                .time_to_live(Duration::from_secs(CACHE_TTL))
-                .build(),
+            cache: Arc::new(MemoryCertCache::new(cache_size)),
         }
     }
 
+    /// Set the cache used to store generated certificates, replacing the in-memory cache.
+    pub fn with_cache(mut self, cache: Box<dyn CertCache>) -> Self {
+        self.cache = Arc::from(cache);
+        self
+    }
+
     fn gen_cert(&self, authority: &Authority) -> Result<rustls::Certificate, ErrorStack> {
+        // The port is only used to route the request, and can't be part of the certificate.
         let mut name_builder = X509NameBuilder::new()?;
         name_builder.append_entry_by_text("CN", authority.host())?;
         let name = name_builder.build();
@@ -110,15 +116,35 @@ impl OpensslAuthority {
 #[async_trait]
 impl CertificateAuthority for OpensslAuthority {
     async fn gen_server_config(&self, authority: &Authority) -> Arc<ServerConfig> {
-        if let Some(server_cfg) = self.cache.get(authority) {
-            debug!("Using cached server config");
-            return server_cfg;
-        }
-        debug!("Generating server config");
+        self.gen_server_config_with_info(authority).await.0
+    }
 
-        let certs = vec![self
-            .gen_cert(authority)
-            .unwrap_or_else(|_| panic!("Failed to generate certificate for {}", authority))];
+    async fn gen_server_config_with_info(
+        &self,
+        authority: &Authority,
+    ) -> (Arc<ServerConfig>, Option<CertInfo>) {
+        let (cert, gen_duration) = match self.cache.get(authority).await {
+            Some(cert) => {
+                debug!("Using cached certificate");
+                (cert, None)
+            }
+            None => {
+                debug!("Generating certificate");
+                let start = Instant::now();
+                let cert = self
+                    .gen_cert(authority)
+                    .unwrap_or_else(|_| panic!("Failed to generate certificate for {}", authority));
+                let gen_duration = start.elapsed();
+                self.cache.insert(authority.clone(), cert.clone()).await;
+                (cert, Some(gen_duration))
+            }
+        };
+        let info = CertInfo {
+            from_cache: gen_duration.is_none(),
+            gen_duration,
+            key_algo: key_algorithm(&self.private_key),
+        };
+        let certs = vec![cert];
 
         let mut server_cfg = ServerConfig::builder()
             .with_safe_defaults()
@@ -132,19 +158,18 @@ impl CertificateAuthority for OpensslAuthority {
             b"http/1.1".to_vec(),
         ];
 
-        let server_cfg = Arc::new(server_cfg);
-
-        self.cache
-            .insert(authority.clone(), Arc::clone(&server_cfg))
-            .await;
+        (Arc::new(server_cfg), Some(info))
+    }
 
-        server_cfg
+    fn ca_cert(&self) -> Option<rustls::Certificate> {
+        self.ca_cert.to_der().ok().map(rustls::Certificate)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use x509_parser::extensions::GeneralName;
 
     fn init_ca(cache_size: u64) -> OpensslAuthority {
         let private_key_bytes: &[u8] = include_bytes!("../../examples/ca/hudsucker.key");
@@ -156,6 +181,24 @@ mod tests {
         OpensslAuthority::new(private_key, ca_cert, MessageDigest::sha256(), cache_size)
     }
 
+    #[test]
+    fn omits_port_from_names() {
+        let ca = init_ca(0);
+        let cert = ca
+            .gen_cert(&Authority::from_static("example.com:8443"))
+            .unwrap();
+        let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).unwrap();
+
+        let common_name = cert.subject().iter_common_name().next().unwrap();
+        assert_eq!(common_name.as_str().unwrap(), "example.com");
+
+        let san = cert.subject_alternative_name().unwrap().unwrap();
+        assert_eq!(
+            san.value.general_names,
+            vec![GeneralName::DNSName("example.com")]
+        );
+    }
+
     #[test]
     fn unique_serial_numbers() {
         let ca = init_ca(0);
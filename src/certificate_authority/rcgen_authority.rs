@@ -1,22 +1,30 @@
 use crate::{
-    certificate_authority::{CertificateAuthority, CACHE_TTL, NOT_BEFORE_OFFSET, TTL_SECS},
-    Error,
+    certificate_authority::{
+        key_algorithm, ocsp, CertCache, CertInfo, CertificateAuthority, ClientCertMode,
+        MemoryCertCache, NOT_BEFORE_OFFSET, TTL_SECS,
+    },
+    Error, TlsFault,
 };
 use async_trait::async_trait;
 use http::uri::Authority;
-use moka::future::Cache;
 use rand::{thread_rng, Rng};
-use rcgen::{DistinguishedName, DnType, KeyPair, RcgenError, SanType};
-use std::sync::Arc;
+use rcgen::{
+    DistinguishedName, DnType, ExtendedKeyUsagePurpose, KeyPair, KeyUsagePurpose, RcgenError,
+    SanType,
+};
+use std::{sync::Arc, time::Instant};
 use time::{Duration, OffsetDateTime};
-use tokio_rustls::rustls::{self, ServerConfig};
-use tracing::debug;
+use tokio_rustls::rustls::{self, ServerConfig, SupportedCipherSuite, SupportedProtocolVersion};
+use tracing::{debug, warn};
+
+/// Host that certificates are issued for when injecting [`TlsFault::WrongHostCertificate`].
+const WRONG_HOST: &str = "wrong-host.hudsucker.invalid";
 
 /// Issues certificates for use when communicating with clients.
 ///
 /// Issues certificates for communicating with clients over TLS. Certificates are cached in memory
-/// up to a max size that is provided when creating the authority. Certificates are generated using
-/// the `rcgen` crate.
+/// up to a max size that is provided when creating the authority, unless a custom cache is set
+/// with [`RcgenAuthority::with_cache`]. Certificates are generated using the `rcgen` crate.
 ///
 /// # Examples
 ///
@@ -44,7 +52,14 @@ use tracing::debug;
 pub struct RcgenAuthority {
     private_key: rustls::PrivateKey,
     ca_cert: rustls::Certificate,
-    cache: Cache<Authority, Arc<ServerConfig>>,
+    cache: Arc<dyn CertCache>,
+    key_usages: Option<Vec<KeyUsagePurpose>>,
+    extended_key_usages: Vec<ExtendedKeyUsagePurpose>,
+    ocsp_stapling: bool,
+    min_tls_version: Option<&'static SupportedProtocolVersion>,
+    max_tls_version: Option<&'static SupportedProtocolVersion>,
+    cipher_suites: Option<Vec<SupportedCipherSuite>>,
+    client_cert_mode: ClientCertMode,
 }
 
 impl RcgenAuthority {
@@ -62,31 +77,173 @@ impl RcgenAuthority {
         let ca = Self {
             private_key,
             ca_cert,
-            cache: Cache::builder()
-                .max_capacity(cache_size)
-                .time_to_live(std::time::Duration::from_secs(CACHE_TTL))
-                .build(),
+            cache: Arc::new(MemoryCertCache::new(cache_size)),
+            key_usages: None,
+            extended_key_usages: vec![ExtendedKeyUsagePurpose::ServerAuth],
+            ocsp_stapling: false,
+            min_tls_version: None,
+            max_tls_version: None,
+            cipher_suites: None,
+            client_cert_mode: ClientCertMode::None,
         };
 
         ca.validate()?;
         Ok(ca)
     }
 
+    /// Set the cache used to store generated certificates, replacing the in-memory cache.
+    pub fn with_cache(mut self, cache: Box<dyn CertCache>) -> Self {
+        self.cache = Arc::from(cache);
+        self
+    }
+
+    /// Set the key usages of generated certificates.
+    ///
+    /// Defaults to `digitalSignature`, plus `keyEncipherment` if the key is an RSA key.
+    pub fn with_key_usages(mut self, key_usages: Vec<KeyUsagePurpose>) -> Self {
+        self.key_usages = Some(key_usages);
+        self
+    }
+
+    /// Set the extended key usages of generated certificates.
+    ///
+    /// Defaults to `serverAuth`. If empty, the extension is omitted.
+    pub fn with_extended_key_usages(
+        mut self,
+        extended_key_usages: Vec<ExtendedKeyUsagePurpose>,
+    ) -> Self {
+        self.extended_key_usages = extended_key_usages;
+        self
+    }
+
+    /// Set whether to staple an OCSP response to generated server configs. Defaults to `false`.
+    ///
+    /// The response is produced and signed by the authority itself, and states that the
+    /// certificate has not been revoked. It is only useful for clients that trust the authority and
+    /// check for stapled responses. A new response is signed each time a server config is built.
+    pub fn with_ocsp_stapling(mut self, ocsp_stapling: bool) -> Self {
+        self.ocsp_stapling = ocsp_stapling;
+        self
+    }
+
+    /// Set the lowest TLS version accepted from clients by generated server configs, such as
+    /// [`rustls::version::TLS13`] to only accept TLS 1.3. Defaults to the lowest version supported
+    /// by rustls.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if the version is higher than the one set with
+    /// [`RcgenAuthority::with_max_tls_version`].
+    pub fn with_min_tls_version(mut self, version: &'static SupportedProtocolVersion) -> Self {
+        self.min_tls_version = Some(version);
+        assert!(
+            !self.tls_versions().is_empty(),
+            "Minimum TLS version is higher than the maximum"
+        );
+        self
+    }
+
+    /// Set the highest TLS version accepted from clients by generated server configs, such as
+    /// [`rustls::version::TLS12`] to only accept TLS 1.2. Defaults to the highest version
+    /// supported by rustls.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if the version is lower than the one set with
+    /// [`RcgenAuthority::with_min_tls_version`].
+    pub fn with_max_tls_version(mut self, version: &'static SupportedProtocolVersion) -> Self {
+        self.max_tls_version = Some(version);
+        assert!(
+            !self.tls_versions().is_empty(),
+            "Maximum TLS version is lower than the minimum"
+        );
+        self
+    }
+
+    /// Set the cipher suites offered to clients by generated server configs, in order of
+    /// preference. The order of the client's suites is ignored, so the first suite supported by
+    /// the client is negotiated. Defaults to the safe defaults of rustls, in the client's order.
+    ///
+    /// At least one of the suites must be usable with the enabled TLS versions.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if none of the suites can be used with the key of the authority,
+    /// such as if only TLS 1.2 suites for ECDSA keys are given for an RSA key.
+    pub fn with_cipher_suites(
+        mut self,
+        cipher_suites: Vec<SupportedCipherSuite>,
+    ) -> Result<Self, Error> {
+        let key = rustls::sign::any_supported_type(&self.private_key)
+            .expect("Failed to parse private key");
+        let compatible = cipher_suites.iter().any(|suite| match suite {
+            SupportedCipherSuite::Tls12(suite) => key.choose_scheme(suite.sign).is_some(),
+            SupportedCipherSuite::Tls13(_) => true,
+        });
+
+        if !compatible {
+            return Err(Error::IncompatibleCipherSuites);
+        }
+
+        self.cipher_suites = Some(cipher_suites);
+        Ok(self)
+    }
+
+    /// Set whether generated server configs request a certificate from clients, so that the
+    /// certificates presented by clients can be inspected. Defaults to [`ClientCertMode::None`].
+    pub fn with_request_client_cert(mut self, mode: ClientCertMode) -> Self {
+        self.client_cert_mode = mode;
+        self
+    }
+
+    /// The root certificate of the authority, DER encoded.
+    pub fn ca_cert_der(&self) -> &rustls::Certificate {
+        &self.ca_cert
+    }
+
+    /// The root certificate of the authority, PEM encoded, for installing in clients.
+    pub fn ca_cert_pem(&self) -> String {
+        pem::encode(&pem::Pem::new("CERTIFICATE", self.ca_cert.0.clone()))
+    }
+
+    fn gen_ocsp_response(&self, cert: &rustls::Certificate) -> Vec<u8> {
+        let this_update = OffsetDateTime::now_utc() - Duration::seconds(NOT_BEFORE_OFFSET);
+        let next_update = this_update + Duration::seconds(TTL_SECS);
+
+        ocsp::good_response(
+            cert,
+            &self.ca_cert,
+            &self.private_key,
+            this_update,
+            next_update,
+        )
+        .unwrap_or_else(|| {
+            warn!("Failed to generate OCSP response");
+            Vec::new()
+        })
+    }
+
     fn gen_cert(&self, authority: &Authority) -> rustls::Certificate {
+        let not_before = OffsetDateTime::now_utc() - Duration::seconds(NOT_BEFORE_OFFSET);
+
+        // The port is only used to route the request, and can't be part of the certificate.
+        self.gen_cert_for(authority.host(), not_before)
+    }
+
+    fn gen_cert_for(&self, host: &str, not_before: OffsetDateTime) -> rustls::Certificate {
         let mut params = rcgen::CertificateParams::default();
         params.serial_number = Some(thread_rng().gen::<u64>().into());
 
-        let not_before = OffsetDateTime::now_utc() - Duration::seconds(NOT_BEFORE_OFFSET);
         params.not_before = not_before;
         params.not_after = not_before + Duration::seconds(TTL_SECS);
 
         let mut distinguished_name = DistinguishedName::new();
-        distinguished_name.push(DnType::CommonName, authority.host());
+        distinguished_name.push(DnType::CommonName, host);
         params.distinguished_name = distinguished_name;
 
         params
             .subject_alt_names
-            .push(SanType::DnsName(authority.host().to_owned()));
+            .push(SanType::DnsName(host.to_owned()));
 
         let key_pair = KeyPair::from_der(&self.private_key.0).expect("Failed to parse private key");
         params.alg = key_pair
@@ -95,6 +252,16 @@ impl RcgenAuthority {
             .expect("Failed to find compatible algorithm");
         params.key_pair = Some(key_pair);
 
+        params.key_usages = match &self.key_usages {
+            Some(key_usages) => key_usages.clone(),
+            None if is_rsa(params.alg) => vec![
+                KeyUsagePurpose::DigitalSignature,
+                KeyUsagePurpose::KeyEncipherment,
+            ],
+            None => vec![KeyUsagePurpose::DigitalSignature],
+        };
+        params.extended_key_usages = self.extended_key_usages.clone();
+
         let key_pair = KeyPair::from_der(&self.private_key.0).expect("Failed to parse private key");
 
         let ca_cert_params = rcgen::CertificateParams::from_ca_cert_der(&self.ca_cert.0, key_pair)
@@ -109,6 +276,64 @@ impl RcgenAuthority {
         )
     }
 
+    fn server_config(&self, cert: rustls::Certificate) -> Arc<ServerConfig> {
+        let ocsp = if self.ocsp_stapling {
+            self.gen_ocsp_response(&cert)
+        } else {
+            Vec::new()
+        };
+
+        let builder = ServerConfig::builder();
+        let builder = match &self.cipher_suites {
+            Some(cipher_suites) => builder.with_cipher_suites(cipher_suites),
+            None => builder.with_safe_default_cipher_suites(),
+        };
+
+        let builder = builder
+            .with_safe_default_kx_groups()
+            .with_protocol_versions(&self.tls_versions())
+            .expect("Failed to build ServerConfig");
+        let builder = match self.client_cert_mode.verifier() {
+            Some(verifier) => builder.with_client_cert_verifier(verifier),
+            None => builder.with_no_client_auth(),
+        };
+
+        let mut server_cfg = builder
+            .with_single_cert_with_ocsp_and_sct(
+                vec![cert],
+                self.private_key.clone(),
+                ocsp,
+                Vec::new(),
+            )
+            .expect("Failed to build ServerConfig");
+
+        server_cfg.ignore_client_order = self.cipher_suites.is_some();
+        server_cfg.alpn_protocols = vec![
+            #[cfg(feature = "http2")]
+            b"h2".to_vec(),
+            b"http/1.1".to_vec(),
+        ];
+
+        Arc::new(server_cfg)
+    }
+
+    /// The TLS versions supported by rustls within the range set on the authority.
+    fn tls_versions(&self) -> Vec<&'static SupportedProtocolVersion> {
+        let number = |version: &SupportedProtocolVersion| version.version.get_u16();
+
+        rustls::ALL_VERSIONS
+            .iter()
+            .copied()
+            .filter(|&version| {
+                self.min_tls_version
+                    .is_none_or(|min| number(version) >= number(min))
+                    && self
+                        .max_tls_version
+                        .is_none_or(|max| number(version) <= number(max))
+            })
+            .collect()
+    }
+
     fn validate(&self) -> Result<(), RcgenError> {
         let key_pair = rcgen::KeyPair::from_der(&self.private_key.0)?;
         rcgen::CertificateParams::from_ca_cert_der(&self.ca_cert.0, key_pair)?;
@@ -116,36 +341,70 @@ impl RcgenAuthority {
     }
 }
 
+fn is_rsa(alg: &rcgen::SignatureAlgorithm) -> bool {
+    [
+        &rcgen::PKCS_RSA_SHA256,
+        &rcgen::PKCS_RSA_SHA384,
+        &rcgen::PKCS_RSA_SHA512,
+    ]
+    .contains(&alg)
+}
+
 #[async_trait]
 impl CertificateAuthority for RcgenAuthority {
     async fn gen_server_config(&self, authority: &Authority) -> Arc<ServerConfig> {
-        if let Some(server_cfg) = self.cache.get(authority) {
-            debug!("Using cached server config");
-            return server_cfg;
-        }
-        debug!("Generating server config");
-
-        let certs = vec![self.gen_cert(authority)];
+        self.gen_server_config_with_info(authority).await.0
+    }
 
-        let mut server_cfg = ServerConfig::builder()
-            .with_safe_defaults()
-            .with_no_client_auth()
-            .with_single_cert(certs, self.private_key.clone())
-            .expect("Failed to build ServerConfig");
+    async fn gen_server_config_with_info(
+        &self,
+        authority: &Authority,
+    ) -> (Arc<ServerConfig>, Option<CertInfo>) {
+        let (cert, gen_duration) = match self.cache.get(authority).await {
+            Some(cert) => {
+                debug!("Using cached certificate");
+                (cert, None)
+            }
+            None => {
+                debug!("Generating certificate");
+                let start = Instant::now();
+                let cert = self.gen_cert(authority);
+                let gen_duration = start.elapsed();
+                self.cache.insert(authority.clone(), cert.clone()).await;
+                (cert, Some(gen_duration))
+            }
+        };
+        let info = CertInfo {
+            from_cache: gen_duration.is_none(),
+            gen_duration,
+            key_algo: key_algorithm(&self.private_key),
+        };
 
-        server_cfg.alpn_protocols = vec![
-            #[cfg(feature = "http2")]
-            b"h2".to_vec(),
-            b"http/1.1".to_vec(),
-        ];
+        (self.server_config(cert), Some(info))
+    }
 
-        let server_cfg = Arc::new(server_cfg);
+    fn ca_cert(&self) -> Option<rustls::Certificate> {
+        Some(self.ca_cert.clone())
+    }
 
-        self.cache
-            .insert(authority.clone(), Arc::clone(&server_cfg))
-            .await;
+    async fn gen_faulty_server_config(
+        &self,
+        authority: &Authority,
+        fault: TlsFault,
+    ) -> Option<Arc<ServerConfig>> {
+        let now = OffsetDateTime::now_utc();
+        let cert = match fault {
+            TlsFault::ExpiredCertificate => self.gen_cert_for(
+                authority.host(),
+                now - Duration::seconds(TTL_SECS) - Duration::days(1),
+            ),
+            TlsFault::WrongHostCertificate => {
+                self.gen_cert_for(WRONG_HOST, now - Duration::seconds(NOT_BEFORE_OFFSET))
+            }
+            _ => return None,
+        };
 
-        server_cfg
+        Some(self.server_config(cert))
     }
 }
 
@@ -153,6 +412,7 @@ impl CertificateAuthority for RcgenAuthority {
 mod tests {
     use super::*;
     use rustls_pemfile as pemfile;
+    use x509_parser::extensions::GeneralName;
 
     fn init_ca(cache_size: u64) -> RcgenAuthority {
         let mut private_key_bytes: &[u8] = include_bytes!("../../examples/ca/hudsucker.key");
@@ -189,6 +449,22 @@ mod tests {
         assert!(result.is_err());
     }
 
+    #[test]
+    fn omits_port_from_names() {
+        let ca = init_ca(0);
+        let cert = ca.gen_cert(&Authority::from_static("example.com:8443"));
+        let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).unwrap();
+
+        let common_name = cert.subject().iter_common_name().next().unwrap();
+        assert_eq!(common_name.as_str().unwrap(), "example.com");
+
+        let san = cert.subject_alternative_name().unwrap().unwrap();
+        assert_eq!(
+            san.value.general_names,
+            vec![GeneralName::DNSName("example.com")]
+        );
+    }
+
     #[test]
     fn unique_serial_numbers() {
         let ca = init_ca(0);
@@ -214,4 +490,196 @@ mod tests {
         assert_ne!(cert1.raw_serial(), cert3.raw_serial());
         assert_ne!(cert2.raw_serial(), cert4.raw_serial());
     }
+
+    #[test]
+    fn default_key_usages() {
+        let ca = init_ca(0);
+        let cert = ca.gen_cert(&Authority::from_static("example.com"));
+        let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).unwrap();
+
+        let eku = cert.extended_key_usage().unwrap().unwrap().value;
+        assert!(eku.server_auth);
+        assert!(!eku.client_auth);
+
+        let key_usage = cert.key_usage().unwrap().unwrap().value;
+        assert!(key_usage.digital_signature());
+    }
+
+    #[test]
+    fn custom_key_usages() {
+        let ca = init_ca(0)
+            .with_key_usages(vec![KeyUsagePurpose::DigitalSignature])
+            .with_extended_key_usages(vec![
+                ExtendedKeyUsagePurpose::ServerAuth,
+                ExtendedKeyUsagePurpose::ClientAuth,
+            ]);
+        let cert = ca.gen_cert(&Authority::from_static("example.com"));
+        let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).unwrap();
+
+        let eku = cert.extended_key_usage().unwrap().unwrap().value;
+        assert!(eku.server_auth);
+        assert!(eku.client_auth);
+
+        let key_usage = cert.key_usage().unwrap().unwrap().value;
+        assert!(key_usage.digital_signature());
+        assert!(!key_usage.key_encipherment());
+    }
+
+    #[test]
+    fn limits_tls_versions() {
+        let ca = init_ca(0);
+        assert_eq!(ca.tls_versions().len(), 2);
+
+        let ca = ca.with_min_tls_version(&rustls::version::TLS13);
+        assert_eq!(ca.tls_versions(), vec![&rustls::version::TLS13]);
+
+        let ca = init_ca(0).with_max_tls_version(&rustls::version::TLS12);
+        assert_eq!(ca.tls_versions(), vec![&rustls::version::TLS12]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Maximum TLS version is lower than the minimum")]
+    fn panics_for_empty_tls_version_range() {
+        init_ca(0)
+            .with_min_tls_version(&rustls::version::TLS13)
+            .with_max_tls_version(&rustls::version::TLS12);
+    }
+
+    #[tokio::test]
+    async fn negotiates_preferred_cipher_suite() {
+        use rustls::cipher_suite::{
+            TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384, TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+        };
+        use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+        let ca = init_ca(0)
+            .with_max_tls_version(&rustls::version::TLS12)
+            .with_cipher_suites(vec![
+                TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+                TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+            ])
+            .unwrap();
+        let server_cfg = ca
+            .gen_server_config(&Authority::from_static("example.com"))
+            .await;
+
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add(ca.ca_cert_der()).unwrap();
+        // The client prefers AES over ChaCha20.
+        let client_cfg = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        let server = tokio::spawn(TlsAcceptor::from(server_cfg).accept(server));
+        let client = TlsConnector::from(Arc::new(client_cfg))
+            .connect("example.com".try_into().unwrap(), client)
+            .await
+            .unwrap();
+        server.await.unwrap().unwrap();
+
+        assert_eq!(
+            client.get_ref().1.negotiated_cipher_suite(),
+            Some(TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256)
+        );
+    }
+
+    #[test]
+    fn error_for_incompatible_cipher_suites() {
+        let result = init_ca(0).with_cipher_suites(vec![
+            rustls::cipher_suite::TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+        ]);
+
+        assert!(matches!(result, Err(Error::IncompatibleCipherSuites)));
+    }
+
+    #[test]
+    fn omits_empty_extended_key_usages() {
+        let ca = init_ca(0).with_extended_key_usages(Vec::new());
+        let cert = ca.gen_cert(&Authority::from_static("example.com"));
+        let (_, cert) = x509_parser::parse_x509_certificate(&cert.0).unwrap();
+
+        assert!(cert.extended_key_usage().unwrap().is_none());
+    }
+
+    #[cfg(feature = "openssl-ca")]
+    #[test]
+    fn valid_ocsp_response() {
+        use openssl::{
+            hash::MessageDigest,
+            ocsp::{OcspCertId, OcspCertStatus, OcspFlag, OcspResponse, OcspResponseStatus},
+            stack::Stack,
+            x509::{store::X509StoreBuilder, X509},
+        };
+
+        let ca = init_ca(0);
+        let cert = ca.gen_cert(&Authority::from_static("example.com"));
+        let response = OcspResponse::from_der(&ca.gen_ocsp_response(&cert)).unwrap();
+        assert_eq!(response.status(), OcspResponseStatus::SUCCESSFUL);
+
+        let cert = X509::from_der(&cert.0).unwrap();
+        let ca_cert = X509::from_der(&ca.ca_cert.0).unwrap();
+        let basic = response.basic().unwrap();
+
+        let mut store = X509StoreBuilder::new().unwrap();
+        store.add_cert(ca_cert.clone()).unwrap();
+        let mut signers = Stack::new().unwrap();
+        signers.push(ca_cert.clone()).unwrap();
+        basic
+            .verify(&signers, &store.build(), OcspFlag::empty())
+            .unwrap();
+
+        let cert_id = OcspCertId::from_cert(MessageDigest::sha1(), &cert, &ca_cert).unwrap();
+        let status = basic.find_status(&cert_id).unwrap();
+        assert_eq!(status.status, OcspCertStatus::GOOD);
+        status.check_validity(0, None).unwrap();
+    }
+
+    #[tokio::test]
+    async fn uses_custom_cache() {
+        use std::{collections::HashMap, sync::Mutex};
+
+        #[derive(Clone, Default)]
+        struct TestCache(Arc<Mutex<HashMap<Authority, rustls::Certificate>>>);
+
+        #[async_trait]
+        impl CertCache for TestCache {
+            async fn get(&self, authority: &Authority) -> Option<rustls::Certificate> {
+                self.0.lock().unwrap().get(authority).cloned()
+            }
+
+            async fn insert(&self, authority: Authority, cert: rustls::Certificate) {
+                assert!(self.0.lock().unwrap().insert(authority, cert).is_none());
+            }
+        }
+
+        let cache = TestCache::default();
+        let ca = init_ca(0).with_cache(Box::new(cache.clone()));
+        let authority = Authority::from_static("example.com");
+
+        ca.gen_server_config(&authority).await;
+        ca.gen_server_config(&authority).await;
+
+        let certs = cache.0.lock().unwrap();
+        assert_eq!(certs.len(), 1);
+        assert!(certs.contains_key(&authority));
+    }
+
+    #[tokio::test]
+    async fn reports_cache_hits() {
+        let ca = init_ca(1_000);
+        let authority = Authority::from_static("example.com");
+
+        let (_, info) = ca.gen_server_config_with_info(&authority).await;
+        let info = info.unwrap();
+        assert!(!info.from_cache);
+        assert!(info.gen_duration.is_some());
+        assert_eq!(info.key_algo, rustls::SignatureAlgorithm::RSA);
+
+        let (_, info) = ca.gen_server_config_with_info(&authority).await;
+        let info = info.unwrap();
+        assert!(info.from_cache);
+        assert_eq!(info.gen_duration, None);
+    }
 }
@@ -0,0 +1,106 @@
+use futures::future::{MapOk, TryFutureExt};
+use hyper::{
+    client::connect::{Connected, Connection},
+    service::Service,
+    Uri,
+};
+use std::{
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// Counts the responses received on a connection. Hyper adds a clone of it to every response
+/// received on the connection, so the clones share the count.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ConnectionUses(Arc<AtomicU64>);
+
+impl ConnectionUses {
+    /// Count a response, returning whether an earlier response was received on the connection.
+    pub fn record(&self) -> bool {
+        self.0.fetch_add(1, Ordering::Relaxed) > 0
+    }
+
+    /// Add a new count to the details of a connection, which is called once per connection.
+    pub fn attach(connected: Connected) -> Connected {
+        connected.extra(Self::default())
+    }
+}
+
+/// A connector that records whether each response was received on a new connection or on one
+/// reused from the client's pool.
+///
+/// This is made available to handlers as [`HttpContext::connection_reused`]. Connections made by
+/// a [`TlsInfoConnector`](crate::TlsInfoConnector) are already recorded, so it only needs to be
+/// used with other connectors.
+///
+/// [`HttpContext::connection_reused`]: crate::HttpContext::connection_reused
+#[derive(Clone, Debug)]
+pub struct ReuseInfoConnector<C>(C);
+
+impl<C> ReuseInfoConnector<C> {
+    /// Wrap a connector, such as a [`hyper::client::HttpConnector`].
+    pub fn new(connector: C) -> Self {
+        Self(connector)
+    }
+}
+
+impl<C> Service<Uri> for ReuseInfoConnector<C>
+where
+    C: Service<Uri>,
+{
+    type Response = ReuseInfoStream<C::Response>;
+    type Error = C::Error;
+    type Future = MapOk<C::Future, fn(C::Response) -> ReuseInfoStream<C::Response>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        self.0.call(uri).map_ok(ReuseInfoStream)
+    }
+}
+
+/// A connection made by a [`ReuseInfoConnector`].
+#[derive(Debug)]
+pub struct ReuseInfoStream<T>(T);
+
+impl<T: Connection> Connection for ReuseInfoStream<T> {
+    fn connected(&self) -> Connected {
+        ConnectionUses::attach(self.0.connected())
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for ReuseInfoStream<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for ReuseInfoStream<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,312 @@
+use crate::{HttpContext, HttpHandler, RequestOrResponse};
+use hyper::{
+    header::{
+        HeaderName, HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+        ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE,
+        ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+    },
+    Body, HeaderMap, Method, Request, Response, StatusCode,
+};
+use std::{sync::Arc, time::Duration};
+
+#[derive(Clone, Debug)]
+struct Policy {
+    origins: Vec<HeaderValue>,
+    any_origin: bool,
+    methods: Vec<Method>,
+    headers: Vec<HeaderName>,
+    max_age: Option<Duration>,
+    credentials: bool,
+}
+
+fn join<T: AsRef<str>>(values: &[T]) -> HeaderValue {
+    let joined = values
+        .iter()
+        .map(AsRef::as_ref)
+        .collect::<Vec<_>>()
+        .join(", ");
+    HeaderValue::from_str(&joined).expect("Failed to build header value")
+}
+
+/// An HTTP handler that answers CORS preflight requests itself, and adds CORS headers to
+/// responses, for requests from allowed origins.
+///
+/// Preflight requests are `OPTIONS` requests with an `access-control-request-method` header.
+/// Those from allowed origins are answered with `204 No Content` and the configured
+/// `access-control-*` headers, without being passed to the inner handler or forwarded. Other
+/// requests from allowed origins are handled as usual, and have `access-control-allow-origin`
+/// added to their responses after the inner handler has handled them. Requests from other origins
+/// are passed through unchanged.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{hyper::Method, CorsHandler, NoopHandler};
+/// use std::time::Duration;
+///
+/// let handler = CorsHandler::new(NoopHandler::default())
+///     .allow_origin("http://localhost:3000")
+///     .allow_methods([Method::GET, Method::POST, Method::DELETE])
+///     .allow_headers(["content-type", "authorization"])
+///     .with_max_age(Duration::from_secs(600));
+/// ```
+#[derive(Clone, Debug)]
+pub struct CorsHandler<H> {
+    inner: H,
+    policy: Arc<Policy>,
+    origin: Option<HeaderValue>,
+}
+
+impl<H> CorsHandler<H> {
+    /// Wrap a handler, without any allowed origins. The allowed methods default to `GET`, `HEAD`,
+    /// and `POST`.
+    pub fn new(inner: H) -> Self {
+        Self {
+            inner,
+            policy: Arc::new(Policy {
+                origins: Vec::new(),
+                any_origin: false,
+                methods: vec![Method::GET, Method::HEAD, Method::POST],
+                headers: Vec::new(),
+                max_age: None,
+                credentials: false,
+            }),
+            origin: None,
+        }
+    }
+
+    fn policy_mut(&mut self) -> &mut Policy {
+        Arc::make_mut(&mut self.policy)
+    }
+
+    /// Allow requests from the origin, such as `https://example.com`. Allowing `*` allows requests
+    /// from any origin.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if the origin isn't a valid header value.
+    pub fn allow_origin(mut self, origin: &str) -> Self {
+        if origin == "*" {
+            self.policy_mut().any_origin = true;
+        } else {
+            let origin = HeaderValue::from_str(origin).expect("Invalid origin");
+            self.policy_mut().origins.push(origin);
+        }
+
+        self
+    }
+
+    /// Set the methods allowed in preflight responses.
+    pub fn allow_methods(mut self, methods: impl IntoIterator<Item = Method>) -> Self {
+        self.policy_mut().methods = methods.into_iter().collect();
+        self
+    }
+
+    /// Set the request headers allowed in preflight responses. Defaults to none.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if any of the headers aren't valid header names.
+    pub fn allow_headers<T: AsRef<str>>(mut self, headers: impl IntoIterator<Item = T>) -> Self {
+        self.policy_mut().headers = headers
+            .into_iter()
+            .map(|header| HeaderName::try_from(header.as_ref()).expect("Invalid header name"))
+            .collect();
+        self
+    }
+
+    /// Set how long clients may cache preflight responses for. Defaults to not being sent.
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.policy_mut().max_age = Some(max_age);
+        self
+    }
+
+    /// Set whether to allow requests with credentials, such as cookies. Defaults to `false`.
+    pub fn allow_credentials(mut self, credentials: bool) -> Self {
+        self.policy_mut().credentials = credentials;
+        self
+    }
+
+    fn allowed_origin<'a>(&self, req: &'a Request<Body>) -> Option<&'a HeaderValue> {
+        req.headers()
+            .get(ORIGIN)
+            .filter(|origin| self.policy.any_origin || self.policy.origins.contains(origin))
+    }
+
+    fn insert_origin_headers(&self, headers: &mut HeaderMap, origin: HeaderValue) {
+        if self.policy.any_origin && !self.policy.credentials {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+        } else {
+            headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+            headers.append(VARY, HeaderValue::from_static("origin"));
+        }
+
+        if self.policy.credentials {
+            headers.insert(
+                ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+        }
+    }
+
+    fn preflight_response(&self, origin: HeaderValue) -> Response<Body> {
+        let mut res = Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header(ACCESS_CONTROL_ALLOW_METHODS, join(&self.policy.methods))
+            .body(Body::empty())
+            .expect("Failed to build response");
+        let headers = res.headers_mut();
+
+        if !self.policy.headers.is_empty() {
+            headers.insert(ACCESS_CONTROL_ALLOW_HEADERS, join(&self.policy.headers));
+        }
+
+        if let Some(max_age) = self.policy.max_age {
+            headers.insert(ACCESS_CONTROL_MAX_AGE, max_age.as_secs().into());
+        }
+
+        self.insert_origin_headers(headers, origin);
+        res
+    }
+}
+
+delegate_http_handler! {
+    impl<H: HttpHandler> HttpHandler for CorsHandler<H> => inner {
+        async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+            self.origin = self.allowed_origin(&req).cloned();
+
+            if let Some(origin) = &self.origin {
+                if req.method() == Method::OPTIONS
+                    && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+                {
+                    return self.preflight_response(origin.clone()).into();
+                }
+            }
+
+            self.inner.handle_request(ctx, req).await
+        }
+
+        async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+            let mut res = self.inner.handle_response(ctx, res).await;
+
+            if let Some(origin) = self.origin.take() {
+                self.insert_origin_headers(res.headers_mut(), origin);
+            }
+
+            res
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_ctx, NoopHandler};
+
+    fn handler() -> CorsHandler<NoopHandler> {
+        CorsHandler::new(NoopHandler::new())
+            .allow_origin("https://allowed.example")
+            .allow_methods([Method::GET, Method::PUT])
+            .allow_headers(["content-type", "x-token"])
+            .with_max_age(Duration::from_secs(600))
+    }
+
+    fn preflight(origin: &str) -> Request<Body> {
+        Request::builder()
+            .method(Method::OPTIONS)
+            .uri("https://api.example/items")
+            .header(ORIGIN, origin)
+            .header(ACCESS_CONTROL_REQUEST_METHOD, "PUT")
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn answers_preflight() {
+        let res = match handler()
+            .handle_request(&test_ctx(), preflight("https://allowed.example"))
+            .await
+        {
+            RequestOrResponse::Response(res) => res,
+            _ => panic!("Preflight request was forwarded"),
+        };
+
+        assert_eq!(res.status(), StatusCode::NO_CONTENT);
+        assert_eq!(
+            res.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://allowed.example"
+        );
+        assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_METHODS], "GET, PUT");
+        assert_eq!(
+            res.headers()[ACCESS_CONTROL_ALLOW_HEADERS],
+            "content-type, x-token"
+        );
+        assert_eq!(res.headers()[ACCESS_CONTROL_MAX_AGE], "600");
+        assert_eq!(res.headers()[VARY], "origin");
+    }
+
+    #[tokio::test]
+    async fn forwards_preflight_from_other_origins() {
+        let req = handler()
+            .handle_request(&test_ctx(), preflight("https://other.example"))
+            .await;
+
+        assert!(matches!(req, RequestOrResponse::Request(_)));
+    }
+
+    #[tokio::test]
+    async fn adds_headers_to_responses() {
+        let mut handler = handler().allow_credentials(true);
+        let req = Request::builder()
+            .uri("https://api.example/items")
+            .header(ORIGIN, "https://allowed.example")
+            .body(Body::empty())
+            .unwrap();
+
+        assert!(matches!(
+            handler.handle_request(&test_ctx(), req).await,
+            RequestOrResponse::Request(_)
+        ));
+
+        let res = handler
+            .handle_response(&test_ctx(), Response::new(Body::empty()))
+            .await;
+
+        assert_eq!(
+            res.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+            "https://allowed.example"
+        );
+        assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+    }
+
+    #[tokio::test]
+    async fn allows_any_origin() {
+        let mut handler = CorsHandler::new(NoopHandler::new()).allow_origin("*");
+        let req = Request::builder()
+            .header(ORIGIN, "https://anywhere.example")
+            .body(Body::empty())
+            .unwrap();
+        handler.handle_request(&test_ctx(), req).await;
+
+        let res = handler
+            .handle_response(&test_ctx(), Response::new(Body::empty()))
+            .await;
+
+        assert_eq!(res.headers()[ACCESS_CONTROL_ALLOW_ORIGIN], "*");
+        assert!(!res.headers().contains_key(VARY));
+    }
+
+    #[tokio::test]
+    async fn skips_requests_without_origin() {
+        let mut handler = handler();
+        handler
+            .handle_request(&test_ctx(), Request::new(Body::empty()))
+            .await;
+
+        let res = handler
+            .handle_response(&test_ctx(), Response::new(Body::empty()))
+            .await;
+
+        assert!(!res.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+}
@@ -1,19 +1,21 @@
 use crate::Error;
-use async_compression::tokio::bufread::{BrotliDecoder, GzipDecoder, ZlibDecoder, ZstdDecoder};
+use async_compression::tokio::bufread::{
+    BrotliDecoder, BrotliEncoder, GzipDecoder, GzipEncoder, ZlibDecoder, ZlibEncoder, ZstdDecoder,
+    ZstdEncoder,
+};
 use bstr::ByteSlice;
 use bytes::Bytes;
 use futures::Stream;
 use hyper::{
     header::{HeaderMap, HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH},
-    Body, Error as HyperError, Request, Response,
+    Body, Error as HyperError, Request, Response, StatusCode,
 };
 use std::{
-    io,
     io::Error as IoError,
     pin::Pin,
     task::{Context, Poll},
 };
-use tokio::io::{AsyncBufRead, AsyncRead, BufReader};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncReadExt, BufReader};
 use tokio_util::io::{ReaderStream, StreamReader};
 
 struct IoStream<T: Stream<Item = Result<Bytes, HyperError>> + Unpin>(T);
@@ -24,7 +26,7 @@ impl<T: Stream<Item = Result<Bytes, HyperError>> + Unpin> Stream for IoStream<T>
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
         match futures::ready!(Pin::new(&mut self.0).poll_next(cx)) {
             Some(Ok(chunk)) => Poll::Ready(Some(Ok(chunk))),
-            Some(Err(err)) => Poll::Ready(Some(Err(IoError::new(io::ErrorKind::Other, err)))),
+            Some(Err(err)) => Poll::Ready(Some(Err(IoError::other(err)))),
             None => Poll::Ready(None),
         }
     }
@@ -37,7 +39,7 @@ enum Decoder {
 
 impl Decoder {
     pub fn decode(self, encoding: &[u8]) -> Result<Self, Error> {
-        if encoding == b"identity" {
+        if is_identity(encoding) {
             return Ok(self);
         }
 
@@ -46,7 +48,8 @@ impl Decoder {
             Self::Decoder(decoder) => Box::new(BufReader::new(decoder)),
         };
 
-        let decoder: Box<dyn AsyncRead + Send + Unpin> = match encoding {
+        let encoding = encoding.to_ascii_lowercase();
+        let decoder: Box<dyn AsyncRead + Send + Unpin> = match &encoding[..] {
             b"gzip" | b"x-gzip" => Box::new(GzipDecoder::new(reader)),
             b"deflate" => Box::new(ZlibDecoder::new(reader)),
             b"br" => Box::new(BrotliDecoder::new(reader)),
@@ -67,7 +70,21 @@ impl From<Decoder> for Body {
     }
 }
 
-fn extract_encodings(headers: &HeaderMap<HeaderValue>) -> impl Iterator<Item = &[u8]> {
+/// Content codings are case-insensitive, and empty list elements are allowed, so both are treated
+/// as no-ops.
+fn is_identity(encoding: &[u8]) -> bool {
+    encoding.is_empty() || encoding.eq_ignore_ascii_case(b"identity")
+}
+
+pub(crate) fn is_supported_encoding(encoding: &[u8]) -> bool {
+    is_identity(encoding)
+        || matches!(
+            &encoding.to_ascii_lowercase()[..],
+            b"gzip" | b"x-gzip" | b"deflate" | b"br" | b"zstd"
+        )
+}
+
+pub(crate) fn extract_encodings(headers: &HeaderMap<HeaderValue>) -> impl Iterator<Item = &[u8]> {
     headers
         .get_all(CONTENT_ENCODING)
         .iter()
@@ -75,7 +92,42 @@ fn extract_encodings(headers: &HeaderMap<HeaderValue>) -> impl Iterator<Item = &
         .flat_map(|val| val.as_bytes().rsplit_str(b",").map(|v| v.trim()))
 }
 
-fn decode_body<'a>(
+/// Whether the body is left as is by the `content-encoding` headers, so its length still holds.
+fn is_identity_only(headers: &HeaderMap<HeaderValue>) -> bool {
+    extract_encodings(headers).all(is_identity)
+}
+
+async fn encode(encoding: &[u8], data: &[u8]) -> Vec<u8> {
+    let encoding = encoding.to_ascii_lowercase();
+    let mut encoder: Box<dyn AsyncRead + Send + Unpin + '_> = match &encoding[..] {
+        b"gzip" | b"x-gzip" => Box::new(GzipEncoder::new(data)),
+        b"deflate" => Box::new(ZlibEncoder::new(data)),
+        b"br" => Box::new(BrotliEncoder::new(data)),
+        b"zstd" => Box::new(ZstdEncoder::new(data)),
+        _ => return data.to_vec(),
+    };
+
+    let mut encoded = Vec::new();
+    encoder
+        .read_to_end(&mut encoded)
+        .await
+        .expect("Failed to encode body");
+    encoded
+}
+
+/// Encode a body with each of the values of the `content-encoding` headers, in the order they
+/// were applied.
+pub(crate) async fn encode_body(encodings: &[HeaderValue], mut data: Vec<u8>) -> Vec<u8> {
+    for value in encodings {
+        for encoding in value.as_bytes().split_str(",") {
+            data = encode(encoding.trim(), &data).await;
+        }
+    }
+
+    data
+}
+
+pub(crate) fn decode_body<'a>(
     encodings: impl IntoIterator<Item = &'a [u8]>,
     body: Body,
 ) -> Result<Body, Error> {
@@ -130,12 +182,17 @@ pub fn decode_request(mut req: Request<Body>) -> Result<Request<Body>, Error> {
         return Ok(req);
     }
 
-    if let Some(val) = req.headers_mut().remove(CONTENT_LENGTH) {
-        if val == "0" {
-            return Ok(req);
-        }
+    if req.headers().get(CONTENT_LENGTH) == Some(&HeaderValue::from_static("0")) {
+        return Ok(req);
+    }
+
+    if is_identity_only(req.headers()) {
+        req.headers_mut().remove(CONTENT_ENCODING);
+        return Ok(req);
     }
 
+    req.headers_mut().remove(CONTENT_LENGTH);
+
     let (mut parts, body) = req.into_parts();
 
     let body = {
@@ -186,12 +243,26 @@ pub fn decode_response(mut res: Response<Body>) -> Result<Response<Body>, Error>
         return Ok(res);
     }
 
-    if let Some(val) = res.headers_mut().remove(CONTENT_LENGTH) {
-        if val == "0" {
-            return Ok(res);
-        }
+    // These responses never have a body. The headers of a `304 Not Modified` describe the cached
+    // representation, so they are left as is.
+    if res.status().is_informational()
+        || res.status() == StatusCode::NO_CONTENT
+        || res.status() == StatusCode::NOT_MODIFIED
+    {
+        return Ok(res);
+    }
+
+    if res.headers().get(CONTENT_LENGTH) == Some(&HeaderValue::from_static("0")) {
+        return Ok(res);
+    }
+
+    if is_identity_only(res.headers()) {
+        res.headers_mut().remove(CONTENT_ENCODING);
+        return Ok(res);
     }
 
+    res.headers_mut().remove(CONTENT_LENGTH);
+
     let (mut parts, body) = res.into_parts();
 
     let body = {
@@ -352,6 +423,54 @@ mod tests {
             assert!(!req.headers().contains_key(CONTENT_ENCODING));
             assert_eq!(&to_bytes(req.into_body()).await.unwrap()[..], content);
         }
+
+        #[tokio::test]
+        async fn identity_encoding() {
+            let req = Request::builder()
+                .header(CONTENT_LENGTH, 12)
+                .header(CONTENT_ENCODING, "identity")
+                .body(Body::from("hello, world"))
+                .unwrap();
+
+            let req = decode_request(req).unwrap();
+
+            assert_eq!(req.headers()[CONTENT_LENGTH], "12");
+            assert!(!req.headers().contains_key(CONTENT_ENCODING));
+            assert_eq!(
+                &to_bytes(req.into_body()).await.unwrap()[..],
+                b"hello, world"
+            );
+        }
+
+        #[tokio::test]
+        async fn identity_and_gzip_encodings() {
+            let content = b"hello, world";
+            let encoder = GzipEncoder::new(&content[..]);
+            let req = Request::builder()
+                .header(CONTENT_LENGTH, 123)
+                .header(CONTENT_ENCODING, "Identity, gzip, identity")
+                .body(Body::wrap_stream(ReaderStream::new(encoder)))
+                .unwrap();
+
+            let req = decode_request(req).unwrap();
+
+            assert!(!req.headers().contains_key(CONTENT_LENGTH));
+            assert!(!req.headers().contains_key(CONTENT_ENCODING));
+            assert_eq!(&to_bytes(req.into_body()).await.unwrap()[..], content);
+        }
+
+        #[test]
+        fn keeps_length_of_empty_body() {
+            let req = Request::builder()
+                .header(CONTENT_LENGTH, 0)
+                .header(CONTENT_ENCODING, "gzip")
+                .body(Body::empty())
+                .unwrap();
+
+            let req = decode_request(req).unwrap();
+
+            assert_eq!(req.headers()[CONTENT_LENGTH], "0");
+        }
     }
 
     mod decode_response {
@@ -375,5 +494,67 @@ mod tests {
             assert!(!res.headers().contains_key(CONTENT_ENCODING));
             assert_eq!(&to_bytes(res.into_body()).await.unwrap()[..], content);
         }
+
+        #[tokio::test]
+        async fn identity_encoding() {
+            let res = Response::builder()
+                .header(CONTENT_LENGTH, 12)
+                .header(CONTENT_ENCODING, "identity")
+                .body(Body::from("hello, world"))
+                .unwrap();
+
+            let res = decode_response(res).unwrap();
+
+            assert_eq!(res.headers()[CONTENT_LENGTH], "12");
+            assert!(!res.headers().contains_key(CONTENT_ENCODING));
+            assert_eq!(
+                &to_bytes(res.into_body()).await.unwrap()[..],
+                b"hello, world"
+            );
+        }
+
+        #[tokio::test]
+        async fn identity_and_gzip_encodings() {
+            let content = b"hello, world";
+            let encoder = GzipEncoder::new(&content[..]);
+            let res = Response::builder()
+                .header(CONTENT_LENGTH, 123)
+                .header(CONTENT_ENCODING, "gzip, identity")
+                .header(CONTENT_ENCODING, "IDENTITY")
+                .body(Body::wrap_stream(ReaderStream::new(encoder)))
+                .unwrap();
+
+            let res = decode_response(res).unwrap();
+
+            assert!(!res.headers().contains_key(CONTENT_LENGTH));
+            assert!(!res.headers().contains_key(CONTENT_ENCODING));
+            assert_eq!(&to_bytes(res.into_body()).await.unwrap()[..], content);
+        }
+
+        #[test]
+        fn keeps_length_of_empty_body() {
+            let res = Response::builder()
+                .header(CONTENT_LENGTH, 0)
+                .header(CONTENT_ENCODING, "gzip")
+                .body(Body::empty())
+                .unwrap();
+
+            let res = decode_response(res).unwrap();
+
+            assert_eq!(res.headers()[CONTENT_LENGTH], "0");
+        }
+
+        #[test]
+        fn keeps_headers_of_not_modified() {
+            let res = Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .header(CONTENT_ENCODING, "gzip")
+                .body(Body::empty())
+                .unwrap();
+
+            let res = decode_response(res).unwrap();
+
+            assert_eq!(res.headers()[CONTENT_ENCODING], "gzip");
+        }
     }
 }
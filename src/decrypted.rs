@@ -0,0 +1,180 @@
+use crate::proxy::{
+    host_limits::HostLimits,
+    socket::{self, ConnectOptions},
+};
+use hyper::http::uri::Authority;
+use std::{
+    fmt, io,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, OnceLock},
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::{
+    rustls::{self, ClientConfig, ServerName},
+    TlsConnector,
+};
+
+trait Io: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin> Io for T {}
+
+/// How the proxy connects to the server of a decrypted stream.
+pub(crate) struct Upstream {
+    pub client_config: Arc<ClientConfig>,
+    pub connect_addr: Option<SocketAddr>,
+    pub connect_options: ConnectOptions,
+    pub host_limit: Option<(Arc<HostLimits>, usize)>,
+}
+
+/// The decrypted stream of an intercepted TLS connection that doesn't carry HTTP, passed to
+/// [`HttpHandler::handle_decrypted_stream`](crate::HttpHandler::handle_decrypted_stream).
+///
+/// Reading from the stream yields the plaintext sent by the client, starting with the bytes read
+/// to detect the protocol, and writing to it sends data to the client over TLS.
+pub struct DecryptedStream {
+    io: Box<dyn Io>,
+    upstream: Upstream,
+}
+
+impl DecryptedStream {
+    pub(crate) fn new<T>(io: T, upstream: Upstream) -> Self
+    where
+        T: AsyncRead + AsyncWrite + Send + Unpin + 'static,
+    {
+        Self {
+            io: Box::new(io),
+            upstream,
+        }
+    }
+
+    /// The config the proxy connects to upstream servers over TLS with. This is the config of the
+    /// client set with [`ProxyBuilder::with_rustls_client`] or
+    /// [`ProxyBuilder::with_upstream_root_store`], including the key log, or one that trusts the
+    /// webpki roots for other clients.
+    ///
+    /// [`ProxyBuilder::with_rustls_client`]: crate::ProxyBuilder::with_rustls_client
+    /// [`ProxyBuilder::with_upstream_root_store`]: crate::ProxyBuilder::with_upstream_root_store
+    pub fn client_config(&self) -> Arc<ClientConfig> {
+        Arc::clone(&self.upstream.client_config)
+    }
+
+    /// Connect to the server over TLS using the given config, and copy data between the client
+    /// and the server until both have closed their side of the connection. The server is
+    /// connected to like the servers of tunnels, at the address from
+    /// [`HttpHandler::upstream_target`](crate::HttpHandler::upstream_target) if one was given.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if the connection to the server can't be established, or if
+    /// either connection fails while data is being copied.
+    pub async fn forward(self, authority: &Authority, config: Arc<ClientConfig>) -> io::Result<()> {
+        let host = authority
+            .host()
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+        let server_name = ServerName::try_from(host)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+        let _permit = match &self.upstream.host_limit {
+            Some((limits, limit)) => Some(limits.acquire(authority, None, *limit).await),
+            None => None,
+        };
+
+        let addr = self
+            .upstream
+            .connect_addr
+            .map_or_else(|| authority.to_string(), |addr| addr.to_string());
+        let server = socket::connect(&addr, &self.upstream.connect_options).await?;
+        let mut server = TlsConnector::from(config)
+            .connect(server_name, server)
+            .await?;
+
+        let mut client = self;
+        tokio::io::copy_bidirectional(&mut client, &mut server).await?;
+        Ok(())
+    }
+}
+
+impl fmt::Debug for DecryptedStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DecryptedStream").finish_non_exhaustive()
+    }
+}
+
+impl AsyncRead for DecryptedStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.io).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for DecryptedStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut *self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut *self.io).poll_shutdown(cx)
+    }
+}
+
+/// Config for connecting to servers when forwarding decrypted streams, which trusts the webpki
+/// roots, for clients that don't use rustls.
+pub(crate) fn default_client_config() -> Arc<ClientConfig> {
+    static CONFIG: OnceLock<Arc<ClientConfig>> = OnceLock::new();
+
+    Arc::clone(CONFIG.get_or_init(|| {
+        let mut roots = rustls::RootCertStore::empty();
+        roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|anchor| {
+            rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                anchor.subject,
+                anchor.spki,
+                anchor.name_constraints,
+            )
+        }));
+
+        Arc::new(
+            ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth(),
+        )
+    }))
+}
+
+/// Whether the first bytes of a decrypted stream look like the start of an HTTP/1.x request or
+/// the HTTP/2 connection preface.
+pub(crate) fn is_http(bytes: &[u8]) -> bool {
+    const PREFIXES: [&[u8]; 10] = [
+        b"GET ", b"POST", b"PUT ", b"HEAD", b"DELE", b"OPTI", b"PATC", b"CONN", b"TRAC", b"PRI ",
+    ];
+
+    PREFIXES.contains(&bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_http() {
+        assert!(is_http(b"GET "));
+        assert!(is_http(b"POST"));
+        assert!(is_http(b"PRI "));
+        assert!(!is_http(b"\x00\x00\x00\x01"));
+        assert!(!is_http(b"GE"));
+    }
+}
@@ -1,6 +1,9 @@
+use hyper::StatusCode;
 #[cfg(feature = "rcgen-ca")]
 use rcgen::RcgenError;
+use std::{error::Error as StdError, io};
 use thiserror::Error;
+use tokio_rustls::rustls::{self, AlertDescription};
 
 #[derive(Debug, Error)]
 #[non_exhaustive]
@@ -11,8 +14,191 @@ pub enum Error {
     Tls(#[from] RcgenError),
     #[error("network error")]
     Network(#[from] hyper::Error),
+    #[cfg(feature = "json")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "json")))]
+    #[error("invalid HAR file")]
+    Har(#[source] serde_json::Error),
     #[error("unable to decode body")]
     Decode,
+    #[cfg(feature = "rcgen-ca")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rcgen-ca")))]
+    #[error("no cipher suite is compatible with the CA key")]
+    IncompatibleCipherSuites,
+    #[cfg(feature = "rcgen-ca")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rcgen-ca")))]
+    #[error("unable to read or write CA files")]
+    CaFile(#[source] io::Error),
+    #[error("unable to open key log file")]
+    KeyLogFile(#[source] io::Error),
+    #[error("unable to bind to address")]
+    Bind(#[source] io::Error),
+    #[error("file descriptor is not a listening TCP socket")]
+    InvalidListener,
+    #[error("invalid redirect")]
+    InvalidRedirect,
+    #[error("upstream response headers are too large")]
+    ResponseHeadersTooLarge,
+    #[error("streaming transform carried over too much of the body")]
+    CarryLimitExceeded,
     #[error("unknown error")]
     Unknown,
 }
+
+/// Why a request could not be forwarded to the upstream server.
+///
+/// This is built by walking the sources of the [`hyper::Error`] returned by the client, so the
+/// details depend on the connector in use. Errors that can't be classified are reported as
+/// [`ProxyError::Other`].
+#[derive(Debug, Error)]
+#[non_exhaustive]
+pub enum ProxyError {
+    /// The host of the upstream server could not be resolved.
+    #[error("failed to resolve upstream host")]
+    Dns(#[source] hyper::Error),
+    /// The upstream server refused the connection.
+    #[error("upstream server refused the connection")]
+    ConnectionRefused(#[source] hyper::Error),
+    /// The TLS handshake with the upstream server failed.
+    #[error("TLS handshake with upstream server failed")]
+    Tls {
+        /// The alert sent by the upstream server, if the handshake failed because of one.
+        alert: Option<AlertDescription>,
+        #[source]
+        source: hyper::Error,
+    },
+    /// Connecting to or receiving a response from the upstream server timed out.
+    #[error("upstream server timed out")]
+    Timeout(#[source] hyper::Error),
+    /// Any other error.
+    #[error("failed to forward request")]
+    Other(#[source] hyper::Error),
+}
+
+impl ProxyError {
+    /// The status of the response sent to the client by default. This is `504 Gateway Timeout`
+    /// for timeouts, and `502 Bad Gateway` otherwise.
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            _ => StatusCode::BAD_GATEWAY,
+        }
+    }
+
+    /// The underlying error returned by the client.
+    pub fn hyper_error(&self) -> &hyper::Error {
+        match self {
+            Self::Dns(err)
+            | Self::ConnectionRefused(err)
+            | Self::Tls { source: err, .. }
+            | Self::Timeout(err)
+            | Self::Other(err) => err,
+        }
+    }
+}
+
+enum Cause {
+    Dns,
+    ConnectionRefused,
+    Tls(Option<AlertDescription>),
+    Timeout,
+}
+
+fn classify(err: &(dyn StdError + 'static)) -> Option<Cause> {
+    let mut next = Some(err);
+
+    while let Some(err) = next {
+        if let Some(err) = err.downcast_ref::<rustls::Error>() {
+            return match err {
+                rustls::Error::AlertReceived(alert) => Some(Cause::Tls(Some(*alert))),
+                _ => Some(Cause::Tls(None)),
+            };
+        }
+
+        // hyper's connector doesn't expose the kind of its errors, only their message.
+        if err.to_string().starts_with("dns error") {
+            return Some(Cause::Dns);
+        }
+
+        if let Some(err) = err.downcast_ref::<io::Error>() {
+            // The source of an I/O error skips the error it wraps, so that is checked directly.
+            if let Some(cause) = err.get_ref().and_then(|inner| classify(inner)) {
+                return Some(cause);
+            }
+
+            return match err.kind() {
+                io::ErrorKind::ConnectionRefused => Some(Cause::ConnectionRefused),
+                io::ErrorKind::TimedOut => Some(Cause::Timeout),
+                _ => None,
+            };
+        }
+
+        next = err.source();
+    }
+
+    None
+}
+
+impl From<hyper::Error> for ProxyError {
+    fn from(err: hyper::Error) -> Self {
+        if err.is_timeout() {
+            return Self::Timeout(err);
+        }
+
+        match classify(&err) {
+            Some(Cause::Dns) => Self::Dns(err),
+            Some(Cause::ConnectionRefused) => Self::ConnectionRefused(err),
+            Some(Cause::Tls(alert)) => Self::Tls { alert, source: err },
+            Some(Cause::Timeout) => Self::Timeout(err),
+            None => Self::Other(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{Client, Uri};
+    use std::net::TcpListener;
+
+    async fn request(uri: Uri) -> ProxyError {
+        Client::new().get(uri).await.unwrap_err().into()
+    }
+
+    #[tokio::test]
+    async fn classifies_connection_refused() {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let err = request(format!("http://{}/", addr).parse().unwrap()).await;
+
+        assert!(matches!(err, ProxyError::ConnectionRefused(_)));
+        assert_eq!(err.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn classifies_dns_error() {
+        let err = request(Uri::from_static("http://hudsucker.invalid/")).await;
+
+        assert!(matches!(err, ProxyError::Dns(_)));
+        assert_eq!(err.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[test]
+    fn classifies_tls_alert() {
+        let err = rustls::Error::AlertReceived(AlertDescription::HandshakeFailure);
+        let err = io::Error::new(io::ErrorKind::InvalidData, err);
+
+        assert!(matches!(
+            classify(&err),
+            Some(Cause::Tls(Some(AlertDescription::HandshakeFailure)))
+        ));
+    }
+
+    #[test]
+    fn classifies_timeout() {
+        let err = io::Error::new(io::ErrorKind::TimedOut, "connect timeout");
+
+        assert!(matches!(classify(&err), Some(Cause::Timeout)));
+    }
+}
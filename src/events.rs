@@ -0,0 +1,80 @@
+use hyper::{http::uri::Authority, Method, StatusCode, Uri};
+use std::{
+    net::SocketAddr,
+    time::{Duration, SystemTime},
+};
+use tokio_rustls::rustls::SignatureAlgorithm;
+
+/// An event in the lifecycle of a client connection, received from the channel created with
+/// [`ProxyBuilder::with_event_channel`](crate::ProxyBuilder::with_event_channel).
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct ProxyEvent {
+    /// Identifier of the client connection, which is unique for the lifetime of the proxy.
+    pub connection_id: u64,
+    /// When the event happened.
+    pub timestamp: SystemTime,
+    /// What happened.
+    pub kind: ProxyEventKind,
+}
+
+/// The kind of a [`ProxyEvent`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ProxyEventKind {
+    /// A client connection was accepted.
+    ConnectionAccepted {
+        /// Address of the client.
+        client_addr: SocketAddr,
+    },
+    /// A certificate was served to the client, after a CONNECT request to the authority was
+    /// intercepted. Only sent if the certificate authority provides the details, as the built-in
+    /// authorities do, see [`CertificateAuthority::gen_server_config_with_info`].
+    ///
+    /// [`CertificateAuthority::gen_server_config_with_info`]:
+    /// crate::certificate_authority::CertificateAuthority::gen_server_config_with_info
+    CertServed {
+        /// Target of the CONNECT request.
+        authority: Authority,
+        /// Whether the certificate was taken from the cache.
+        from_cache: bool,
+        /// How long it took to generate the certificate, if it wasn't taken from the cache.
+        gen_duration: Option<Duration>,
+        /// Algorithm of the key that the certificate was issued for.
+        key_algo: SignatureAlgorithm,
+    },
+    /// A TLS connection was established with the client, after a CONNECT request to the
+    /// authority was intercepted.
+    TlsEstablished {
+        /// Target of the CONNECT request.
+        authority: Authority,
+    },
+    /// A request was received from the client.
+    RequestStarted {
+        /// Identifier of the request, which is unique for the lifetime of the proxy.
+        request_id: u64,
+        /// Method of the request.
+        method: Method,
+        /// URI of the request, as it was received.
+        uri: Uri,
+    },
+    /// The response to a request was passed back to the client. Its body may still be streaming.
+    ResponseCompleted {
+        /// Identifier of the request.
+        request_id: u64,
+        /// Status of the response.
+        status: StatusCode,
+    },
+    /// A WebSocket session was opened with the server.
+    WebSocketOpened {
+        /// URI of the server.
+        uri: Uri,
+    },
+    /// A WebSocket session was closed in both directions.
+    WebSocketClosed {
+        /// URI of the server.
+        uri: Uri,
+    },
+    /// The client connection closed, along with everything that was opened from it.
+    ConnectionClosed,
+}
@@ -0,0 +1,140 @@
+use hyper::http::Extensions;
+use std::{
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+/// A typed store for passing state between the handling of a request and of its response,
+/// available in [`HttpContext::extensions`](crate::HttpContext::extensions).
+///
+/// Each request gets a new store, which is shared with the context passed to
+/// [`HttpHandler::handle_response`](crate::HttpHandler::handle_response) or
+/// [`HttpHandler::handle_error`](crate::HttpHandler::handle_error) for the same request. Like
+/// [`http::Extensions`](hyper::http::Extensions), it holds at most one value of each type, so
+/// handlers should store values of types of their own.
+///
+/// The store can be used through a shared reference, but the values are behind a lock, so they are
+/// cloned out of it rather than borrowed. The lock is never held across an `.await`.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{
+///     async_trait::async_trait,
+///     hyper::{Body, Request, Response},
+///     HttpContext, HttpHandler, RequestOrResponse,
+/// };
+/// use std::time::Instant;
+///
+/// #[derive(Clone)]
+/// struct Started(Instant);
+///
+/// #[derive(Clone)]
+/// pub struct TimingHandler;
+///
+/// #[async_trait]
+/// impl HttpHandler for TimingHandler {
+///     async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+///         ctx.extensions.insert(Started(Instant::now()));
+///         req.into()
+///     }
+///
+///     async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+///         if let Some(Started(started)) = ctx.extensions.get::<Started>() {
+///             println!("Response received after {:?}", started.elapsed());
+///         }
+///         res
+///     }
+/// }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ExchangeExtensions(Arc<Mutex<Extensions>>);
+
+impl ExchangeExtensions {
+    fn lock(&self) -> MutexGuard<'_, Extensions> {
+        self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Insert a value into the store. If a value of this type was already stored, it is replaced
+    /// and returned.
+    pub fn insert<T: Send + Sync + 'static>(&self, val: T) -> Option<T> {
+        self.lock().insert(val)
+    }
+
+    /// Get a clone of the value of a type from the store.
+    pub fn get<T: Clone + Send + Sync + 'static>(&self) -> Option<T> {
+        self.lock().get::<T>().cloned()
+    }
+
+    /// Remove the value of a type from the store, and return it.
+    pub fn remove<T: Send + Sync + 'static>(&self) -> Option<T> {
+        self.lock().remove::<T>()
+    }
+
+    /// Call a function with a mutable reference to the value of a type in the store, inserting
+    /// its default value first if there isn't one.
+    pub fn update<T, R>(&self, f: impl FnOnce(&mut T) -> R) -> R
+    where
+        T: Default + Send + Sync + 'static,
+    {
+        let mut extensions = self.lock();
+        if extensions.get::<T>().is_none() {
+            extensions.insert(T::default());
+        }
+
+        f(extensions.get_mut::<T>().expect("Value was just inserted"))
+    }
+
+    /// Remove all values from the store.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+}
+
+impl PartialEq for ExchangeExtensions {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for ExchangeExtensions {}
+
+impl Hash for ExchangeExtensions {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Arc::as_ptr(&self.0).hash(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shares_values_between_clones() {
+        let extensions = ExchangeExtensions::default();
+        let shared = extensions.clone();
+
+        assert_eq!(extensions.insert(1u32), None);
+        assert_eq!(shared.get::<u32>(), Some(1));
+        assert_eq!(shared.insert(2u32), Some(1));
+        assert_eq!(extensions.remove::<u32>(), Some(2));
+        assert_eq!(shared.get::<u32>(), None);
+        assert_eq!(extensions, shared);
+        assert_ne!(extensions, ExchangeExtensions::default());
+    }
+
+    #[test]
+    fn updates_values() {
+        let extensions = ExchangeExtensions::default();
+
+        extensions.update(|count: &mut u32| *count += 1);
+        let count = extensions.update(|count: &mut u32| {
+            *count += 1;
+            *count
+        });
+
+        assert_eq!(count, 2);
+        extensions.clear();
+        assert_eq!(extensions.get::<u32>(), None);
+    }
+}
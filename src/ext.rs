@@ -0,0 +1,386 @@
+use crate::Error;
+use hyper::{
+    ext::ReasonPhrase,
+    header::{AsHeaderName, HeaderMap, HeaderName, HeaderValue, LOCATION},
+    Request, Response, StatusCode,
+};
+use std::fmt::Display;
+use tracing::warn;
+
+fn convert<K, V>(name: K, value: V) -> Option<(HeaderName, HeaderValue)>
+where
+    K: TryInto<HeaderName>,
+    K::Error: Display,
+    V: TryInto<HeaderValue>,
+    V::Error: Display,
+{
+    let name = match name.try_into() {
+        Ok(name) => name,
+        Err(e) => {
+            warn!("Skipping invalid header name: {}", e);
+            return None;
+        }
+    };
+
+    match value.try_into() {
+        Ok(value) => Some((name, value)),
+        Err(e) => {
+            warn!("Skipping invalid value for header {}: {}", name, e);
+            None
+        }
+    }
+}
+
+fn set_header<K, V>(headers: &mut HeaderMap, name: K, value: V)
+where
+    K: TryInto<HeaderName>,
+    K::Error: Display,
+    V: TryInto<HeaderValue>,
+    V::Error: Display,
+{
+    if let Some((name, value)) = convert(name, value) {
+        headers.insert(name, value);
+    }
+}
+
+fn append_header<K, V>(headers: &mut HeaderMap, name: K, value: V)
+where
+    K: TryInto<HeaderName>,
+    K::Error: Display,
+    V: TryInto<HeaderValue>,
+    V::Error: Display,
+{
+    if let Some((name, value)) = convert(name, value) {
+        headers.append(name, value);
+    }
+}
+
+fn header_str(headers: &HeaderMap, name: impl AsHeaderName) -> Option<&str> {
+    headers.get(name).and_then(|value| value.to_str().ok())
+}
+
+/// Extension methods for manipulating the headers of a [`Request`].
+///
+/// Invalid header names and values are logged and skipped instead of causing a panic.
+pub trait RequestExt {
+    /// Set a header, replacing any existing values.
+    fn set_header<K, V>(&mut self, name: K, value: V) -> &mut Self
+    where
+        K: TryInto<HeaderName>,
+        K::Error: Display,
+        V: TryInto<HeaderValue>,
+        V::Error: Display;
+
+    /// Append a header, keeping any existing values.
+    fn append_header<K, V>(&mut self, name: K, value: V) -> &mut Self
+    where
+        K: TryInto<HeaderName>,
+        K::Error: Display,
+        V: TryInto<HeaderValue>,
+        V::Error: Display;
+
+    /// Remove a header, returning its first value if it was present.
+    fn remove_header(&mut self, name: impl AsHeaderName) -> Option<HeaderValue>;
+
+    /// Get the first value of a header as a string. Returns `None` if the header is missing or is
+    /// not valid visible ASCII.
+    fn header_str(&self, name: impl AsHeaderName) -> Option<&str>;
+}
+
+impl<B> RequestExt for Request<B> {
+    fn set_header<K, V>(&mut self, name: K, value: V) -> &mut Self
+    where
+        K: TryInto<HeaderName>,
+        K::Error: Display,
+        V: TryInto<HeaderValue>,
+        V::Error: Display,
+    {
+        set_header(self.headers_mut(), name, value);
+        self
+    }
+
+    fn append_header<K, V>(&mut self, name: K, value: V) -> &mut Self
+    where
+        K: TryInto<HeaderName>,
+        K::Error: Display,
+        V: TryInto<HeaderValue>,
+        V::Error: Display,
+    {
+        append_header(self.headers_mut(), name, value);
+        self
+    }
+
+    fn remove_header(&mut self, name: impl AsHeaderName) -> Option<HeaderValue> {
+        self.headers_mut().remove(name)
+    }
+
+    fn header_str(&self, name: impl AsHeaderName) -> Option<&str> {
+        header_str(self.headers(), name)
+    }
+}
+
+/// Extension methods for building a [`Response`] and manipulating its headers.
+///
+/// Invalid header names and values are logged and skipped instead of causing a panic.
+pub trait ResponseExt {
+    /// Set a header, replacing any existing values.
+    fn set_header<K, V>(&mut self, name: K, value: V) -> &mut Self
+    where
+        K: TryInto<HeaderName>,
+        K::Error: Display,
+        V: TryInto<HeaderValue>,
+        V::Error: Display;
+
+    /// Append a header, keeping any existing values.
+    fn append_header<K, V>(&mut self, name: K, value: V) -> &mut Self
+    where
+        K: TryInto<HeaderName>,
+        K::Error: Display,
+        V: TryInto<HeaderValue>,
+        V::Error: Display;
+
+    /// Remove a header, returning its first value if it was present.
+    fn remove_header(&mut self, name: impl AsHeaderName) -> Option<HeaderValue>;
+
+    /// Get the first value of a header as a string. Returns `None` if the header is missing or is
+    /// not valid visible ASCII.
+    fn header_str(&self, name: impl AsHeaderName) -> Option<&str>;
+
+    /// Set a custom reason phrase, such as `Totally Fine` in `HTTP/1.1 200 Totally Fine`, to send
+    /// to the client instead of the canonical one for the status code.
+    ///
+    /// This only applies when the response is sent to the client over HTTP/1, as HTTP/2 has no
+    /// reason phrases. Responses forwarded from HTTP/1 servers keep their original reason phrase.
+    fn set_reason_phrase(&mut self, reason: impl AsRef<[u8]>) -> &mut Self;
+
+    /// Build a redirect response to the given location, with an empty body.
+    ///
+    /// # Errors
+    ///
+    /// This will return [`Error::InvalidRedirect`] if the status is not one of `301`, `302`,
+    /// `303`, `307`, or `308`, or if the location is not a valid header value.
+    fn redirect<V>(status: StatusCode, location: V) -> Result<Self, Error>
+    where
+        Self: Sized,
+        V: TryInto<HeaderValue>;
+}
+
+impl<B: Default> ResponseExt for Response<B> {
+    fn set_header<K, V>(&mut self, name: K, value: V) -> &mut Self
+    where
+        K: TryInto<HeaderName>,
+        K::Error: Display,
+        V: TryInto<HeaderValue>,
+        V::Error: Display,
+    {
+        set_header(self.headers_mut(), name, value);
+        self
+    }
+
+    fn append_header<K, V>(&mut self, name: K, value: V) -> &mut Self
+    where
+        K: TryInto<HeaderName>,
+        K::Error: Display,
+        V: TryInto<HeaderValue>,
+        V::Error: Display,
+    {
+        append_header(self.headers_mut(), name, value);
+        self
+    }
+
+    fn remove_header(&mut self, name: impl AsHeaderName) -> Option<HeaderValue> {
+        self.headers_mut().remove(name)
+    }
+
+    fn header_str(&self, name: impl AsHeaderName) -> Option<&str> {
+        header_str(self.headers(), name)
+    }
+
+    fn set_reason_phrase(&mut self, reason: impl AsRef<[u8]>) -> &mut Self {
+        match ReasonPhrase::try_from(reason.as_ref()) {
+            Ok(reason) => {
+                self.extensions_mut().insert(reason);
+            }
+            Err(e) => warn!("Skipping invalid reason phrase: {}", e),
+        }
+
+        self
+    }
+
+    fn redirect<V>(status: StatusCode, location: V) -> Result<Self, Error>
+    where
+        V: TryInto<HeaderValue>,
+    {
+        if !matches!(
+            status,
+            StatusCode::MOVED_PERMANENTLY
+                | StatusCode::FOUND
+                | StatusCode::SEE_OTHER
+                | StatusCode::TEMPORARY_REDIRECT
+                | StatusCode::PERMANENT_REDIRECT
+        ) {
+            return Err(Error::InvalidRedirect);
+        }
+
+        let location = location.try_into().map_err(|_| Error::InvalidRedirect)?;
+        let mut res = Response::new(B::default());
+        *res.status_mut() = status;
+        res.headers_mut().insert(LOCATION, location);
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::{header::CONTENT_TYPE, Body};
+
+    mod request_ext {
+        use super::*;
+
+        #[test]
+        fn sets_header() {
+            let mut req = Request::new(Body::empty());
+            req.append_header("x-test", "foo")
+                .append_header("x-test", "bar")
+                .set_header("x-test", "baz");
+
+            assert_eq!(req.headers().get_all("x-test").iter().count(), 1);
+            assert_eq!(req.header_str("x-test"), Some("baz"));
+        }
+
+        #[test]
+        fn appends_header() {
+            let mut req = Request::new(Body::empty());
+            req.append_header("x-test", "foo")
+                .append_header("x-test", String::from("bar"));
+
+            let values: Vec<_> = req.headers().get_all("x-test").iter().collect();
+            assert_eq!(values, vec!["foo", "bar"]);
+        }
+
+        #[test]
+        fn removes_header() {
+            let mut req = Request::new(Body::empty());
+            req.set_header(CONTENT_TYPE, "text/plain");
+
+            assert_eq!(req.remove_header(CONTENT_TYPE).unwrap(), "text/plain");
+            assert_eq!(req.remove_header(CONTENT_TYPE), None);
+        }
+
+        #[test]
+        fn skips_invalid_header_name() {
+            let mut req = Request::new(Body::empty());
+            req.set_header("invalid name", "foo");
+
+            assert!(req.headers().is_empty());
+        }
+
+        #[test]
+        fn skips_invalid_header_value() {
+            let mut req = Request::new(Body::empty());
+            req.set_header("x-test", "foo")
+                .set_header("x-test", "bar\r\nx-injected: baz")
+                .append_header("x-test", "\n");
+
+            assert_eq!(req.headers().len(), 1);
+            assert_eq!(req.header_str("x-test"), Some("foo"));
+        }
+
+        #[test]
+        fn header_str_is_none_for_opaque_value() {
+            let mut req = Request::new(Body::empty());
+            req.set_header("x-test", HeaderValue::from_bytes(b"caf\xc3\xa9").unwrap());
+
+            assert_eq!(req.header_str("x-test"), None);
+            assert_eq!(req.header_str("x-missing"), None);
+        }
+    }
+
+    mod response_ext {
+        use super::*;
+
+        #[test]
+        fn sets_header() {
+            let mut res = Response::new(Body::empty());
+            res.append_header("x-test", "foo")
+                .set_header("x-test", "bar");
+
+            assert_eq!(res.headers().get_all("x-test").iter().count(), 1);
+            assert_eq!(res.header_str("x-test"), Some("bar"));
+        }
+
+        #[test]
+        fn appends_header() {
+            let mut res = Response::new(Body::empty());
+            res.append_header("x-test", "foo")
+                .append_header("x-test", "bar");
+
+            let values: Vec<_> = res.headers().get_all("x-test").iter().collect();
+            assert_eq!(values, vec!["foo", "bar"]);
+        }
+
+        #[test]
+        fn removes_header() {
+            let mut res = Response::new(Body::empty());
+            res.set_header(CONTENT_TYPE, "text/plain");
+
+            assert_eq!(res.remove_header(CONTENT_TYPE).unwrap(), "text/plain");
+            assert_eq!(res.remove_header(CONTENT_TYPE), None);
+        }
+
+        #[test]
+        fn skips_invalid_header_value() {
+            let mut res = Response::new(Body::empty());
+            res.set_header("x-test", "foo")
+                .set_header("x-test", "bar\0");
+
+            assert_eq!(res.headers().len(), 1);
+            assert_eq!(res.header_str("x-test"), Some("foo"));
+        }
+
+        #[test]
+        fn sets_reason_phrase() {
+            let mut res = Response::new(Body::empty());
+            res.set_reason_phrase("Totally Fine")
+                .set_reason_phrase("Not\r\nFine");
+
+            assert_eq!(
+                res.extensions().get::<ReasonPhrase>().unwrap().as_bytes(),
+                b"Totally Fine"
+            );
+        }
+
+        #[test]
+        fn builds_redirect() {
+            let res =
+                Response::<Body>::redirect(StatusCode::FOUND, "https://example.com/").unwrap();
+
+            assert_eq!(res.status(), StatusCode::FOUND);
+            assert_eq!(res.header_str(LOCATION), Some("https://example.com/"));
+        }
+
+        #[test]
+        fn rejects_non_redirect_status() {
+            for status in [
+                StatusCode::OK,
+                StatusCode::MULTIPLE_CHOICES,
+                StatusCode::NOT_MODIFIED,
+                StatusCode::NOT_FOUND,
+            ] {
+                assert!(matches!(
+                    Response::<Body>::redirect(status, "/"),
+                    Err(Error::InvalidRedirect)
+                ));
+            }
+        }
+
+        #[test]
+        fn rejects_invalid_location() {
+            assert!(matches!(
+                Response::<Body>::redirect(StatusCode::FOUND, "/\r\n"),
+                Err(Error::InvalidRedirect)
+            ));
+        }
+    }
+}
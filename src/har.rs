@@ -0,0 +1,476 @@
+use crate::{Error, HttpContext, HttpHandler, RequestOrResponse};
+use base64::Engine;
+use bytes::Bytes;
+use hyper::{
+    body::to_bytes,
+    header::{HeaderName, HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, TRANSFER_ENCODING},
+    http::uri::Scheme,
+    Body, HeaderMap, Method, Request, Response, StatusCode, Uri,
+};
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::{error, warn};
+
+#[derive(Deserialize)]
+struct Har {
+    log: HarLog,
+}
+
+#[derive(Deserialize)]
+struct HarLog {
+    entries: Vec<HarEntry>,
+}
+
+#[derive(Deserialize)]
+struct HarEntry {
+    request: HarRequest,
+    response: HarResponse,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HarRequest {
+    method: String,
+    url: String,
+    post_data: Option<HarPostData>,
+}
+
+#[derive(Deserialize)]
+struct HarPostData {
+    text: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct HarResponse {
+    status: u16,
+    headers: Vec<HarHeader>,
+    content: HarContent,
+}
+
+#[derive(Deserialize)]
+struct HarHeader {
+    name: String,
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct HarContent {
+    text: Option<String>,
+    encoding: Option<String>,
+}
+
+/// How strictly requests must match recorded requests to be replayed.
+#[cfg_attr(docsrs, doc(cfg(feature = "json")))]
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum HarMatching {
+    /// The method and the URL, including the query, must match.
+    #[default]
+    Url,
+    /// The method and the URL must match, ignoring the query.
+    UrlWithoutQuery,
+    /// The method, the URL, including the query, and the body must match. Requests are buffered
+    /// in full to compare their bodies.
+    UrlAndBody,
+}
+
+/// What to do with requests that don't match any recorded request.
+#[cfg_attr(docsrs, doc(cfg(feature = "json")))]
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub enum HarFallback {
+    /// Pass the request to the inner handler, and forward it to the upstream server as usual.
+    #[default]
+    Forward,
+    /// Respond with `404 Not Found`.
+    NotFound,
+}
+
+/// The parts of a URL compared when matching requests. Default ports are made explicit, so that
+/// `https://example.com/` matches `https://example.com:443/`.
+#[derive(Debug, Eq, PartialEq)]
+struct UrlKey {
+    scheme: String,
+    host: String,
+    port: u16,
+    path: String,
+    query: Option<String>,
+}
+
+impl UrlKey {
+    fn new(uri: &Uri) -> Option<Self> {
+        let scheme = uri.scheme()?;
+        let default_port = if *scheme == Scheme::HTTPS {
+            443
+        } else if *scheme == Scheme::HTTP {
+            80
+        } else {
+            return None;
+        };
+
+        Some(Self {
+            scheme: scheme.as_str().to_owned(),
+            host: uri.host()?.to_ascii_lowercase(),
+            port: uri.port_u16().unwrap_or(default_port),
+            path: uri.path().to_owned(),
+            query: uri.query().map(ToOwned::to_owned),
+        })
+    }
+}
+
+#[derive(Debug)]
+struct ReplayEntry {
+    method: Method,
+    url: UrlKey,
+    body: Bytes,
+    status: StatusCode,
+    headers: HeaderMap,
+    content: Bytes,
+}
+
+impl ReplayEntry {
+    fn new(entry: HarEntry) -> Option<Self> {
+        let method = entry.request.method.parse().ok()?;
+        let url = UrlKey::new(&entry.request.url.parse().ok()?)?;
+        let body = entry
+            .request
+            .post_data
+            .and_then(|post_data| post_data.text)
+            .unwrap_or_default()
+            .into();
+        let status = StatusCode::from_u16(entry.response.status).ok()?;
+
+        let mut headers = HeaderMap::new();
+        for header in entry.response.headers {
+            headers.append(
+                HeaderName::try_from(header.name).ok()?,
+                HeaderValue::try_from(header.value).ok()?,
+            );
+        }
+
+        // HAR files record the decoded content, so the headers describing the encoded body no
+        // longer apply.
+        headers.remove(CONTENT_ENCODING);
+        headers.remove(TRANSFER_ENCODING);
+
+        let text = entry.response.content.text.unwrap_or_default();
+        let content: Bytes = match entry.response.content.encoding.as_deref() {
+            Some("base64") => base64::engine::general_purpose::STANDARD
+                .decode(text)
+                .ok()?
+                .into(),
+            _ => text.into(),
+        };
+        headers.insert(CONTENT_LENGTH, content.len().into());
+
+        Some(Self {
+            method,
+            url,
+            body,
+            status,
+            headers,
+            content,
+        })
+    }
+
+    fn matches(&self, matching: HarMatching, method: &Method, url: &UrlKey, body: &[u8]) -> bool {
+        if self.method != method
+            || self.url.scheme != url.scheme
+            || self.url.host != url.host
+            || self.url.port != url.port
+            || self.url.path != url.path
+        {
+            return false;
+        }
+
+        match matching {
+            HarMatching::Url => self.url.query == url.query,
+            HarMatching::UrlWithoutQuery => true,
+            HarMatching::UrlAndBody => self.url.query == url.query && self.body == body,
+        }
+    }
+
+    fn response(&self) -> Response<Body> {
+        let mut res = Response::new(Body::from(self.content.clone()));
+        *res.status_mut() = self.status;
+        *res.headers_mut() = self.headers.clone();
+        res
+    }
+}
+
+fn not_found() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::NOT_FOUND)
+        .body(Body::empty())
+        .expect("Failed to build response")
+}
+
+/// An HTTP handler that serves responses recorded in a HAR file, turning the proxy into a mock
+/// server that replays captured traffic.
+///
+/// Requests are matched against the recorded requests by method and URL, with the strictness set
+/// by [`HarReplayHandler::with_matching`]. If several recorded requests match, the first one is
+/// replayed. Requests that don't match any recorded request are handled as set by
+/// [`HarReplayHandler::with_fallback`], which defaults to forwarding them.
+///
+/// Recorded responses are served with their status and headers, and with their decoded content,
+/// so `content-encoding` is removed and `content-length` is set to the length of the content.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use hudsucker::{HarFallback, HarReplayHandler, NoopHandler};
+///
+/// let har = std::fs::read("recording.har").unwrap();
+/// let handler = HarReplayHandler::new(NoopHandler::default(), &har)
+///     .unwrap()
+///     .with_fallback(HarFallback::NotFound);
+/// ```
+#[cfg_attr(docsrs, doc(cfg(feature = "json")))]
+#[derive(Clone, Debug)]
+pub struct HarReplayHandler<H> {
+    inner: H,
+    entries: Arc<Vec<ReplayEntry>>,
+    matching: HarMatching,
+    fallback: HarFallback,
+}
+
+impl<H> HarReplayHandler<H> {
+    /// Wrap a handler, replaying the entries of a HAR file. Entries that can't be replayed, such
+    /// as those with invalid URLs or headers, are skipped.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if the HAR file can't be parsed.
+    pub fn new(inner: H, har: &[u8]) -> Result<Self, Error> {
+        let har: Har = serde_json::from_slice(har).map_err(Error::Har)?;
+        let entries = har
+            .log
+            .entries
+            .into_iter()
+            .filter_map(|entry| {
+                let url = entry.request.url.clone();
+                let entry = ReplayEntry::new(entry);
+
+                if entry.is_none() {
+                    warn!("Skipping HAR entry that can't be replayed: {}", url);
+                }
+
+                entry
+            })
+            .collect();
+
+        Ok(Self {
+            inner,
+            entries: Arc::new(entries),
+            matching: HarMatching::default(),
+            fallback: HarFallback::default(),
+        })
+    }
+
+    /// Set how strictly requests must match recorded requests. Defaults to [`HarMatching::Url`].
+    pub fn with_matching(mut self, matching: HarMatching) -> Self {
+        self.matching = matching;
+        self
+    }
+
+    /// Set what to do with requests that don't match any recorded request. Defaults to
+    /// [`HarFallback::Forward`].
+    pub fn with_fallback(mut self, fallback: HarFallback) -> Self {
+        self.fallback = fallback;
+        self
+    }
+
+    fn find(&self, method: &Method, uri: &Uri, body: &[u8]) -> Option<&ReplayEntry> {
+        let url = UrlKey::new(uri)?;
+        self.entries
+            .iter()
+            .find(|entry| entry.matches(self.matching, method, &url, body))
+    }
+}
+
+delegate_http_handler! {
+    impl<H: HttpHandler> HttpHandler for HarReplayHandler<H> => inner {
+        async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+            let (req, body) = if self.matching == HarMatching::UrlAndBody {
+                let (parts, body) = req.into_parts();
+                let body = match to_bytes(body).await {
+                    Ok(body) => body,
+                    Err(e) => {
+                        error!("Failed to read request body: {}", e);
+                        return Response::builder()
+                            .status(StatusCode::BAD_REQUEST)
+                            .body(Body::empty())
+                            .expect("Failed to build response")
+                            .into();
+                    }
+                };
+
+                (Request::from_parts(parts, Body::from(body.clone())), body)
+            } else {
+                (req, Bytes::new())
+            };
+
+            if let Some(entry) = self.find(req.method(), req.uri(), &body) {
+                return entry.response().into();
+            }
+
+            match self.fallback {
+                HarFallback::Forward => self.inner.handle_request(ctx, req).await,
+                HarFallback::NotFound => not_found().into(),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_ctx, NoopHandler};
+
+    const HAR: &str = r#"{
+        "log": {
+            "version": "1.2",
+            "entries": [
+                {
+                    "request": {
+                        "method": "GET",
+                        "url": "https://example.com/users?page=1",
+                        "headers": []
+                    },
+                    "response": {
+                        "status": 200,
+                        "headers": [
+                            { "name": "Content-Type", "value": "application/json" },
+                            { "name": "Content-Encoding", "value": "gzip" },
+                            { "name": "Content-Length", "value": "1000" }
+                        ],
+                        "content": { "text": "[\"alice\"]", "mimeType": "application/json" }
+                    }
+                },
+                {
+                    "request": {
+                        "method": "POST",
+                        "url": "https://example.com/login",
+                        "postData": { "text": "user=alice" }
+                    },
+                    "response": {
+                        "status": 204,
+                        "headers": [],
+                        "content": { "text": "" }
+                    }
+                },
+                {
+                    "request": { "method": "GET", "url": "http://example.com/logo.png" },
+                    "response": {
+                        "status": 200,
+                        "headers": [],
+                        "content": { "text": "iVBORw==", "encoding": "base64" }
+                    }
+                },
+                {
+                    "request": { "method": "GET", "url": "not a url" },
+                    "response": { "status": 200, "headers": [], "content": {} }
+                }
+            ]
+        }
+    }"#;
+
+    fn handler() -> HarReplayHandler<NoopHandler> {
+        HarReplayHandler::new(NoopHandler::new(), HAR.as_bytes()).unwrap()
+    }
+
+    fn request(method: Method, uri: &str, body: &'static str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    async fn replayed(
+        handler: &mut HarReplayHandler<NoopHandler>,
+        req: Request<Body>,
+    ) -> Option<Response<Body>> {
+        match handler.handle_request(&test_ctx(), req).await {
+            RequestOrResponse::Response(res) => Some(res),
+            _ => None,
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_matching_request() {
+        let res = replayed(
+            &mut handler(),
+            request(Method::GET, "https://example.com:443/users?page=1", ""),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "application/json");
+        assert_eq!(res.headers()[CONTENT_LENGTH], "9");
+        assert!(!res.headers().contains_key(CONTENT_ENCODING));
+        assert_eq!(to_bytes(res.into_body()).await.unwrap(), r#"["alice"]"#);
+
+        let res = replayed(
+            &mut handler(),
+            request(Method::GET, "http://example.com/logo.png", ""),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            to_bytes(res.into_body()).await.unwrap(),
+            &[0x89, b'P', b'N', b'G'][..]
+        );
+    }
+
+    #[tokio::test]
+    async fn forwards_unmatched_request() {
+        for req in [
+            request(Method::GET, "https://example.com/users?page=2", ""),
+            request(Method::POST, "https://example.com/users?page=1", ""),
+            request(Method::GET, "http://example.com/users?page=1", ""),
+        ] {
+            assert!(replayed(&mut handler(), req).await.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn responds_not_found_for_unmatched_request() {
+        let res = replayed(
+            &mut handler().with_fallback(HarFallback::NotFound),
+            request(Method::GET, "https://example.com/missing", ""),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn matches_with_strictness() {
+        let mut handler = handler().with_matching(HarMatching::UrlWithoutQuery);
+        let req = request(Method::GET, "https://example.com/users?page=2", "");
+        assert!(replayed(&mut handler, req).await.is_some());
+
+        let mut handler = handler.with_matching(HarMatching::UrlAndBody);
+        let req = request(Method::POST, "https://example.com/login", "user=alice");
+        assert!(replayed(&mut handler, req).await.is_some());
+
+        let req = request(Method::POST, "https://example.com/login", "user=bob");
+        match handler.handle_request(&test_ctx(), req).await {
+            RequestOrResponse::Request(req) => {
+                assert_eq!(to_bytes(req.into_body()).await.unwrap(), "user=bob")
+            }
+            _ => panic!("Request was replayed"),
+        }
+    }
+
+    #[test]
+    fn skips_invalid_entries() {
+        assert_eq!(handler().entries.len(), 3);
+        assert!(HarReplayHandler::new(NoopHandler::new(), b"{}").is_err());
+    }
+}
@@ -0,0 +1,99 @@
+use hyper::header::{HeaderMap, HeaderName, HeaderValue, SERVER, VIA};
+
+/// Removes or rewrites headers of all responses from upstream servers, for example to strip
+/// headers that fingerprint the server.
+///
+/// The policy is applied after [`HttpHandler::handle_response`](crate::HttpHandler::handle_response),
+/// so it also applies to headers added by the handler. Headers are removed before they are
+/// rewritten.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{
+///     hyper::header::{HeaderName, HeaderValue, SERVER},
+///     ResponseHeaderPolicy,
+/// };
+///
+/// let policy = ResponseHeaderPolicy::new()
+///     .remove(HeaderName::from_static("x-aspnet-version"))
+///     .rewrite(SERVER, HeaderValue::from_static("proxy"));
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ResponseHeaderPolicy {
+    remove: Vec<HeaderName>,
+    rewrite: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl ResponseHeaderPolicy {
+    /// Create a new policy, which doesn't change any headers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a new policy that removes the `Server`, `X-Powered-By`, and `Via` headers.
+    pub fn anonymize() -> Self {
+        Self::new()
+            .remove(SERVER)
+            .remove(HeaderName::from_static("x-powered-by"))
+            .remove(VIA)
+    }
+
+    /// Remove all values of the header.
+    pub fn remove(mut self, name: HeaderName) -> Self {
+        self.remove.push(name);
+        self
+    }
+
+    /// Replace all values of the header with the value, if the header is present.
+    pub fn rewrite(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.rewrite.push((name, value));
+        self
+    }
+
+    pub(crate) fn apply(&self, headers: &mut HeaderMap) {
+        for name in &self.remove {
+            headers.remove(name);
+        }
+
+        for (name, value) in &self.rewrite {
+            if headers.contains_key(name) {
+                headers.insert(name, value.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn removes_and_rewrites_headers() {
+        let mut headers = HeaderMap::new();
+        headers.insert(SERVER, HeaderValue::from_static("nginx"));
+        headers.insert("x-powered-by", HeaderValue::from_static("PHP/8.2"));
+        headers.append(VIA, HeaderValue::from_static("1.1 a"));
+        headers.append(VIA, HeaderValue::from_static("1.1 b"));
+        headers.insert("x-version", HeaderValue::from_static("1.2.3"));
+        headers.insert("content-type", HeaderValue::from_static("text/plain"));
+
+        ResponseHeaderPolicy::anonymize()
+            .rewrite(
+                HeaderName::from_static("x-version"),
+                HeaderValue::from_static("hidden"),
+            )
+            .rewrite(
+                HeaderName::from_static("x-missing"),
+                HeaderValue::from_static("added"),
+            )
+            .apply(&mut headers);
+
+        assert!(!headers.contains_key(SERVER));
+        assert!(!headers.contains_key("x-powered-by"));
+        assert!(!headers.contains_key(VIA));
+        assert!(!headers.contains_key("x-missing"));
+        assert_eq!(headers["x-version"], "hidden");
+        assert_eq!(headers["content-type"], "text/plain");
+    }
+}
@@ -0,0 +1,91 @@
+use hyper::http::uri::Authority;
+
+/// A set of hosts, matched against the authority of requests.
+///
+/// Hosts are matched exactly, ignoring case and the port. A host starting with `*.` matches any
+/// subdomain of the rest of the host, but not the host itself.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{hyper::http::uri::Authority, HostFilter};
+///
+/// let filter = HostFilter::new()
+///     .with_host("ads.example.com")
+///     .with_host("*.tracker.com");
+///
+/// assert!(filter.matches(&Authority::from_static("ads.example.com:443")));
+/// assert!(filter.matches(&Authority::from_static("cdn.tracker.com")));
+/// assert!(!filter.matches(&Authority::from_static("tracker.com")));
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct HostFilter {
+    hosts: Vec<String>,
+}
+
+impl HostFilter {
+    /// Create a new filter, which doesn't match any hosts.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Match the host, or its subdomains if it starts with `*.`.
+    pub fn with_host(mut self, host: impl Into<String>) -> Self {
+        self.hosts.push(host.into().to_ascii_lowercase());
+        self
+    }
+
+    /// Whether the host of the authority is matched by the filter.
+    pub fn matches(&self, authority: &Authority) -> bool {
+        let host = authority
+            .host()
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .trim_end_matches('.')
+            .to_ascii_lowercase();
+
+        self.hosts
+            .iter()
+            .any(|pattern| match pattern.strip_prefix("*.") {
+                Some(domain) => host
+                    .strip_suffix(domain)
+                    .is_some_and(|subdomain| subdomain.len() > 1 && subdomain.ends_with('.')),
+                None => host == *pattern,
+            })
+    }
+}
+
+/// How tunnels to blackholed hosts are closed, see
+/// [`ProxyBuilder::with_blackhole`](crate::ProxyBuilder::with_blackhole).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum BlackholeMode {
+    /// Discard anything sent by the client and close the connection normally.
+    #[default]
+    Close,
+    /// Reset the connection. Tunnels that aren't backed by the client's socket, such as those
+    /// requested within intercepted connections, are closed normally instead.
+    Reset,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_hosts() {
+        let filter = HostFilter::new()
+            .with_host("Example.com")
+            .with_host("*.example.org")
+            .with_host("::1");
+
+        assert!(filter.matches(&Authority::from_static("example.com")));
+        assert!(filter.matches(&Authority::from_static("EXAMPLE.COM:8080")));
+        assert!(filter.matches(&Authority::from_static("example.com.")));
+        assert!(filter.matches(&Authority::from_static("a.b.example.org:443")));
+        assert!(filter.matches(&Authority::from_static("[::1]:443")));
+        assert!(!filter.matches(&Authority::from_static("www.example.com")));
+        assert!(!filter.matches(&Authority::from_static("example.org")));
+        assert!(!filter.matches(&Authority::from_static("badexample.org")));
+    }
+}
@@ -0,0 +1,523 @@
+use crate::{
+    body::{collect_with_limit, BodyError},
+    decode_response,
+    decoder::{encode_body, extract_encodings, is_supported_encoding},
+    HttpContext, HttpHandler, RequestOrResponse,
+};
+use bstr::ByteSlice;
+use hyper::{
+    header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE},
+    Body, Method, Request, Response, StatusCode,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::sync::Arc;
+use tracing::{error, warn};
+
+const DEFAULT_MAX_SIZE: usize = 1024 * 1024;
+
+fn is_json(content_type: &[u8]) -> bool {
+    let essence = content_type
+        .split_str(";")
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    essence == b"application/json" || essence.ends_with(b"+json")
+}
+
+/// An operation of a JSON Patch, as defined by [RFC 6902].
+///
+/// Paths are JSON Pointers, as defined by [RFC 6901]. Operations can be deserialized from a JSON
+/// Patch document, such as `[{ "op": "remove", "path": "/a/0" }]`.
+///
+/// [RFC 6902]: https://datatracker.ietf.org/doc/html/rfc6902
+/// [RFC 6901]: https://datatracker.ietf.org/doc/html/rfc6901
+#[cfg_attr(docsrs, doc(cfg(feature = "json")))]
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum PatchOperation {
+    /// Add a value to an object or insert it into an array, replacing any existing member.
+    Add { path: String, value: Value },
+    /// Remove the value at the path, which must exist.
+    Remove { path: String },
+    /// Replace the value at the path, which must exist.
+    Replace { path: String, value: Value },
+    /// Remove the value at `from` and add it at the path.
+    Move { from: String, path: String },
+    /// Copy the value at `from` to the path.
+    Copy { from: String, path: String },
+    /// Check that the value at the path is equal to the value, otherwise the patch fails.
+    Test { path: String, value: Value },
+}
+
+/// Apply the operations in order. If any of them fail, the value is left unchanged.
+fn apply(value: &mut Value, operations: &[PatchOperation]) -> Result<(), &'static str> {
+    let mut patched = value.clone();
+
+    for operation in operations {
+        match operation {
+            PatchOperation::Add { path, value } => add(&mut patched, path, value.clone())?,
+            PatchOperation::Remove { path } => {
+                remove(&mut patched, path)?;
+            }
+            PatchOperation::Replace { path, value } => {
+                *patched.pointer_mut(path).ok_or("path does not exist")? = value.clone();
+            }
+            PatchOperation::Move { from, path } => {
+                if path.starts_with(from.as_str()) && path[from.len()..].starts_with('/') {
+                    return Err("cannot move a value into itself");
+                }
+
+                let value = remove(&mut patched, from)?;
+                add(&mut patched, path, value)?;
+            }
+            PatchOperation::Copy { from, path } => {
+                let value = patched.pointer(from).ok_or("from does not exist")?.clone();
+                add(&mut patched, path, value)?;
+            }
+            PatchOperation::Test { path, value } => {
+                if patched.pointer(path) != Some(value) {
+                    return Err("test failed");
+                }
+            }
+        }
+    }
+
+    *value = patched;
+    Ok(())
+}
+
+/// Split a pointer into the pointer to its parent and its last reference token.
+fn split_pointer(path: &str) -> Result<(&str, String), &'static str> {
+    let (parent, token) = path.rsplit_once('/').ok_or("invalid path")?;
+    Ok((parent, token.replace("~1", "/").replace("~0", "~")))
+}
+
+fn array_index(token: &str, len: usize) -> Result<usize, &'static str> {
+    if token.is_empty() || (token.len() > 1 && token.starts_with('0')) {
+        return Err("invalid array index");
+    }
+
+    match token.parse() {
+        Ok(index) if index <= len => Ok(index),
+        _ => Err("array index out of bounds"),
+    }
+}
+
+fn add(root: &mut Value, path: &str, value: Value) -> Result<(), &'static str> {
+    if path.is_empty() {
+        *root = value;
+        return Ok(());
+    }
+
+    let (parent, token) = split_pointer(path)?;
+
+    match root.pointer_mut(parent).ok_or("parent does not exist")? {
+        Value::Object(object) => {
+            object.insert(token, value);
+        }
+        Value::Array(array) if token == "-" => array.push(value),
+        Value::Array(array) => {
+            let index = array_index(&token, array.len())?;
+            array.insert(index, value);
+        }
+        _ => return Err("parent is not an object or array"),
+    }
+
+    Ok(())
+}
+
+fn remove(root: &mut Value, path: &str) -> Result<Value, &'static str> {
+    let (parent, token) = split_pointer(path)?;
+
+    match root.pointer_mut(parent).ok_or("parent does not exist")? {
+        Value::Object(object) => object.remove(&token).ok_or("path does not exist"),
+        Value::Array(array) => {
+            let index = array_index(&token, array.len())?;
+
+            if index == array.len() {
+                return Err("array index out of bounds");
+            }
+
+            Ok(array.remove(index))
+        }
+        _ => Err("parent is not an object or array"),
+    }
+}
+
+fn bad_gateway() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::BAD_GATEWAY)
+        .body(Body::empty())
+        .expect("Failed to build response")
+}
+
+/// An HTTP handler that applies JSON Patch operations to the bodies of JSON responses.
+///
+/// Patches are added for routes, which match the path of the request exactly, or any path
+/// starting with the route if it ends with `*`. All patches for matching routes are applied in
+/// the order they were added, after the inner handler has handled the response. If an operation
+/// fails, for example because a path doesn't exist, the body is sent unchanged.
+///
+/// Responses are only patched if their `content-type` is `application/json` or ends with `+json`.
+/// Their bodies are decoded and buffered in full before being patched, and encoded again with the
+/// original `content-encoding` and sent with an updated `content-length`. Bodies larger than the
+/// maximum size, or that aren't valid JSON, are sent decoded without being modified.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{serde_json::json, JsonPatchHandler, NoopHandler, PatchOperation};
+///
+/// let handler = JsonPatchHandler::new(NoopHandler::default())
+///     .patch(
+///         "/api/user",
+///         [PatchOperation::Replace {
+///             path: "/role".to_owned(),
+///             value: json!("admin"),
+///         }],
+///     )
+///     .patch(
+///         "/api/*",
+///         [PatchOperation::Remove {
+///             path: "/tracking".to_owned(),
+///         }],
+///     );
+/// ```
+#[cfg_attr(docsrs, doc(cfg(feature = "json")))]
+#[derive(Clone, Debug)]
+pub struct JsonPatchHandler<H> {
+    inner: H,
+    routes: Arc<Vec<(String, Vec<PatchOperation>)>>,
+    max_size: usize,
+    path: Option<String>,
+}
+
+impl<H> JsonPatchHandler<H> {
+    /// Wrap a handler, without any patches. The maximum size defaults to 1 MiB.
+    pub fn new(inner: H) -> Self {
+        Self {
+            inner,
+            routes: Arc::new(Vec::new()),
+            max_size: DEFAULT_MAX_SIZE,
+            path: None,
+        }
+    }
+
+    /// Add operations to apply to responses to requests for the route.
+    pub fn patch(
+        mut self,
+        route: impl Into<String>,
+        operations: impl IntoIterator<Item = PatchOperation>,
+    ) -> Self {
+        Arc::make_mut(&mut self.routes).push((route.into(), operations.into_iter().collect()));
+        self
+    }
+
+    /// Set the maximum size in bytes of a decoded body that will be patched.
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    fn operations(&self) -> Vec<PatchOperation> {
+        let Some(path) = &self.path else {
+            return Vec::new();
+        };
+
+        self.routes
+            .iter()
+            .filter(|(route, _)| match route.strip_suffix('*') {
+                Some(prefix) => path.starts_with(prefix),
+                None => path == route,
+            })
+            .flat_map(|(_, operations)| operations.iter().cloned())
+            .collect()
+    }
+
+    fn should_patch(&self, res: &Response<Body>) -> bool {
+        res.status() != StatusCode::NO_CONTENT
+            && res.status() != StatusCode::NOT_MODIFIED
+            && res
+                .headers()
+                .get(CONTENT_TYPE)
+                .is_some_and(|content_type| is_json(content_type.as_bytes()))
+            && extract_encodings(res.headers()).all(is_supported_encoding)
+    }
+
+    async fn rewrite(
+        &self,
+        res: Response<Body>,
+        operations: Vec<PatchOperation>,
+    ) -> Result<Response<Body>, crate::Error> {
+        let encodings: Vec<HeaderValue> = res
+            .headers()
+            .get_all(CONTENT_ENCODING)
+            .iter()
+            .cloned()
+            .collect();
+        let (mut parts, body) = decode_response(res)?.into_parts();
+
+        let mut buffer = match collect_with_limit(body, self.max_size).await {
+            Ok(buffer) => buffer.to_vec(),
+            Err(BodyError::TooLarge { body, .. }) => return Ok(Response::from_parts(parts, body)),
+            Err(BodyError::Network(e)) => return Err(e.into()),
+        };
+
+        match serde_json::from_slice::<Value>(&buffer) {
+            Ok(mut value) => match apply(&mut value, &operations) {
+                Ok(()) => buffer = serde_json::to_vec(&value).expect("Failed to serialize JSON"),
+                Err(e) => warn!("Failed to apply JSON patch: {}", e),
+            },
+            Err(e) => warn!("Failed to parse JSON body: {}", e),
+        }
+
+        let buffer = encode_body(&encodings, buffer).await;
+
+        for value in encodings {
+            parts.headers.append(CONTENT_ENCODING, value);
+        }
+
+        parts.headers.insert(CONTENT_LENGTH, buffer.len().into());
+        Ok(Response::from_parts(parts, Body::from(buffer)))
+    }
+}
+
+delegate_http_handler! {
+    impl<H: HttpHandler> HttpHandler for JsonPatchHandler<H> => inner {
+        async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+            // Responses to HEAD requests have no body, but keep the length of the body they describe.
+            self.path = (req.method() != Method::HEAD).then(|| req.uri().path().to_owned());
+            self.inner.handle_request(ctx, req).await
+        }
+
+        async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+            let res = self.inner.handle_response(ctx, res).await;
+            let operations = self.operations();
+
+            if operations.is_empty() || !self.should_patch(&res) {
+                return res;
+            }
+
+            match self.rewrite(res, operations).await {
+                Ok(res) => res,
+                Err(e) => {
+                    error!("Failed to patch response: {}", e);
+                    bad_gateway()
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_ctx, NoopHandler};
+    use hyper::body::to_bytes;
+    use serde_json::json;
+
+    fn patched(value: Value, operations: Value) -> Result<Value, &'static str> {
+        let operations: Vec<PatchOperation> = serde_json::from_value(operations).unwrap();
+        let mut value = value;
+        apply(&mut value, &operations).map(|()| value)
+    }
+
+    #[test]
+    fn adds_values() {
+        assert_eq!(
+            patched(
+                json!({ "a": [1, 3] }),
+                json!([
+                    { "op": "add", "path": "/b", "value": "x" },
+                    { "op": "add", "path": "/a/1", "value": 2 },
+                    { "op": "add", "path": "/a/-", "value": 4 },
+                ])
+            ),
+            Ok(json!({ "a": [1, 2, 3, 4], "b": "x" }))
+        );
+        assert!(patched(
+            json!({ "a": [] }),
+            json!([{ "op": "add", "path": "/a/1", "value": 1 }])
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn removes_values() {
+        assert_eq!(
+            patched(
+                json!({ "a": [1, 2], "b/c": true }),
+                json!([
+                    { "op": "remove", "path": "/a/0" },
+                    { "op": "remove", "path": "/b~1c" },
+                ])
+            ),
+            Ok(json!({ "a": [2] }))
+        );
+        assert!(patched(json!({}), json!([{ "op": "remove", "path": "/a" }])).is_err());
+    }
+
+    #[test]
+    fn replaces_values() {
+        assert_eq!(
+            patched(
+                json!({ "a": { "b": 1 } }),
+                json!([{ "op": "replace", "path": "/a/b", "value": [true] }])
+            ),
+            Ok(json!({ "a": { "b": [true] } }))
+        );
+        assert!(patched(
+            json!({}),
+            json!([{ "op": "replace", "path": "/a", "value": 1 }])
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn moves_copies_and_tests_values() {
+        assert_eq!(
+            patched(
+                json!({ "a": 1, "b": {} }),
+                json!([
+                    { "op": "test", "path": "/a", "value": 1 },
+                    { "op": "copy", "from": "/a", "path": "/c" },
+                    { "op": "move", "from": "/a", "path": "/b/a" },
+                ])
+            ),
+            Ok(json!({ "b": { "a": 1 }, "c": 1 }))
+        );
+        assert!(patched(
+            json!({ "a": {} }),
+            json!([{ "op": "move", "from": "/a", "path": "/a/b" }])
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn leaves_value_unchanged_on_failure() {
+        let mut value = json!({ "a": 1 });
+        let operations = [
+            PatchOperation::Remove {
+                path: "/a".to_owned(),
+            },
+            PatchOperation::Test {
+                path: "/a".to_owned(),
+                value: json!(1),
+            },
+        ];
+
+        assert!(apply(&mut value, &operations).is_err());
+        assert_eq!(value, json!({ "a": 1 }));
+    }
+
+    fn handler() -> JsonPatchHandler<NoopHandler> {
+        JsonPatchHandler::new(NoopHandler::new())
+            .patch(
+                "/user",
+                [PatchOperation::Replace {
+                    path: "/name".to_owned(),
+                    value: json!("patched"),
+                }],
+            )
+            .patch(
+                "/api/*",
+                [PatchOperation::Remove {
+                    path: "/secret".to_owned(),
+                }],
+            )
+    }
+
+    async fn response(
+        handler: &mut JsonPatchHandler<NoopHandler>,
+        method: Method,
+        path: &str,
+        content_type: &str,
+        body: &'static str,
+    ) -> Response<Body> {
+        let req = Request::builder()
+            .method(method)
+            .uri(path)
+            .body(Body::empty())
+            .unwrap();
+        handler.handle_request(&test_ctx(), req).await;
+
+        let res = Response::builder()
+            .header(CONTENT_TYPE, content_type)
+            .header(CONTENT_LENGTH, body.len())
+            .body(Body::from(body))
+            .unwrap();
+        handler.handle_response(&test_ctx(), res).await
+    }
+
+    #[tokio::test]
+    async fn patches_matching_routes() {
+        let res = response(
+            &mut handler(),
+            Method::GET,
+            "/user",
+            "application/json",
+            r#"{"name":"original"}"#,
+        )
+        .await;
+
+        assert_eq!(res.headers()[CONTENT_LENGTH], "18");
+        assert_eq!(
+            &to_bytes(res.into_body()).await.unwrap()[..],
+            br#"{"name":"patched"}"#
+        );
+
+        let res = response(
+            &mut handler(),
+            Method::GET,
+            "/api/keys",
+            "application/vnd.api+json",
+            r#"{"id":1,"secret":"abc"}"#,
+        )
+        .await;
+
+        assert_eq!(
+            &to_bytes(res.into_body()).await.unwrap()[..],
+            br#"{"id":1}"#
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_other_responses() {
+        let body = r#"{"name":"original"}"#;
+
+        for (method, path, content_type) in [
+            (Method::GET, "/other", "application/json"),
+            (Method::GET, "/user", "text/plain"),
+            (Method::HEAD, "/user", "application/json"),
+        ] {
+            let res = response(&mut handler(), method, path, content_type, body).await;
+            assert_eq!(to_bytes(res.into_body()).await.unwrap(), body);
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_invalid_json() {
+        let res = response(
+            &mut handler(),
+            Method::GET,
+            "/user",
+            "application/json",
+            "not json",
+        )
+        .await;
+
+        assert_eq!(to_bytes(res.into_body()).await.unwrap(), "not json");
+    }
+
+    #[test]
+    fn detects_json_content_types() {
+        assert!(is_json(b"application/json"));
+        assert!(is_json(b"Application/JSON; charset=utf-8"));
+        assert!(is_json(b"application/ld+json"));
+        assert!(!is_json(b"text/json-ish"));
+        assert!(!is_json(b"text/html"));
+    }
+}
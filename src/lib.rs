@@ -8,25 +8,377 @@
 //!
 //! ## Features
 //!
-//! - `decoder`: Enables [`decode_request`] and [`decode_response`] helpers (enabled by default).
+//! - `decoder`: Enables [`decode_request`] and [`decode_response`] helpers, and
+//!   [`ReplaceHandler`] (enabled by default).
 //! - `full`: Enables all features.
 //! - `http2`: Enables HTTP/2 support.
+//! - `json`: Enables [`JsonPatchHandler`], which applies JSON Patch operations to responses, and
+//!   [`HarReplayHandler`], which replays responses recorded in HAR files.
+//! - `metrics`: Enables [`PrometheusMetrics`], which records metrics about requests and renders
+//!   them in the Prometheus text format.
 //! - `native-tls-client`: Enables [`ProxyBuilder::with_native_tls_client`].
 //! - `openssl-ca`: Enables [`certificate_authority::OpensslAuthority`].
-//! - `rcgen-ca`: Enables [`certificate_authority::RcgenAuthority`] (enabled by default).
+//! - `rcgen-ca`: Enables [`certificate_authority::RcgenAuthority`] and
+//!   [`certificate_authority::CaBuilder`] (enabled by default).
 //! - `rustls-client`: Enables [`ProxyBuilder::with_rustls_client`] (enabled by default).
+//! - `test-util`: Enables [`test_util`] helpers for testing handlers.
+//! - `tower`: Enables [`ServiceHandler`], which uses a `tower` service as an HTTP handler.
 
+/// Implements [`HttpHandler`] for a handler that wraps another one, delegating every method that
+/// isn't given to the wrapped handler.
+///
+/// The wrapped handler is either a field, as in `=> inner`, or chosen for each request by a pair
+/// of methods taking the [`HttpContext`], as in `=> handler, handler_mut`. Handlers chosen by
+/// methods have to give [`HttpHandler::on_connection_close`], which has no [`HttpContext`].
+macro_rules! delegate_http_handler {
+    (
+        impl<$param:ident: $bound:ident> $trait:ident for $ty:ty => $($target:ident),+ {
+            $($items:tt)*
+        }
+    ) => {
+        delegate_http_handler!(
+            @names [] [$param, $bound, $trait, $ty, [$($target),+]] { $($items)* } $($items)*
+        );
+    };
+
+    (@target $this:ident $ctx:ident [$field:ident]) => { $this.$field };
+    (@target $this:ident $ctx:ident [$get:ident, $get_mut:ident]) => { $this.$get($ctx) };
+    (@target_mut $this:ident $ctx:ident [$field:ident]) => { $this.$field };
+    (@target_mut $this:ident $ctx:ident [$get:ident, $get_mut:ident]) => { $this.$get_mut($ctx) };
+
+    // Collect the names of the methods that are given.
+    (
+        @names [$($names:ident)*] $head:tt $items:tt
+        $(#[$attr:meta])* async fn $name:ident $args:tt $(-> $ret:ty)? $body:block $($rest:tt)*
+    ) => {
+        delegate_http_handler!(@names [$($names)* $name] $head $items $($rest)*);
+    };
+    (
+        @names [$($names:ident)*] $head:tt $items:tt
+        $(#[$attr:meta])* fn $name:ident $args:tt $(-> $ret:ty)? $body:block $($rest:tt)*
+    ) => {
+        delegate_http_handler!(@names [$($names)* $name] $head $items $($rest)*);
+    };
+    (@names $names:tt [$param:ident, $bound:ident, $trait:ident, $ty:ty, $target:tt] $items:tt) => {
+        delegate_http_handler!(@build $names $names [$param, $bound, $trait, $ty] $items {}
+            handle_request {
+                async fn handle_request(
+                    &mut self,
+                    ctx: &$crate::HttpContext,
+                    req: ::hyper::Request<::hyper::Body>,
+                ) -> $crate::RequestOrResponse {
+                    delegate_http_handler!(@target_mut self ctx $target)
+                        .handle_request(ctx, req)
+                        .await
+                }
+            }
+            handle_response {
+                async fn handle_response(
+                    &mut self,
+                    ctx: &$crate::HttpContext,
+                    res: ::hyper::Response<::hyper::Body>,
+                ) -> ::hyper::Response<::hyper::Body> {
+                    delegate_http_handler!(@target_mut self ctx $target)
+                        .handle_response(ctx, res)
+                        .await
+                }
+            }
+            handle_error {
+                async fn handle_error(
+                    &mut self,
+                    ctx: &$crate::HttpContext,
+                    err: ::hyper::Error,
+                ) -> ::hyper::Response<::hyper::Body> {
+                    delegate_http_handler!(@target_mut self ctx $target)
+                        .handle_error(ctx, err)
+                        .await
+                }
+            }
+            handle_proxy_error {
+                async fn handle_proxy_error(
+                    &mut self,
+                    ctx: &$crate::HttpContext,
+                    err: $crate::ProxyError,
+                ) -> ::hyper::Response<::hyper::Body> {
+                    delegate_http_handler!(@target_mut self ctx $target)
+                        .handle_proxy_error(ctx, err)
+                        .await
+                }
+            }
+            span_fields {
+                fn span_fields(
+                    &self,
+                    ctx: &$crate::HttpContext,
+                    req: &::hyper::Request<::hyper::Body>,
+                ) -> Vec<(&'static str, String)> {
+                    delegate_http_handler!(@target self ctx $target).span_fields(ctx, req)
+                }
+            }
+            should_process {
+                fn should_process(
+                    &self,
+                    ctx: &$crate::HttpContext,
+                    req: &::hyper::Request<::hyper::Body>,
+                ) -> bool {
+                    delegate_http_handler!(@target self ctx $target).should_process(ctx, req)
+                }
+            }
+            rewrite_uri {
+                async fn rewrite_uri(
+                    &mut self,
+                    ctx: &$crate::HttpContext,
+                    uri: ::hyper::Uri,
+                ) -> ::hyper::Uri {
+                    delegate_http_handler!(@target_mut self ctx $target)
+                        .rewrite_uri(ctx, uri)
+                        .await
+                }
+            }
+            upstream_version {
+                fn upstream_version(
+                    &self,
+                    ctx: &$crate::HttpContext,
+                    req: &::hyper::Request<::hyper::Body>,
+                ) -> ::hyper::Version {
+                    delegate_http_handler!(@target self ctx $target).upstream_version(ctx, req)
+                }
+            }
+            should_intercept {
+                async fn should_intercept(
+                    &mut self,
+                    ctx: &$crate::HttpContext,
+                    req: &::hyper::Request<::hyper::Body>,
+                ) -> bool {
+                    delegate_http_handler!(@target_mut self ctx $target)
+                        .should_intercept(ctx, req)
+                        .await
+                }
+            }
+            intercept_decision {
+                async fn intercept_decision(
+                    &mut self,
+                    ctx: &$crate::HttpContext,
+                    req: &::hyper::Request<::hyper::Body>,
+                ) -> $crate::Decision {
+                    delegate_http_handler!(@target_mut self ctx $target)
+                        .intercept_decision(ctx, req)
+                        .await
+                }
+            }
+            should_sniff {
+                fn should_sniff(
+                    &self,
+                    ctx: &$crate::HttpContext,
+                    authority: &::hyper::http::uri::Authority,
+                ) -> bool {
+                    delegate_http_handler!(@target self ctx $target).should_sniff(ctx, authority)
+                }
+            }
+            connect_scheme {
+                fn connect_scheme(
+                    &self,
+                    ctx: &$crate::HttpContext,
+                    authority: &::hyper::http::uri::Authority,
+                ) -> Option<::hyper::http::uri::Scheme> {
+                    delegate_http_handler!(@target self ctx $target).connect_scheme(ctx, authority)
+                }
+            }
+            upstream_target {
+                fn upstream_target(
+                    &self,
+                    ctx: &$crate::HttpContext,
+                    req: &::hyper::Request<::hyper::Body>,
+                ) -> Option<$crate::UpstreamTarget> {
+                    delegate_http_handler!(@target self ctx $target).upstream_target(ctx, req)
+                }
+            }
+            handle_decrypted_stream {
+                async fn handle_decrypted_stream(
+                    &mut self,
+                    ctx: &$crate::HttpContext,
+                    stream: $crate::DecryptedStream,
+                    authority: ::hyper::http::uri::Authority,
+                ) {
+                    delegate_http_handler!(@target_mut self ctx $target)
+                        .handle_decrypted_stream(ctx, stream, authority)
+                        .await
+                }
+            }
+            on_connection_close {
+                async fn on_connection_close(&mut self, ctx: &$crate::ConnContext) {
+                    delegate_http_handler!(@target_mut self ctx $target)
+                        .on_connection_close(ctx)
+                        .await
+                }
+            }
+        );
+    };
+
+    // Go through the methods of the trait, skipping those that are given, and delegating the rest.
+    (
+        @build $names:tt $cursor:tt [$param:ident, $bound:ident, $trait:ident, $ty:ty]
+        { $($items:tt)* } { $($acc:tt)* }
+    ) => {
+        #[$crate::async_trait::async_trait]
+        impl<$param: $bound> $trait for $ty {
+            $($items)*
+            $($acc)*
+        }
+    };
+    (
+        @build $names:tt [handle_request $($skip:ident)*] $head:tt $items:tt $acc:tt
+        handle_request $skip_item:tt $($todo:tt)*
+    ) => {
+        delegate_http_handler!(@build $names $names $head $items $acc $($todo)*);
+    };
+    (
+        @build $names:tt [handle_response $($skip:ident)*] $head:tt $items:tt $acc:tt
+        handle_response $skip_item:tt $($todo:tt)*
+    ) => {
+        delegate_http_handler!(@build $names $names $head $items $acc $($todo)*);
+    };
+    (
+        @build $names:tt [handle_error $($skip:ident)*] $head:tt $items:tt $acc:tt
+        handle_error $skip_item:tt $($todo:tt)*
+    ) => {
+        delegate_http_handler!(@build $names $names $head $items $acc $($todo)*);
+    };
+    (
+        @build $names:tt [handle_proxy_error $($skip:ident)*] $head:tt $items:tt $acc:tt
+        handle_proxy_error $skip_item:tt $($todo:tt)*
+    ) => {
+        delegate_http_handler!(@build $names $names $head $items $acc $($todo)*);
+    };
+    (
+        @build $names:tt [span_fields $($skip:ident)*] $head:tt $items:tt $acc:tt
+        span_fields $skip_item:tt $($todo:tt)*
+    ) => {
+        delegate_http_handler!(@build $names $names $head $items $acc $($todo)*);
+    };
+    (
+        @build $names:tt [should_process $($skip:ident)*] $head:tt $items:tt $acc:tt
+        should_process $skip_item:tt $($todo:tt)*
+    ) => {
+        delegate_http_handler!(@build $names $names $head $items $acc $($todo)*);
+    };
+    (
+        @build $names:tt [rewrite_uri $($skip:ident)*] $head:tt $items:tt $acc:tt
+        rewrite_uri $skip_item:tt $($todo:tt)*
+    ) => {
+        delegate_http_handler!(@build $names $names $head $items $acc $($todo)*);
+    };
+    (
+        @build $names:tt [upstream_version $($skip:ident)*] $head:tt $items:tt $acc:tt
+        upstream_version $skip_item:tt $($todo:tt)*
+    ) => {
+        delegate_http_handler!(@build $names $names $head $items $acc $($todo)*);
+    };
+    (
+        @build $names:tt [should_intercept $($skip:ident)*] $head:tt $items:tt $acc:tt
+        should_intercept $skip_item:tt $($todo:tt)*
+    ) => {
+        delegate_http_handler!(@build $names $names $head $items $acc $($todo)*);
+    };
+    (
+        @build $names:tt [intercept_decision $($skip:ident)*] $head:tt $items:tt $acc:tt
+        intercept_decision $skip_item:tt $($todo:tt)*
+    ) => {
+        delegate_http_handler!(@build $names $names $head $items $acc $($todo)*);
+    };
+    (
+        @build $names:tt [should_sniff $($skip:ident)*] $head:tt $items:tt $acc:tt
+        should_sniff $skip_item:tt $($todo:tt)*
+    ) => {
+        delegate_http_handler!(@build $names $names $head $items $acc $($todo)*);
+    };
+    (
+        @build $names:tt [connect_scheme $($skip:ident)*] $head:tt $items:tt $acc:tt
+        connect_scheme $skip_item:tt $($todo:tt)*
+    ) => {
+        delegate_http_handler!(@build $names $names $head $items $acc $($todo)*);
+    };
+    (
+        @build $names:tt [upstream_target $($skip:ident)*] $head:tt $items:tt $acc:tt
+        upstream_target $skip_item:tt $($todo:tt)*
+    ) => {
+        delegate_http_handler!(@build $names $names $head $items $acc $($todo)*);
+    };
+    (
+        @build $names:tt [handle_decrypted_stream $($skip:ident)*] $head:tt $items:tt $acc:tt
+        handle_decrypted_stream $skip_item:tt $($todo:tt)*
+    ) => {
+        delegate_http_handler!(@build $names $names $head $items $acc $($todo)*);
+    };
+    (
+        @build $names:tt [on_connection_close $($skip:ident)*] $head:tt $items:tt $acc:tt
+        on_connection_close $skip_item:tt $($todo:tt)*
+    ) => {
+        delegate_http_handler!(@build $names $names $head $items $acc $($todo)*);
+    };
+    (@build $names:tt [$skip:ident $($rest:ident)*] $head:tt $items:tt $acc:tt $($todo:tt)*) => {
+        delegate_http_handler!(@build $names [$($rest)*] $head $items $acc $($todo)*);
+    };
+    (
+        @build $names:tt [] $head:tt $items:tt { $($acc:tt)* }
+        $skip_name:ident { $($item:tt)* } $($todo:tt)*
+    ) => {
+        delegate_http_handler!(@build $names $names $head $items { $($acc)* $($item)* } $($todo)*);
+    };
+}
+
+mod alpn;
+mod connection_reuse;
+mod cors;
 #[cfg(feature = "decoder")]
 mod decoder;
+mod decrypted;
 mod error;
+mod events;
+mod exchange;
+mod ext;
+#[cfg(feature = "json")]
+mod har;
+mod header_policy;
+mod host_filter;
+#[cfg(feature = "json")]
+mod json_patch;
+mod logging;
+#[cfg(feature = "metrics")]
+mod metrics;
+mod mirror;
 mod noop;
+#[cfg(target_os = "linux")]
+mod peer_cred;
+mod policy;
+mod protocol;
 mod proxy;
+#[cfg(feature = "decoder")]
+mod replace;
+mod retry;
 mod rewind;
+#[cfg(feature = "tower")]
+mod service;
+mod stream_transform;
+mod tap;
+mod timeout;
+mod tls_fault;
+mod trace_context;
+mod transform;
+mod upgrade;
+mod upstream_target;
+mod upstream_tls;
 
+pub mod body;
 pub mod certificate_authority;
+#[cfg(feature = "test-util")]
+#[cfg_attr(docsrs, doc(cfg(feature = "test-util")))]
+pub mod test_util;
 
 use futures::{Sink, SinkExt, Stream, StreamExt};
-use hyper::{Body, Request, Response, StatusCode, Uri};
+use hyper::{
+    http::uri::{Authority, Scheme},
+    Body, Request, Response, Uri, Version,
+};
 use std::net::SocketAddr;
 use tokio_tungstenite::tungstenite::{self, Message};
 use tracing::error;
@@ -34,26 +386,72 @@ use tracing::error;
 pub(crate) use rewind::Rewind;
 
 pub use async_trait;
+pub use bytes;
 pub use futures;
 pub use hyper;
 #[cfg(feature = "openssl-ca")]
 pub use openssl;
+#[cfg(feature = "rcgen-ca")]
+pub use rcgen;
+#[cfg(feature = "json")]
+pub use serde_json;
 pub use tokio_rustls::rustls;
 pub use tokio_tungstenite;
 
+pub use alpn::AlpnRouter;
+pub use connection_reuse::{ReuseInfoConnector, ReuseInfoStream};
+pub use cors::CorsHandler;
 #[cfg(feature = "decoder")]
 pub use decoder::{decode_request, decode_response};
-pub use error::Error;
+pub use decrypted::DecryptedStream;
+pub use error::{Error, ProxyError};
+pub use events::{ProxyEvent, ProxyEventKind};
+pub use exchange::ExchangeExtensions;
+pub use ext::{RequestExt, ResponseExt};
+#[cfg(feature = "json")]
+pub use har::{HarFallback, HarMatching, HarReplayHandler};
+pub use header_policy::ResponseHeaderPolicy;
+pub use host_filter::{BlackholeMode, HostFilter};
+#[cfg(feature = "json")]
+pub use json_patch::{JsonPatchHandler, PatchOperation};
+pub use logging::LoggingWebSocketHandler;
+#[cfg(feature = "metrics")]
+pub use metrics::{MetricsHandler, PrometheusMetrics};
+pub use mirror::{MirrorRecord, MirrorSink};
 pub use noop::*;
+#[cfg(target_os = "linux")]
+pub use peer_cred::PeerCred;
+pub use policy::{Decision, InterceptPolicy, InterceptPolicyHandle};
+pub use protocol::{Protocol, ProtocolMatcher, ProtocolMatchers};
 pub use proxy::*;
+#[cfg(feature = "decoder")]
+pub use replace::ReplaceHandler;
+pub use retry::RetryPolicy;
+#[cfg(feature = "tower")]
+pub use service::ServiceHandler;
+pub use stream_transform::{Lines, StreamTransform, Streaming, TransformBody};
+pub use tap::{tap_body, BodyFrame, Framing};
+pub use timeout::UpstreamTimeout;
+pub use tls_fault::{HandshakeDelay, TlsFault, TlsFaults};
+pub use transform::{BodyTransform, Pipeline, Replace};
+#[cfg(feature = "decoder")]
+pub use transform::{Decompress, Recompress};
+pub use upgrade::Upgrade;
+pub use upstream_target::{UpstreamTarget, UpstreamTargetConnector};
+pub use upstream_tls::*;
 
 /// Enum representing either an HTTP request or response.
+///
+/// More variants may be added in the future, so matches on it need a wildcard arm.
 #[derive(Debug)]
+#[non_exhaustive]
 pub enum RequestOrResponse {
     /// HTTP Request
     Request(Request<Body>),
     /// HTTP Response
     Response(Response<Body>),
+    /// HTTP Response that upgrades the client connection to a custom protocol
+    Upgrade(Upgrade),
 }
 
 impl From<Request<Body>> for RequestOrResponse {
@@ -68,12 +466,110 @@ impl From<Response<Body>> for RequestOrResponse {
     }
 }
 
+impl From<Upgrade> for RequestOrResponse {
+    fn from(upgrade: Upgrade) -> Self {
+        Self::Upgrade(upgrade)
+    }
+}
+
 /// Context for HTTP requests and responses.
 #[derive(Clone, Debug, Eq, Hash, PartialEq)]
 #[non_exhaustive]
 pub struct HttpContext {
     /// Address of the client that is sending the request.
     pub client_addr: SocketAddr,
+    /// Form of the request target sent by the client, before it was normalized by the proxy.
+    pub request_target_form: TargetForm,
+    /// Whether the request asks to upgrade the connection to a WebSocket. Such requests are
+    /// upgraded after [`HttpHandler::handle_request`] returns them, unless the handler changes them
+    /// so that they no longer ask for an upgrade.
+    pub is_websocket_upgrade: bool,
+    /// Details of the TLS connection to the upstream server. This is only set when handling a
+    /// response received over TLS by a client that records them, such as the one created by
+    /// [`ProxyBuilder::with_rustls_client`].
+    pub upstream_tls: Option<UpstreamTlsInfo>,
+    /// Whether the response was received on a connection reused from the client's pool, rather
+    /// than on a new connection. This is only set when handling a response received by a client
+    /// that records it, such as the one created by [`ProxyBuilder::with_rustls_client`], or one
+    /// built on a [`ReuseInfoConnector`].
+    pub connection_reused: Option<bool>,
+    /// The certificate chain presented by the client, starting with its own certificate. This is
+    /// only set for requests within intercepted TLS connections, when the certificate authority
+    /// requests client certificates, such as with
+    /// [`RcgenAuthority::with_request_client_cert`](certificate_authority::RcgenAuthority::with_request_client_cert).
+    pub client_cert: Option<Vec<rustls::Certificate>>,
+    /// The protocol negotiated with ALPN by the client, such as `h2` or `http/1.1`. This is only
+    /// set for requests within intercepted TLS connections, when the client offered a protocol
+    /// supported by the proxy.
+    pub client_alpn: Option<Vec<u8>>,
+    /// Credentials of the local process that opened the client connection. This is only set for
+    /// loopback clients when enabled with [`ProxyBuilder::with_peer_credentials`].
+    ///
+    /// The proxy accepts TCP connections, and `SO_PEERCRED` and `getpeereid` only report the
+    /// credentials of peers on Unix domain sockets. The owner of the client's socket is found in
+    /// `/proc/net/tcp` instead, which is only available on Linux.
+    #[cfg(target_os = "linux")]
+    #[cfg_attr(docsrs, doc(cfg(target_os = "linux")))]
+    pub peer_cred: Option<PeerCred>,
+    /// Handle for triggering the graceful shutdown of the proxy.
+    pub shutdown: ShutdownHandle,
+    /// Store for passing state from the handling of the request to the handling of its response.
+    pub extensions: ExchangeExtensions,
+}
+
+/// A context for a request from a loopback client, for testing handlers.
+#[cfg(test)]
+pub(crate) fn test_ctx() -> HttpContext {
+    HttpContext {
+        client_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+        request_target_form: TargetForm::Absolute,
+        is_websocket_upgrade: false,
+        upstream_tls: None,
+        connection_reused: None,
+        client_cert: None,
+        client_alpn: None,
+        #[cfg(target_os = "linux")]
+        peer_cred: None,
+        shutdown: ShutdownHandle::default(),
+        extensions: ExchangeExtensions::default(),
+    }
+}
+
+/// Context for client connections.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct ConnContext {
+    /// Address of the client that opened the connection.
+    pub client_addr: SocketAddr,
+}
+
+/// Form of the request target in an HTTP request line.
+///
+/// HTTP/2 requests don't have a request line, so they are reported as [`TargetForm::Absolute`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum TargetForm {
+    /// A path and optional query, such as `/index.html?q=1`.
+    Origin,
+    /// An absolute URI, such as `http://example.com/index.html`.
+    Absolute,
+    /// An authority, such as `example.com:443`. Only used for CONNECT requests.
+    Authority,
+    /// A single asterisk (`*`). Only used for server-wide OPTIONS requests.
+    Asterisk,
+}
+
+impl TargetForm {
+    pub(crate) fn from_uri(uri: &Uri) -> Self {
+        if uri.scheme().is_some() {
+            Self::Absolute
+        } else if uri.authority().is_some() {
+            Self::Authority
+        } else if uri.path() == "*" {
+            Self::Asterisk
+        } else {
+            Self::Origin
+        }
+    }
 }
 
 /// Context for websocket messages.
@@ -98,11 +594,20 @@ pub enum WebSocketContext {
 /// Handler for HTTP requests and responses.
 ///
 /// Each request/response pair is passed to the same instance of the handler.
+///
+/// # Informational responses
+///
+/// Only final responses and `101 Switching Protocols` are passed to
+/// [`HttpHandler::handle_response`]. Relaying other informational (1xx) responses, such as
+/// `103 Early Hints`, needs a hyper version whose client hands them to the caller and whose server
+/// can send them, which hyper 0.14 can't do: its client discards them, and its server only sends
+/// `100 Continue` on its own, when the body of a request with `Expect: 100-continue` is first read.
 #[async_trait::async_trait]
 pub trait HttpHandler: Clone + Send + Sync + 'static {
     /// This handler will be called for each HTTP request. It can either return a modified request,
     /// or a response. If a request is returned, it will be sent to the upstream server. If a
-    /// response is returned, it will be sent to the client.
+    /// response is returned, it will be sent to the client. If an [`Upgrade`] is returned, its
+    /// response will be sent to the client and the upgraded connection passed to its handler.
     async fn handle_request(
         &mut self,
         _ctx: &HttpContext,
@@ -117,19 +622,126 @@ pub trait HttpHandler: Clone + Send + Sync + 'static {
         res
     }
 
-    /// This handler will be called if a proxy request fails. Default response is a 502 Bad Gateway.
-    async fn handle_error(&mut self, _ctx: &HttpContext, err: hyper::Error) -> Response<Body> {
-        error!("Failed to forward request: {}", err);
+    /// This handler will be called if a proxy request fails. Defaults to classifying the error as
+    /// a [`ProxyError`] and calling [`HttpHandler::handle_proxy_error`].
+    async fn handle_error(&mut self, ctx: &HttpContext, err: hyper::Error) -> Response<Body> {
+        self.handle_proxy_error(ctx, err.into()).await
+    }
+
+    /// This handler will be called with the classified error if a proxy request fails, unless
+    /// [`HttpHandler::handle_error`] is overridden. Default response has the status given by
+    /// [`ProxyError::status`], which is 504 Gateway Timeout for timeouts, and 502 Bad Gateway
+    /// otherwise.
+    async fn handle_proxy_error(&mut self, _ctx: &HttpContext, err: ProxyError) -> Response<Body> {
+        error!("Failed to forward request: {}: {}", err, err.hyper_error());
         Response::builder()
-            .status(StatusCode::BAD_GATEWAY)
+            .status(err.status())
             .body(Body::empty())
             .expect("Failed to build response")
     }
 
+    /// Additional fields to attach to the span created for each HTTP request. The fields are
+    /// recorded as `name=value` pairs in the span's `custom` field, so they appear alongside the
+    /// output of all child spans. Defaults to no additional fields.
+    fn span_fields(&self, _ctx: &HttpContext, _req: &Request<Body>) -> Vec<(&'static str, String)> {
+        Vec::new()
+    }
+
+    /// Whether to run the handler for a request. If `false` is returned, the request is forwarded
+    /// without calling [`HttpHandler::handle_request`], [`HttpHandler::rewrite_uri`], or
+    /// [`HttpHandler::handle_response`], its body isn't decoded, and the request and response
+    /// pipelines aren't applied, which avoids the cost of handling requests that don't need to be
+    /// inspected, such as static assets. Defaults to `true` for all requests.
+    fn should_process(&self, _ctx: &HttpContext, _req: &Request<Body>) -> bool {
+        true
+    }
+
+    /// This handler will be called for each HTTP request that is forwarded to the upstream server,
+    /// just before the outbound request is built. It can return a different URI to send the
+    /// request to, for example to upgrade the scheme to `https` or to map paths onto another
+    /// server. Defaults to returning the URI unchanged.
+    async fn rewrite_uri(&mut self, _ctx: &HttpContext, uri: Uri) -> Uri {
+        uri
+    }
+
+    /// The HTTP version to use when forwarding a request to the upstream server. This is called
+    /// after [`HttpHandler::rewrite_uri`]. Defaults to HTTP/1.1 for all requests.
+    ///
+    /// The version of the connection is negotiated by the client's connector, so this can't
+    /// downgrade a connection that negotiated HTTP/2. If HTTP/2 is returned and the connection
+    /// uses HTTP/1, the request fails instead of being sent over HTTP/1.
+    fn upstream_version(&self, _ctx: &HttpContext, _req: &Request<Body>) -> Version {
+        Version::HTTP_11
+    }
+
     /// Whether a CONNECT request should be intercepted. Defaults to `true` for all requests.
     async fn should_intercept(&mut self, _ctx: &HttpContext, _req: &Request<Body>) -> bool {
         true
     }
+
+    /// Decide whether a CONNECT request should be intercepted, along with the reason for the
+    /// decision. The reason is recorded in the span for the connection. Defaults to calling
+    /// [`HttpHandler::should_intercept`]. Override this to delegate to an [`InterceptPolicy`].
+    async fn intercept_decision(&mut self, ctx: &HttpContext, req: &Request<Body>) -> Decision {
+        Decision {
+            intercept: self.should_intercept(ctx, req).await,
+            reason: "should_intercept",
+        }
+    }
+
+    /// Whether to read the first bytes sent through a CONNECT tunnel to detect the protocol in
+    /// use. If `false` is returned, the tunnel is forwarded to the server as is without being
+    /// intercepted, which is needed for protocols where the server speaks first, such as SMTP.
+    /// Defaults to `true` for all requests.
+    fn should_sniff(&self, _ctx: &HttpContext, _authority: &Authority) -> bool {
+        true
+    }
+
+    /// The scheme to use for the requests sent through an intercepted CONNECT tunnel, overriding
+    /// the one inferred from the protocol detected in the tunnel.
+    ///
+    /// By default, requests over TLS are sent upstream with `https`, and plaintext requests with
+    /// `http`. If a scheme is returned, it is used for the tunnel regardless. Tunnels whose
+    /// protocol couldn't be detected are then served as plaintext HTTP instead of being forwarded
+    /// as is, which helps with clients that use non-standard ports or upgrade to TLS themselves.
+    /// Defaults to `None` for all requests.
+    fn connect_scheme(&self, _ctx: &HttpContext, _authority: &Authority) -> Option<Scheme> {
+        None
+    }
+
+    /// Where to send a request upstream, overriding the host used for SNI, the address to connect
+    /// to, or the `Host` header. This is called after [`HttpHandler::upstream_version`], and with
+    /// CONNECT requests, which only use the address to connect to for tunnels that aren't
+    /// intercepted and for decrypted streams. Defaults to `None` for all requests.
+    fn upstream_target(&self, _ctx: &HttpContext, _req: &Request<Body>) -> Option<UpstreamTarget> {
+        None
+    }
+
+    /// This handler will be called with the decrypted stream of an intercepted TLS connection that
+    /// doesn't carry HTTP, such as a database protocol over TLS. The protocol is detected from the
+    /// first bytes sent by the client, unless it negotiated a protocol with ALPN, in which case the
+    /// stream is always served as HTTP. If the client doesn't send anything before the timeout set
+    /// with [`ProxyBuilder::with_sniff_timeout`], the stream is also passed to this handler.
+    ///
+    /// Defaults to forwarding the stream to the server with [`DecryptedStream::forward`], over a
+    /// new TLS connection using [`DecryptedStream::client_config`]. Use `forward` with a custom
+    /// config to trust other roots.
+    async fn handle_decrypted_stream(
+        &mut self,
+        _ctx: &HttpContext,
+        stream: DecryptedStream,
+        authority: Authority,
+    ) {
+        let config = stream.client_config();
+        if let Err(e) = stream.forward(&authority, config).await {
+            error!("Failed to forward decrypted stream: {}", e);
+        }
+    }
+
+    /// This handler will be called once a client connection has closed, after any tunnels,
+    /// WebSockets, and upgraded connections opened through it have also closed. It is called
+    /// regardless of whether the connection closed cleanly or due to an error.
+    async fn on_connection_close(&mut self, _ctx: &ConnContext) {}
 }
 
 /// Handler for WebSocket messages.
@@ -137,6 +749,11 @@ pub trait HttpHandler: Clone + Send + Sync + 'static {
 /// Messages sent over the same WebSocket Stream are passed to the same instance of the handler.
 #[async_trait::async_trait]
 pub trait WebSocketHandler: Clone + Send + Sync + 'static {
+    /// This handler will be called with the handshake request before it is sent to the server, so
+    /// that headers such as `Sec-WebSocket-Protocol`, `Origin`, and `Sec-WebSocket-Extensions`
+    /// can be changed.
+    fn rewrite_handshake_request(&self, _req: &mut Request<()>) {}
+
     /// This handler is responsible for forwarding WebSocket messages from a Stream to a Sink and
     /// recovering from any potential errors.
     async fn handle_websocket(
@@ -0,0 +1,147 @@
+use crate::{WebSocketContext, WebSocketHandler};
+use futures::{Sink, SinkExt, Stream, StreamExt};
+use std::fmt::Write;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tracing::{debug, error, trace};
+
+/// A WebSocket handler that logs each message and forwards it unchanged.
+///
+/// The direction, kind and size of each message are logged at the `debug` level. A preview of its
+/// content is logged at the `trace` level, truncated to a configurable length. Text is logged as
+/// is, and binary data as hex.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::LoggingWebSocketHandler;
+///
+/// let handler = LoggingWebSocketHandler::new().with_preview_len(32);
+/// ```
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct LoggingWebSocketHandler {
+    preview_len: usize,
+}
+
+impl LoggingWebSocketHandler {
+    /// Create a new handler, previewing up to 64 characters of each message.
+    pub fn new() -> Self {
+        Self { preview_len: 64 }
+    }
+
+    /// Set the maximum number of characters of text messages, or bytes of binary messages, to
+    /// include in previews.
+    pub fn with_preview_len(mut self, preview_len: usize) -> Self {
+        self.preview_len = preview_len;
+        self
+    }
+
+    fn log(&self, ctx: &WebSocketContext, message: &Message) {
+        let direction = match ctx {
+            WebSocketContext::ClientToServer { src, dst } => format!("{} -> {}", src, dst),
+            WebSocketContext::ServerToClient { src, dst } => format!("{} -> {}", src, dst),
+        };
+
+        let (kind, payload): (_, &[u8]) = match message {
+            Message::Text(text) => ("text", text.as_bytes()),
+            Message::Binary(data) => ("binary", data),
+            Message::Ping(data) => ("ping", data),
+            Message::Pong(data) => ("pong", data),
+            Message::Close(_) => ("close", &[]),
+            Message::Frame(frame) => ("frame", frame.payload()),
+        };
+
+        debug!(
+            "WebSocket {} message, {} bytes, {}",
+            kind,
+            payload.len(),
+            direction
+        );
+
+        match message {
+            Message::Text(text) => trace!("{:?}", text_preview(text, self.preview_len)),
+            Message::Close(Some(frame)) => trace!("{} {}", frame.code, frame.reason),
+            Message::Close(None) => (),
+            _ => trace!("{}", hex_preview(payload, self.preview_len)),
+        }
+    }
+}
+
+impl Default for LoggingWebSocketHandler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn text_preview(text: &str, max_len: usize) -> String {
+    match text.char_indices().nth(max_len) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text.to_owned(),
+    }
+}
+
+fn hex_preview(data: &[u8], max_len: usize) -> String {
+    let mut preview = String::with_capacity(max_len.min(data.len()) * 2 + 3);
+    for byte in data.iter().take(max_len) {
+        write!(preview, "{:02x}", byte).expect("Failed to write to string");
+    }
+
+    if data.len() > max_len {
+        preview.push_str("...");
+    }
+
+    preview
+}
+
+#[async_trait::async_trait]
+impl WebSocketHandler for LoggingWebSocketHandler {
+    async fn handle_websocket(
+        self,
+        ctx: WebSocketContext,
+        mut stream: impl Stream<Item = Result<Message, tungstenite::Error>> + Unpin + Send + 'static,
+        mut sink: impl Sink<Message, Error = tungstenite::Error> + Unpin + Send + 'static,
+    ) {
+        while let Some(message) = stream.next().await {
+            let message = match message {
+                Ok(message) => message,
+                Err(e) => {
+                    error!("WebSocket message error: {}", e);
+
+                    match sink.send(Message::Close(None)).await {
+                        Err(tungstenite::Error::ConnectionClosed) => (),
+                        Err(e) => error!("WebSocket close error: {}", e),
+                        _ => (),
+                    };
+
+                    break;
+                }
+            };
+
+            self.log(&ctx, &message);
+
+            match sink.send(message).await {
+                Err(tungstenite::Error::ConnectionClosed) => (),
+                Err(e) => error!("WebSocket send error: {}", e),
+                _ => (),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncates_text_preview() {
+        assert_eq!(text_preview("hello", 5), "hello");
+        assert_eq!(text_preview("hello world", 5), "hello...");
+        assert_eq!(text_preview("héllo", 2), "hé...");
+    }
+
+    #[test]
+    fn truncates_hex_preview() {
+        assert_eq!(hex_preview(&[0x00, 0xff], 2), "00ff");
+        assert_eq!(hex_preview(&[0x00, 0xff, 0x10], 2), "00ff...");
+        assert_eq!(hex_preview(&[], 2), "");
+    }
+}
@@ -0,0 +1,312 @@
+use crate::{ConnContext, HttpContext, HttpHandler, RequestOrResponse};
+use hyper::{header::CONTENT_TYPE, Body, Request, Response, StatusCode};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+/// Host that the metrics are served from by [`MetricsHandler`].
+const HOST: &str = "hudsucker.internal";
+
+const CONTENT_TYPE_TEXT: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Upper bounds of the request duration buckets, in seconds. These are the default buckets of the
+/// Prometheus client libraries.
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (bucket, &bound) in self.buckets.iter_mut().zip(&BUCKETS) {
+            if value <= bound {
+                *bucket += 1;
+            }
+        }
+
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    requests: BTreeMap<String, u64>,
+    responses: BTreeMap<u16, u64>,
+    errors: u64,
+    connections_closed: u64,
+    durations: Histogram,
+}
+
+/// Metrics about the requests handled by a proxy, which can be rendered in the Prometheus text
+/// exposition format.
+///
+/// Metrics are recorded by the handler returned from [`PrometheusMetrics::handler`], which also
+/// serves them from `http://hudsucker.internal/metrics`. Clones share the same metrics.
+///
+/// The following metrics are recorded:
+///
+/// - `hudsucker_requests_total`, a counter of the requests passed to the handler, by `method`.
+/// - `hudsucker_responses_total`, a counter of the responses passed to the handler, by `status`.
+/// - `hudsucker_errors_total`, a counter of the requests that failed to be forwarded.
+/// - `hudsucker_connections_closed_total`, a counter of the client connections that have closed.
+/// - `hudsucker_request_duration_seconds`, a histogram of the time from a request being passed to
+///   the handler until its response or error is.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{NoopHandler, PrometheusMetrics};
+///
+/// let metrics = PrometheusMetrics::new();
+/// let handler = metrics.handler(NoopHandler::default());
+///
+/// assert!(metrics.render().contains("hudsucker_requests_total"));
+/// ```
+#[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+#[derive(Clone, Debug, Default)]
+pub struct PrometheusMetrics(Arc<Mutex<State>>);
+
+impl PrometheusMetrics {
+    /// Create a new set of metrics, with nothing recorded.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wrap a handler, so that the requests it handles are recorded.
+    pub fn handler<H>(&self, inner: H) -> MetricsHandler<H> {
+        MetricsHandler {
+            inner,
+            metrics: self.clone(),
+            started: None,
+        }
+    }
+
+    fn record(&self, f: impl FnOnce(&mut State)) {
+        f(&mut self.0.lock().unwrap_or_else(|e| e.into_inner()));
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let state = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let mut out = String::new();
+
+        header(
+            &mut out,
+            "hudsucker_requests_total",
+            "Requests passed to the handler.",
+            "counter",
+        );
+        for (method, count) in &state.requests {
+            let _ = writeln!(
+                out,
+                "hudsucker_requests_total{{method=\"{}\"}} {}",
+                method, count
+            );
+        }
+
+        header(
+            &mut out,
+            "hudsucker_responses_total",
+            "Responses passed to the handler.",
+            "counter",
+        );
+        for (status, count) in &state.responses {
+            let _ = writeln!(
+                out,
+                "hudsucker_responses_total{{status=\"{}\"}} {}",
+                status, count
+            );
+        }
+
+        header(
+            &mut out,
+            "hudsucker_errors_total",
+            "Requests that failed to be forwarded.",
+            "counter",
+        );
+        let _ = writeln!(out, "hudsucker_errors_total {}", state.errors);
+
+        header(
+            &mut out,
+            "hudsucker_connections_closed_total",
+            "Client connections that have closed.",
+            "counter",
+        );
+        let _ = writeln!(
+            out,
+            "hudsucker_connections_closed_total {}",
+            state.connections_closed
+        );
+
+        header(
+            &mut out,
+            "hudsucker_request_duration_seconds",
+            "Time taken to handle requests.",
+            "histogram",
+        );
+        let durations = &state.durations;
+        for (bucket, bound) in durations.buckets.iter().zip(BUCKETS) {
+            let _ = writeln!(
+                out,
+                "hudsucker_request_duration_seconds_bucket{{le=\"{}\"}} {}",
+                bound, bucket
+            );
+        }
+        let _ = writeln!(
+            out,
+            "hudsucker_request_duration_seconds_bucket{{le=\"+Inf\"}} {}",
+            durations.count
+        );
+        let _ = writeln!(
+            out,
+            "hudsucker_request_duration_seconds_sum {}",
+            durations.sum
+        );
+        let _ = writeln!(
+            out,
+            "hudsucker_request_duration_seconds_count {}",
+            durations.count
+        );
+
+        out
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// An HTTP handler that records [`PrometheusMetrics`] about the requests it handles, created with
+/// [`PrometheusMetrics::handler`].
+///
+/// Requests to `http://hudsucker.internal/metrics` are answered with the rendered metrics, without
+/// being passed to the inner handler or forwarded. All other methods are passed through to the
+/// inner handler after being recorded.
+#[cfg_attr(docsrs, doc(cfg(feature = "metrics")))]
+#[derive(Clone, Debug)]
+pub struct MetricsHandler<H> {
+    inner: H,
+    metrics: PrometheusMetrics,
+    started: Option<Instant>,
+}
+
+impl<H> MetricsHandler<H> {
+    fn finish(&mut self, state: &mut State) {
+        if let Some(started) = self.started.take() {
+            state.durations.observe(started.elapsed().as_secs_f64());
+        }
+    }
+}
+
+fn is_metrics_request(req: &Request<Body>) -> bool {
+    req.uri()
+        .host()
+        .is_some_and(|host| host.eq_ignore_ascii_case(HOST))
+        && req.uri().path() == "/metrics"
+}
+
+delegate_http_handler! {
+    impl<H: HttpHandler> HttpHandler for MetricsHandler<H> => inner {
+        async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+            if is_metrics_request(&req) {
+                return Response::builder()
+                    .status(StatusCode::OK)
+                    .header(CONTENT_TYPE, CONTENT_TYPE_TEXT)
+                    .body(Body::from(self.metrics.render()))
+                    .expect("Failed to build response")
+                    .into();
+            }
+
+            self.started = Some(Instant::now());
+            self.metrics.record(|state| {
+                *state
+                    .requests
+                    .entry(req.method().as_str().to_owned())
+                    .or_default() += 1;
+            });
+
+            self.inner.handle_request(ctx, req).await
+        }
+
+        async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+            let res = self.inner.handle_response(ctx, res).await;
+
+            let metrics = self.metrics.clone();
+            metrics.record(|state| {
+                *state.responses.entry(res.status().as_u16()).or_default() += 1;
+                self.finish(state);
+            });
+
+            res
+        }
+
+        async fn handle_error(&mut self, ctx: &HttpContext, err: hyper::Error) -> Response<Body> {
+            let metrics = self.metrics.clone();
+            metrics.record(|state| {
+                state.errors += 1;
+                self.finish(state);
+            });
+
+            self.inner.handle_error(ctx, err).await
+        }
+
+        fn should_process(&self, ctx: &HttpContext, req: &Request<Body>) -> bool {
+            is_metrics_request(req) || self.inner.should_process(ctx, req)
+        }
+
+        async fn on_connection_close(&mut self, ctx: &ConnContext) {
+            self.metrics.record(|state| state.connections_closed += 1);
+            self.inner.on_connection_close(ctx).await
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{test_ctx, NoopHandler};
+
+    #[tokio::test]
+    async fn records_requests() {
+        let metrics = PrometheusMetrics::new();
+        let mut handler = metrics.handler(NoopHandler::new());
+
+        for _ in 0..2 {
+            let req = Request::get("http://example.com/")
+                .body(Body::empty())
+                .unwrap();
+            handler.handle_request(&test_ctx(), req).await;
+            handler
+                .handle_response(&test_ctx(), Response::new(Body::empty()))
+                .await;
+        }
+
+        let rendered = metrics.render();
+        assert!(rendered.contains("hudsucker_requests_total{method=\"GET\"} 2\n"));
+        assert!(rendered.contains("hudsucker_responses_total{status=\"200\"} 2\n"));
+        assert!(rendered.contains("hudsucker_request_duration_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(rendered.contains("hudsucker_request_duration_seconds_count 2\n"));
+        assert!(rendered.contains("# TYPE hudsucker_request_duration_seconds histogram\n"));
+    }
+
+    #[test]
+    fn fills_cumulative_buckets() {
+        let mut histogram = Histogram::default();
+        histogram.observe(0.02);
+        histogram.observe(3.0);
+
+        assert_eq!(histogram.buckets, [0, 0, 1, 1, 1, 1, 1, 1, 1, 2, 2]);
+        assert_eq!(histogram.count, 2);
+    }
+}
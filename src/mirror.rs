@@ -0,0 +1,58 @@
+use async_trait::async_trait;
+use bytes::Bytes;
+use hyper::{HeaderMap, Method, StatusCode, Uri, Version};
+use std::net::SocketAddr;
+use tokio::sync::mpsc;
+
+/// A copy of a request and its response, sent to the sink set with
+/// [`ProxyBuilder::with_mirror`](crate::ProxyBuilder::with_mirror).
+///
+/// Bodies are captured as they are forwarded, up to 64 KiB each. The record is sent once the
+/// response body has been forwarded, or dropped because the client disconnected.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct MirrorRecord {
+    /// Address of the client that sent the request.
+    pub client_addr: SocketAddr,
+    /// Method of the request.
+    pub method: Method,
+    /// URI of the request.
+    pub uri: Uri,
+    /// Version of the request.
+    pub version: Version,
+    /// Headers of the request.
+    pub request_headers: HeaderMap,
+    /// The start of the request body, as far as it was read.
+    pub request_body: Bytes,
+    /// Whether the request body was longer than what was captured.
+    pub request_body_truncated: bool,
+    /// Status of the response, or `None` if no response was sent, such as when the client
+    /// disconnected first.
+    pub status: Option<StatusCode>,
+    /// Headers of the response.
+    pub response_headers: HeaderMap,
+    /// The start of the response body, as far as it was forwarded.
+    pub response_body: Bytes,
+    /// Whether the response body was longer than what was captured.
+    pub response_body_truncated: bool,
+}
+
+/// Consumes the records of mirrored traffic.
+///
+/// Records are queued and passed to the sink one at a time by a separate task, so a slow sink
+/// never delays the requests being forwarded. If the queue is full, records are dropped and
+/// counted by [`ProxyControl::mirror_records_dropped`](crate::ProxyControl::mirror_records_dropped).
+///
+/// This is implemented for [`mpsc::Sender`], which waits for capacity in the channel.
+#[async_trait]
+pub trait MirrorSink: Send + Sync + 'static {
+    /// Consume a record.
+    async fn consume(&self, record: MirrorRecord);
+}
+
+#[async_trait]
+impl MirrorSink for mpsc::Sender<MirrorRecord> {
+    async fn consume(&self, record: MirrorRecord) {
+        let _ = self.send(record).await;
+    }
+}
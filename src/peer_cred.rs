@@ -0,0 +1,167 @@
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+/// Credentials of the local process that opened a connection to the proxy.
+///
+/// These are only available for clients connecting over the loopback interface, when enabled
+/// with [`ProxyBuilder::with_peer_credentials`](crate::ProxyBuilder::with_peer_credentials).
+#[cfg_attr(docsrs, doc(cfg(target_os = "linux")))]
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct PeerCred {
+    /// User ID of the owner of the client's socket.
+    pub uid: u32,
+    inode: u64,
+}
+
+impl PeerCred {
+    /// Find the process ID of a process that has the client's socket open. This is `None` if the
+    /// process can't be found, such as when it has exited, or when it belongs to another user and
+    /// the proxy isn't privileged.
+    ///
+    /// This scans the open files of every process in `/proc`, so it is slow and blocks the
+    /// current thread. It is only done when called, and should be called with
+    /// [`tokio::task::spawn_blocking`] from async code.
+    pub fn pid(&self) -> Option<u32> {
+        find_process(self.inode)
+    }
+}
+
+/// Normalize IPv4-mapped IPv6 addresses, so that connections to dual-stack sockets are matched.
+fn canonical(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), addr.port()),
+            None => addr,
+        },
+        IpAddr::V4(_) => addr,
+    }
+}
+
+/// Parse an address from `/proc/net/tcp` or `/proc/net/tcp6`, where each 32-bit word of the IP
+/// address is printed in host byte order.
+fn parse_addr(addr: &str) -> Option<SocketAddr> {
+    let (ip, port) = addr.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+
+    let word = |i: usize| -> Option<[u8; 4]> {
+        Some(
+            u32::from_str_radix(ip.get(i * 8..i * 8 + 8)?, 16)
+                .ok()?
+                .to_ne_bytes(),
+        )
+    };
+
+    let ip: IpAddr = match ip.len() {
+        8 => Ipv4Addr::from(word(0)?).into(),
+        32 => {
+            let mut octets = [0; 16];
+            for i in 0..4 {
+                octets[i * 4..i * 4 + 4].copy_from_slice(&word(i)?);
+            }
+            Ipv6Addr::from(octets).into()
+        }
+        _ => return None,
+    };
+
+    Some(canonical(SocketAddr::new(ip, port)))
+}
+
+/// Find the owner and inode of the socket with the given local and remote addresses in the
+/// contents of `/proc/net/tcp` or `/proc/net/tcp6`.
+fn find_socket(table: &str, local: SocketAddr, remote: SocketAddr) -> Option<(u32, u64)> {
+    table.lines().skip(1).find_map(|line| {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+
+        if parse_addr(fields.get(1)?)? != local || parse_addr(fields.get(2)?)? != remote {
+            return None;
+        }
+
+        Some((fields.get(7)?.parse().ok()?, fields.get(9)?.parse().ok()?))
+    })
+}
+
+/// Find a process that has the socket with the given inode open.
+fn find_process(inode: u64) -> Option<u32> {
+    let target = format!("socket:[{}]", inode);
+
+    fs::read_dir("/proc").ok()?.flatten().find_map(|entry| {
+        let pid = entry.file_name().to_str()?.parse().ok()?;
+        let found = fs::read_dir(entry.path().join("fd"))
+            .ok()?
+            .flatten()
+            .any(|fd| fs::read_link(fd.path()).is_ok_and(|link| link.as_os_str() == &*target));
+
+        found.then_some(pid)
+    })
+}
+
+/// Look up the credentials of the process that opened a connection from `client_addr` to
+/// `local_addr`, if the client is on the loopback interface.
+///
+/// `SO_PEERCRED` is only supported by Unix domain sockets, so the client's socket is found in the
+/// kernel's table of TCP sockets instead. Finding the process is left to [`PeerCred::pid`].
+pub(crate) fn lookup(client_addr: SocketAddr, local_addr: SocketAddr) -> Option<PeerCred> {
+    let (client_addr, local_addr) = (canonical(client_addr), canonical(local_addr));
+
+    if !client_addr.ip().is_loopback() {
+        return None;
+    }
+
+    let (uid, inode) = ["/proc/net/tcp", "/proc/net/tcp6"]
+        .into_iter()
+        .filter_map(|path| fs::read_to_string(path).ok())
+        .find_map(|table| find_socket(&table, client_addr, local_addr))?;
+
+    Some(PeerCred { uid, inode })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{TcpListener, TcpStream};
+
+    #[test]
+    fn parses_addresses() {
+        let ipv4 = u32::from_ne_bytes([127, 0, 0, 1]);
+        assert_eq!(
+            parse_addr(&format!("{:08X}:1F90", ipv4)),
+            Some(SocketAddr::from(([127, 0, 0, 1], 8080)))
+        );
+
+        let ffff = u32::from_ne_bytes([0, 0, 0xff, 0xff]);
+        assert_eq!(
+            parse_addr(&format!("{:08X}{:08X}{:08X}{:08X}:0050", 0, 0, ffff, ipv4)),
+            Some(SocketAddr::from(([127, 0, 0, 1], 80)))
+        );
+
+        assert_eq!(parse_addr("invalid"), None);
+    }
+
+    #[test]
+    fn finds_own_credentials() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (_server, client_addr) = listener.accept().unwrap();
+
+        let peer_cred = lookup(client_addr, listener.local_addr().unwrap()).unwrap();
+
+        // SAFETY: getuid is always successful.
+        assert_eq!(peer_cred.uid, unsafe { libc::getuid() });
+        assert_eq!(peer_cred.pid(), Some(std::process::id()));
+        drop(client);
+    }
+
+    #[test]
+    fn skips_remote_clients() {
+        assert_eq!(
+            lookup(
+                SocketAddr::from(([192, 0, 2, 1], 1234)),
+                SocketAddr::from(([192, 0, 2, 2], 8080))
+            ),
+            None
+        );
+    }
+}
@@ -0,0 +1,318 @@
+use arc_swap::ArcSwap;
+use hyper::http::uri::Authority;
+use std::sync::Arc;
+
+/// The outcome of deciding whether to intercept a CONNECT request.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct Decision {
+    /// Whether the request should be intercepted.
+    pub intercept: bool,
+    /// Why the decision was made. This is recorded in the span for the connection.
+    pub reason: &'static str,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct Rule {
+    host: String,
+    decision: Decision,
+}
+
+impl Rule {
+    fn matches(&self, host: &str) -> bool {
+        match self.host.strip_prefix("*.") {
+            Some(domain) => host
+                .strip_suffix(domain)
+                .is_some_and(|subdomain| subdomain.ends_with('.')),
+            None => host == self.host,
+        }
+    }
+}
+
+/// A set of rules deciding which hosts to intercept.
+///
+/// Rules are tried in the order they were added, and the first rule matching the host of the
+/// request decides whether it is intercepted. If no rule matches, the default decision is used.
+/// Hosts are either matched exactly, or if they start with `*.`, against all of their subdomains.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{
+///     async_trait::async_trait,
+///     hyper::{Body, Request},
+///     Decision, HttpContext, HttpHandler, InterceptPolicy,
+/// };
+///
+/// #[derive(Clone)]
+/// pub struct PolicyHandler {
+///     policy: InterceptPolicy,
+/// }
+///
+/// impl PolicyHandler {
+///     pub fn new() -> Self {
+///         Self {
+///             policy: InterceptPolicy::deny_by_default()
+///                 .deny("bank.example.com", "financial services are never decrypted")
+///                 .allow("*.example.com", "example.com is monitored"),
+///         }
+///     }
+/// }
+///
+/// #[async_trait]
+/// impl HttpHandler for PolicyHandler {
+///     async fn intercept_decision(&mut self, _ctx: &HttpContext, req: &Request<Body>) -> Decision {
+///         self.policy.decide_request(req)
+///     }
+/// }
+/// ```
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct InterceptPolicy {
+    rules: Vec<Rule>,
+    default: Decision,
+}
+
+impl InterceptPolicy {
+    /// Create a policy that intercepts hosts which don't match any rule.
+    pub fn allow_by_default() -> Self {
+        Self {
+            rules: Vec::new(),
+            default: Decision {
+                intercept: true,
+                reason: "allowed by default",
+            },
+        }
+    }
+
+    /// Create a policy that doesn't intercept hosts which don't match any rule.
+    pub fn deny_by_default() -> Self {
+        Self {
+            rules: Vec::new(),
+            default: Decision {
+                intercept: false,
+                reason: "denied by default",
+            },
+        }
+    }
+
+    /// Add a rule that intercepts the given host.
+    pub fn allow(self, host: impl Into<String>, reason: &'static str) -> Self {
+        self.rule(host.into(), true, reason)
+    }
+
+    /// Add a rule that doesn't intercept the given host.
+    pub fn deny(self, host: impl Into<String>, reason: &'static str) -> Self {
+        self.rule(host.into(), false, reason)
+    }
+
+    fn rule(mut self, host: String, intercept: bool, reason: &'static str) -> Self {
+        self.rules.push(Rule {
+            host: host.to_ascii_lowercase(),
+            decision: Decision { intercept, reason },
+        });
+        self
+    }
+
+    /// Decide whether to intercept requests to the given authority.
+    pub fn decide(&self, authority: &Authority) -> Decision {
+        let host = authority.host().to_ascii_lowercase();
+
+        self.rules
+            .iter()
+            .find(|rule| rule.matches(&host))
+            .map_or(self.default, |rule| rule.decision)
+    }
+
+    /// Decide whether to intercept the given CONNECT request. Requests without an authority use
+    /// the default decision.
+    pub fn decide_request<T>(&self, req: &hyper::Request<T>) -> Decision {
+        match req.uri().authority() {
+            Some(authority) => self.decide(authority),
+            None => self.default,
+        }
+    }
+}
+
+impl Default for InterceptPolicy {
+    fn default() -> Self {
+        Self::allow_by_default()
+    }
+}
+
+/// A shared handle to an [`InterceptPolicy`] that can be replaced while the proxy is running, for
+/// example from an admin API.
+///
+/// Clones of the handle share the same policy. Loading the policy is lock-free, so it can be
+/// consulted for every CONNECT request, and a new policy applies to tunnels opened after it is
+/// stored.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{
+///     async_trait::async_trait,
+///     hyper::{Body, Request},
+///     HttpContext, HttpHandler, InterceptPolicy, InterceptPolicyHandle,
+/// };
+///
+/// #[derive(Clone)]
+/// pub struct PolicyHandler {
+///     policy: InterceptPolicyHandle,
+/// }
+///
+/// #[async_trait]
+/// impl HttpHandler for PolicyHandler {
+///     async fn should_intercept(&mut self, _ctx: &HttpContext, req: &Request<Body>) -> bool {
+///         self.policy.decide_request(req).intercept
+///     }
+/// }
+///
+/// let policy = InterceptPolicyHandle::new(InterceptPolicy::deny_by_default());
+/// let handler = PolicyHandler {
+///     policy: policy.clone(),
+/// };
+///
+/// // Later, while the proxy is running:
+/// policy.store(InterceptPolicy::deny_by_default().allow("*.example.com", "monitored"));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct InterceptPolicyHandle {
+    policy: Arc<ArcSwap<InterceptPolicy>>,
+}
+
+impl InterceptPolicyHandle {
+    /// Create a handle to the given policy.
+    pub fn new(policy: InterceptPolicy) -> Self {
+        Self {
+            policy: Arc::new(ArcSwap::from_pointee(policy)),
+        }
+    }
+
+    /// Get the current policy.
+    pub fn load(&self) -> Arc<InterceptPolicy> {
+        self.policy.load_full()
+    }
+
+    /// Replace the policy.
+    pub fn store(&self, policy: InterceptPolicy) {
+        self.policy.store(Arc::new(policy));
+    }
+
+    /// Replace the policy with one derived from the current policy. The function may be called
+    /// more than once if the policy is replaced concurrently.
+    pub fn update<F>(&self, f: F)
+    where
+        F: Fn(&InterceptPolicy) -> InterceptPolicy,
+    {
+        self.policy.rcu(|policy| f(policy));
+    }
+
+    /// Decide whether to intercept requests to the given authority with the current policy.
+    pub fn decide(&self, authority: &Authority) -> Decision {
+        self.policy.load().decide(authority)
+    }
+
+    /// Decide whether to intercept the given CONNECT request with the current policy.
+    pub fn decide_request<T>(&self, req: &hyper::Request<T>) -> Decision {
+        self.policy.load().decide_request(req)
+    }
+}
+
+impl From<InterceptPolicy> for InterceptPolicyHandle {
+    fn from(policy: InterceptPolicy) -> Self {
+        Self::new(policy)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn authority(authority: &'static str) -> Authority {
+        Authority::from_static(authority)
+    }
+
+    #[test]
+    fn uses_default_decision() {
+        let allow = InterceptPolicy::allow_by_default();
+        let deny = InterceptPolicy::deny_by_default();
+
+        assert_eq!(
+            allow.decide(&authority("example.com:443")),
+            Decision {
+                intercept: true,
+                reason: "allowed by default"
+            }
+        );
+        assert_eq!(
+            deny.decide(&authority("example.com:443")),
+            Decision {
+                intercept: false,
+                reason: "denied by default"
+            }
+        );
+    }
+
+    #[test]
+    fn matches_exact_host() {
+        let policy = InterceptPolicy::deny_by_default().allow("Example.com", "allowed");
+
+        assert!(policy.decide(&authority("example.com:443")).intercept);
+        assert!(policy.decide(&authority("EXAMPLE.COM")).intercept);
+        assert!(!policy.decide(&authority("www.example.com:443")).intercept);
+        assert!(!policy.decide(&authority("notexample.com:443")).intercept);
+    }
+
+    #[test]
+    fn matches_subdomains() {
+        let policy = InterceptPolicy::deny_by_default().allow("*.example.com", "allowed");
+
+        assert!(policy.decide(&authority("www.example.com:443")).intercept);
+        assert!(policy.decide(&authority("a.b.example.com:443")).intercept);
+        assert!(!policy.decide(&authority("example.com:443")).intercept);
+        assert!(!policy.decide(&authority("wwwexample.com:443")).intercept);
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let policy = InterceptPolicy::allow_by_default()
+            .deny("secure.example.com", "sensitive")
+            .allow("*.example.com", "monitored");
+
+        assert_eq!(
+            policy.decide(&authority("secure.example.com:443")),
+            Decision {
+                intercept: false,
+                reason: "sensitive"
+            }
+        );
+        assert_eq!(
+            policy.decide(&authority("www.example.com:443")).reason,
+            "monitored"
+        );
+    }
+
+    #[test]
+    fn uses_default_decision_without_authority() {
+        let policy = InterceptPolicy::deny_by_default().allow("*.example.com", "allowed");
+        let req = hyper::Request::builder().uri("/").body(()).unwrap();
+
+        assert_eq!(policy.decide_request(&req).reason, "denied by default");
+    }
+
+    #[test]
+    fn handle_shares_updates() {
+        let handle = InterceptPolicyHandle::new(InterceptPolicy::deny_by_default());
+        let clone = handle.clone();
+
+        assert!(!clone.decide(&authority("example.com:443")).intercept);
+
+        handle.update(|policy| policy.clone().allow("example.com", "allowed"));
+        assert_eq!(
+            clone.decide(&authority("example.com:443")).reason,
+            "allowed"
+        );
+
+        handle.store(InterceptPolicy::allow_by_default());
+        assert_eq!(*clone.load(), InterceptPolicy::allow_by_default());
+    }
+}
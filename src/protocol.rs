@@ -0,0 +1,103 @@
+/// How to handle an intercepted CONNECT tunnel.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Protocol {
+    /// Serve the tunnel as plain HTTP.
+    Http,
+    /// Accept a TLS connection using a certificate from the CA, and serve it as HTTPS.
+    Tls,
+    /// Forward the tunnel to the server as is.
+    Tunnel,
+}
+
+/// A function that classifies the first bytes sent through a CONNECT tunnel.
+///
+/// The slice holds up to the first 4 bytes sent by the client, and may be shorter if fewer were
+/// received. Returning `None` passes the bytes on to the next matcher.
+pub type ProtocolMatcher = fn(&[u8]) -> Option<Protocol>;
+
+/// The matchers used to detect the protocol of intercepted CONNECT tunnels.
+///
+/// Matchers are tried in the order they were registered, and the first to return a [`Protocol`]
+/// decides how the tunnel is handled. Tunnels that no matcher recognizes are forwarded as is. By
+/// default, [`ProtocolMatchers::http`] and [`ProtocolMatchers::tls`] are registered.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{Protocol, ProtocolMatchers};
+///
+/// fn ssh(bytes: &[u8]) -> Option<Protocol> {
+///     bytes.starts_with(b"SSH-").then_some(Protocol::Tunnel)
+/// }
+///
+/// let matchers = ProtocolMatchers::default().register(ssh);
+/// assert_eq!(matchers.classify(b"SSH-"), Some(Protocol::Tunnel));
+/// ```
+#[derive(Clone, Debug)]
+pub struct ProtocolMatchers(Vec<ProtocolMatcher>);
+
+impl ProtocolMatchers {
+    /// Create an empty set of matchers, without the built-in matchers.
+    pub fn empty() -> Self {
+        Self(Vec::new())
+    }
+
+    /// Register a matcher, to be tried after all previously registered matchers.
+    pub fn register(mut self, matcher: ProtocolMatcher) -> Self {
+        self.0.push(matcher);
+        self
+    }
+
+    /// Classify the first bytes sent through a tunnel.
+    pub fn classify(&self, bytes: &[u8]) -> Option<Protocol> {
+        self.0.iter().find_map(|matcher| matcher(bytes))
+    }
+
+    /// Built-in matcher detecting plain HTTP `GET` requests, such as WebSocket handshakes.
+    pub fn http(bytes: &[u8]) -> Option<Protocol> {
+        (bytes == b"GET ").then_some(Protocol::Http)
+    }
+
+    /// Built-in matcher detecting TLS handshakes.
+    pub fn tls(bytes: &[u8]) -> Option<Protocol> {
+        bytes.starts_with(b"\x16\x03").then_some(Protocol::Tls)
+    }
+}
+
+impl Default for ProtocolMatchers {
+    fn default() -> Self {
+        Self::empty().register(Self::http).register(Self::tls)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_builtin_protocols() {
+        let matchers = ProtocolMatchers::default();
+
+        assert_eq!(matchers.classify(b"GET "), Some(Protocol::Http));
+        assert_eq!(matchers.classify(b"\x16\x03\x01\x02"), Some(Protocol::Tls));
+        assert_eq!(matchers.classify(b"POST"), None);
+        assert_eq!(matchers.classify(b"GE"), None);
+    }
+
+    #[test]
+    fn tries_matchers_in_order() {
+        let matchers = ProtocolMatchers::empty()
+            .register(|bytes| bytes.starts_with(b"\x16").then_some(Protocol::Tunnel))
+            .register(ProtocolMatchers::tls);
+
+        assert_eq!(
+            matchers.classify(b"\x16\x03\x01\x02"),
+            Some(Protocol::Tunnel)
+        );
+    }
+
+    #[test]
+    fn empty_matches_nothing() {
+        assert_eq!(ProtocolMatchers::empty().classify(b"GET "), None);
+    }
+}
@@ -0,0 +1,159 @@
+use hyper::server::{accept::Accept, conn::AddrStream};
+use std::{
+    future::Future,
+    io,
+    ops::ControlFlow,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::time::Sleep;
+use tracing::error;
+
+pub(crate) type AcceptErrorHandler =
+    Arc<dyn Fn(&io::Error) -> ControlFlow<(), Duration> + Send + Sync>;
+
+/// Logs the error and keeps accepting connections, backing off briefly when the process has run
+/// out of file descriptors so that it doesn't spin until some are closed.
+pub(crate) fn default_accept_error_handler(e: &io::Error) -> ControlFlow<(), Duration> {
+    error!("Failed to accept connection: {}", e);
+
+    if is_too_many_open_files(e) {
+        ControlFlow::Continue(Duration::from_millis(100))
+    } else {
+        ControlFlow::Continue(Duration::ZERO)
+    }
+}
+
+fn is_too_many_open_files(e: &io::Error) -> bool {
+    // EMFILE and ENFILE, or WSAEMFILE on Windows.
+    if cfg!(windows) {
+        e.raw_os_error() == Some(10024)
+    } else {
+        matches!(e.raw_os_error(), Some(23 | 24))
+    }
+}
+
+/// Wraps the listener so that errors accepting connections are passed to the handler set with
+/// [`ProxyBuilder::with_accept_error_handler`](crate::ProxyBuilder::with_accept_error_handler),
+/// instead of stopping the server.
+pub(crate) struct AcceptErrors<I> {
+    incoming: I,
+    handler: AcceptErrorHandler,
+    backoff: Option<Pin<Box<Sleep>>>,
+}
+
+impl<I> AcceptErrors<I> {
+    pub fn new(incoming: I, handler: AcceptErrorHandler) -> Self {
+        Self {
+            incoming,
+            handler,
+            backoff: None,
+        }
+    }
+}
+
+impl<I> Accept for AcceptErrors<I>
+where
+    I: Accept<Conn = AddrStream, Error = io::Error> + Unpin,
+{
+    type Conn = AddrStream;
+    type Error = io::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        loop {
+            if let Some(backoff) = self.backoff.as_mut() {
+                futures::ready!(backoff.as_mut().poll(cx));
+                self.backoff = None;
+            }
+
+            match futures::ready!(Pin::new(&mut self.incoming).poll_accept(cx)) {
+                Some(Err(e)) => match (self.handler)(&e) {
+                    ControlFlow::Continue(delay) if delay.is_zero() => (),
+                    ControlFlow::Continue(delay) => {
+                        self.backoff = Some(Box::pin(tokio::time::sleep(delay)));
+                    }
+                    ControlFlow::Break(()) => return Poll::Ready(Some(Err(e))),
+                },
+                conn => return Poll::Ready(conn),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        collections::VecDeque,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    struct Errors(VecDeque<io::Error>);
+
+    impl Accept for Errors {
+        type Conn = AddrStream;
+        type Error = io::Error;
+
+        fn poll_accept(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+            Poll::Ready(self.0.pop_front().map(Err))
+        }
+    }
+
+    fn errors(count: usize) -> Errors {
+        Errors(
+            (0..count)
+                .map(|_| io::Error::from_raw_os_error(24))
+                .collect(),
+        )
+    }
+
+    async fn accept<I: Accept + Unpin>(incoming: &mut I) -> Option<Result<I::Conn, I::Error>> {
+        futures::future::poll_fn(|cx| Pin::new(&mut *incoming).poll_accept(cx)).await
+    }
+
+    #[tokio::test]
+    async fn continues_after_errors() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let handler: AcceptErrorHandler = Arc::new({
+            let calls = Arc::clone(&calls);
+            move |_| {
+                calls.fetch_add(1, Ordering::SeqCst);
+                ControlFlow::Continue(Duration::from_millis(1))
+            }
+        });
+        let mut incoming = AcceptErrors::new(errors(3), handler);
+
+        assert!(accept(&mut incoming).await.is_none());
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn stops_on_break() {
+        let handler: AcceptErrorHandler = Arc::new(|_| ControlFlow::Break(()));
+        let mut incoming = AcceptErrors::new(errors(3), handler);
+
+        let err = accept(&mut incoming).await.unwrap().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(24));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn backs_off_when_out_of_file_descriptors() {
+        assert_eq!(
+            default_accept_error_handler(&io::Error::from_raw_os_error(24)),
+            ControlFlow::Continue(Duration::from_millis(100))
+        );
+        assert_eq!(
+            default_accept_error_handler(&io::Error::from(io::ErrorKind::Other)),
+            ControlFlow::Continue(Duration::ZERO)
+        );
+    }
+}
@@ -0,0 +1,202 @@
+use crate::decoder::{decode_body, encode_body, extract_encodings, is_supported_encoding};
+use bytes::Bytes;
+use hyper::{
+    body::to_bytes,
+    header::{HeaderMap, HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH, TRANSFER_ENCODING},
+    Body, Request, Response, StatusCode,
+};
+use tracing::warn;
+
+/// A body that was decoded before being passed to a handler, and what is needed to encode it again.
+pub(crate) struct Decoded {
+    encodings: Vec<HeaderValue>,
+    raw: Bytes,
+    decoded: Bytes,
+}
+
+/// Buffer and decode a body, updating the headers to describe the decoded body.
+///
+/// Bodies without a supported `content-encoding` are returned as is. Bodies that fail to decode
+/// are passed on without being decoded, so that the handler sees what was received.
+pub(crate) async fn decode(
+    headers: &mut HeaderMap,
+    body: Body,
+) -> Result<(Body, Option<Decoded>), hyper::Error> {
+    if !headers.contains_key(CONTENT_ENCODING)
+        || !extract_encodings(headers).all(is_supported_encoding)
+    {
+        return Ok((body, None));
+    }
+
+    let raw = to_bytes(body).await?;
+    let decoded = decode_body(extract_encodings(headers), Body::from(raw.clone()))
+        .expect("Failed to build decoder for supported encodings");
+
+    let decoded = match to_bytes(decoded).await {
+        Ok(decoded) => decoded,
+        Err(e) => {
+            warn!("Failed to decode body, passing it on as is: {}", e);
+            return Ok((Body::from(raw), None));
+        }
+    };
+
+    let encodings = headers.get_all(CONTENT_ENCODING).iter().cloned().collect();
+    headers.remove(CONTENT_ENCODING);
+    headers.remove(TRANSFER_ENCODING);
+    headers.insert(CONTENT_LENGTH, decoded.len().into());
+
+    Ok((
+        Body::from(decoded.clone()),
+        Some(Decoded {
+            encodings,
+            raw,
+            decoded,
+        }),
+    ))
+}
+
+/// Encode a body returned by a handler with its original encoding.
+///
+/// If the body wasn't modified, the original encoded body is sent instead of being encoded again.
+/// Bodies that the handler set a `content-encoding` for are returned as is.
+pub(crate) async fn encode(
+    headers: &mut HeaderMap,
+    body: Body,
+    decoded: Decoded,
+) -> Result<Body, hyper::Error> {
+    if headers.contains_key(CONTENT_ENCODING) {
+        return Ok(body);
+    }
+
+    let body = to_bytes(body).await?;
+    let encoded = if body == decoded.decoded {
+        decoded.raw
+    } else {
+        encode_body(&decoded.encodings, body.to_vec()).await.into()
+    };
+
+    for value in decoded.encodings {
+        headers.append(CONTENT_ENCODING, value);
+    }
+
+    headers.remove(TRANSFER_ENCODING);
+    headers.insert(CONTENT_LENGTH, encoded.len().into());
+    Ok(Body::from(encoded))
+}
+
+/// Decode a request before it is passed to the handler, when auto decoding is enabled.
+pub(crate) async fn decode_request(
+    req: Request<Body>,
+) -> Result<(Request<Body>, Option<Decoded>), hyper::Error> {
+    let (mut parts, body) = req.into_parts();
+    let (body, decoded) = decode(&mut parts.headers, body).await?;
+    Ok((Request::from_parts(parts, body), decoded))
+}
+
+/// Encode a request returned by the handler, if it was decoded.
+pub(crate) async fn encode_request(
+    req: Request<Body>,
+    decoded: Option<Decoded>,
+) -> Result<Request<Body>, hyper::Error> {
+    let Some(decoded) = decoded else {
+        return Ok(req);
+    };
+
+    let (mut parts, body) = req.into_parts();
+    let body = encode(&mut parts.headers, body, decoded).await?;
+    Ok(Request::from_parts(parts, body))
+}
+
+/// Decode a response before it is passed to the handler, when auto decoding is enabled.
+/// Responses that never have a body are left as is.
+pub(crate) async fn decode_response(
+    res: Response<Body>,
+) -> Result<(Response<Body>, Option<Decoded>), hyper::Error> {
+    if res.status().is_informational()
+        || res.status() == StatusCode::NO_CONTENT
+        || res.status() == StatusCode::NOT_MODIFIED
+    {
+        return Ok((res, None));
+    }
+
+    let (mut parts, body) = res.into_parts();
+    let (body, decoded) = decode(&mut parts.headers, body).await?;
+    Ok((Response::from_parts(parts, body), decoded))
+}
+
+/// Encode a response returned by the handler, if it was decoded.
+pub(crate) async fn encode_response(
+    res: Response<Body>,
+    decoded: Option<Decoded>,
+) -> Result<Response<Body>, hyper::Error> {
+    let Some(decoded) = decoded else {
+        return Ok(res);
+    };
+
+    let (mut parts, body) = res.into_parts();
+    let body = encode(&mut parts.headers, body, decoded).await?;
+    Ok(Response::from_parts(parts, body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn gzip(data: &[u8]) -> Bytes {
+        encode_body(&[HeaderValue::from_static("gzip")], data.to_vec())
+            .await
+            .into()
+    }
+
+    fn gzip_headers() -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_ENCODING, HeaderValue::from_static("gzip"));
+        headers
+    }
+
+    #[tokio::test]
+    async fn passes_through_unmodified_body() {
+        let raw = gzip(b"hello").await;
+        let mut headers = gzip_headers();
+
+        let (body, decoded) = decode(&mut headers, Body::from(raw.clone())).await.unwrap();
+        assert!(!headers.contains_key(CONTENT_ENCODING));
+        assert_eq!(headers[CONTENT_LENGTH], "5");
+
+        let body = encode(&mut headers, body, decoded.unwrap()).await.unwrap();
+        assert_eq!(headers[CONTENT_ENCODING], "gzip");
+        assert_eq!(to_bytes(body).await.unwrap(), raw);
+    }
+
+    #[tokio::test]
+    async fn encodes_modified_body() {
+        let mut headers = gzip_headers();
+
+        let (_, decoded) = decode(&mut headers, Body::from(gzip(b"hello").await))
+            .await
+            .unwrap();
+        let body = encode(&mut headers, Body::from("goodbye"), decoded.unwrap())
+            .await
+            .unwrap();
+
+        let body = to_bytes(body).await.unwrap();
+        assert_eq!(headers[CONTENT_LENGTH], body.len().to_string().as_str());
+        assert_eq!(
+            to_bytes(decode_body([&b"gzip"[..]], Body::from(body)).unwrap())
+                .await
+                .unwrap(),
+            "goodbye"
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_body_that_fails_to_decode() {
+        let mut headers = gzip_headers();
+
+        let (body, decoded) = decode(&mut headers, Body::from("not gzip")).await.unwrap();
+
+        assert!(decoded.is_none());
+        assert_eq!(headers[CONTENT_ENCODING], "gzip");
+        assert_eq!(to_bytes(body).await.unwrap(), "not gzip");
+    }
+}
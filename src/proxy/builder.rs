@@ -1,19 +1,61 @@
+use super::{keylog::KeyLogFile, socket::BufferSizes, Config, Events};
+#[cfg(feature = "rustls-client")]
+use crate::TlsInfoConnector;
+#[cfg(any(feature = "rustls-client", feature = "native-tls-client"))]
+use crate::UpstreamTargetConnector;
 use crate::{
-    certificate_authority::CertificateAuthority, HttpHandler, NoopHandler, Proxy, WebSocketHandler,
+    certificate_authority::CertificateAuthority, BlackholeMode, Error, HandshakeDelay, HostFilter,
+    HttpHandler, MirrorSink, NoopHandler, Pipeline, ProtocolMatchers, Proxy, ProxyControl,
+    ProxyEvent, ResponseHeaderPolicy, RetryPolicy, ShutdownHandle, TlsFaults, WebSocketHandler,
 };
 use hyper::{
     client::{connect::Connect, Client, HttpConnector},
+    ext::ReasonPhrase,
+    header::{HeaderMap, HeaderName, HeaderValue},
     server::conn::AddrIncoming,
+    Body, Response, Uri,
 };
 #[cfg(feature = "rustls-client")]
-use hyper_rustls::{HttpsConnector as RustlsConnector, HttpsConnectorBuilder};
+use hyper_rustls::{ConfigBuilderExt, HttpsConnector as RustlsConnector, HttpsConnectorBuilder};
 #[cfg(feature = "native-tls-client")]
 use hyper_tls::HttpsConnector as NativeTlsConnector;
+#[cfg(unix)]
+use std::os::unix::io::{FromRawFd, RawFd};
 use std::{
+    io,
     net::{SocketAddr, TcpListener},
+    ops::{ControlFlow, RangeInclusive},
+    path::Path,
     sync::Arc,
+    time::Duration,
 };
+use tokio::sync::broadcast;
+use tokio_rustls::rustls;
 use tokio_tungstenite::Connector;
+use tracing::Span;
+
+/// The connector that the built-in clients make TCP connections with, before TLS is negotiated.
+#[cfg(any(feature = "rustls-client", feature = "native-tls-client"))]
+fn upstream_http_connector() -> UpstreamTargetConnector<HttpConnector> {
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    UpstreamTargetConnector::new(http)
+}
+
+/// Build the clients of the built-in connectors: one that pools connections, and one for requests
+/// with an [`UpstreamTarget::connect_addr`](crate::UpstreamTarget::connect_addr), whose
+/// connections aren't shared with requests that are sent to the host itself.
+#[cfg(any(feature = "rustls-client", feature = "native-tls-client"))]
+fn upstream_clients<C: Connect + Clone>(connector: C) -> (Client<C>, Client<C>) {
+    let mut builder = Client::builder();
+    builder
+        .http1_title_case_headers(true)
+        .http1_preserve_header_case(true);
+
+    let client = builder.build(connector.clone());
+    let unpooled_client = builder.pool_max_idle_per_host(0).build(connector);
+    (client, unpooled_client)
+}
 
 /// A builder for creating a [`Proxy`].
 ///
@@ -76,6 +118,7 @@ impl ProxyBuilder<WantsAddr> {
     pub fn with_addr(self, addr: SocketAddr) -> ProxyBuilder<WantsClient> {
         ProxyBuilder(WantsClient {
             als: AddrListenerServer::Addr(addr),
+            keylog: None,
         })
     }
 
@@ -83,9 +126,32 @@ impl ProxyBuilder<WantsAddr> {
     pub fn with_listener(self, listener: TcpListener) -> ProxyBuilder<WantsClient> {
         ProxyBuilder(WantsClient {
             als: AddrListenerServer::Listener(listener),
+            keylog: None,
         })
     }
 
+    /// Use a listening socket inherited from the parent process, such as with systemd socket
+    /// activation.
+    ///
+    /// # Errors
+    ///
+    /// This will return [`Error::InvalidListener`] if the file descriptor is not a listening TCP
+    /// socket. The file descriptor is not closed in that case.
+    ///
+    /// # Safety
+    ///
+    /// The file descriptor must be open and not owned by anything else, as ownership of it is
+    /// transferred to the proxy.
+    #[cfg(unix)]
+    #[cfg_attr(docsrs, doc(cfg(unix)))]
+    pub unsafe fn from_raw_fd(fd: RawFd) -> Result<ProxyBuilder<WantsClient>, Error> {
+        if !is_tcp_listener(fd) {
+            return Err(Error::InvalidListener);
+        }
+
+        Ok(Self::new().with_listener(TcpListener::from_raw_fd(fd)))
+    }
+
     /// Set a custom server builder to use for the proxy server.
     pub fn with_server(
         self,
@@ -93,6 +159,7 @@ impl ProxyBuilder<WantsAddr> {
     ) -> ProxyBuilder<WantsClient> {
         ProxyBuilder(WantsClient {
             als: AddrListenerServer::Server(Box::new(server)),
+            keylog: None,
         })
     }
 }
@@ -103,33 +170,131 @@ impl Default for ProxyBuilder<WantsAddr> {
     }
 }
 
+#[cfg(unix)]
+fn is_tcp_listener(fd: RawFd) -> bool {
+    fn getsockopt(fd: RawFd, name: libc::c_int) -> Option<libc::c_int> {
+        let mut value: libc::c_int = 0;
+        let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                name,
+                &mut value as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+
+        (ret == 0).then_some(value)
+    }
+
+    let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    let ret =
+        unsafe { libc::getsockname(fd, &mut addr as *mut _ as *mut libc::sockaddr, &mut len) };
+
+    ret == 0
+        && matches!(
+            addr.ss_family as libc::c_int,
+            libc::AF_INET | libc::AF_INET6
+        )
+        && getsockopt(fd, libc::SO_TYPE) == Some(libc::SOCK_STREAM)
+        && getsockopt(fd, libc::SO_ACCEPTCONN) == Some(1)
+}
+
 /// Builder state that needs a client.
 #[derive(Debug)]
 pub struct WantsClient {
     als: AddrListenerServer,
+    keylog: Option<Arc<KeyLogFile>>,
 }
 
 impl ProxyBuilder<WantsClient> {
+    /// Append the TLS secrets of intercepted connections to a file in the NSS key log format, also
+    /// known as `SSLKEYLOGFILE`, so that captured traffic can be decrypted by tools such as
+    /// Wireshark. The file is created if it doesn't exist.
+    ///
+    /// The secrets of connections with clients are always logged. Those of connections with
+    /// upstream servers are only logged by the clients created with
+    /// [`with_rustls_client`](Self::with_rustls_client) and
+    /// [`with_upstream_root_store`](Self::with_upstream_root_store), so this must be called first.
+    ///
+    /// # Errors
+    ///
+    /// This will return [`Error::KeyLogFile`] if the file can't be opened.
+    pub fn with_keylog(self, path: impl AsRef<Path>) -> Result<Self, Error> {
+        let keylog = KeyLogFile::open(path).map_err(Error::KeyLogFile)?;
+
+        Ok(ProxyBuilder(WantsClient {
+            keylog: Some(Arc::new(keylog)),
+            ..self.0
+        }))
+    }
+
     /// Use a hyper-rustls connector.
     #[cfg(feature = "rustls-client")]
     #[cfg_attr(docsrs, doc(cfg(feature = "rustls-client")))]
-    pub fn with_rustls_client(self) -> ProxyBuilder<WantsCa<RustlsConnector<HttpConnector>>> {
-        let https = HttpsConnectorBuilder::new()
+    pub fn with_rustls_client(
+        self,
+    ) -> ProxyBuilder<
+        WantsCa<TlsInfoConnector<RustlsConnector<UpstreamTargetConnector<HttpConnector>>>>,
+    > {
+        let tls_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
             .with_webpki_roots()
+            .with_no_client_auth();
+
+        self.with_rustls_connector(tls_config)
+    }
+
+    /// Use a hyper-rustls connector that only trusts the given root certificates when connecting
+    /// to upstream servers, instead of the webpki roots. This is needed when upstream traffic
+    /// passes through a TLS inspecting firewall, or to trust a private CA.
+    #[cfg(feature = "rustls-client")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "rustls-client")))]
+    pub fn with_upstream_root_store(
+        self,
+        root_store: rustls::RootCertStore,
+    ) -> ProxyBuilder<
+        WantsCa<TlsInfoConnector<RustlsConnector<UpstreamTargetConnector<HttpConnector>>>>,
+    > {
+        let tls_config = rustls::ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(root_store)
+            .with_no_client_auth();
+
+        self.with_rustls_connector(tls_config)
+    }
+
+    #[cfg(feature = "rustls-client")]
+    fn with_rustls_connector(
+        self,
+        mut tls_config: rustls::ClientConfig,
+    ) -> ProxyBuilder<
+        WantsCa<TlsInfoConnector<RustlsConnector<UpstreamTargetConnector<HttpConnector>>>>,
+    > {
+        if let Some(keylog) = &self.0.keylog {
+            tls_config.key_log = Arc::clone(keylog) as _;
+        }
+        let upstream_tls_config = Arc::new(tls_config.clone());
+
+        let https = HttpsConnectorBuilder::new()
+            .with_tls_config(tls_config)
             .https_or_http()
             .enable_http1();
 
         #[cfg(feature = "http2")]
         let https = https.enable_http2();
 
-        let https = https.build();
+        let https = TlsInfoConnector::new(https.wrap_connector(upstream_http_connector()));
+        let (client, unpooled_client) = upstream_clients(https);
 
         ProxyBuilder(WantsCa {
             als: self.0.als,
-            client: Client::builder()
-                .http1_title_case_headers(true)
-                .http1_preserve_header_case(true)
-                .build(https),
+            keylog: self.0.keylog,
+            upstream_tls_config: Some(upstream_tls_config),
+            client,
+            unpooled_client,
         })
     }
 
@@ -138,25 +303,33 @@ impl ProxyBuilder<WantsClient> {
     #[cfg_attr(docsrs, doc(cfg(feature = "native-tls-client")))]
     pub fn with_native_tls_client(
         self,
-    ) -> ProxyBuilder<WantsCa<NativeTlsConnector<HttpConnector>>> {
-        let https = NativeTlsConnector::new();
+    ) -> ProxyBuilder<WantsCa<NativeTlsConnector<UpstreamTargetConnector<HttpConnector>>>> {
+        let https = NativeTlsConnector::new_with_connector(upstream_http_connector());
+        let (client, unpooled_client) = upstream_clients(https);
 
         ProxyBuilder(WantsCa {
             als: self.0.als,
-            client: Client::builder()
-                .http1_title_case_headers(true)
-                .http1_preserve_header_case(true)
-                .build(https),
+            keylog: self.0.keylog,
+            upstream_tls_config: None,
+            client,
+            unpooled_client,
         })
     }
 
     /// Use a custom client.
+    ///
+    /// The client is also used for requests with an
+    /// [`UpstreamTarget::connect_addr`](crate::UpstreamTarget::connect_addr), which share its
+    /// pooled connections.
     pub fn with_client<C>(self, client: Client<C>) -> ProxyBuilder<WantsCa<C>>
     where
         C: Connect + Clone + Send + Sync + 'static,
     {
         ProxyBuilder(WantsCa {
             als: self.0.als,
+            keylog: self.0.keylog,
+            upstream_tls_config: None,
+            unpooled_client: client.clone(),
             client,
         })
     }
@@ -167,6 +340,9 @@ impl ProxyBuilder<WantsClient> {
 pub struct WantsCa<C> {
     als: AddrListenerServer,
     client: Client<C>,
+    unpooled_client: Client<C>,
+    keylog: Option<Arc<KeyLogFile>>,
+    upstream_tls_config: Option<Arc<rustls::ClientConfig>>,
 }
 
 impl<C> ProxyBuilder<WantsCa<C>> {
@@ -178,10 +354,16 @@ impl<C> ProxyBuilder<WantsCa<C>> {
         ProxyBuilder(WantsHandlers {
             als: self.0.als,
             client: self.0.client,
+            unpooled_client: self.0.unpooled_client,
             ca,
             http_handler: NoopHandler::new(),
             websocket_handler: NoopHandler::new(),
             websocket_connector: None,
+            config: Config {
+                keylog: self.0.keylog,
+                upstream_tls_config: self.0.upstream_tls_config,
+                ..Config::default()
+            },
         })
     }
 }
@@ -190,10 +372,12 @@ impl<C> ProxyBuilder<WantsCa<C>> {
 pub struct WantsHandlers<C, CA, H, W> {
     als: AddrListenerServer,
     client: Client<C>,
+    unpooled_client: Client<C>,
     ca: CA,
     http_handler: H,
     websocket_handler: W,
     websocket_connector: Option<Connector>,
+    config: Config,
 }
 
 impl<C, CA, H, W> ProxyBuilder<WantsHandlers<C, CA, H, W>> {
@@ -205,10 +389,12 @@ impl<C, CA, H, W> ProxyBuilder<WantsHandlers<C, CA, H, W>> {
         ProxyBuilder(WantsHandlers {
             als: self.0.als,
             client: self.0.client,
+            unpooled_client: self.0.unpooled_client,
             ca: self.0.ca,
             http_handler,
             websocket_handler: self.0.websocket_handler,
             websocket_connector: self.0.websocket_connector,
+            config: self.0.config,
         })
     }
 
@@ -220,10 +406,12 @@ impl<C, CA, H, W> ProxyBuilder<WantsHandlers<C, CA, H, W>> {
         ProxyBuilder(WantsHandlers {
             als: self.0.als,
             client: self.0.client,
+            unpooled_client: self.0.unpooled_client,
             ca: self.0.ca,
             http_handler: self.0.http_handler,
             websocket_handler,
             websocket_connector: self.0.websocket_connector,
+            config: self.0.config,
         })
     }
 
@@ -235,15 +423,755 @@ impl<C, CA, H, W> ProxyBuilder<WantsHandlers<C, CA, H, W>> {
         })
     }
 
+    /// Set the function used to build the response sent to the client when a request cannot be
+    /// forwarded to the upstream server.
+    ///
+    /// The function receives the error and the URI of the failed request. When set, it is used
+    /// instead of [`HttpHandler::handle_error`].
+    pub fn with_error_page<F>(self, error_page: F) -> Self
+    where
+        F: Fn(&hyper::Error, &Uri) -> Response<Body> + Send + Sync + 'static,
+    {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                error_page: Some(Arc::new(error_page)),
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set the fraction of connections that create tracing spans.
+    ///
+    /// The rate is clamped to between `0.0` and `1.0`. Connections that are not sampled use
+    /// disabled spans, though events such as errors are still logged. Defaults to `1.0`, meaning
+    /// spans are created for all connections.
+    pub fn with_span_sampling(self, rate: f64) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                span_sampling: rate.clamp(0.0, 1.0),
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set the span that the spans created by the proxy are children of.
+    ///
+    /// Each request is handled in a span created within the parent span, and tasks spawned for
+    /// tunnels, upgrades and WebSockets are instrumented with children of that request span, so
+    /// all of the proxy's activity is scoped within the parent. By default the request spans have
+    /// no parent.
+    pub fn with_parent_span(self, span: Span) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                parent_span: span,
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set whether multiple `Cookie` headers should be joined into a single header before a
+    /// request is forwarded. Defaults to `true`, as HTTP/1.x only supports a single `Cookie`
+    /// header.
+    pub fn with_join_cookies(self, join_cookies: bool) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                join_cookies,
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set the function used to join multiple `Cookie` headers into a single header, for example
+    /// to remove duplicate cookies or reorder them. The function receives the values of the
+    /// headers in the order they were sent, and is called whenever a request has a `Cookie`
+    /// header. Defaults to joining the values with `"; "`.
+    ///
+    /// This has no effect if joining cookies is disabled with [`ProxyBuilder::with_join_cookies`].
+    pub fn with_cookie_joiner<F>(self, joiner: F) -> Self
+    where
+        F: Fn(&[&HeaderValue]) -> HeaderValue + Send + Sync + 'static,
+    {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                cookie_joiner: Some(Arc::new(joiner)),
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set how long to wait for the client to send data through a CONNECT tunnel before giving up
+    /// on detecting its protocol. Once the timeout elapses, the tunnel is forwarded to the server
+    /// as is without being intercepted, so that protocols where the server speaks first, such as
+    /// SMTP, don't hang. Defaults to waiting indefinitely.
+    pub fn with_sniff_timeout(self, timeout: Duration) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                sniff_timeout: Some(timeout),
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set how long to wait for tunnels and WebSockets to close once the proxy is shutting down.
+    /// Defaults to 5 seconds.
+    pub fn with_drain_timeout(self, timeout: Duration) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                drain_timeout: timeout,
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set the function called when accepting a connection fails, for example because the process
+    /// has run out of file descriptors.
+    ///
+    /// Returning [`ControlFlow::Continue`] keeps accepting connections after the given delay, and
+    /// returning [`ControlFlow::Break`] stops the proxy, which then returns the error from
+    /// [`Proxy::start`]. By default errors are logged, and the proxy keeps accepting connections,
+    /// waiting 100 milliseconds first if it has run out of file descriptors.
+    ///
+    /// This isn't used with [`ProxyBuilder::with_server`], whose [`AddrIncoming`] handles errors
+    /// itself.
+    pub fn with_accept_error_handler<F>(self, handler: F) -> Self
+    where
+        F: Fn(&io::Error) -> ControlFlow<(), Duration> + Send + Sync + 'static,
+    {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                accept_error_handler: Arc::new(handler),
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set the sizes in bytes of the receive and send buffers (`SO_RCVBUF` and `SO_SNDBUF`) of
+    /// sockets, for tuning the throughput of large transfers through tunnels. Defaults to the
+    /// sizes chosen by the operating system.
+    ///
+    /// The sizes are set on the listening socket, which accepted sockets inherit, and on the
+    /// sockets connecting to the servers of tunnels that aren't intercepted. The buffers used to
+    /// copy data through those tunnels are also given the size of the receive buffer, unless set
+    /// with [`ProxyBuilder::with_tunnel_buffer_size`]. The client
+    /// set with [`ProxyBuilder::with_client`] isn't affected, so the sizes of its sockets should be
+    /// set on its connector, such as with [`HttpConnector::set_recv_buffer_size`].
+    ///
+    /// The sizes are a hint, and operating systems may adjust them. For example, Linux doubles
+    /// them to allow for bookkeeping, and caps them at `net.core.rmem_max` and
+    /// `net.core.wmem_max`. Failures to set them are logged and otherwise ignored.
+    pub fn with_socket_buffer_sizes(self, recv: u32, send: u32) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                socket_buffer_sizes: Some(BufferSizes { recv, send }),
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set the size of the buffers used to copy data in each direction through tunnels that aren't
+    /// intercepted, and through forwarded `h2c` upgrades. Defaults to the receive buffer size set
+    /// with [`ProxyBuilder::with_socket_buffer_sizes`], or 8 KiB.
+    ///
+    /// Larger buffers need fewer reads and writes to copy large transfers, at the cost of more
+    /// memory for each open tunnel.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `size` is zero.
+    pub fn with_tunnel_buffer_size(self, size: usize) -> Self {
+        assert!(size > 0, "Tunnel buffer size must be greater than zero");
+
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                tunnel_buffer_size: Some(size),
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set the range of source ports used by connections to the servers of tunnels that aren't
+    /// intercepted, for firewalls and NAT rules that only allow outbound connections from certain
+    /// ports. Defaults to ports chosen by the operating system.
+    ///
+    /// Each connection uses a free port in the range, which may be shared with connections to
+    /// other servers. If there are none, the connection fails, and the error is logged. The client
+    /// set with [`ProxyBuilder::with_client`] isn't affected, so the ports of its connections
+    /// should be set on its connector.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is empty or includes port 0.
+    pub fn with_outbound_port_range(self, range: RangeInclusive<u16>) -> Self {
+        assert!(
+            !range.is_empty() && *range.start() > 0,
+            "Outbound port range must be non-empty and exclude port 0"
+        );
+
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                outbound_port_range: Some(range),
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set the policy for removing or rewriting headers of responses from upstream servers, such
+    /// as [`ResponseHeaderPolicy::anonymize`] to strip headers that fingerprint the server. The
+    /// policy applies to all responses, after they have been handled. Defaults to not changing
+    /// any headers.
+    pub fn with_response_header_policy(self, policy: ResponseHeaderPolicy) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                response_header_policy: Some(policy),
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Look up the credentials of local processes connecting over the loopback interface, and
+    /// expose them in [`HttpContext::peer_cred`](crate::HttpContext::peer_cred). Defaults to
+    /// false.
+    ///
+    /// TCP sockets don't support `SO_PEERCRED`, so the credentials are found by matching the
+    /// client's socket in `/proc/net/tcp` once per connection. Finding the process ID scans the
+    /// open files of all processes, so it is only done when asked for with
+    /// [`PeerCred::pid`](crate::PeerCred::pid).
+    #[cfg(target_os = "linux")]
+    #[cfg_attr(docsrs, doc(cfg(target_os = "linux")))]
+    pub fn with_peer_credentials(self, peer_credentials: bool) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                peer_credentials,
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set the maximum number of connections that are open to each upstream host at once,
+    /// counting both forwarded requests and tunnels. Requests and tunnels beyond the limit wait
+    /// until a connection to the host is closed. Defaults to no limit.
+    ///
+    /// A forwarded request holds a connection until its response body has been sent to the client,
+    /// so this also limits the connections kept by the client's pool. The current counts are
+    /// available from [`ProxyControl::host_connections`](crate::ProxyControl::host_connections).
+    ///
+    /// # Panics
+    ///
+    /// This will panic if the limit is zero.
+    pub fn with_per_host_connection_limit(self, limit: usize) -> Self {
+        assert!(limit > 0, "Per-host connection limit must be at least 1");
+
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                per_host_connection_limit: Some(limit),
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set how long HTTP/1.1 keep-alive connections from clients, including intercepted ones, are
+    /// kept open while waiting for the next request. Defaults to no timeout.
+    ///
+    /// The timeout starts once the response to the last request has been sent, and restarts
+    /// whenever the client sends more data. It doesn't apply while a request is being handled, or
+    /// to tunnels and upgraded connections. This isn't used with [`ProxyBuilder::with_server`].
+    pub fn with_client_idle_timeout(self, timeout: Duration) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                client_idle_timeout: Some(timeout),
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Blackhole CONNECT requests to the matched hosts. The client is told that the tunnel was
+    /// established, but nothing it sends is forwarded and the connection is then closed as set by
+    /// the mode, so it can't tell the host was blocked by the proxy. Defaults to no hosts.
+    ///
+    /// Blackholed tunnels are neither intercepted nor passed to the handler.
+    pub fn with_blackhole(self, hosts: HostFilter, mode: BlackholeMode) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                blackhole: Some((hosts, mode)),
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set whether identical GET requests that are in flight at the same time are coalesced into a
+    /// single upstream request. Defaults to `false`.
+    ///
+    /// Requests are identical if they have the same URI and the same `Accept`, `Accept-Encoding`,
+    /// `Accept-Language`, `Authorization`, `Cookie`, and `Range` headers, after the handler has
+    /// processed them. If others arrived while the first request was in flight, its response is
+    /// buffered and shared with them, and they are each passed to the handler separately.
+    /// Otherwise, the response is streamed as usual. Responses with bodies larger than 1 MiB
+    /// aren't buffered, and the requests waiting for them are sent upstream themselves.
+    ///
+    /// The contexts of requests that share another's response don't have
+    /// [`HttpContext::upstream_tls`](crate::HttpContext::upstream_tls) or
+    /// [`HttpContext::connection_reused`](crate::HttpContext::connection_reused) set.
+    pub fn with_request_coalescing(self, request_coalescing: bool) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                request_coalescing,
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set the policy for retrying requests whose upstream response has one of the given
+    /// statuses, waiting with exponential backoff between attempts. See [`RetryPolicy`] for which
+    /// requests are retried. Defaults to not retrying requests.
+    pub fn with_retry_policy(self, policy: RetryPolicy) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                retry_policy: Some(policy),
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set a sink that is sent a copy of each request and its response, including the start of
+    /// their bodies, while they are forwarded as usual. CONNECT requests aren't mirrored, but
+    /// requests within intercepted tunnels are.
+    ///
+    /// Records are queued for the sink without waiting, and dropped if the queue is full. See
+    /// [`MirrorSink`] for details.
+    pub fn with_mirror(self, sink: impl MirrorSink) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                mirror: Some(Arc::new(sink)),
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Create a channel that is sent [`ProxyEvent`]s about the lifecycle of client connections,
+    /// such as connections being accepted and closed, and requests being started and completed.
+    /// Returns the builder along with a receiver, from which further receivers can be created
+    /// with [`broadcast::Receiver::resubscribe`].
+    ///
+    /// Events are dropped while there are no receivers. Receivers that fall more than `capacity`
+    /// events behind miss the oldest ones, see [`broadcast`] for details.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `capacity` is zero.
+    pub fn with_event_channel(self, capacity: usize) -> (Self, broadcast::Receiver<ProxyEvent>) {
+        let (sender, receiver) = broadcast::channel(capacity);
+        let builder = ProxyBuilder(WantsHandlers {
+            config: Config {
+                events: Some(Arc::new(Events::new(sender))),
+                ..self.0.config
+            },
+            ..self.0
+        });
+        (builder, receiver)
+    }
+
+    /// Only forward the given request headers upstream, removing all others. Defaults to
+    /// forwarding all headers.
+    ///
+    /// Headers are filtered after the handler has processed the request. `Content-Length` and
+    /// `Transfer-Encoding` are always kept, since they are needed to forward the body, and the
+    /// `Host` header is set from the URI. Headers added by the proxy, such as `traceparent`, have
+    /// to be allowed to be forwarded. Headers set with
+    /// [`ProxyBuilder::with_request_header_blocklist`] are removed even if they are allowed.
+    pub fn with_request_header_allowlist(
+        self,
+        headers: impl IntoIterator<Item = HeaderName>,
+    ) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                request_header_allowlist: Some(headers.into_iter().collect()),
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Remove the given request headers before forwarding requests upstream, for example to strip
+    /// headers used for tracking. Defaults to no headers.
+    ///
+    /// Headers are removed after the handler has processed the request.
+    pub fn with_request_header_blocklist(
+        self,
+        headers: impl IntoIterator<Item = HeaderName>,
+    ) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                request_header_blocklist: headers.into_iter().collect(),
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Replace the `User-Agent` header of requests forwarded upstream, for origins that behave
+    /// differently depending on the client. Defaults to forwarding the client's `User-Agent`.
+    ///
+    /// The header is replaced after the handler has processed the request, and before the
+    /// allowlist and blocklist are applied.
+    pub fn with_user_agent_override(self, user_agent: HeaderValue) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                user_agent_override: Some(user_agent),
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set whether the proxy should identify itself by appending `hudsucker/<version>` to the
+    /// `User-Agent` header of requests forwarded upstream. Requests without a `User-Agent` header
+    /// are given one. Defaults to `false`.
+    ///
+    /// The identity is appended after [`ProxyBuilder::with_user_agent_override`] is applied.
+    pub fn with_user_agent_identity(self, user_agent_identity: bool) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                user_agent_identity,
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set whether requests from clients upgrading to cleartext HTTP/2 with `Upgrade: h2c` should
+    /// be forwarded with the upgrade. Defaults to `false`.
+    ///
+    /// By default the `h2c` upgrade is removed from requests, so that they are forwarded and
+    /// answered as plain HTTP/1.1 requests. When enabled, the upgrade is forwarded to the upstream
+    /// server, and if the server accepts it, the client connection is tunneled to the server. The
+    /// HTTP/2 traffic sent over the tunnel isn't passed to the handler.
+    pub fn with_h2c_upgrade(self, h2c_upgrade: bool) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                h2c_upgrade,
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set other addresses the proxy can be reached at, such as the addresses of each interface of
+    /// a multi-homed host, or the address of a load balancer in front of it. Defaults to none.
+    ///
+    /// CONNECT requests and forwarded requests to the proxy itself would be forwarded back to the
+    /// proxy in a loop, so they are answered with `508 Loop Detected` instead. The proxy is always
+    /// reached at the local address of the client's connection, and otherwise at these addresses.
+    /// An address with an unspecified IP, such as `0.0.0.0:8080`, matches every IP on its port.
+    ///
+    /// Hosts are only resolved to check for a loop if their port is one the proxy is reached on.
+    pub fn with_self_addrs(self, addrs: impl IntoIterator<Item = SocketAddr>) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                self_addrs: addrs.into_iter().collect(),
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set the pipeline of transforms applied to the bodies of requests before they are forwarded
+    /// upstream, after the handler has processed them. Defaults to no pipeline.
+    ///
+    /// Requests without a body, and requests that aren't processed by the handler, as decided by
+    /// [`HttpHandler::should_process`], aren't transformed. Requests whose body fails to be
+    /// transformed are answered with a `400 Bad Request` response.
+    pub fn with_request_pipeline(self, pipeline: Pipeline) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                request_pipeline: Some(pipeline),
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set the pipeline of transforms applied to the bodies of responses from upstream, after the
+    /// handler has processed them. Defaults to no pipeline.
+    ///
+    /// Responses that never have a body, such as responses to HEAD requests, and responses to
+    /// requests that aren't processed by the handler aren't transformed. Responses whose body
+    /// fails to be transformed are replaced with a `502 Bad Gateway` response.
+    pub fn with_response_pipeline(self, pipeline: Pipeline) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                response_pipeline: Some(pipeline),
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set the matchers used to detect the protocol of intercepted CONNECT tunnels. Defaults to
+    /// [`ProtocolMatchers::default`], which detects plain HTTP and TLS.
+    pub fn with_protocol_matchers(self, protocol_matchers: ProtocolMatchers) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                protocol_matchers,
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set the maximum number of headers a client may send in a request. Requests with more
+    /// headers are rejected with `431 Request Header Fields Too Large`. Regardless of this limit,
+    /// requests with more than 100 headers are always rejected.
+    pub fn with_max_headers(self, count: usize) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                max_headers: Some(count),
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set the maximum total size in bytes of the header names and values a client may send in
+    /// a request. Requests with larger headers are rejected with
+    /// `431 Request Header Fields Too Large`.
+    ///
+    /// This also limits the buffer used to read request heads, though it is never made smaller
+    /// than 8 KiB. Servers set with [`ProxyBuilder::with_server`] keep their own buffer size.
+    pub fn with_max_header_bytes(self, bytes: usize) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                max_header_bytes: Some(bytes),
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set the maximum number of requests a client may have in flight at once on a single
+    /// connection. This is sent to HTTP/2 clients as `SETTINGS_MAX_CONCURRENT_STREAMS`, and
+    /// streams beyond it are refused. HTTP/1 connections always handle one request at a time, even
+    /// when clients pipeline them. Defaults to no limit.
+    ///
+    /// # Panics
+    ///
+    /// This will panic if the limit is zero.
+    #[cfg(feature = "http2")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "http2")))]
+    pub fn with_max_concurrent_streams(self, limit: u32) -> Self {
+        assert!(limit > 0, "Maximum concurrent streams must be at least 1");
+
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                max_concurrent_streams: Some(limit),
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set the maximum total size in bytes of the header names and values an upstream server may
+    /// send in a response. Responses with larger headers aren't passed on to the client, and are
+    /// handled as a failed request instead, which by default responds with `502 Bad Gateway`.
+    ///
+    /// The error passed to [`HttpHandler::handle_error`] has
+    /// [`Error::ResponseHeadersTooLarge`](crate::Error::ResponseHeadersTooLarge)
+    /// as its source.
+    pub fn with_max_response_header_bytes(self, bytes: usize) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                max_response_header_bytes: Some(bytes),
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set whether to propagate W3C Trace Context `traceparent` headers. When enabled, each
+    /// forwarded request is sent with a `traceparent` header for a child of the span in the
+    /// incoming header, or for a new trace if the request didn't have a valid one. The trace ID is
+    /// recorded in the `trace_id` field of the request's span. Defaults to `false`.
+    pub fn with_trace_propagation(self, trace_propagation: bool) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                trace_propagation,
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set headers to send in the `200` response to CONNECT requests that are accepted, such as
+    /// `Proxy-Agent`. Defaults to no headers.
+    pub fn with_connect_headers(self, headers: HeaderMap) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                connect_headers: headers,
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set the reason phrase of the `200` response to CONNECT requests that are accepted, such as
+    /// `Connection Established`. This only applies to clients connected over HTTP/1. Defaults to
+    /// `OK`.
+    pub fn with_connect_reason_phrase(self, reason: ReasonPhrase) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                connect_reason_phrase: Some(reason),
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set whether to reject requests with both `Content-Length` and `Transfer-Encoding` headers
+    /// with `400 Bad Request`, instead of forwarding them. Such requests are a common way of
+    /// smuggling requests past proxies, as servers may disagree about where their bodies end.
+    /// Defaults to `true`.
+    pub fn with_smuggling_protection(self, smuggling_protection: bool) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                smuggling_protection,
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set whether to serve the root certificate of the CA at `http://hudsucker.internal/cert`,
+    /// so that clients can install it by browsing to it through the proxy. Defaults to `false`.
+    ///
+    /// The certificate is PEM encoded, unless the path ends with `.der` or the `Accept` header
+    /// asks for a DER encoded certificate. Paths ending with `.pem` are always PEM encoded. This
+    /// requires the CA to provide its root certificate with [`CertificateAuthority::ca_cert`].
+    pub fn with_ca_cert_endpoint(self, ca_cert_endpoint: bool) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                ca_cert_endpoint,
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set whether to decode the bodies of requests and responses before passing them to the
+    /// handler, so that it always sees plain bodies. Defaults to `false`.
+    ///
+    /// Bodies with a supported `content-encoding` are buffered in full and decoded. Once the
+    /// handler has returned, they are encoded again with the original encoding, or if they weren't
+    /// modified, the original body is forwarded as is. Bodies that the handler sets a new
+    /// `content-encoding` for are forwarded as is.
+    #[cfg(feature = "decoder")]
+    #[cfg_attr(docsrs, doc(cfg(feature = "decoder")))]
+    pub fn with_auto_decode(self, auto_decode: bool) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                auto_decode,
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set the faults to inject into the TLS handshakes of intercepted connections, for testing
+    /// how clients cope with invalid certificates and failed handshakes. Defaults to no faults.
+    pub fn with_tls_faults(self, tls_faults: TlsFaults) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                tls_faults: Some(tls_faults),
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
+    /// Set a delay to add before the TLS handshakes of intercepted connections, for simulating
+    /// slow certificate generation or network latency. Defaults to no delay.
+    pub fn with_handshake_delay(self, handshake_delay: HandshakeDelay) -> Self {
+        ProxyBuilder(WantsHandlers {
+            config: Config {
+                handshake_delay: Some(handshake_delay),
+                ..self.0.config
+            },
+            ..self.0
+        })
+    }
+
     /// Build the proxy.
     pub fn build(self) -> Proxy<C, CA, H, W> {
         Proxy {
             als: self.0.als,
             client: self.0.client,
+            unpooled_client: self.0.unpooled_client,
             ca: Arc::new(self.0.ca),
             http_handler: self.0.http_handler,
             websocket_handler: self.0.websocket_handler,
             websocket_connector: self.0.websocket_connector,
+            config: Arc::new(self.0.config),
+            control: ProxyControl::default(),
+            shutdown: ShutdownHandle::default(),
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::{
+        net::{TcpStream, UdpSocket},
+        os::unix::io::{AsRawFd, IntoRawFd},
+    };
+
+    mod from_raw_fd {
+        use super::*;
+
+        #[test]
+        fn accepts_tcp_listener() {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let fd = listener.into_raw_fd();
+
+            assert!(unsafe { ProxyBuilder::from_raw_fd(fd) }.is_ok());
+        }
+
+        #[test]
+        fn rejects_connected_tcp_socket() {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+
+            assert!(matches!(
+                unsafe { ProxyBuilder::from_raw_fd(stream.as_raw_fd()) },
+                Err(Error::InvalidListener)
+            ));
+        }
+
+        #[test]
+        fn rejects_udp_socket() {
+            let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+            assert!(matches!(
+                unsafe { ProxyBuilder::from_raw_fd(socket.as_raw_fd()) },
+                Err(Error::InvalidListener)
+            ));
         }
     }
 }
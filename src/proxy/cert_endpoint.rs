@@ -0,0 +1,144 @@
+use hyper::{
+    header::{ACCEPT, CONTENT_DISPOSITION, CONTENT_TYPE},
+    Body, Request, Response, StatusCode,
+};
+use tokio_rustls::rustls;
+
+/// Host that the root certificate of the CA is served from.
+const HOST: &str = "hudsucker.internal";
+
+const PEM_CONTENT_TYPE: &str = "application/x-pem-file";
+const DER_CONTENT_TYPE: &str = "application/x-x509-ca-cert";
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Format {
+    Pem,
+    Der,
+}
+
+/// Whether the request is for the endpoint serving the root certificate.
+pub(crate) fn is_cert_request<T>(req: &Request<T>) -> bool {
+    req.uri()
+        .host()
+        .is_some_and(|host| host.eq_ignore_ascii_case(HOST))
+}
+
+fn format<T>(req: &Request<T>) -> Option<Format> {
+    match req.uri().path() {
+        "/cert.pem" => Some(Format::Pem),
+        "/cert.der" => Some(Format::Der),
+        "/cert" => {
+            let wants_der = req
+                .headers()
+                .get_all(ACCEPT)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .any(|media_type| {
+                    let media_type = media_type.split(';').next().unwrap_or_default().trim();
+                    media_type.eq_ignore_ascii_case(DER_CONTENT_TYPE)
+                        || media_type.eq_ignore_ascii_case("application/pkix-cert")
+                });
+
+            Some(if wants_der { Format::Der } else { Format::Pem })
+        }
+        _ => None,
+    }
+}
+
+/// Build the response serving the root certificate, or `404 Not Found` if the path is unknown or
+/// the CA doesn't provide its root certificate.
+pub(crate) fn response<T>(
+    req: &Request<T>,
+    ca_cert: Option<rustls::Certificate>,
+) -> Response<Body> {
+    let (format, ca_cert) = match (format(req), ca_cert) {
+        (Some(format), Some(ca_cert)) => (format, ca_cert),
+        _ => {
+            return Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .expect("Failed to build response");
+        }
+    };
+
+    let (content_type, filename, body) = match format {
+        Format::Pem => (
+            PEM_CONTENT_TYPE,
+            "hudsucker-ca.pem",
+            Body::from(pem::encode(&pem::Pem::new("CERTIFICATE", ca_cert.0))),
+        ),
+        Format::Der => (DER_CONTENT_TYPE, "hudsucker-ca.der", Body::from(ca_cert.0)),
+    };
+
+    Response::builder()
+        .header(CONTENT_TYPE, content_type)
+        .header(
+            CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{}\"", filename),
+        )
+        .body(body)
+        .expect("Failed to build response")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(uri: &str, accept: Option<&str>) -> Request<()> {
+        let mut builder = Request::builder().uri(uri);
+        if let Some(accept) = accept {
+            builder = builder.header(ACCEPT, accept);
+        }
+
+        builder.body(()).unwrap()
+    }
+
+    #[test]
+    fn matches_host() {
+        assert!(is_cert_request(&request(
+            "http://hudsucker.internal/cert",
+            None
+        )));
+        assert!(is_cert_request(&request(
+            "http://Hudsucker.Internal:80/",
+            None
+        )));
+        assert!(!is_cert_request(&request("http://example.com/cert", None)));
+        assert!(!is_cert_request(&request("/cert", None)));
+    }
+
+    #[test]
+    fn negotiates_format() {
+        let uri = "http://hudsucker.internal/cert";
+
+        assert_eq!(format(&request(uri, None)), Some(Format::Pem));
+        assert_eq!(format(&request(uri, Some("*/*"))), Some(Format::Pem));
+        assert_eq!(
+            format(&request(
+                uri,
+                Some("text/html, application/x-x509-ca-cert;q=0.9")
+            )),
+            Some(Format::Der)
+        );
+        assert_eq!(
+            format(&request("http://hudsucker.internal/cert.der", None)),
+            Some(Format::Der)
+        );
+        assert_eq!(
+            format(&request(
+                "http://hudsucker.internal/cert.pem",
+                Some("application/pkix-cert")
+            )),
+            Some(Format::Pem)
+        );
+        assert_eq!(format(&request("http://hudsucker.internal/", None)), None);
+    }
+
+    #[test]
+    fn not_found_without_ca_cert() {
+        let res = response(&request("http://hudsucker.internal/cert", None), None);
+
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+}
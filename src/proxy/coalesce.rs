@@ -0,0 +1,302 @@
+use crate::{
+    body::{collect_with_limit, BodyError},
+    upstream_target::ConnectTo,
+};
+use bytes::Bytes;
+use futures::{
+    channel::oneshot,
+    future::{BoxFuture, FutureExt, Shared},
+    stream,
+};
+use hyper::{
+    header::{
+        HeaderMap, HeaderName, HeaderValue, ACCEPT, ACCEPT_ENCODING, ACCEPT_LANGUAGE,
+        AUTHORIZATION, CONTENT_LENGTH, COOKIE, RANGE, TRANSFER_ENCODING,
+    },
+    Body, Method, Request, Response, StatusCode, Version,
+};
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+/// Largest response body that is buffered to be shared with coalesced requests. Responses with
+/// larger bodies are streamed to the first request, and the others are sent upstream again.
+pub(crate) const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// Request headers that are commonly used to choose the response, so requests are only coalesced
+/// if they are the same.
+const KEY_HEADERS: [HeaderName; 6] = [
+    ACCEPT,
+    ACCEPT_ENCODING,
+    ACCEPT_LANGUAGE,
+    AUTHORIZATION,
+    COOKIE,
+    RANGE,
+];
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub(crate) struct Key {
+    uri: String,
+    connect_addr: Option<SocketAddr>,
+    headers: Vec<Vec<HeaderValue>>,
+}
+
+impl Key {
+    /// The key of a request, if it can be coalesced. Only GET requests without a body are.
+    pub fn from_request(req: &Request<Body>) -> Option<Self> {
+        if req.method() != Method::GET
+            || req.headers().contains_key(CONTENT_LENGTH)
+            || req.headers().contains_key(TRANSFER_ENCODING)
+        {
+            return None;
+        }
+
+        Some(Self {
+            uri: req.uri().to_string(),
+            connect_addr: match req.extensions().get() {
+                Some(&ConnectTo::Addr(addr)) => Some(addr),
+                _ => None,
+            },
+            headers: KEY_HEADERS
+                .iter()
+                .map(|name| req.headers().get_all(name).iter().cloned().collect())
+                .collect(),
+        })
+    }
+}
+
+/// A buffered response that is shared with coalesced requests.
+#[derive(Debug)]
+pub(crate) struct SharedResponse {
+    status: StatusCode,
+    version: Version,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl SharedResponse {
+    pub fn to_response(&self) -> Response<Body> {
+        let mut res = Response::new(Body::from(self.body.clone()));
+        *res.status_mut() = self.status;
+        *res.version_mut() = self.version;
+        *res.headers_mut() = self.headers.clone();
+        res
+    }
+}
+
+type InFlight = Shared<BoxFuture<'static, Option<Arc<SharedResponse>>>>;
+
+/// Identical GET requests that are in flight at once, when enabled with
+/// [`ProxyBuilder::with_request_coalescing`](crate::ProxyBuilder::with_request_coalescing).
+#[derive(Debug, Default)]
+pub(crate) struct Coalescer {
+    in_flight: Mutex<HashMap<Key, InFlight>>,
+}
+
+/// Whether a request is the first of its kind, and has to be sent upstream.
+pub(crate) enum Join {
+    Leader(Leader),
+    Follower(InFlight),
+}
+
+impl Coalescer {
+    pub fn join(self: &Arc<Self>, key: Key) -> Join {
+        let mut in_flight = self.in_flight.lock().expect("Failed to lock coalescer");
+
+        if let Some(shared) = in_flight.get(&key) {
+            return Join::Follower(shared.clone());
+        }
+
+        let (tx, rx) = oneshot::channel();
+        in_flight.insert(
+            key.clone(),
+            rx.map(|res| res.ok().flatten()).boxed().shared(),
+        );
+
+        Join::Leader(Leader {
+            coalescer: Arc::clone(self),
+            key,
+            tx: Some(tx),
+        })
+    }
+}
+
+/// Sends the response of the first request to the requests coalesced with it. If it is dropped
+/// without a response, they are sent upstream themselves.
+pub(crate) struct Leader {
+    coalescer: Arc<Coalescer>,
+    key: Key,
+    tx: Option<oneshot::Sender<Option<Arc<SharedResponse>>>>,
+}
+
+impl Leader {
+    /// Share the response with the coalesced requests if its body fits within the limit,
+    /// returning the response to send to the first request. If no requests were coalesced with
+    /// it, the response is returned as is, without buffering its body.
+    pub async fn share(mut self, res: Response<Body>) -> Response<Body> {
+        if !self.has_followers() {
+            return res;
+        }
+
+        let (parts, body) = res.into_parts();
+        let (res, shared) = match collect_with_limit(body, MAX_BODY_SIZE).await {
+            Ok(body) => {
+                let shared = Arc::new(SharedResponse {
+                    status: parts.status,
+                    version: parts.version,
+                    headers: parts.headers.clone(),
+                    body: body.clone(),
+                });
+                (Response::from_parts(parts, Body::from(body)), Some(shared))
+            }
+            Err(BodyError::TooLarge { body, .. }) => (Response::from_parts(parts, body), None),
+            Err(BodyError::Network(e)) => {
+                let body = Body::wrap_stream(stream::once(async move { Err::<Bytes, _>(e) }));
+                (Response::from_parts(parts, body), None)
+            }
+        };
+
+        self.finish(shared);
+        res
+    }
+
+    /// Whether any requests are waiting for the response. If none are, requests that arrive after
+    /// this are sent upstream themselves.
+    fn has_followers(&mut self) -> bool {
+        let mut in_flight = self
+            .coalescer
+            .in_flight
+            .lock()
+            .expect("Failed to lock coalescer");
+
+        // Each follower holds a clone of the future kept in the map.
+        let has_followers = in_flight
+            .get(&self.key)
+            .and_then(Shared::strong_count)
+            .is_some_and(|count| count > 1);
+        if !has_followers {
+            in_flight.remove(&self.key);
+        }
+
+        has_followers
+    }
+
+    fn finish(&mut self, shared: Option<Arc<SharedResponse>>) {
+        // Requests that arrive after this are sent upstream again, since the response may no
+        // longer be fresh.
+        if let Ok(mut in_flight) = self.coalescer.in_flight.lock() {
+            in_flight.remove(&self.key);
+        }
+
+        if let Some(tx) = self.tx.take() {
+            let _ = tx.send(shared);
+        }
+    }
+}
+
+impl Drop for Leader {
+    fn drop(&mut self) {
+        self.finish(None);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    fn request(accept: &str) -> Request<Body> {
+        Request::get("http://example.com/")
+            .header(ACCEPT, accept)
+            .header("x-other", accept)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn keys_on_relevant_headers() {
+        assert_eq!(
+            Key::from_request(&request("text/html")),
+            Key::from_request(&request("text/html"))
+        );
+        assert_ne!(
+            Key::from_request(&request("text/html")),
+            Key::from_request(&request("application/json"))
+        );
+
+        let mut post = request("text/html");
+        *post.method_mut() = Method::POST;
+        assert_eq!(Key::from_request(&post), None);
+    }
+
+    #[tokio::test]
+    async fn shares_response() {
+        let coalescer = Arc::new(Coalescer::default());
+        let key = Key::from_request(&request("*/*")).unwrap();
+
+        let Join::Leader(leader) = coalescer.join(key.clone()) else {
+            panic!("Expected leader");
+        };
+        let Join::Follower(follower) = coalescer.join(key.clone()) else {
+            panic!("Expected follower");
+        };
+
+        let res = leader.share(Response::new(Body::from("hello"))).await;
+        assert_eq!(
+            hyper::body::to_bytes(res.into_body()).await.unwrap(),
+            "hello"
+        );
+
+        let shared = follower.await.unwrap().to_response();
+        assert_eq!(
+            hyper::body::to_bytes(shared.into_body()).await.unwrap(),
+            "hello"
+        );
+
+        assert!(matches!(coalescer.join(key), Join::Leader(_)));
+    }
+
+    #[tokio::test]
+    async fn streams_without_followers() {
+        let coalescer = Arc::new(Coalescer::default());
+        let key = Key::from_request(&request("*/*")).unwrap();
+
+        let Join::Leader(leader) = coalescer.join(key.clone()) else {
+            panic!("Expected leader");
+        };
+
+        // The body isn't finished, so the response would never be returned if it were buffered.
+        let (mut sender, body) = Body::channel();
+        sender
+            .send_data(Bytes::from_static(b"hello"))
+            .await
+            .unwrap();
+
+        let res = tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            leader.share(Response::new(body)),
+        )
+        .await
+        .expect("Response was delayed");
+        let mut body = res.into_body();
+        assert_eq!(body.next().await.unwrap().unwrap(), "hello");
+
+        assert!(matches!(coalescer.join(key), Join::Leader(_)));
+    }
+
+    #[tokio::test]
+    async fn releases_followers_on_drop() {
+        let coalescer = Arc::new(Coalescer::default());
+        let key = Key::from_request(&request("*/*")).unwrap();
+
+        let leader = coalescer.join(key.clone());
+        let Join::Follower(follower) = coalescer.join(key) else {
+            panic!("Expected follower");
+        };
+
+        drop(leader);
+        assert!(follower.await.is_none());
+    }
+}
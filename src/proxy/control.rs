@@ -0,0 +1,119 @@
+use super::{
+    coalesce::Coalescer,
+    host_limits::HostLimits,
+    sessions::{Sessions, WebSocketSession},
+};
+use futures::task::AtomicWaker;
+use hyper::{http::uri::Authority, service::Service};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+
+#[derive(Debug, Default)]
+struct State {
+    paused: AtomicBool,
+    waker: AtomicWaker,
+    sessions: Arc<Sessions>,
+    host_limits: Arc<HostLimits>,
+    coalescer: Arc<Coalescer>,
+    mirror_dropped: AtomicU64,
+}
+
+/// A handle for controlling a running [`Proxy`](crate::Proxy).
+///
+/// This is obtained with [`Proxy::control`](crate::Proxy::control) before the proxy is started,
+/// and can be cloned and used from any task.
+#[derive(Clone, Debug, Default)]
+pub struct ProxyControl(Arc<State>);
+
+impl ProxyControl {
+    /// Stop accepting new connections. Existing connections are kept open and continue to be
+    /// served. New connections wait in the listener's backlog until the proxy is resumed.
+    pub fn pause(&self) {
+        self.0.paused.store(true, Ordering::SeqCst);
+    }
+
+    /// Resume accepting new connections after [`ProxyControl::pause`].
+    pub fn resume(&self) {
+        self.0.paused.store(false, Ordering::SeqCst);
+        self.0.waker.wake();
+    }
+
+    /// Whether the proxy has been paused.
+    pub fn is_paused(&self) -> bool {
+        self.0.paused.load(Ordering::SeqCst)
+    }
+
+    /// Get a snapshot of the WebSocket sessions that are currently open, with the number of
+    /// messages and bytes received in each direction.
+    pub fn websocket_sessions(&self) -> Vec<WebSocketSession> {
+        self.0.sessions.snapshot()
+    }
+
+    /// Get the number of connections currently open to each upstream host, including tunnels.
+    /// Hosts are identified by their host and port.
+    ///
+    /// Connections are only counted if a limit is set with
+    /// [`ProxyBuilder::with_per_host_connection_limit`](crate::ProxyBuilder::with_per_host_connection_limit).
+    pub fn host_connections(&self) -> HashMap<Authority, usize> {
+        self.0.host_limits.snapshot()
+    }
+
+    /// Get the number of records of mirrored traffic that were dropped because the queue for
+    /// the sink set with [`ProxyBuilder::with_mirror`](crate::ProxyBuilder::with_mirror) was
+    /// full.
+    pub fn mirror_records_dropped(&self) -> u64 {
+        self.0.mirror_dropped.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn sessions(&self) -> &Arc<Sessions> {
+        &self.0.sessions
+    }
+
+    pub(crate) fn host_limits(&self) -> &Arc<HostLimits> {
+        &self.0.host_limits
+    }
+
+    pub(crate) fn coalescer(&self) -> &Arc<Coalescer> {
+        &self.0.coalescer
+    }
+
+    pub(crate) fn record_mirror_dropped(&self) {
+        self.0.mirror_dropped.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Wraps the service used to make a service for each connection, so that the server doesn't
+/// accept new connections while the proxy is paused.
+pub(crate) struct Pausable<M> {
+    pub make_service: M,
+    pub control: ProxyControl,
+}
+
+impl<'t, T, M: Service<&'t T>> Service<&'t T> for Pausable<M> {
+    type Response = M::Response;
+    type Error = M::Error;
+    type Future = M::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if self.control.is_paused() {
+            self.control.0.waker.register(cx.waker());
+
+            // Check again in case the proxy was resumed before the waker was registered.
+            if self.control.is_paused() {
+                return Poll::Pending;
+            }
+        }
+
+        self.make_service.poll_ready(cx)
+    }
+
+    fn call(&mut self, target: &'t T) -> Self::Future {
+        self.make_service.call(target)
+    }
+}
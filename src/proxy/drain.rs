@@ -0,0 +1,194 @@
+use futures::{future::BoxFuture, FutureExt, Stream};
+use std::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::sync::{mpsc, watch};
+use tokio_tungstenite::tungstenite::{
+    self,
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Message,
+};
+use tracing::warn;
+
+/// Tracks the tasks that outlive the connection they were started from, such as tunnels and
+/// WebSocket forwarders, so that they can be shut down cleanly along with the proxy.
+pub(crate) struct Drain {
+    signal: watch::Sender<bool>,
+    done_tx: mpsc::Sender<()>,
+    done_rx: mpsc::Receiver<()>,
+}
+
+impl Drain {
+    pub fn new() -> Self {
+        let (done_tx, done_rx) = mpsc::channel(1);
+
+        Self {
+            signal: watch::channel(false).0,
+            done_tx,
+            done_rx,
+        }
+    }
+
+    /// Create a watch to be held by a tracked task until it has finished.
+    pub fn watch(&self) -> DrainWatch {
+        DrainWatch {
+            signal: self.signal.subscribe(),
+            _done: self.done_tx.clone(),
+        }
+    }
+
+    /// Notify all tracked tasks that the proxy is shutting down.
+    pub fn signal(&self) {
+        self.signal.send_replace(true);
+    }
+
+    /// Wait for all tracked tasks to finish, giving up once the timeout elapses.
+    pub async fn wait(self, timeout: Duration) {
+        let Self {
+            signal: _signal,
+            done_tx,
+            mut done_rx,
+        } = self;
+        drop(done_tx);
+
+        if tokio::time::timeout(timeout, done_rx.recv()).await.is_err() {
+            warn!("Timed out waiting for tunnels and WebSockets to close");
+        }
+    }
+}
+
+/// Held by a task tracked by a [`Drain`].
+#[derive(Clone)]
+pub(crate) struct DrainWatch {
+    signal: watch::Receiver<bool>,
+    _done: mpsc::Sender<()>,
+}
+
+impl DrainWatch {
+    /// Resolves once the proxy is shutting down. Never resolves if the [`Drain`] was dropped
+    /// without being signaled.
+    pub fn signaled(&self) -> impl Future<Output = ()> + Send + 'static {
+        let mut signal = self.signal.clone();
+
+        async move {
+            while !*signal.borrow() {
+                if signal.changed().await.is_err() {
+                    futures::future::pending::<()>().await;
+                }
+            }
+        }
+    }
+}
+
+/// Wraps one side of a WebSocket so that it yields a Close frame once the proxy is shutting down,
+/// which is then forwarded to the other peer, and ends afterwards.
+pub(crate) struct Draining<S> {
+    stream: S,
+    signaled: Option<BoxFuture<'static, ()>>,
+    closed: bool,
+    _watch: DrainWatch,
+}
+
+impl<S> Draining<S> {
+    pub fn new(stream: S, watch: DrainWatch) -> Self {
+        Self {
+            stream,
+            signaled: Some(watch.signaled().boxed()),
+            closed: false,
+            _watch: watch,
+        }
+    }
+}
+
+impl<S> Stream for Draining<S>
+where
+    S: Stream<Item = Result<Message, tungstenite::Error>> + Unpin,
+{
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.closed {
+            return Poll::Ready(None);
+        }
+
+        if let Some(signaled) = &mut self.signaled {
+            if signaled.as_mut().poll(cx).is_ready() {
+                self.closed = true;
+
+                return Poll::Ready(Some(Ok(Message::Close(Some(CloseFrame {
+                    code: CloseCode::Away,
+                    reason: "Proxy shutting down".into(),
+                })))));
+            }
+        }
+
+        let item = futures::ready!(Pin::new(&mut self.stream).poll_next(cx));
+
+        // Once the peer has closed the connection itself, there is nothing left to close.
+        if matches!(item, None | Some(Ok(Message::Close(_)))) {
+            self.signaled = None;
+        }
+
+        Poll::Ready(item)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{stream, StreamExt};
+
+    #[tokio::test]
+    async fn closes_stream_once_signaled() {
+        let drain = Drain::new();
+        let mut stream = Draining::new(stream::pending(), drain.watch());
+
+        drain.signal();
+
+        assert!(matches!(
+            stream.next().await,
+            Some(Ok(Message::Close(Some(CloseFrame {
+                code: CloseCode::Away,
+                ..
+            }))))
+        ));
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn doesnt_close_stream_twice() {
+        let drain = Drain::new();
+        let mut stream = Draining::new(
+            stream::iter([Ok(Message::Close(None))]).chain(stream::pending()),
+            drain.watch(),
+        );
+
+        assert!(matches!(
+            stream.next().await,
+            Some(Ok(Message::Close(None)))
+        ));
+
+        drain.signal();
+        let next = tokio::time::timeout(Duration::from_millis(50), stream.next()).await;
+        assert!(next.is_err());
+    }
+
+    #[tokio::test]
+    async fn waits_for_watches() {
+        let drain = Drain::new();
+        let watch = drain.watch();
+
+        tokio::spawn(async move {
+            watch.signaled().await;
+            drop(watch);
+        });
+
+        drain.signal();
+        tokio::time::timeout(Duration::from_secs(1), drain.wait(Duration::from_secs(5)))
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,86 @@
+use crate::{ProxyEvent, ProxyEventKind};
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+use tokio::sync::broadcast;
+
+/// Sends the events of a proxy to the channel created with
+/// [`ProxyBuilder::with_event_channel`](crate::ProxyBuilder::with_event_channel).
+#[derive(Debug)]
+pub(crate) struct Events {
+    sender: broadcast::Sender<ProxyEvent>,
+    next_connection_id: AtomicU64,
+    next_request_id: AtomicU64,
+}
+
+impl Events {
+    pub fn new(sender: broadcast::Sender<ProxyEvent>) -> Self {
+        Self {
+            sender,
+            next_connection_id: AtomicU64::new(0),
+            next_request_id: AtomicU64::new(0),
+        }
+    }
+
+    /// Assign an identifier to a newly accepted connection, and send its
+    /// [`ProxyEventKind::ConnectionAccepted`] event.
+    pub fn connection(self: &Arc<Self>, client_addr: SocketAddr) -> ConnectionEvents {
+        let events = ConnectionEvents {
+            events: Arc::clone(self),
+            connection_id: self.next_connection_id.fetch_add(1, Ordering::Relaxed),
+        };
+        events.emit(ProxyEventKind::ConnectionAccepted { client_addr });
+        events
+    }
+}
+
+/// Sends the events of a single client connection.
+#[derive(Clone, Debug)]
+pub(crate) struct ConnectionEvents {
+    events: Arc<Events>,
+    connection_id: u64,
+}
+
+impl ConnectionEvents {
+    /// Send an event. Events are dropped if there are no receivers.
+    pub fn emit(&self, kind: ProxyEventKind) {
+        let _ = self.events.sender.send(ProxyEvent {
+            connection_id: self.connection_id,
+            timestamp: SystemTime::now(),
+            kind,
+        });
+    }
+
+    /// Assign an identifier to a new request.
+    pub fn next_request_id(&self) -> u64 {
+        self.events.next_request_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Create a guard that sends an event when it is dropped.
+    pub fn on_drop(&self, kind: ProxyEventKind) -> EmitOnDrop {
+        EmitOnDrop {
+            events: self.clone(),
+            kind: Some(kind),
+        }
+    }
+}
+
+/// Sends an event when dropped, created with [`ConnectionEvents::on_drop`].
+#[derive(Debug)]
+pub(crate) struct EmitOnDrop {
+    events: ConnectionEvents,
+    kind: Option<ProxyEventKind>,
+}
+
+impl Drop for EmitOnDrop {
+    fn drop(&mut self) {
+        if let Some(kind) = self.kind.take() {
+            self.events.emit(kind);
+        }
+    }
+}
@@ -0,0 +1,125 @@
+use hyper::{
+    header::{HeaderMap, HeaderName, HeaderValue, CONNECTION, UPGRADE},
+    Request,
+};
+
+const HTTP2_SETTINGS: HeaderName = HeaderName::from_static("http2-settings");
+
+/// Whether the request asks to upgrade the connection to cleartext HTTP/2.
+pub(crate) fn is_upgrade_request<T>(req: &Request<T>) -> bool {
+    tokens(req.headers(), &UPGRADE).any(is_h2c)
+}
+
+/// Remove the upgrade to cleartext HTTP/2 from a request, so that it is forwarded as a plain
+/// HTTP/1.1 request. Any other protocols offered in the `Upgrade` header are kept.
+pub(crate) fn strip_upgrade(headers: &mut HeaderMap) {
+    let protocols: Vec<_> = tokens(headers, &UPGRADE)
+        .filter(|protocol| !is_h2c(protocol))
+        .collect();
+    let protocols = join(&protocols);
+
+    let mut removed = vec!["http2-settings"];
+    if protocols.is_none() {
+        removed.push("upgrade");
+    }
+    let options: Vec<_> = tokens(headers, &CONNECTION)
+        .filter(|option| !removed.iter().any(|r| option.eq_ignore_ascii_case(r)))
+        .collect();
+    let options = join(&options);
+
+    headers.remove(HTTP2_SETTINGS);
+    replace(headers, UPGRADE, protocols);
+    replace(headers, CONNECTION, options);
+}
+
+fn is_h2c(protocol: &str) -> bool {
+    protocol.eq_ignore_ascii_case("h2c")
+}
+
+/// The comma-separated tokens of a header, ignoring values that aren't valid strings.
+fn tokens<'a>(headers: &'a HeaderMap, name: &HeaderName) -> impl Iterator<Item = &'a str> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+}
+
+fn join(tokens: &[&str]) -> Option<HeaderValue> {
+    if tokens.is_empty() {
+        return None;
+    }
+
+    // The tokens were taken from valid header values.
+    Some(HeaderValue::from_str(&tokens.join(", ")).expect("Failed to join header tokens"))
+}
+
+fn replace(headers: &mut HeaderMap, name: HeaderName, value: Option<HeaderValue>) {
+    match value {
+        Some(value) => {
+            headers.insert(name, value);
+        }
+        None => {
+            headers.remove(name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upgrade_request(upgrade: &str, connection: &str) -> Request<()> {
+        Request::builder()
+            .uri("http://example.com/")
+            .header(UPGRADE, upgrade)
+            .header(CONNECTION, connection)
+            .header(HTTP2_SETTINGS, "AAMAAABkAARAAAAAAAIAAAAA")
+            .body(())
+            .unwrap()
+    }
+
+    #[test]
+    fn detects_upgrade_request() {
+        assert!(is_upgrade_request(&upgrade_request("h2c", "Upgrade")));
+        assert!(is_upgrade_request(&upgrade_request(
+            "foo/1, H2C",
+            "Upgrade"
+        )));
+        assert!(!is_upgrade_request(&upgrade_request(
+            "websocket",
+            "Upgrade"
+        )));
+        assert!(!is_upgrade_request(&Request::new(())));
+    }
+
+    #[test]
+    fn strips_upgrade() {
+        let mut req = upgrade_request("h2c", "keep-alive, Upgrade, HTTP2-Settings");
+        strip_upgrade(req.headers_mut());
+
+        assert_eq!(req.headers().get(UPGRADE), None);
+        assert_eq!(req.headers().get(HTTP2_SETTINGS), None);
+        assert_eq!(req.headers()[CONNECTION], "keep-alive");
+    }
+
+    #[test]
+    fn keeps_other_protocols() {
+        let mut req = upgrade_request("h2c, foo/1", "Upgrade, HTTP2-Settings");
+        strip_upgrade(req.headers_mut());
+
+        assert_eq!(req.headers()[UPGRADE], "foo/1");
+        assert_eq!(req.headers().get(HTTP2_SETTINGS), None);
+        assert_eq!(req.headers()[CONNECTION], "Upgrade");
+    }
+
+    #[test]
+    fn removes_empty_connection_header() {
+        let mut req = upgrade_request("h2c", "Upgrade, HTTP2-Settings");
+        strip_upgrade(req.headers_mut());
+
+        assert_eq!(req.headers().get(CONNECTION), None);
+    }
+}
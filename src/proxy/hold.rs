@@ -0,0 +1,114 @@
+use bytes::Bytes;
+use hyper::{
+    body::{HttpBody, SizeHint},
+    Body, HeaderMap, Response,
+};
+use std::{
+    any::Any,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// Values kept alive until the body of a response has been sent. They are carried in the
+/// extensions of the response until it is handed to hyper, so they are released early if a handler
+/// replaces the response with one that doesn't keep its extensions.
+#[derive(Default)]
+struct Held(Vec<Box<dyn Any + Send + Sync>>);
+
+/// Keep a value alive until the body of the response has been sent.
+pub(crate) fn hold<T: Send + Sync + 'static>(res: &mut Response<Body>, value: T) {
+    let extensions = res.extensions_mut();
+    match extensions.get_mut::<Held>() {
+        Some(held) => held.0.push(Box::new(value)),
+        None => {
+            extensions.insert(Held(vec![Box::new(value)]));
+        }
+    }
+}
+
+/// The body of a response sent to the client, which keeps the values held for the response alive
+/// until it has been sent. Everything else is passed through to the wrapped body, so that
+/// trailers, the size hint, and whether the body has ended are kept.
+pub(crate) struct HoldBody {
+    body: Body,
+    _held: Option<Held>,
+}
+
+impl HoldBody {
+    /// Take the values held in the extensions of a response, and move them into its body.
+    pub fn from_response(res: Response<Body>) -> Response<Self> {
+        let (mut parts, body) = res.into_parts();
+        let held = parts.extensions.remove::<Held>();
+
+        Response::from_parts(parts, Self { body, _held: held })
+    }
+}
+
+impl HttpBody for HoldBody {
+    type Data = Bytes;
+    type Error = hyper::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Pin::new(&mut self.body).poll_data(cx)
+    }
+
+    fn poll_trailers(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Pin::new(&mut self.body).poll_trailers(cx)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn holds_until_dropped() {
+        let value = Arc::new(());
+        let mut res = Response::new(Body::from("hello"));
+        hold(&mut res, Arc::clone(&value));
+        hold(&mut res, Arc::clone(&value));
+
+        let res = HoldBody::from_response(res);
+        assert!(res.extensions().get::<Held>().is_none());
+        assert_eq!(Arc::strong_count(&value), 3);
+        assert_eq!(res.body().size_hint().exact(), Some(5));
+
+        drop(res);
+        assert_eq!(Arc::strong_count(&value), 1);
+    }
+
+    #[tokio::test]
+    async fn keeps_trailers() {
+        let (mut sender, body) = Body::channel();
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", "0".parse().unwrap());
+        sender.send_trailers(trailers.clone()).await.unwrap();
+        drop(sender);
+
+        let mut body = HoldBody::from_response(Response::new(body)).into_body();
+        assert!(body.data().await.is_none());
+        assert_eq!(body.trailers().await.unwrap(), Some(trailers));
+    }
+
+    #[test]
+    fn keeps_empty_bodies() {
+        let res = HoldBody::from_response(Response::new(Body::empty()));
+        assert!(res.body().is_end_stream());
+        assert_eq!(res.body().size_hint().exact(), Some(0));
+    }
+}
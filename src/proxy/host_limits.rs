@@ -0,0 +1,180 @@
+use http::uri::{Authority, Scheme};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::Semaphore;
+
+#[derive(Debug)]
+struct Host {
+    semaphore: Semaphore,
+    active: AtomicUsize,
+}
+
+/// Limits the number of connections to each upstream host, when a limit is set with
+/// [`ProxyBuilder::with_per_host_connection_limit`](crate::ProxyBuilder::with_per_host_connection_limit).
+#[derive(Debug, Default)]
+pub(crate) struct HostLimits {
+    hosts: Mutex<HashMap<Authority, Arc<Host>>>,
+}
+
+impl HostLimits {
+    /// Wait until a connection to the given host can be opened. The returned permit must be held
+    /// for as long as the connection is in use.
+    pub async fn acquire(
+        self: &Arc<Self>,
+        authority: &Authority,
+        scheme: Option<&Scheme>,
+        limit: usize,
+    ) -> HostPermit {
+        let key = key(authority, scheme);
+        let host = Arc::clone(
+            self.hosts
+                .lock()
+                .expect("Failed to lock host limits")
+                .entry(key.clone())
+                .or_insert_with(|| {
+                    Arc::new(Host {
+                        semaphore: Semaphore::new(limit),
+                        active: AtomicUsize::new(0),
+                    })
+                }),
+        );
+
+        // The permit is returned when the `HostPermit` is dropped, since it can't hold a
+        // reference to the semaphore alongside the `Arc` that keeps it alive.
+        host.semaphore
+            .acquire()
+            .await
+            .expect("Host semaphore closed")
+            .forget();
+        host.active.fetch_add(1, Ordering::SeqCst);
+
+        HostPermit {
+            limits: Arc::clone(self),
+            key,
+            host,
+        }
+    }
+
+    /// The number of connections currently open to each host.
+    pub fn snapshot(&self) -> HashMap<Authority, usize> {
+        self.hosts
+            .lock()
+            .expect("Failed to lock host limits")
+            .iter()
+            .map(|(key, host)| (key.clone(), host.active.load(Ordering::SeqCst)))
+            .filter(|&(_, active)| active > 0)
+            .collect()
+    }
+}
+
+/// Allows a connection to a host to be open. Dropping the permit lets the next waiting connection
+/// to the host be opened.
+#[derive(Debug)]
+pub(crate) struct HostPermit {
+    limits: Arc<HostLimits>,
+    key: Authority,
+    host: Arc<Host>,
+}
+
+impl Drop for HostPermit {
+    fn drop(&mut self) {
+        self.host.active.fetch_sub(1, Ordering::SeqCst);
+        self.host.semaphore.add_permits(1);
+
+        // Hosts are removed once no permits are held or waited for, since each holds a reference.
+        if let Ok(mut hosts) = self.limits.hosts.lock() {
+            if Arc::strong_count(&self.host) == 2 {
+                hosts.remove(&self.key);
+            }
+        }
+    }
+}
+
+/// The host and port of an authority, using the default port of the scheme if it has none, so
+/// that requests with and without the default port share a limit.
+fn key(authority: &Authority, scheme: Option<&Scheme>) -> Authority {
+    if authority.port().is_some() {
+        return authority.clone();
+    }
+
+    let port = if scheme == Some(&Scheme::HTTPS) {
+        443
+    } else {
+        80
+    };
+
+    format!("{}:{}", authority.host(), port)
+        .parse()
+        .unwrap_or_else(|_| authority.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn waits_for_permit() {
+        let limits = Arc::new(HostLimits::default());
+        let authority = Authority::from_static("example.com");
+
+        let first = limits.acquire(&authority, None, 1).await;
+        assert_eq!(
+            limits.snapshot(),
+            HashMap::from([(Authority::from_static("example.com:80"), 1)])
+        );
+
+        let second = tokio::spawn({
+            let limits = Arc::clone(&limits);
+            async move {
+                limits
+                    .acquire(&Authority::from_static("example.com:80"), None, 1)
+                    .await
+            }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!second.is_finished());
+
+        drop(first);
+        let second = tokio::time::timeout(Duration::from_secs(1), second)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(limits.snapshot().len(), 1);
+
+        drop(second);
+        assert!(limits.snapshot().is_empty());
+        assert!(limits.hosts.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn limits_hosts_separately() {
+        let limits = Arc::new(HostLimits::default());
+
+        let _first = limits
+            .acquire(
+                &Authority::from_static("example.com"),
+                Some(&Scheme::HTTPS),
+                1,
+            )
+            .await;
+        let _second = tokio::time::timeout(Duration::from_secs(1), {
+            limits.acquire(&Authority::from_static("example.org"), None, 1)
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(
+            limits.snapshot(),
+            HashMap::from([
+                (Authority::from_static("example.com:443"), 1),
+                (Authority::from_static("example.org:80"), 1),
+            ])
+        );
+    }
+}
@@ -0,0 +1,264 @@
+use super::hold;
+use futures::task::AtomicWaker;
+use hyper::{server::accept::Accept, Body, Response, StatusCode};
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    time::{Instant, Sleep},
+};
+
+/// Tracks the requests being handled on a client connection, so that it can be closed once it
+/// has been idle for the timeout set with
+/// [`ProxyBuilder::with_client_idle_timeout`](crate::ProxyBuilder::with_client_idle_timeout).
+#[derive(Debug)]
+pub(crate) struct IdleTracker {
+    timeout: Duration,
+    active: AtomicUsize,
+    disabled: AtomicBool,
+    waker: AtomicWaker,
+}
+
+impl IdleTracker {
+    /// Count a request as being handled until the returned guard is dropped.
+    pub fn start(self: &Arc<Self>) -> IdleGuard {
+        self.active.fetch_add(1, Ordering::SeqCst);
+        IdleGuard(Arc::clone(self))
+    }
+}
+
+/// Keeps the connection from being idle while a request is handled.
+#[derive(Debug)]
+pub(crate) struct IdleGuard(Arc<IdleTracker>);
+
+impl IdleGuard {
+    /// Keep the connection active until the body of the response has been sent. Connections that
+    /// are upgraded or turned into tunnels aren't closed for being idle.
+    pub fn hold(self, mut res: Response<Body>, connect: bool) -> Response<Body> {
+        if res.status() == StatusCode::SWITCHING_PROTOCOLS || (connect && res.status().is_success())
+        {
+            self.0.disabled.store(true, Ordering::SeqCst);
+            return res;
+        }
+
+        hold::hold(&mut res, self);
+        res
+    }
+}
+
+impl Drop for IdleGuard {
+    fn drop(&mut self) {
+        self.0.active.fetch_sub(1, Ordering::SeqCst);
+        self.0.waker.wake();
+    }
+}
+
+#[derive(Debug)]
+struct Idle {
+    tracker: Arc<IdleTracker>,
+    sleep: Pin<Box<Sleep>>,
+    was_active: bool,
+}
+
+impl Idle {
+    fn reset(&mut self) {
+        let deadline = Instant::now() + self.tracker.timeout;
+        self.sleep.as_mut().reset(deadline);
+    }
+
+    /// Whether the connection has been idle for the timeout. The timer restarts whenever the last
+    /// request being handled finishes.
+    fn poll_expired(&mut self, cx: &mut Context<'_>) -> bool {
+        if self.tracker.disabled.load(Ordering::SeqCst) {
+            return false;
+        }
+
+        self.tracker.waker.register(cx.waker());
+        if self.tracker.active.load(Ordering::SeqCst) > 0 {
+            self.was_active = true;
+            return false;
+        }
+
+        if self.was_active {
+            self.was_active = false;
+            self.reset();
+        }
+
+        self.sleep.as_mut().poll(cx).is_ready()
+    }
+}
+
+/// A client connection that reads as closed once it has been idle for the timeout, which makes
+/// hyper close it.
+#[derive(Debug)]
+pub(crate) struct IdleStream<I> {
+    inner: I,
+    idle: Option<Idle>,
+}
+
+impl<I> IdleStream<I> {
+    pub fn new(inner: I, timeout: Option<Duration>) -> Self {
+        let idle = timeout.map(|timeout| Idle {
+            tracker: Arc::new(IdleTracker {
+                timeout,
+                active: AtomicUsize::new(0),
+                disabled: AtomicBool::new(false),
+                waker: AtomicWaker::new(),
+            }),
+            sleep: Box::pin(tokio::time::sleep(timeout)),
+            was_active: false,
+        });
+
+        Self { inner, idle }
+    }
+
+    pub fn get_ref(&self) -> &I {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> I {
+        self.inner
+    }
+
+    /// The tracker for the requests handled on the connection, if it has a timeout.
+    pub fn tracker(&self) -> Option<Arc<IdleTracker>> {
+        self.idle.as_ref().map(|idle| Arc::clone(&idle.tracker))
+    }
+}
+
+impl<I: AsyncRead + Unpin> AsyncRead for IdleStream<I> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+
+        if let Poll::Ready(res) = Pin::new(&mut this.inner).poll_read(cx, buf) {
+            if let Some(idle) = &mut this.idle {
+                idle.reset();
+            }
+            return Poll::Ready(res);
+        }
+
+        if this.idle.as_mut().is_some_and(|idle| idle.poll_expired(cx)) {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+impl<I: AsyncWrite + Unpin> AsyncWrite for IdleStream<I> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// Wraps the listener so that accepted connections are closed once they have been idle for the
+/// timeout, if one is set.
+pub(crate) struct IdleConnections<I> {
+    incoming: I,
+    timeout: Option<Duration>,
+}
+
+impl<I> IdleConnections<I> {
+    pub fn new(incoming: I, timeout: Option<Duration>) -> Self {
+        Self { incoming, timeout }
+    }
+}
+
+impl<I: Accept + Unpin> Accept for IdleConnections<I> {
+    type Conn = IdleStream<I::Conn>;
+    type Error = I::Error;
+
+    fn poll_accept(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Conn, Self::Error>>> {
+        let timeout = self.timeout;
+        Pin::new(&mut self.incoming)
+            .poll_accept(cx)
+            .map_ok(|conn| IdleStream::new(conn, timeout))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::proxy::hold::HoldBody;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    #[tokio::test]
+    async fn reads_eof_once_idle() {
+        let (client, server) = tokio::io::duplex(64);
+        let mut stream = IdleStream::new(server, Some(Duration::from_millis(50)));
+        let tracker = stream.tracker().unwrap();
+
+        let guard = tracker.start();
+        let read = tokio::time::timeout(Duration::from_millis(100), stream.read_u8()).await;
+        assert!(read.is_err());
+
+        drop(guard);
+        let mut rest = Vec::new();
+        tokio::time::timeout(Duration::from_secs(1), stream.read_to_end(&mut rest))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(rest.is_empty());
+        drop(client);
+    }
+
+    #[tokio::test]
+    async fn never_expires_once_disabled() {
+        let (mut client, server) = tokio::io::duplex(64);
+        let mut stream = IdleStream::new(server, Some(Duration::from_millis(50)));
+        let tracker = stream.tracker().unwrap();
+
+        let res = tracker.start().hold(Response::new(Body::empty()), true);
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let read = tokio::time::timeout(Duration::from_millis(100), stream.read_u8()).await;
+        assert!(read.is_err());
+
+        client.write_all(b"a").await.unwrap();
+        assert_eq!(stream.read_u8().await.unwrap(), b'a');
+    }
+
+    #[tokio::test]
+    async fn active_until_body_is_sent() {
+        let (_client, server) = tokio::io::duplex(64);
+        let stream = IdleStream::new(server, Some(Duration::from_millis(50)));
+        let tracker = stream.tracker().unwrap();
+
+        let res = tracker
+            .start()
+            .hold(Response::new(Body::from("hello")), false);
+        let res = HoldBody::from_response(res);
+        assert_eq!(tracker.active.load(Ordering::SeqCst), 1);
+
+        drop(res);
+        assert_eq!(tracker.active.load(Ordering::SeqCst), 0);
+    }
+}
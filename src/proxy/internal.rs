@@ -1,25 +1,118 @@
+#[cfg(feature = "decoder")]
+use super::auto_decode;
+use super::{
+    cert_endpoint,
+    coalesce::{Coalescer, Join, Key},
+    drain::{DrainWatch, Draining},
+    events::{ConnectionEvents, EmitOnDrop},
+    h2c,
+    hold::{self, HoldBody},
+    host_limits::{HostLimits, HostPermit},
+    idle::{IdleStream, IdleTracker},
+    max_forwards,
+    mirror::Mirror,
+    request_line::{self, RequestLine},
+    self_addrs::{self, Loop},
+    sessions::{Direction, Sessions},
+    socket::{self, ConnectOptions},
+    Config, ShutdownHandle,
+};
 use crate::{
-    certificate_authority::CertificateAuthority, HttpContext, HttpHandler, RequestOrResponse,
-    Rewind, WebSocketContext, WebSocketHandler,
+    body::{collect_with_limit, BodyError},
+    certificate_authority::CertificateAuthority,
+    connection_reuse::ConnectionUses,
+    decrypted::{self, DecryptedStream},
+    retry::{self, RetryPolicy},
+    tls_fault::alert_record,
+    trace_context::{TraceParent, TRACEPARENT},
+    upstream_target::{self, ConnectTo},
+    BlackholeMode, Decision, Error, ExchangeExtensions, HttpContext, HttpHandler, Protocol,
+    ProxyEventKind, RequestOrResponse, Rewind, TargetForm, TlsFault, TlsFaults, Upgrade,
+    UpstreamTimeout, WebSocketContext, WebSocketHandler,
+};
+use futures::{
+    future::{self, BoxFuture, Either},
+    Sink, Stream, StreamExt,
 };
-use futures::{Sink, Stream, StreamExt};
 use http::uri::{Authority, Scheme};
 use hyper::{
-    client::connect::Connect, header::Entry, server::conn::Http, service::service_fn,
-    upgrade::Upgraded, Body, Client, Method, Request, Response, StatusCode, Uri,
+    body::HttpBody,
+    client::connect::Connect,
+    header::{Entry, HeaderMap, HeaderValue, CONTENT_LENGTH, TRANSFER_ENCODING, USER_AGENT},
+    server::conn::{AddrStream, Http},
+    service::service_fn,
+    upgrade::{OnUpgrade, Upgraded},
+    Body, Client, Method, Request, Response, StatusCode, Uri,
+};
+use std::{
+    convert::Infallible,
+    error::Error as StdError,
+    fmt,
+    future::Future,
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
 };
-use std::{convert::Infallible, future::Future, net::SocketAddr, sync::Arc};
 use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncWrite},
-    net::TcpStream,
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     task::JoinHandle,
 };
-use tokio_rustls::TlsAcceptor;
+use tokio_rustls::{
+    rustls::{self, ServerConfig},
+    server::TlsStream,
+    TlsAcceptor,
+};
 use tokio_tungstenite::{
     tungstenite::{self, Message},
     Connector, WebSocketStream,
 };
-use tracing::{error, info_span, instrument, warn, Instrument, Span};
+use tracing::{error, field, info, info_span, warn, Instrument, Span};
+
+/// Creates an `info` level span if the connection was sampled, otherwise returns a disabled span.
+macro_rules! sampled_span {
+    ($sampled:expr, $($args:tt)+) => {
+        if $sampled {
+            info_span!($($args)+)
+        } else {
+            Span::none()
+        }
+    };
+}
+
+fn header_fields_too_large() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE)
+        .body(Body::empty())
+        .expect("Failed to build response")
+}
+
+fn bad_gateway() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::BAD_GATEWAY)
+        .body(Body::empty())
+        .expect("Failed to build response")
+}
+
+/// Total size of the names and values of the headers.
+fn header_bytes(headers: &HeaderMap) -> usize {
+    headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len())
+        .sum()
+}
+
+/// Wrap an error in a [`hyper::Error`], so that it can be passed to [`HttpHandler::handle_error`].
+/// Hyper's errors can't be built directly, so this is done by failing to read a body.
+async fn into_hyper_error(err: impl Into<Box<dyn StdError + Send + Sync>>) -> hyper::Error {
+    let err = err.into();
+    let body = Body::wrap_stream(futures::stream::once(
+        async move { Err::<bytes::Bytes, _>(err) },
+    ));
+    hyper::body::to_bytes(body)
+        .await
+        .expect_err("Failed to build error")
+}
 
 fn bad_request() -> Response<Body> {
     Response::builder()
@@ -28,6 +121,28 @@ fn bad_request() -> Response<Body> {
         .expect("Failed to build response")
 }
 
+/// Whether the length of a request body is ambiguous. Servers disagreeing about which of these
+/// headers to use is the basis of request smuggling, so such requests are never forwarded.
+fn is_ambiguous_length(headers: &HeaderMap) -> bool {
+    headers.contains_key(CONTENT_LENGTH) && headers.contains_key(TRANSFER_ENCODING)
+}
+
+struct SpanFields(Vec<(&'static str, String)>);
+
+impl fmt::Display for SpanFields {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (name, value)) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+
+            write!(f, "{}={}", name, value)?;
+        }
+
+        Ok(())
+    }
+}
+
 fn spawn_with_trace<T: Send + Sync + 'static>(
     fut: impl Future<Output = T> + Send + 'static,
     span: Span,
@@ -35,13 +150,56 @@ fn spawn_with_trace<T: Send + Sync + 'static>(
     tokio::spawn(fut.instrument(span))
 }
 
+/// Spawns a future once dropped, used to notify the handler that a connection has closed.
+///
+/// The guard is shared by everything that keeps the client connection alive, so it is only
+/// dropped once the connection and any tunnels or upgrades have finished.
+pub(crate) struct ConnectionGuard(Mutex<Option<BoxFuture<'static, ()>>>);
+
+impl ConnectionGuard {
+    pub fn new(on_close: impl Future<Output = ()> + Send + 'static) -> Self {
+        Self(Mutex::new(Some(Box::pin(on_close))))
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let on_close = match self.0.get_mut() {
+            Ok(on_close) => on_close.take(),
+            Err(e) => e.into_inner().take(),
+        };
+
+        if let (Some(on_close), Ok(handle)) = (on_close, tokio::runtime::Handle::try_current()) {
+            handle.spawn(on_close);
+        }
+    }
+}
+
 pub(crate) struct InternalProxy<C, CA, H, W> {
     pub ca: Arc<CA>,
     pub client: Client<C>,
+    /// The client for requests with a connect address, which doesn't pool connections.
+    pub unpooled_client: Client<C>,
     pub http_handler: H,
     pub websocket_handler: W,
     pub websocket_connector: Option<Connector>,
+    pub config: Arc<Config>,
     pub client_addr: SocketAddr,
+    pub local_addr: SocketAddr,
+    pub client_cert: Option<Vec<rustls::Certificate>>,
+    pub client_alpn: Option<Vec<u8>>,
+    #[cfg(target_os = "linux")]
+    pub peer_cred: Option<crate::PeerCred>,
+    pub span_sampled: bool,
+    pub connection: Arc<ConnectionGuard>,
+    pub drain: DrainWatch,
+    pub sessions: Arc<Sessions>,
+    pub host_limits: Arc<HostLimits>,
+    pub coalescer: Arc<Coalescer>,
+    pub mirror: Option<Mirror>,
+    pub events: Option<ConnectionEvents>,
+    pub idle: Option<Arc<IdleTracker>>,
+    pub shutdown: ShutdownHandle,
 }
 
 impl<C, CA, H, W> Clone for InternalProxy<C, CA, H, W>
@@ -54,10 +212,27 @@ where
         InternalProxy {
             ca: Arc::clone(&self.ca),
             client: self.client.clone(),
+            unpooled_client: self.unpooled_client.clone(),
             http_handler: self.http_handler.clone(),
             websocket_handler: self.websocket_handler.clone(),
             websocket_connector: self.websocket_connector.clone(),
+            config: Arc::clone(&self.config),
             client_addr: self.client_addr,
+            local_addr: self.local_addr,
+            client_cert: self.client_cert.clone(),
+            client_alpn: self.client_alpn.clone(),
+            #[cfg(target_os = "linux")]
+            peer_cred: self.peer_cred,
+            span_sampled: self.span_sampled,
+            connection: Arc::clone(&self.connection),
+            drain: self.drain.clone(),
+            sessions: Arc::clone(&self.sessions),
+            host_limits: Arc::clone(&self.host_limits),
+            coalescer: Arc::clone(&self.coalescer),
+            mirror: self.mirror.clone(),
+            events: self.events.clone(),
+            idle: self.idle.clone(),
+            shutdown: self.shutdown.clone(),
         }
     }
 }
@@ -69,159 +244,882 @@ where
     H: HttpHandler,
     W: WebSocketHandler,
 {
-    fn context(&self) -> HttpContext {
+    fn context(&self, req: &Request<Body>, request_target_form: TargetForm) -> HttpContext {
         HttpContext {
             client_addr: self.client_addr,
+            request_target_form,
+            is_websocket_upgrade: hyper_tungstenite::is_upgrade_request(req),
+            upstream_tls: None,
+            connection_reused: None,
+            client_cert: self.client_cert.clone(),
+            client_alpn: self.client_alpn.clone(),
+            #[cfg(target_os = "linux")]
+            peer_cred: self.peer_cred,
+            shutdown: self.shutdown.clone(),
+            extensions: ExchangeExtensions::default(),
         }
     }
 
-    #[instrument(
-        skip_all,
-        fields(
+    pub(crate) fn proxy(
+        self,
+        req: Request<Body>,
+        target_form: TargetForm,
+    ) -> impl Future<Output = Result<Response<HoldBody>, Infallible>> {
+        let span = sampled_span!(
+            self.span_sampled,
+            "proxy",
             version = ?req.version(),
             method = %req.method(),
-            uri=%req.uri(),
+            uri = %req.uri(),
             client_addr = %self.client_addr,
-        )
-    )]
-    pub(crate) async fn proxy(mut self, req: Request<Body>) -> Result<Response<Body>, Infallible> {
-        let ctx = self.context();
-
-        let req = match self
-            .http_handler
-            .handle_request(&ctx, req)
-            .instrument(info_span!("handle_request"))
-            .await
-        {
-            RequestOrResponse::Request(req) => req,
-            RequestOrResponse::Response(res) => return Ok(res),
+            custom = field::Empty,
+            trace_id = field::Empty,
+        );
+
+        let ctx = self.context(&req, target_form);
+        let span_fields = self.http_handler.span_fields(&ctx, &req);
+        if !span_fields.is_empty() {
+            span.record("custom", field::display(SpanFields(span_fields)));
+        }
+
+        let idle = self.idle.as_ref().map(IdleTracker::start);
+        let request_events = self.events.clone().map(|events| {
+            let request_id = events.next_request_id();
+            events.emit(ProxyEventKind::RequestStarted {
+                request_id,
+                method: req.method().clone(),
+                uri: req.uri().clone(),
+            });
+            (events, request_id)
+        });
+        let connect = req.method() == Method::CONNECT;
+        let (req, exchange) = match &self.mirror {
+            Some(mirror) if !connect => {
+                let (req, exchange) = mirror.start(self.client_addr, req);
+                (req, Some(exchange))
+            }
+            _ => (req, None),
+        };
+        let res = self.process_request(ctx, req).instrument(span);
+
+        async move {
+            let res = res.await?;
+            if let Some((events, request_id)) = request_events {
+                events.emit(ProxyEventKind::ResponseCompleted {
+                    request_id,
+                    status: res.status(),
+                });
+            }
+            let res = match exchange {
+                Some(exchange) => exchange.finish(res),
+                None => res,
+            };
+            let res = match idle {
+                Some(idle) => idle.hold(res, connect),
+                None => res,
+            };
+            Ok(HoldBody::from_response(res))
+        }
+    }
+
+    fn exceeds_header_limits(&self, headers: &HeaderMap) -> bool {
+        if let Some(max_headers) = self.config.max_headers {
+            if headers.len() > max_headers {
+                warn!(
+                    "Rejecting request with {} headers, limit is {}",
+                    headers.len(),
+                    max_headers
+                );
+                return true;
+            }
+        }
+
+        if let Some(max_header_bytes) = self.config.max_header_bytes {
+            let bytes = header_bytes(headers);
+            if bytes > max_header_bytes {
+                warn!(
+                    "Rejecting request with {} bytes of headers, limit is {}",
+                    bytes, max_header_bytes
+                );
+                return true;
+            }
+        }
+
+        false
+    }
+
+    async fn process_request(
+        mut self,
+        ctx: HttpContext,
+        req: Request<Body>,
+    ) -> Result<Response<Body>, Infallible> {
+        if self.exceeds_header_limits(req.headers()) {
+            return Ok(header_fields_too_large());
+        }
+
+        if self.config.smuggling_protection && is_ambiguous_length(req.headers()) {
+            warn!("Rejecting request with both Content-Length and Transfer-Encoding headers");
+            return Ok(bad_request());
+        }
+
+        if self.config.ca_cert_endpoint && cert_endpoint::is_cert_request(&req) {
+            return Ok(cert_endpoint::response(&req, self.ca.ca_cert()));
+        }
+
+        let process = self.http_handler.should_process(&ctx, &req);
+        let client_head = req.method() == Method::HEAD;
+        // TRACE requests are forwarded without their body being decoded or transformed.
+        let trace = req.method() == Method::TRACE;
+
+        #[cfg(feature = "decoder")]
+        let (req, decoded) = if process && self.config.auto_decode && !trace {
+            match auto_decode::decode_request(req).await {
+                Ok(decoded) => decoded,
+                Err(e) => {
+                    error!("Failed to read request body: {}", e);
+                    return Ok(bad_request());
+                }
+            }
+        } else {
+            (req, None)
+        };
+
+        let mut req = if process {
+            match self
+                .http_handler
+                .handle_request(&ctx, req)
+                .instrument(sampled_span!(self.span_sampled, "handle_request"))
+                .await
+            {
+                RequestOrResponse::Request(req) => req,
+                RequestOrResponse::Response(res) => return Ok(res),
+                RequestOrResponse::Upgrade(upgrade) => return Ok(self.process_upgrade(upgrade)),
+            }
+        } else {
+            req
         };
 
+        #[cfg(feature = "decoder")]
+        {
+            req = match auto_decode::encode_request(req, decoded).await {
+                Ok(req) => req,
+                Err(e) => {
+                    error!("Failed to read request body: {}", e);
+                    return Ok(bad_request());
+                }
+            };
+        }
+
         if req.method() == Method::CONNECT {
-            Ok(self.process_connect(req))
+            let connect_addr = self
+                .http_handler
+                .upstream_target(&ctx, &req)
+                .and_then(|target| target.connect_addr);
+            let connect_addr = match req.uri().authority() {
+                Some(authority) => match self.resolve(authority, 443, connect_addr).await {
+                    Ok(resolved) => connect_addr.or(resolved),
+                    Err(Loop) => return Ok(self_addrs::loop_detected(authority)),
+                },
+                None => connect_addr,
+            };
+
+            Ok(self.process_connect(ctx, req, connect_addr))
         } else if hyper_tungstenite::is_upgrade_request(&req) {
             Ok(self.upgrade_websocket(req))
         } else {
-            let res = self
-                .client
-                .request(normalize_request(req))
-                .instrument(info_span!("proxy_request"))
-                .await;
+            if process {
+                *req.uri_mut() = self
+                    .http_handler
+                    .rewrite_uri(&ctx, std::mem::take(req.uri_mut()))
+                    .instrument(sampled_span!(self.span_sampled, "rewrite_uri"))
+                    .await;
+            }
+
+            if self.config.trace_propagation {
+                let traceparent = TraceParent::from_headers(req.headers())
+                    .map_or_else(TraceParent::new, |parent| parent.child());
+                Span::current().record("trace_id", traceparent.trace_id());
+                req.headers_mut()
+                    .insert(TRACEPARENT, traceparent.header_value());
+            }
+
+            if let Some(res) = max_forwards::apply(&mut req) {
+                return Ok(res);
+            }
+
+            // Requests with `Expect: 100-continue` are forwarded with the header. The origin's
+            // interim `100 Continue` is not relayed, since hyper's client discards it. Instead,
+            // the proxy's own server sends `100 Continue` to the client when the body is first
+            // polled, which is once the upstream connection is made and the body is forwarded.
+            let uri = req.uri().clone();
+            let is_head = req.method() == Method::HEAD;
+            *req.version_mut() = self.http_handler.upstream_version(&ctx, &req);
+
+            // Upgrades can only be forwarded over HTTP/1.1.
+            let client_upgrade = if h2c::is_upgrade_request(&req) {
+                if self.config.h2c_upgrade && req.version() == hyper::Version::HTTP_11 {
+                    Some(hyper::upgrade::on(&mut req))
+                } else {
+                    h2c::strip_upgrade(req.headers_mut());
+                    None
+                }
+            } else {
+                None
+            };
+
+            let target = self.http_handler.upstream_target(&ctx, &req);
+            let mut req = sampled_span!(self.span_sampled, "normalize_request")
+                .in_scope(|| normalize_request(req, &self.config));
+            let connect_addr = target.as_ref().and_then(|target| target.connect_addr);
+            if let Some(target) = target {
+                target.apply(&mut req);
+            }
+            if let Some(authority) = req.uri().authority().cloned() {
+                let default_port = if req.uri().scheme() == Some(&Scheme::HTTPS) {
+                    443
+                } else {
+                    80
+                };
+                match self.resolve(&authority, default_port, connect_addr).await {
+                    // Only clients built on an `UpstreamTargetConnector` connect to the resolved
+                    // address, others resolve the host again.
+                    Ok(Some(addr)) => {
+                        req.extensions_mut().insert(ConnectTo::Resolved(addr));
+                    }
+                    Ok(None) => (),
+                    Err(Loop) => return Ok(self_addrs::loop_detected(&authority)),
+                }
+            }
+            let req = match &self.config.request_pipeline {
+                Some(pipeline) if process && !trace && !req.body().is_end_stream() => {
+                    let (mut parts, body) = req.into_parts();
+                    match pipeline
+                        .apply(&mut parts.headers, &mut parts.extensions, body)
+                        .instrument(sampled_span!(self.span_sampled, "request_pipeline"))
+                        .await
+                    {
+                        Ok(body) => Request::from_parts(parts, body),
+                        Err(e) => {
+                            error!("Failed to transform request body: {}", e);
+                            return Ok(bad_request());
+                        }
+                    }
+                }
+                _ => req,
+            };
+            // Upgrades aren't coalesced, since the upgraded connection can't be shared.
+            let res = match client_upgrade {
+                Some(client_upgrade) => self
+                    .forward_request(req)
+                    .await
+                    .map(|res| self.tunnel_h2c(res, client_upgrade)),
+                None => self.send_request(req).await,
+            };
+
+            // Every response is counted, so that later responses on the connection are reported
+            // as reused even if they aren't processed.
+            let connection_reused = res.as_ref().ok().and_then(|res| {
+                res.extensions()
+                    .get::<ConnectionUses>()
+                    .map(ConnectionUses::record)
+            });
+
+            // A handler may have changed the method. Responses to HEAD requests describe a body
+            // they don't have, so their length isn't passed on to handlers and clients that expect
+            // the body. Hyper already leaves out the body of responses to HEAD requests.
+            let res = res.map(|mut res| {
+                if is_head && !client_head {
+                    res.headers_mut().remove(hyper::header::CONTENT_LENGTH);
+                }
+                res
+            });
 
+            // The response body is streamed to the client as it is received. If the client
+            // disconnects, the body is dropped, which closes the upstream connection instead of
+            // reading the rest of the response.
             match res {
-                Ok(res) => Ok(self
-                    .http_handler
-                    .handle_response(&ctx, res)
-                    .instrument(info_span!("handle_response"))
-                    .await),
-                Err(err) => Ok(self
-                    .http_handler
-                    .handle_error(&ctx, err)
-                    .instrument(info_span!("handle_error"))
-                    .await),
+                Ok(res) if !process => Ok(self.apply_header_policy(res)),
+                Ok(mut res) => {
+                    let ctx = HttpContext {
+                        upstream_tls: res.extensions_mut().remove(),
+                        connection_reused,
+                        ..ctx
+                    };
+
+                    // Responses to HEAD requests have no body, but describe the encoded body.
+                    #[cfg(feature = "decoder")]
+                    let (res, decoded) = if self.config.auto_decode && !is_head {
+                        match auto_decode::decode_response(res).await {
+                            Ok(decoded) => decoded,
+                            Err(err) => return Ok(self.http_handler.handle_error(&ctx, err).await),
+                        }
+                    } else {
+                        (res, None)
+                    };
+
+                    let res = self
+                        .http_handler
+                        .handle_response(&ctx, res)
+                        .instrument(sampled_span!(self.span_sampled, "handle_response"))
+                        .await;
+
+                    #[cfg(feature = "decoder")]
+                    let res = match auto_decode::encode_response(res, decoded).await {
+                        Ok(res) => res,
+                        Err(err) => self.http_handler.handle_error(&ctx, err).await,
+                    };
+
+                    Ok(self.finish_response(res, is_head).await)
+                }
+                Err(err) => match &self.config.error_page {
+                    Some(error_page) => {
+                        error!("Failed to forward request: {}", err);
+                        Ok(error_page(&err, &uri))
+                    }
+                    None => Ok(self
+                        .http_handler
+                        .handle_error(&ctx, err)
+                        .instrument(sampled_span!(self.span_sampled, "handle_error"))
+                        .await),
+                },
+            }
+        }
+    }
+
+    /// Send a request upstream. If coalescing is enabled, a GET request that is identical to one
+    /// already in flight waits to share its response, and is only sent if that can't be shared.
+    async fn send_request(&self, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+        let join = if self.config.request_coalescing {
+            Key::from_request(&req).map(|key| self.coalescer.join(key))
+        } else {
+            None
+        };
+
+        match join {
+            Some(Join::Leader(leader)) => {
+                let res = self.forward_request(req).await?;
+                Ok(leader.share(res).await)
+            }
+            Some(Join::Follower(in_flight)) => {
+                match in_flight
+                    .instrument(sampled_span!(self.span_sampled, "wait_for_coalesced"))
+                    .await
+                {
+                    Some(shared) => Ok(shared.to_response()),
+                    None => self.forward_request(req).await,
+                }
             }
+            None => self.forward_request(req).await,
         }
     }
 
-    fn process_connect(mut self, mut req: Request<Body>) -> Response<Body> {
+    async fn forward_request(
+        &self,
+        mut req: Request<Body>,
+    ) -> Result<Response<Body>, hyper::Error> {
+        let permit = match req.uri().authority() {
+            Some(authority) => self.host_permit(authority, req.uri().scheme()).await,
+            None => None,
+        };
+
+        // Connections to a connect address aren't shared with requests to the host itself, unlike
+        // those to the address the host was already resolved to.
+        let connect_to = req.extensions_mut().remove::<ConnectTo>();
+        let client = match connect_to {
+            Some(ConnectTo::Addr(_)) => &self.unpooled_client,
+            Some(ConnectTo::Resolved(_)) | None => &self.client,
+        };
+
+        let timeout = req.extensions().get::<UpstreamTimeout>().copied();
+        let res = async {
+            match &self.config.retry_policy {
+                Some(policy) if policy.allows(req.method()) => {
+                    self.request_with_retries(client, req, policy).await
+                }
+                _ => {
+                    client
+                        .request(req)
+                        .instrument(sampled_span!(self.span_sampled, "proxy_request"))
+                        .await
+                }
+            }
+        };
+        let res = upstream_target::connect_to(connect_to, res);
+        let res = match timeout {
+            Some(UpstreamTimeout(timeout)) => match tokio::time::timeout(timeout, res).await {
+                Ok(res) => res?,
+                Err(_) => {
+                    warn!("Upstream server didn't respond within {:?}", timeout);
+                    let err = io::Error::new(io::ErrorKind::TimedOut, "upstream request timed out");
+                    return Err(into_hyper_error(err).await);
+                }
+            },
+            None => res.await?,
+        };
+
+        if let Some(max_bytes) = self.config.max_response_header_bytes {
+            let bytes = header_bytes(res.headers());
+            if bytes > max_bytes {
+                warn!(
+                    "Rejecting response with {} bytes of headers, limit is {}",
+                    bytes, max_bytes
+                );
+                return Err(into_hyper_error(Error::ResponseHeadersTooLarge).await);
+            }
+        }
+
+        Ok(hold_permit(res, permit))
+    }
+
+    /// Send a request upstream, sending it again while the response has a status that is retried
+    /// by the policy. The body is buffered so that it can be sent again, unless it is too large, in
+    /// which case the request is sent once.
+    async fn request_with_retries(
+        &self,
+        client: &Client<C>,
+        req: Request<Body>,
+        policy: &RetryPolicy,
+    ) -> Result<Response<Body>, hyper::Error> {
+        let (parts, body) = req.into_parts();
+        let body = if body.is_end_stream() {
+            Ok(bytes::Bytes::new())
+        } else {
+            collect_with_limit(body, retry::MAX_BODY_SIZE).await
+        };
+        let body = match body {
+            Ok(body) => body,
+            Err(BodyError::Network(e)) => return Err(e),
+            Err(BodyError::TooLarge { body, .. }) => {
+                return client
+                    .request(Request::from_parts(parts, body))
+                    .instrument(sampled_span!(self.span_sampled, "proxy_request"))
+                    .await
+            }
+        };
+
+        // Extensions can't be cloned, so only the first attempt has them.
+        let mut head = Request::new(());
+        *head.method_mut() = parts.method.clone();
+        *head.uri_mut() = parts.uri.clone();
+        *head.version_mut() = parts.version;
+        *head.headers_mut() = parts.headers.clone();
+        let retry = |body: bytes::Bytes| {
+            let mut req = Request::new(Body::from(body));
+            *req.method_mut() = head.method().clone();
+            *req.uri_mut() = head.uri().clone();
+            *req.version_mut() = head.version();
+            *req.headers_mut() = head.headers().clone();
+            req
+        };
+
+        let mut req = Request::from_parts(parts, Body::from(body.clone()));
+        let mut retries = 0;
+
+        loop {
+            let res = client
+                .request(req)
+                .instrument(sampled_span!(self.span_sampled, "proxy_request", retries))
+                .await?;
+
+            if !policy.should_retry(res.status(), retries) {
+                return Ok(res);
+            }
+
+            let delay = policy.delay(retries);
+            info!(
+                "Retrying request after {} response in {:?}",
+                res.status(),
+                delay
+            );
+            drop(res);
+            tokio::time::sleep(delay).await;
+
+            retries += 1;
+            req = retry(body.clone());
+        }
+    }
+
+    /// Wait until a connection to the host can be opened, if the connections to each host are
+    /// limited.
+    async fn host_permit(
+        &self,
+        authority: &Authority,
+        scheme: Option<&Scheme>,
+    ) -> Option<HostPermit> {
+        let limit = self.config.per_host_connection_limit?;
+        Some(
+            self.host_limits
+                .acquire(authority, scheme, limit)
+                .instrument(sampled_span!(self.span_sampled, "wait_for_host"))
+                .await,
+        )
+    }
+
+    /// Apply the response pipeline, if the response can have a body, and the header policy to a
+    /// response from upstream.
+    async fn finish_response(&self, res: Response<Body>, is_head: bool) -> Response<Body> {
+        let res = match &self.config.response_pipeline {
+            Some(pipeline)
+                if !is_head
+                    && !res.status().is_informational()
+                    && res.status() != StatusCode::NO_CONTENT
+                    && res.status() != StatusCode::NOT_MODIFIED =>
+            {
+                let (mut parts, body) = res.into_parts();
+                match pipeline
+                    .apply(&mut parts.headers, &mut parts.extensions, body)
+                    .instrument(sampled_span!(self.span_sampled, "response_pipeline"))
+                    .await
+                {
+                    Ok(body) => Response::from_parts(parts, body),
+                    Err(e) => {
+                        error!("Failed to transform response body: {}", e);
+                        bad_gateway()
+                    }
+                }
+            }
+            _ => res,
+        };
+
+        self.apply_header_policy(res)
+    }
+
+    fn apply_header_policy(&self, mut res: Response<Body>) -> Response<Body> {
+        if let Some(policy) = &self.config.response_header_policy {
+            policy.apply(res.headers_mut());
+        }
+
+        res
+    }
+
+    async fn server_config(
+        &self,
+        authority: &Authority,
+        fault: Option<TlsFault>,
+    ) -> Arc<ServerConfig> {
+        if let Some(fault) = fault {
+            warn!("Injecting TLS fault {:?}", fault);
+
+            match self.ca.gen_faulty_server_config(authority, fault).await {
+                Some(server_config) => return server_config,
+                None => warn!(
+                    "Certificate authority doesn't support TLS fault {:?}",
+                    fault
+                ),
+            }
+        }
+
+        let (server_config, info) = self.ca.gen_server_config_with_info(authority).await;
+
+        if let (Some(events), Some(info)) = (&self.events, info) {
+            events.emit(ProxyEventKind::CertServed {
+                authority: authority.clone(),
+                from_cache: info.from_cache,
+                gen_duration: info.gen_duration,
+                key_algo: info.key_algo,
+            });
+        }
+
+        match &self.config.keylog {
+            Some(keylog) => {
+                let mut server_config = ServerConfig::clone(&server_config);
+                server_config.key_log = Arc::clone(keylog) as _;
+                Arc::new(server_config)
+            }
+            None => server_config,
+        }
+    }
+
+    /// The size of the buffers used to copy data in each direction through tunnels.
+    fn tunnel_buf_size(&self) -> usize {
+        socket::copy_buf_size(
+            self.config.tunnel_buffer_size,
+            self.config.socket_buffer_sizes,
+        )
+    }
+
+    /// The options for connecting to the servers of tunnels.
+    fn connect_options(&self) -> ConnectOptions {
+        ConnectOptions {
+            buffer_sizes: self.config.socket_buffer_sizes,
+            port_range: self.config.outbound_port_range.clone(),
+        }
+    }
+
+    /// How to connect to the server of a decrypted stream, which is done like for tunnels.
+    fn decrypted_upstream(&self, connect_addr: Option<SocketAddr>) -> decrypted::Upstream {
+        decrypted::Upstream {
+            client_config: self
+                .config
+                .upstream_tls_config
+                .clone()
+                .unwrap_or_else(decrypted::default_client_config),
+            connect_addr,
+            connect_options: self.connect_options(),
+            host_limit: self
+                .config
+                .per_host_connection_limit
+                .map(|limit| (Arc::clone(&self.host_limits), limit)),
+        }
+    }
+
+    fn connect_response(&self) -> Response<Body> {
+        let mut res = Response::new(Body::empty());
+        *res.headers_mut() = self.config.connect_headers.clone();
+
+        if let Some(reason) = &self.config.connect_reason_phrase {
+            res.extensions_mut().insert(reason.clone());
+        }
+
+        res
+    }
+
+    /// Check whether connecting to an authority would connect back to the proxy, returning the
+    /// address it was resolved to, if it had to be resolved.
+    async fn resolve(
+        &self,
+        authority: &Authority,
+        default_port: u16,
+        connect_addr: Option<SocketAddr>,
+    ) -> Result<Option<SocketAddr>, Loop> {
+        self_addrs::resolve(
+            authority,
+            default_port,
+            connect_addr,
+            self.local_addr,
+            &self.config.self_addrs,
+        )
+        .await
+    }
+
+    fn process_connect(
+        mut self,
+        ctx: HttpContext,
+        mut req: Request<Body>,
+        connect_addr: Option<SocketAddr>,
+    ) -> Response<Body> {
         match req.uri().authority().cloned() {
             Some(authority) => {
-                let span = info_span!("process_connect");
+                let res = self.connect_response();
+                let span = sampled_span!(
+                    self.span_sampled,
+                    "process_connect",
+                    intercept = field::Empty,
+                    intercept_reason = field::Empty,
+                );
                 let fut = async move {
+                    let blackhole = self
+                        .config
+                        .blackhole
+                        .as_ref()
+                        .filter(|(hosts, _)| hosts.matches(&authority))
+                        .map(|&(_, mode)| mode);
+
                     match hyper::upgrade::on(&mut req).await {
+                        Ok(upgraded) if blackhole.is_some() => {
+                            record_decision(Decision {
+                                intercept: false,
+                                reason: "blackholed",
+                            });
+                            info!("Blackholing tunnel to {}", authority);
+                            close_blackholed(upgraded, blackhole.unwrap_or_default()).await;
+                        }
+                        Ok(upgraded) if !self.http_handler.should_sniff(&ctx, &authority) => {
+                            record_decision(Decision {
+                                intercept: false,
+                                reason: "not sniffed",
+                            });
+                            tunnel(
+                                upgraded,
+                                &authority,
+                                connect_addr,
+                                &self.drain,
+                                &self.connect_options(),
+                                self.tunnel_buf_size(),
+                                self.host_permit(&authority, None).await,
+                            )
+                            .await;
+                        }
                         Ok(mut upgraded) => {
                             let mut buffer = [0; 4];
-                            let bytes_read = match upgraded.read(&mut buffer).await {
-                                Ok(bytes_read) => bytes_read,
-                                Err(e) => {
+                            let read = upgraded.read(&mut buffer);
+                            let read = match self.config.sniff_timeout {
+                                Some(timeout) => tokio::time::timeout(timeout, read).await,
+                                None => Ok(read.await),
+                            };
+
+                            let bytes_read = match read {
+                                Ok(Ok(bytes_read)) => bytes_read,
+                                Ok(Err(e)) => {
                                     error!("Failed to read from upgraded connection: {}", e);
                                     return;
                                 }
+                                Err(_) => {
+                                    record_decision(Decision {
+                                        intercept: false,
+                                        reason: "sniff timeout",
+                                    });
+                                    tunnel(
+                                        upgraded,
+                                        &authority,
+                                        connect_addr,
+                                        &self.drain,
+                                        &self.connect_options(),
+                                        self.tunnel_buf_size(),
+                                        self.host_permit(&authority, None).await,
+                                    )
+                                    .await;
+                                    return;
+                                }
                             };
 
-                            let mut upgraded = Rewind::new_buffered(
+                            let upgraded = Rewind::new_buffered(
                                 upgraded,
                                 bytes::Bytes::copy_from_slice(buffer[..bytes_read].as_ref()),
                             );
 
-                            if self
-                                .http_handler
-                                .should_intercept(&self.context(), &req)
-                                .await
-                            {
-                                if buffer == *b"GET " {
-                                    if let Err(e) =
-                                        self.serve_stream(upgraded, Scheme::HTTP, authority).await
-                                    {
-                                        error!("WebSocket connect error: {}", e);
-                                    }
+                            let decision = self.http_handler.intercept_decision(&ctx, &req).await;
+                            record_decision(decision);
+
+                            if decision.intercept {
+                                let bytes = &buffer[..bytes_read];
+                                let scheme = self.http_handler.connect_scheme(&ctx, &authority);
+                                let protocol = match self.config.protocol_matchers.classify(bytes) {
+                                    None if scheme.is_some() => Some(Protocol::Http),
+                                    protocol => protocol,
+                                };
+
+                                match protocol {
+                                    Some(Protocol::Http) => {
+                                        let scheme = scheme.unwrap_or(Scheme::HTTP);
+                                        if let Err(e) =
+                                            self.serve_stream(upgraded, scheme, authority).await
+                                        {
+                                            error!("WebSocket connect error: {}", e);
+                                        }
 
-                                    return;
-                                } else if buffer[..2] == *b"\x16\x03" {
-                                    let server_config = self
-                                        .ca
-                                        .gen_server_config(&authority)
-                                        .instrument(info_span!("gen_server_config"))
-                                        .await;
-
-                                    let stream = match TlsAcceptor::from(server_config)
-                                        .accept(upgraded)
-                                        .await
-                                    {
-                                        Ok(stream) => stream,
-                                        Err(e) => {
-                                            error!("Failed to establish TLS connection: {}", e);
+                                        return;
+                                    }
+                                    Some(Protocol::Tls) => {
+                                        let fault = self
+                                            .config
+                                            .tls_faults
+                                            .as_ref()
+                                            .and_then(TlsFaults::choose);
+
+                                        if let Some(TlsFault::Alert(alert)) = fault {
+                                            warn!("Injecting TLS alert {:?}", alert);
+                                            let mut upgraded = upgraded;
+                                            let _ = upgraded.write_all(&alert_record(alert)).await;
+                                            let _ = upgraded.shutdown().await;
                                             return;
                                         }
-                                    };
-
-                                    if let Err(e) =
-                                        self.serve_stream(stream, Scheme::HTTPS, authority).await
-                                    {
-                                        if !e
-                                            .to_string()
-                                            .starts_with("error shutting down connection")
+
+                                        if let Some(delay) = &self.config.handshake_delay {
+                                            tokio::time::sleep(delay.sample()).await;
+                                        }
+
+                                        let server_config = self
+                                            .server_config(&authority, fault)
+                                            .instrument(sampled_span!(
+                                                self.span_sampled,
+                                                "gen_server_config"
+                                            ))
+                                            .await;
+
+                                        let stream = match TlsAcceptor::from(server_config)
+                                            .accept(upgraded)
+                                            .await
                                         {
-                                            error!("HTTPS connect error: {}", e);
+                                            Ok(stream) => stream,
+                                            Err(e) => {
+                                                error!("Failed to establish TLS connection: {}", e);
+                                                return;
+                                            }
+                                        };
+                                        let client_cert = stream
+                                            .get_ref()
+                                            .1
+                                            .peer_certificates()
+                                            .map(<[_]>::to_vec);
+                                        let client_alpn =
+                                            stream.get_ref().1.alpn_protocol().map(<[_]>::to_vec);
+                                        if let Some(events) = &self.events {
+                                            events.emit(ProxyEventKind::TlsEstablished {
+                                                authority: authority.clone(),
+                                            });
                                         }
-                                    }
 
-                                    return;
-                                } else {
-                                    warn!(
+                                        let stream = match self.sniff_decrypted(stream).await {
+                                            Ok(Ok(stream)) => stream,
+                                            Ok(Err(stream)) => {
+                                                self.http_handler
+                                                    .handle_decrypted_stream(
+                                                        &ctx,
+                                                        DecryptedStream::new(
+                                                            stream,
+                                                            self.decrypted_upstream(connect_addr),
+                                                        ),
+                                                        authority,
+                                                    )
+                                                    .await;
+                                                return;
+                                            }
+                                            Err(e) => {
+                                                error!("Failed to read from TLS connection: {}", e);
+                                                return;
+                                            }
+                                        };
+
+                                        self.client_cert = client_cert;
+                                        self.client_alpn = client_alpn;
+                                        let scheme = scheme.unwrap_or(Scheme::HTTPS);
+                                        if let Err(e) =
+                                            self.serve_stream(stream, scheme, authority).await
+                                        {
+                                            if !e
+                                                .to_string()
+                                                .starts_with("error shutting down connection")
+                                            {
+                                                error!("HTTPS connect error: {}", e);
+                                            }
+                                        }
+
+                                        return;
+                                    }
+                                    Some(Protocol::Tunnel) => (),
+                                    None => warn!(
                                         "Unknown protocol, read '{:02X?}' from upgraded connection",
-                                        &buffer[..bytes_read]
-                                    );
+                                        bytes
+                                    ),
                                 }
                             }
 
-                            let mut server = match TcpStream::connect(authority.as_ref()).await {
-                                Ok(server) => server,
-                                Err(e) => {
-                                    error!("Failed to connect to {}: {}", authority, e);
-                                    return;
-                                }
-                            };
-
-                            if let Err(e) =
-                                tokio::io::copy_bidirectional(&mut upgraded, &mut server).await
-                            {
-                                error!("Failed to tunnel to {}: {}", authority, e);
-                            }
+                            tunnel(
+                                upgraded,
+                                &authority,
+                                connect_addr,
+                                &self.drain,
+                                &self.connect_options(),
+                                self.tunnel_buf_size(),
+                                self.host_permit(&authority, None).await,
+                            )
+                            .await;
                         }
                         Err(e) => error!("Upgrade error: {}", e),
                     };
                 };
 
                 spawn_with_trace(fut, span);
-                Response::new(Body::empty())
+                res
             }
             None => bad_request(),
         }
     }
 
-    #[instrument(skip_all)]
     fn upgrade_websocket(self, req: Request<Body>) -> Response<Body> {
+        let _span = sampled_span!(self.span_sampled, "upgrade_websocket").entered();
+
         let mut req = {
             let (mut parts, _) = req.into_parts();
 
@@ -247,11 +1145,12 @@ where
 
         match hyper_tungstenite::upgrade(&mut req, None) {
             Ok((res, websocket)) => {
-                let span = info_span!("websocket");
+                let span = sampled_span!(self.span_sampled, "websocket");
                 let fut = async move {
                     match websocket.await {
                         Ok(ws) => {
-                            if let Err(e) = self.handle_websocket(ws, req).await {
+                            let span = sampled_span!(self.span_sampled, "handle_websocket");
+                            if let Err(e) = self.handle_websocket(ws, req).instrument(span).await {
                                 error!("Failed to handle WebSocket: {}", e);
                             }
                         }
@@ -268,12 +1167,12 @@ where
         }
     }
 
-    #[instrument(skip_all)]
     async fn handle_websocket(
         self,
         server_socket: WebSocketStream<Upgraded>,
-        req: Request<()>,
+        mut req: Request<()>,
     ) -> Result<(), tungstenite::Error> {
+        self.websocket_handler.rewrite_handshake_request(&mut req);
         let uri = req.uri().clone();
 
         #[cfg(any(feature = "rustls-client", feature = "native-tls-client"))]
@@ -292,35 +1191,99 @@ where
         let (client_sink, client_stream) = client_socket.split();
 
         let InternalProxy {
-            websocket_handler, ..
+            websocket_handler,
+            connection,
+            drain,
+            sessions,
+            events,
+            ..
         } = self;
 
+        // The upgraded connection is with the client, so its messages are sent to the server.
+        let session = sessions.register(self.client_addr, uri.clone());
+        let server_stream = session.count(server_stream, Direction::ClientToServer);
+        let client_stream = session.count(client_stream, Direction::ServerToClient);
+        drop(session);
+
+        let closed = events.map(|events| {
+            events.emit(ProxyEventKind::WebSocketOpened { uri: uri.clone() });
+            Arc::new(events.on_drop(ProxyEventKind::WebSocketClosed { uri: uri.clone() }))
+        });
+
         spawn_message_forwarder(
-            server_stream,
+            Draining::new(server_stream, drain.clone()),
             client_sink,
             websocket_handler.clone(),
             WebSocketContext::ServerToClient {
                 src: uri.clone(),
                 dst: self.client_addr,
             },
+            self.span_sampled,
+            Arc::clone(&connection),
+            closed.clone(),
         );
 
         spawn_message_forwarder(
-            client_stream,
+            Draining::new(client_stream, drain),
             server_sink,
             websocket_handler,
             WebSocketContext::ClientToServer {
                 src: self.client_addr,
                 dst: uri,
             },
+            self.span_sampled,
+            connection,
+            closed,
         );
 
         Ok(())
     }
 
-    #[instrument(skip_all)]
+    /// Read the first bytes of a decrypted TLS connection, to check whether it carries HTTP. The
+    /// stream is returned in `Ok` if it does, or if the client negotiated an HTTP protocol with
+    /// ALPN, and in `Err` otherwise. Streams that don't send anything before the sniff timeout
+    /// are treated as not carrying HTTP.
+    async fn sniff_decrypted<I>(
+        &self,
+        mut stream: TlsStream<I>,
+    ) -> io::Result<Result<Rewind<TlsStream<I>>, Rewind<TlsStream<I>>>>
+    where
+        I: AsyncRead + AsyncWrite + Unpin,
+    {
+        if stream.get_ref().1.alpn_protocol().is_some() {
+            return Ok(Ok(Rewind::new(stream)));
+        }
+
+        let mut buffer = [0; 4];
+        let mut bytes_read = 0;
+        let read = async {
+            while bytes_read < buffer.len() {
+                match stream.read(&mut buffer[bytes_read..]).await? {
+                    0 => break,
+                    n => bytes_read += n,
+                }
+            }
+            Ok::<_, io::Error>(())
+        };
+
+        let read = match self.config.sniff_timeout {
+            Some(timeout) => tokio::time::timeout(timeout, read).await,
+            None => Ok(read.await),
+        };
+        let timed_out = match read {
+            Ok(result) => result.map(|()| false)?,
+            Err(_) => true,
+        };
+
+        let is_http = !timed_out && decrypted::is_http(&buffer[..bytes_read]);
+        let stream =
+            Rewind::new_buffered(stream, bytes::Bytes::copy_from_slice(&buffer[..bytes_read]));
+
+        Ok(if is_http { Ok(stream) } else { Err(stream) })
+    }
+
     async fn serve_stream<I>(
-        self,
+        mut self,
         stream: I,
         scheme: Scheme,
         authority: Authority,
@@ -328,7 +1291,34 @@ where
     where
         I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
     {
+        let span = sampled_span!(self.span_sampled, "serve_stream");
+        let mut stream = IdleStream::new(stream, self.config.client_idle_timeout);
+        self.idle = stream.tracker();
+
+        // Hyper rejects request lines it can't parse with a 400 response, so check the first one
+        // here to tell clients using other versions of HTTP, and close on anything else.
+        let buffered = match request_line::read(&mut stream).await {
+            Ok((_, Some(RequestLine::UnsupportedVersion))) => {
+                span.in_scope(|| warn!("Unsupported HTTP version in request line"));
+                let _ = stream.write_all(request_line::VERSION_NOT_SUPPORTED).await;
+                let _ = stream.shutdown().await;
+                return Ok(());
+            }
+            Ok((_, Some(RequestLine::Malformed))) => {
+                span.in_scope(|| warn!("Malformed request line, closing connection"));
+                return Ok(());
+            }
+            Ok((buffered, _)) => buffered,
+            Err(e) => {
+                span.in_scope(|| error!("Failed to read request line: {}", e));
+                return Ok(());
+            }
+        };
+        let stream = Rewind::new_buffered(stream, buffered);
+
         let service = service_fn(|mut req| {
+            let target_form = TargetForm::from_uri(req.uri());
+
             if req.version() == hyper::Version::HTTP_10 || req.version() == hyper::Version::HTTP_11
             {
                 let (mut parts, body) = req.into_parts();
@@ -343,13 +1333,181 @@ where
                 req = Request::from_parts(parts, body);
             };
 
-            self.clone().proxy(req)
+            self.clone().proxy(req, target_form)
         });
 
-        Http::new()
-            .serve_connection(stream, service)
-            .with_upgrades()
-            .await
+        let mut http = Http::new();
+        if let Some(max_buf_size) = self.config.max_buf_size() {
+            http.max_buf_size(max_buf_size);
+        }
+        #[cfg(feature = "http2")]
+        if let Some(limit) = self.config.max_concurrent_streams {
+            http.http2_max_concurrent_streams(limit);
+        }
+
+        let conn = http.serve_connection(stream, service).with_upgrades();
+        let drained = self.drain.signaled();
+
+        async move {
+            futures::pin_mut!(conn, drained);
+
+            match future::select(conn.as_mut(), drained).await {
+                Either::Left((res, _)) => res,
+                Either::Right(((), _)) => {
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            }
+        }
+        .instrument(span)
+        .await
+    }
+
+    fn process_upgrade(&self, upgrade: Upgrade) -> Response<Body> {
+        let Upgrade {
+            response,
+            on_upgrade,
+            handler,
+        } = upgrade;
+
+        let span = sampled_span!(self.span_sampled, "process_upgrade");
+        let connection = Arc::clone(&self.connection);
+        let fut = async move {
+            match on_upgrade.await {
+                Ok(upgraded) => handler(upgraded).await,
+                Err(e) => error!("Upgrade error: {}", e),
+            }
+
+            drop(connection);
+        };
+
+        spawn_with_trace(fut, span);
+        response
+    }
+
+    /// Tunnel the client connection to the upstream server if it accepted an upgrade to
+    /// cleartext HTTP/2.
+    fn tunnel_h2c(&self, mut res: Response<Body>, client_upgrade: OnUpgrade) -> Response<Body> {
+        if res.status() != StatusCode::SWITCHING_PROTOCOLS {
+            return res;
+        }
+
+        let upstream_upgrade = hyper::upgrade::on(&mut res);
+        let buf_size = self.tunnel_buf_size();
+        let span = sampled_span!(self.span_sampled, "tunnel_h2c");
+        let connection = Arc::clone(&self.connection);
+        let fut = async move {
+            match future::try_join(client_upgrade, upstream_upgrade).await {
+                Ok((mut client, mut upstream)) => {
+                    if let Err(e) = tokio::io::copy_bidirectional_with_sizes(
+                        &mut client,
+                        &mut upstream,
+                        buf_size,
+                        buf_size,
+                    )
+                    .await
+                    {
+                        error!("Failed to tunnel h2c connection: {}", e);
+                    }
+                }
+                Err(e) => error!("Upgrade error: {}", e),
+            }
+
+            drop(connection);
+        };
+
+        spawn_with_trace(fut, span);
+        res
+    }
+}
+
+/// Keep the connection to the host counted as open until the response body has been sent.
+fn hold_permit(mut res: Response<Body>, permit: Option<HostPermit>) -> Response<Body> {
+    if let Some(permit) = permit {
+        hold::hold(&mut res, permit);
+    }
+    res
+}
+
+/// Record whether a tunnel is intercepted, and why, on the current `process_connect` span.
+fn record_decision(decision: Decision) {
+    Span::current()
+        .record("intercept", decision.intercept)
+        .record("intercept_reason", decision.reason);
+}
+
+/// Close a tunnel to a blackholed host without reading anything sent by the client.
+async fn close_blackholed(upgraded: Upgraded, mode: BlackholeMode) {
+    let mut upgraded = match mode {
+        BlackholeMode::Reset => match reset(upgraded) {
+            Ok(()) => return,
+            Err(upgraded) => upgraded,
+        },
+        _ => upgraded,
+    };
+
+    let _ = upgraded.shutdown().await;
+}
+
+/// Reset the client's connection, if the tunnel is backed by its socket.
+fn reset(upgraded: Upgraded) -> Result<(), Upgraded> {
+    let stream = match upgraded.downcast::<IdleStream<AddrStream>>() {
+        Ok(parts) => parts.io.into_inner(),
+        Err(upgraded) => upgraded.downcast::<AddrStream>()?.io,
+    }
+    .into_inner();
+
+    // Closing a socket with a zero linger timeout sends a RST instead of a FIN.
+    if let Err(e) = socket2::SockRef::from(&stream).set_linger(Some(Duration::ZERO)) {
+        error!("Failed to reset blackholed tunnel: {}", e);
+    }
+
+    Ok(())
+}
+
+async fn tunnel<I>(
+    mut upgraded: I,
+    authority: &Authority,
+    connect_addr: Option<SocketAddr>,
+    drain: &DrainWatch,
+    connect_options: &ConnectOptions,
+    buf_size: usize,
+    _permit: Option<HostPermit>,
+) where
+    I: AsyncRead + AsyncWrite + Unpin,
+{
+    let addr = connect_addr.map_or_else(|| authority.to_string(), |addr| addr.to_string());
+    let mut server = match socket::connect(&addr, connect_options).await {
+        Ok(server) => server,
+        Err(e) => {
+            error!("Failed to connect to {}: {}", addr, e);
+            return;
+        }
+    };
+
+    let drained = {
+        let copy = tokio::io::copy_bidirectional_with_sizes(
+            &mut upgraded,
+            &mut server,
+            buf_size,
+            buf_size,
+        );
+        let drained = drain.signaled();
+        futures::pin_mut!(copy, drained);
+
+        match future::select(copy, drained).await {
+            Either::Left((Ok(_), _)) => false,
+            Either::Left((Err(e), _)) => {
+                error!("Failed to tunnel to {}: {}", authority, e);
+                false
+            }
+            Either::Right(((), _)) => true,
+        }
+    };
+
+    if drained {
+        let _ = upgraded.shutdown().await;
+        let _ = server.shutdown().await;
     }
 }
 
@@ -358,30 +1516,81 @@ fn spawn_message_forwarder(
     sink: impl Sink<Message, Error = tungstenite::Error> + Unpin + Send + 'static,
     handler: impl WebSocketHandler,
     ctx: WebSocketContext,
+    span_sampled: bool,
+    connection: Arc<ConnectionGuard>,
+    closed: Option<Arc<EmitOnDrop>>,
 ) {
-    let span = info_span!("message_forwarder", context = ?ctx);
-    let fut = handler.handle_websocket(ctx, stream, sink);
+    let span = sampled_span!(span_sampled, "message_forwarder", context = ?ctx);
+    let fut = async move {
+        handler.handle_websocket(ctx, stream, sink).await;
+        drop(closed);
+        drop(connection);
+    };
     spawn_with_trace(fut, span);
 }
 
-#[instrument(skip_all)]
-fn normalize_request<T>(mut req: Request<T>) -> Request<T> {
+/// The product token appended to the `User-Agent` header when the proxy identifies itself.
+const USER_AGENT_IDENTITY: &str = concat!("hudsucker/", env!("CARGO_PKG_VERSION"));
+
+fn normalize_request<T>(mut req: Request<T>, config: &Config) -> Request<T> {
     // Hyper will automatically add a Host header if needed.
     req.headers_mut().remove(hyper::header::HOST);
 
+    if let Some(user_agent) = &config.user_agent_override {
+        req.headers_mut().insert(USER_AGENT, user_agent.clone());
+    }
+
+    if config.user_agent_identity {
+        let user_agent = match req.headers().get(USER_AGENT) {
+            Some(user_agent) => HeaderValue::from_bytes(
+                &[user_agent.as_bytes(), b" ", USER_AGENT_IDENTITY.as_bytes()].concat(),
+            )
+            .expect("Failed to append to User-Agent"),
+            None => HeaderValue::from_static(USER_AGENT_IDENTITY),
+        };
+        req.headers_mut().insert(USER_AGENT, user_agent);
+    }
+
+    // The headers that frame the body are always kept, since it can't be forwarded without them.
+    if let Some(allowlist) = &config.request_header_allowlist {
+        let removed: Vec<_> = req
+            .headers()
+            .keys()
+            .filter(|&name| {
+                name != CONTENT_LENGTH && name != TRANSFER_ENCODING && !allowlist.contains(name)
+            })
+            .cloned()
+            .collect();
+
+        for name in removed {
+            req.headers_mut().remove(name);
+        }
+    }
+
+    for name in &config.request_header_blocklist {
+        req.headers_mut().remove(name);
+    }
+
     // HTTP/2 supports multiple cookie headers, but HTTP/1.x only supports one.
-    if let Entry::Occupied(mut cookies) = req.headers_mut().entry(hyper::header::COOKIE) {
-        let joined_cookies = bstr::join(b"; ", cookies.iter());
-        cookies.insert(joined_cookies.try_into().expect("Failed to join cookies"));
+    if config.join_cookies {
+        if let Entry::Occupied(mut cookies) = req.headers_mut().entry(hyper::header::COOKIE) {
+            let joined_cookies = match &config.cookie_joiner {
+                Some(joiner) => joiner(&cookies.iter().collect::<Vec<_>>()),
+                None => bstr::join(b"; ", cookies.iter())
+                    .try_into()
+                    .expect("Failed to join cookies"),
+            };
+            cookies.insert(joined_cookies);
+        }
     }
 
-    *req.version_mut() = hyper::Version::HTTP_11;
     req
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::proxy::drain::Drain;
     use tokio_rustls::rustls::ServerConfig;
 
     struct CA;
@@ -399,10 +1608,27 @@ mod tests {
         InternalProxy {
             ca: Arc::new(CA),
             client: hyper::Client::new(),
+            unpooled_client: hyper::Client::new(),
             http_handler: crate::NoopHandler::new(),
             websocket_handler: crate::NoopHandler::new(),
             websocket_connector: None,
+            config: Arc::new(Config::default()),
             client_addr: "127.0.0.1:8080".parse().unwrap(),
+            local_addr: "127.0.0.1:3128".parse().unwrap(),
+            client_cert: None,
+            client_alpn: None,
+            #[cfg(target_os = "linux")]
+            peer_cred: None,
+            span_sampled: true,
+            connection: Arc::new(ConnectionGuard::new(async {})),
+            drain: Drain::new().watch(),
+            sessions: Arc::default(),
+            host_limits: Arc::default(),
+            coalescer: Arc::default(),
+            mirror: None,
+            events: None,
+            idle: None,
+            shutdown: ShutdownHandle::default(),
         }
     }
 
@@ -416,8 +1642,35 @@ mod tests {
         }
     }
 
+    mod sampled_span {
+        use super::*;
+
+        #[test]
+        fn disabled_if_not_sampled() {
+            tracing::subscriber::with_default(tracing_subscriber::registry(), || {
+                assert!(!sampled_span!(true, "test").is_disabled());
+                assert!(sampled_span!(false, "test").is_disabled());
+            });
+        }
+    }
+
+    mod span_fields {
+        use super::*;
+
+        #[test]
+        fn formats_fields() {
+            let fields = SpanFields(vec![
+                ("user_id", "42".to_owned()),
+                ("tenant", "example".to_owned()),
+            ]);
+
+            assert_eq!(fields.to_string(), "user_id=42 tenant=example");
+        }
+    }
+
     mod normalize_request {
         use super::*;
+        use hyper::header::HeaderName;
 
         #[test]
         fn removes_host_header() {
@@ -427,7 +1680,7 @@ mod tests {
                 .body(())
                 .unwrap();
 
-            let req = normalize_request(req);
+            let req = normalize_request(req, &Config::default());
 
             assert_eq!(req.headers().get(hyper::header::HOST), None);
         }
@@ -441,7 +1694,7 @@ mod tests {
                 .body(())
                 .unwrap();
 
-            let req = normalize_request(req);
+            let req = normalize_request(req, &Config::default());
 
             assert_eq!(
                 req.headers().get_all(hyper::header::COOKIE).iter().count(),
@@ -453,6 +1706,221 @@ mod tests {
                 Some(&"foo=bar; baz=qux".parse().unwrap())
             );
         }
+
+        #[test]
+        fn joins_cookies_with_custom_joiner() {
+            let req = Request::builder()
+                .uri("http://example.com/")
+                .header(hyper::header::COOKIE, "foo=bar; baz=qux")
+                .header(hyper::header::COOKIE, "foo=quux")
+                .body(())
+                .unwrap();
+
+            // Keep the last value of each cookie, in the order the names first appeared.
+            let config = Config {
+                cookie_joiner: Some(Arc::new(|values: &[&hyper::header::HeaderValue]| {
+                    let mut cookies: Vec<(&str, &str)> = Vec::new();
+                    for cookie in values
+                        .iter()
+                        .flat_map(|value| value.to_str().unwrap().split("; "))
+                    {
+                        let (name, _) = cookie.split_once('=').unwrap();
+                        match cookies.iter_mut().find(|(n, _)| *n == name) {
+                            Some(existing) => existing.1 = cookie,
+                            None => cookies.push((name, cookie)),
+                        }
+                    }
+
+                    let joined: Vec<_> = cookies.into_iter().map(|(_, cookie)| cookie).collect();
+                    hyper::header::HeaderValue::from_str(&joined.join("; ")).unwrap()
+                })),
+                ..Config::default()
+            };
+            let req = normalize_request(req, &config);
+
+            assert_eq!(
+                req.headers()
+                    .get_all(hyper::header::COOKIE)
+                    .iter()
+                    .collect::<Vec<_>>(),
+                vec!["foo=quux; baz=qux"]
+            );
+        }
+
+        #[test]
+        fn preserves_cookies_if_join_disabled() {
+            let req = Request::builder()
+                .uri("http://example.com/")
+                .header(hyper::header::COOKIE, "foo=bar")
+                .header(hyper::header::COOKIE, "baz=qux")
+                .body(())
+                .unwrap();
+
+            let config = Config {
+                join_cookies: false,
+                ..Config::default()
+            };
+            let req = normalize_request(req, &config);
+
+            assert_eq!(
+                req.headers()
+                    .get_all(hyper::header::COOKIE)
+                    .iter()
+                    .collect::<Vec<_>>(),
+                vec!["foo=bar", "baz=qux"]
+            );
+        }
+
+        fn tracked_request() -> Request<()> {
+            Request::builder()
+                .uri("http://example.com/")
+                .header(hyper::header::ACCEPT, "*/*")
+                .header(hyper::header::REFERER, "http://example.org/")
+                .header(CONTENT_LENGTH, "0")
+                .header("x-client-id", "1")
+                .body(())
+                .unwrap()
+        }
+
+        #[test]
+        fn removes_blocked_headers() {
+            let config = Config {
+                request_header_blocklist: vec![
+                    hyper::header::REFERER,
+                    HeaderName::from_static("x-client-id"),
+                ],
+                ..Config::default()
+            };
+            let req = normalize_request(tracked_request(), &config);
+
+            let names: Vec<_> = req.headers().keys().map(HeaderName::as_str).collect();
+            assert_eq!(names, vec!["accept", "content-length"]);
+        }
+
+        #[test]
+        fn keeps_allowed_and_framing_headers() {
+            let config = Config {
+                request_header_allowlist: Some(vec![hyper::header::ACCEPT]),
+                ..Config::default()
+            };
+            let req = normalize_request(tracked_request(), &config);
+
+            let names: Vec<_> = req.headers().keys().map(HeaderName::as_str).collect();
+            assert_eq!(names, vec!["accept", "content-length"]);
+        }
+
+        #[test]
+        fn blocklist_overrides_allowlist() {
+            let config = Config {
+                request_header_allowlist: Some(vec![hyper::header::ACCEPT, hyper::header::REFERER]),
+                request_header_blocklist: vec![hyper::header::REFERER],
+                ..Config::default()
+            };
+            let req = normalize_request(tracked_request(), &config);
+
+            let names: Vec<_> = req.headers().keys().map(HeaderName::as_str).collect();
+            assert_eq!(names, vec!["accept", "content-length"]);
+        }
+
+        fn user_agent_request(user_agent: Option<&str>) -> Request<()> {
+            let mut req = Request::builder().uri("http://example.com/");
+            if let Some(user_agent) = user_agent {
+                req = req.header(USER_AGENT, user_agent);
+            }
+            req.body(()).unwrap()
+        }
+
+        #[test]
+        fn preserves_user_agent() {
+            let req = normalize_request(user_agent_request(Some("curl/8.0")), &Config::default());
+
+            assert_eq!(req.headers()[USER_AGENT], "curl/8.0");
+        }
+
+        #[test]
+        fn overrides_user_agent() {
+            let config = Config {
+                user_agent_override: Some(HeaderValue::from_static("bot/1.0")),
+                ..Config::default()
+            };
+
+            let req = normalize_request(user_agent_request(Some("curl/8.0")), &config);
+            assert_eq!(req.headers()[USER_AGENT], "bot/1.0");
+
+            let req = normalize_request(user_agent_request(None), &config);
+            assert_eq!(req.headers()[USER_AGENT], "bot/1.0");
+        }
+
+        #[test]
+        fn appends_user_agent_identity() {
+            let config = Config {
+                user_agent_identity: true,
+                ..Config::default()
+            };
+
+            let req = normalize_request(user_agent_request(Some("curl/8.0")), &config);
+            assert_eq!(
+                req.headers()[USER_AGENT],
+                format!("curl/8.0 {}", USER_AGENT_IDENTITY)
+            );
+
+            let req = normalize_request(user_agent_request(None), &config);
+            assert_eq!(req.headers()[USER_AGENT], USER_AGENT_IDENTITY);
+        }
+
+        #[test]
+        fn appends_user_agent_identity_to_override() {
+            let config = Config {
+                user_agent_override: Some(HeaderValue::from_static("bot/1.0")),
+                user_agent_identity: true,
+                ..Config::default()
+            };
+            let req = normalize_request(user_agent_request(Some("curl/8.0")), &config);
+
+            assert_eq!(
+                req.headers()[USER_AGENT],
+                format!("bot/1.0 {}", USER_AGENT_IDENTITY)
+            );
+        }
+    }
+
+    mod proxy {
+        use super::*;
+        use hyper::header::CONTENT_TYPE;
+
+        #[tokio::test]
+        async fn uses_error_page_on_upstream_failure() {
+            let addr = std::net::TcpListener::bind("127.0.0.1:0")
+                .unwrap()
+                .local_addr()
+                .unwrap();
+
+            let mut proxy = build_proxy();
+            proxy.config = Arc::new(Config {
+                error_page: Some(Arc::new(|err, uri| {
+                    Response::builder()
+                        .status(StatusCode::BAD_GATEWAY)
+                        .header(CONTENT_TYPE, "text/html")
+                        .body(Body::from(format!("<h1>{}</h1><p>{}</p>", uri, err)))
+                        .unwrap()
+                })),
+                ..Config::default()
+            });
+
+            let uri: Uri = format!("http://{}/foo", addr).parse().unwrap();
+            let req = Request::builder()
+                .uri(uri.clone())
+                .body(Body::empty())
+                .unwrap();
+
+            let res = proxy.proxy(req, TargetForm::Absolute).await.unwrap();
+
+            assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+            assert_eq!(res.headers()[CONTENT_TYPE], "text/html");
+
+            let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+            assert!(body.starts_with(format!("<h1>{}</h1>", uri).as_bytes()));
+        }
     }
 
     mod process_connect {
@@ -461,13 +1929,13 @@ mod tests {
         #[test]
         fn returns_bad_request_if_missing_authority() {
             let proxy = build_proxy();
-
             let req = Request::builder()
                 .uri("/foo/bar?baz")
                 .body(Body::empty())
                 .unwrap();
+            let ctx = proxy.context(&req, TargetForm::Origin);
 
-            let res = proxy.process_connect(req);
+            let res = proxy.process_connect(ctx, req, None);
 
             assert_eq!(res.status(), StatusCode::BAD_REQUEST)
         }
@@ -0,0 +1,56 @@
+use std::{
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    path::Path,
+    sync::Mutex,
+};
+use tokio_rustls::rustls::KeyLog;
+use tracing::error;
+
+/// Appends TLS secrets to a file in the NSS key log format, also known as `SSLKEYLOGFILE`.
+#[derive(Debug)]
+pub(crate) struct KeyLogFile(Mutex<File>);
+
+impl KeyLogFile {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(Self(Mutex::new(file)))
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+impl KeyLog for KeyLogFile {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let line = format!("{} {} {}\n", label, hex(client_random), hex(secret));
+        let mut file = self.0.lock().unwrap_or_else(|e| e.into_inner());
+
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            error!("Failed to write to key log file: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn appends_lines() {
+        let path = std::env::temp_dir().join(format!("hudsucker-keylog-{}", std::process::id()));
+        std::fs::write(&path, "existing\n").unwrap();
+
+        let keylog = KeyLogFile::open(&path).unwrap();
+        keylog.log("CLIENT_RANDOM", &[0x01, 0xab], &[0xff]);
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(contents, "existing\nCLIENT_RANDOM 01ab ff\n");
+    }
+}
@@ -0,0 +1,104 @@
+use hyper::{
+    header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, MAX_FORWARDS},
+    Body, Method, Request, Response, StatusCode,
+};
+
+/// Apply the `Max-Forwards` header of TRACE and OPTIONS requests.
+///
+/// If the header is zero, the proxy is the final recipient and the response to send to the
+/// client is returned. Otherwise it is decremented before the request is forwarded. Other
+/// methods, and values that aren't a valid number, are left as is.
+pub(crate) fn apply<T>(req: &mut Request<T>) -> Option<Response<Body>> {
+    if req.method() != Method::TRACE && req.method() != Method::OPTIONS {
+        return None;
+    }
+
+    let max_forwards = req
+        .headers()
+        .get(MAX_FORWARDS)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+
+    if max_forwards > 0 {
+        req.headers_mut()
+            .insert(MAX_FORWARDS, HeaderValue::from(max_forwards - 1));
+        return None;
+    }
+
+    Some(if req.method() == Method::TRACE {
+        trace_response(req)
+    } else {
+        Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_LENGTH, 0)
+            .body(Body::empty())
+            .expect("Failed to build response")
+    })
+}
+
+/// Build the response to a TRACE request, which echoes the request head as it was received.
+fn trace_response<T>(req: &Request<T>) -> Response<Body> {
+    let mut head = format!("{} {} {:?}\r\n", req.method(), req.uri(), req.version()).into_bytes();
+    for (name, value) in req.headers() {
+        head.extend_from_slice(name.as_str().as_bytes());
+        head.extend_from_slice(b": ");
+        head.extend_from_slice(value.as_bytes());
+        head.extend_from_slice(b"\r\n");
+    }
+    head.extend_from_slice(b"\r\n");
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "message/http")
+        .header(CONTENT_LENGTH, head.len())
+        .body(Body::from(head))
+        .expect("Failed to build response")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, max_forwards: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri("http://example.com/")
+            .header(MAX_FORWARDS, max_forwards)
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[test]
+    fn decrements() {
+        let mut req = request(Method::OPTIONS, "3");
+        assert!(apply(&mut req).is_none());
+        assert_eq!(req.headers()[MAX_FORWARDS], "2");
+    }
+
+    #[tokio::test]
+    async fn echoes_trace_at_zero() {
+        let mut req = request(Method::TRACE, "0");
+        let res = apply(&mut req).unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()[CONTENT_TYPE], "message/http");
+        assert_eq!(
+            hyper::body::to_bytes(res.into_body()).await.unwrap(),
+            "TRACE http://example.com/ HTTP/1.1\r\nmax-forwards: 0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn ignores_other_requests() {
+        let mut req = request(Method::GET, "0");
+        assert!(apply(&mut req).is_none());
+        assert_eq!(req.headers()[MAX_FORWARDS], "0");
+
+        let mut req = request(Method::TRACE, "none");
+        assert!(apply(&mut req).is_none());
+        assert_eq!(req.headers()[MAX_FORWARDS], "none");
+    }
+}
@@ -0,0 +1,184 @@
+use crate::{MirrorRecord, MirrorSink, ProxyControl};
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use hyper::{body::HttpBody, Body, HeaderMap, Request, Response};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::mpsc;
+
+/// Number of records that can be queued for the sink before new records are dropped.
+const QUEUE_SIZE: usize = 1024;
+
+/// Largest part of each body that is captured.
+const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// Sends records of the requests handled by the proxy to the sink set with
+/// [`ProxyBuilder::with_mirror`](crate::ProxyBuilder::with_mirror).
+#[derive(Clone)]
+pub(crate) struct Mirror {
+    queue: mpsc::Sender<MirrorRecord>,
+    control: ProxyControl,
+}
+
+impl Mirror {
+    /// Spawn the task that passes queued records to the sink.
+    pub fn spawn(sink: Arc<dyn MirrorSink>, control: ProxyControl) -> Self {
+        let (queue, mut records) = mpsc::channel(QUEUE_SIZE);
+        tokio::spawn(async move {
+            while let Some(record) = records.recv().await {
+                sink.consume(record).await;
+            }
+        });
+
+        Self { queue, control }
+    }
+
+    /// Start capturing a request, returning it with its body wrapped to be captured as it is
+    /// forwarded.
+    pub fn start(&self, client_addr: SocketAddr, req: Request<Body>) -> (Request<Body>, Exchange) {
+        let request_body = Arc::new(Mutex::new(Captured::default()));
+        let record = MirrorRecord {
+            client_addr,
+            method: req.method().clone(),
+            uri: req.uri().clone(),
+            version: req.version(),
+            request_headers: req.headers().clone(),
+            request_body: Bytes::new(),
+            request_body_truncated: false,
+            status: None,
+            response_headers: HeaderMap::new(),
+            response_body: Bytes::new(),
+            response_body_truncated: false,
+        };
+
+        let req = req.map(|body| {
+            if body.is_end_stream() {
+                return body;
+            }
+
+            let captured = Arc::clone(&request_body);
+            Body::wrap_stream(body.inspect(move |chunk| {
+                if let Ok(chunk) = chunk {
+                    lock(&captured).push(chunk);
+                }
+            }))
+        });
+
+        let exchange = Exchange {
+            mirror: self.clone(),
+            record: Some(record),
+            request_body,
+            response_body: Captured::default(),
+        };
+        (req, exchange)
+    }
+}
+
+fn lock(captured: &Mutex<Captured>) -> std::sync::MutexGuard<'_, Captured> {
+    captured.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[derive(Debug, Default)]
+struct Captured {
+    data: BytesMut,
+    truncated: bool,
+}
+
+impl Captured {
+    fn push(&mut self, chunk: &[u8]) {
+        let space = MAX_BODY_SIZE - self.data.len();
+        if chunk.len() > space {
+            self.truncated = true;
+        }
+        self.data
+            .extend_from_slice(&chunk[..chunk.len().min(space)]);
+    }
+
+    fn take(&mut self) -> (Bytes, bool) {
+        (std::mem::take(&mut self.data).freeze(), self.truncated)
+    }
+}
+
+/// A request being captured. The record is queued once this is dropped, which is once the
+/// response body has been forwarded.
+pub(crate) struct Exchange {
+    mirror: Mirror,
+    record: Option<MirrorRecord>,
+    request_body: Arc<Mutex<Captured>>,
+    response_body: Captured,
+}
+
+impl Exchange {
+    /// Capture the response, returning it with its body wrapped to be captured as it is
+    /// forwarded.
+    pub fn finish(mut self, res: Response<Body>) -> Response<Body> {
+        if let Some(record) = &mut self.record {
+            record.status = Some(res.status());
+            record.response_headers = res.headers().clone();
+        }
+
+        if res.body().is_end_stream() {
+            return res;
+        }
+
+        res.map(|body| {
+            Body::wrap_stream(body.inspect(move |chunk| {
+                if let Ok(chunk) = chunk {
+                    self.response_body.push(chunk);
+                }
+            }))
+        })
+    }
+}
+
+impl Drop for Exchange {
+    fn drop(&mut self) {
+        let Some(mut record) = self.record.take() else {
+            return;
+        };
+
+        (record.request_body, record.request_body_truncated) = lock(&self.request_body).take();
+        (record.response_body, record.response_body_truncated) = self.response_body.take();
+
+        if self.mirror.queue.try_send(record).is_err() {
+            self.mirror.control.record_mirror_dropped();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn caps_captured_body() {
+        let mut captured = Captured::default();
+        captured.push(&[0; MAX_BODY_SIZE - 1]);
+        assert!(!captured.truncated);
+
+        captured.push(b"ab");
+        let (data, truncated) = captured.take();
+        assert_eq!(data.len(), MAX_BODY_SIZE);
+        assert!(truncated);
+    }
+
+    #[tokio::test]
+    async fn counts_dropped_records() {
+        let control = ProxyControl::default();
+        let (queue, _records) = mpsc::channel(1);
+        let mirror = Mirror {
+            queue,
+            control: control.clone(),
+        };
+
+        for _ in 0..3 {
+            let req = Request::new(Body::from("hello"));
+            let (_, exchange) = mirror.start(SocketAddr::from(([127, 0, 0, 1], 0)), req);
+            drop(exchange.finish(Response::new(Body::empty())));
+        }
+
+        assert_eq!(control.mirror_records_dropped(), 2);
+    }
+}
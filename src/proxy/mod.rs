@@ -1,20 +1,182 @@
+mod accept;
+#[cfg(feature = "decoder")]
+mod auto_decode;
+mod cert_endpoint;
+mod coalesce;
+mod control;
+mod drain;
+mod events;
+mod h2c;
+mod hold;
+pub(crate) mod host_limits;
+mod idle;
 mod internal;
+mod keylog;
+mod max_forwards;
+mod mirror;
+mod request_line;
+mod self_addrs;
+mod sessions;
+mod shutdown;
+pub(crate) mod socket;
 
 pub mod builder;
 
-use crate::{certificate_authority::CertificateAuthority, Error, HttpHandler, WebSocketHandler};
+use crate::{
+    certificate_authority::CertificateAuthority, BlackholeMode, ConnContext, Error, HandshakeDelay,
+    HostFilter, HttpHandler, MirrorSink, Pipeline, ProtocolMatchers, ProxyEventKind,
+    ResponseHeaderPolicy, RetryPolicy, TargetForm, TlsFaults, WebSocketHandler,
+};
+use accept::{AcceptErrorHandler, AcceptErrors};
 use builder::{AddrListenerServer, WantsAddr};
+use control::Pausable;
+use drain::Drain;
+use events::Events;
+use futures::future::{self, Either};
 use hyper::{
     client::connect::Connect,
-    server::conn::AddrStream,
+    ext::ReasonPhrase,
+    header::{HeaderMap, HeaderName, HeaderValue},
+    server::{
+        conn::{AddrIncoming, AddrStream},
+        Builder,
+    },
     service::{make_service_fn, service_fn},
-    Client, Server,
+    Body, Client, Request, Response, Server, Uri,
+};
+use idle::{IdleConnections, IdleStream, IdleTracker};
+use internal::{ConnectionGuard, InternalProxy};
+use keylog::KeyLogFile;
+use mirror::Mirror;
+use socket::BufferSizes;
+use socket2::SockRef;
+use std::{
+    convert::Infallible, future::Future, net::SocketAddr, ops::RangeInclusive, pin::pin, sync::Arc,
+    time::Duration,
 };
-use internal::InternalProxy;
-use std::{convert::Infallible, future::Future, sync::Arc};
+use tokio_rustls::rustls::ClientConfig;
 use tokio_tungstenite::Connector;
+use tracing::{Instrument, Span};
 
 pub use builder::ProxyBuilder;
+pub use control::ProxyControl;
+pub use sessions::{MessageCounts, WebSocketSession};
+pub use shutdown::ShutdownHandle;
+
+pub(crate) type ErrorPage = Arc<dyn Fn(&hyper::Error, &Uri) -> Response<Body> + Send + Sync>;
+pub(crate) type CookieJoiner = Arc<dyn Fn(&[&HeaderValue]) -> HeaderValue + Send + Sync>;
+
+/// Options set on a [`ProxyBuilder`] that are shared by all connections.
+#[derive(Clone)]
+pub(crate) struct Config {
+    pub error_page: Option<ErrorPage>,
+    pub span_sampling: f64,
+    pub parent_span: Span,
+    pub join_cookies: bool,
+    pub cookie_joiner: Option<CookieJoiner>,
+    pub sniff_timeout: Option<Duration>,
+    pub protocol_matchers: ProtocolMatchers,
+    pub max_headers: Option<usize>,
+    pub max_header_bytes: Option<usize>,
+    pub max_response_header_bytes: Option<usize>,
+    #[cfg(feature = "http2")]
+    pub max_concurrent_streams: Option<u32>,
+    pub trace_propagation: bool,
+    pub connect_headers: HeaderMap,
+    pub connect_reason_phrase: Option<ReasonPhrase>,
+    pub smuggling_protection: bool,
+    pub drain_timeout: Duration,
+    pub ca_cert_endpoint: bool,
+    pub tls_faults: Option<TlsFaults>,
+    pub handshake_delay: Option<HandshakeDelay>,
+    pub keylog: Option<Arc<KeyLogFile>>,
+    pub upstream_tls_config: Option<Arc<ClientConfig>>,
+    pub accept_error_handler: AcceptErrorHandler,
+    pub socket_buffer_sizes: Option<BufferSizes>,
+    pub tunnel_buffer_size: Option<usize>,
+    pub outbound_port_range: Option<RangeInclusive<u16>>,
+    pub response_header_policy: Option<ResponseHeaderPolicy>,
+    pub per_host_connection_limit: Option<usize>,
+    pub client_idle_timeout: Option<Duration>,
+    pub blackhole: Option<(HostFilter, BlackholeMode)>,
+    pub request_coalescing: bool,
+    pub retry_policy: Option<RetryPolicy>,
+    pub mirror: Option<Arc<dyn MirrorSink>>,
+    pub events: Option<Arc<Events>>,
+    pub request_header_allowlist: Option<Vec<HeaderName>>,
+    pub request_header_blocklist: Vec<HeaderName>,
+    pub user_agent_override: Option<HeaderValue>,
+    pub user_agent_identity: bool,
+    pub h2c_upgrade: bool,
+    pub self_addrs: Vec<SocketAddr>,
+    pub request_pipeline: Option<Pipeline>,
+    pub response_pipeline: Option<Pipeline>,
+    #[cfg(target_os = "linux")]
+    pub peer_credentials: bool,
+    #[cfg(feature = "decoder")]
+    pub auto_decode: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            error_page: None,
+            span_sampling: 1.0,
+            parent_span: Span::none(),
+            join_cookies: true,
+            cookie_joiner: None,
+            sniff_timeout: None,
+            protocol_matchers: ProtocolMatchers::default(),
+            max_headers: None,
+            max_header_bytes: None,
+            max_response_header_bytes: None,
+            #[cfg(feature = "http2")]
+            max_concurrent_streams: None,
+            trace_propagation: false,
+            connect_headers: HeaderMap::new(),
+            connect_reason_phrase: None,
+            smuggling_protection: true,
+            drain_timeout: Duration::from_secs(5),
+            ca_cert_endpoint: false,
+            tls_faults: None,
+            handshake_delay: None,
+            keylog: None,
+            upstream_tls_config: None,
+            accept_error_handler: Arc::new(accept::default_accept_error_handler),
+            socket_buffer_sizes: None,
+            tunnel_buffer_size: None,
+            outbound_port_range: None,
+            response_header_policy: None,
+            per_host_connection_limit: None,
+            client_idle_timeout: None,
+            blackhole: None,
+            request_coalescing: false,
+            retry_policy: None,
+            mirror: None,
+            events: None,
+            request_header_allowlist: None,
+            request_header_blocklist: Vec::new(),
+            user_agent_override: None,
+            user_agent_identity: false,
+            h2c_upgrade: false,
+            self_addrs: Vec::new(),
+            request_pipeline: None,
+            response_pipeline: None,
+            #[cfg(target_os = "linux")]
+            peer_credentials: false,
+            #[cfg(feature = "decoder")]
+            auto_decode: false,
+        }
+    }
+}
+
+impl Config {
+    /// Size of the buffer used to read request heads, which must fit within the header limit.
+    /// Hyper doesn't accept buffers smaller than 8 KiB.
+    pub fn max_buf_size(&self) -> Option<usize> {
+        self.max_header_bytes.map(|bytes| bytes.max(8192))
+    }
+}
 
 /// A proxy server. This must be constructed with a [`ProxyBuilder`].
 ///
@@ -72,9 +234,13 @@ pub struct Proxy<C, CA, H, W> {
     als: AddrListenerServer,
     ca: Arc<CA>,
     client: Client<C>,
+    unpooled_client: Client<C>,
     http_handler: H,
     websocket_handler: W,
     websocket_connector: Option<Connector>,
+    config: Arc<Config>,
+    control: ProxyControl,
+    shutdown: ShutdownHandle,
 }
 
 impl Proxy<(), (), (), ()> {
@@ -91,48 +257,233 @@ where
     H: HttpHandler,
     W: WebSocketHandler,
 {
+    /// Get a handle for controlling the proxy once it has been started.
+    pub fn control(&self) -> ProxyControl {
+        self.control.clone()
+    }
+
+    /// Get a handle for triggering the graceful shutdown of the proxy once it has been started.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
     /// Attempts to start the proxy server.
     ///
+    /// Once the shutdown signal resolves, or a shutdown is triggered with a [`ShutdownHandle`], the
+    /// proxy stops accepting connections and waits for
+    /// existing ones to finish. Tunnels are shut down, and WebSockets are sent a Close frame in
+    /// both directions. This returns once everything has closed, or the timeout set with
+    /// [`ProxyBuilder::with_drain_timeout`] has elapsed.
+    ///
     /// # Errors
     ///
     /// This will return an error if the proxy server is unable to be started.
     pub async fn start<F: Future<Output = ()>>(self, shutdown_signal: F) -> Result<(), Error> {
-        let make_service = make_service_fn(move |conn: &AddrStream| {
+        let max_buf_size = self.config.max_buf_size();
+        #[cfg(feature = "http2")]
+        let max_concurrent_streams = self.config.max_concurrent_streams;
+        let drain_timeout = self.config.drain_timeout;
+        let accept_error_handler = Arc::clone(&self.config.accept_error_handler);
+        let socket_buffer_sizes = self.config.socket_buffer_sizes;
+        let drain = Drain::new();
+        let drain_watch = drain.watch();
+        let sessions = Arc::clone(self.control.sessions());
+        let host_limits = Arc::clone(self.control.host_limits());
+        let coalescer = Arc::clone(self.control.coalescer());
+        let mirror = self
+            .config
+            .mirror
+            .clone()
+            .map(|sink| Mirror::spawn(sink, self.control.clone()));
+        let shutdown_handle = self.shutdown.clone();
+        let client_idle_timeout = self.config.client_idle_timeout;
+        let control = self.control.clone();
+        let make_connection = move |client_addr, local_addr, idle: Option<Arc<IdleTracker>>| {
             let client = self.client.clone();
+            let unpooled_client = self.unpooled_client.clone();
             let ca = Arc::clone(&self.ca);
             let http_handler = self.http_handler.clone();
             let websocket_handler = self.websocket_handler.clone();
             let websocket_connector = self.websocket_connector.clone();
-            let client_addr = conn.remote_addr();
+            let config = Arc::clone(&self.config);
+            let drain = drain_watch.clone();
+            let sessions = Arc::clone(&sessions);
+            let host_limits = Arc::clone(&host_limits);
+            let coalescer = Arc::clone(&coalescer);
+            let mirror = mirror.clone();
+            let shutdown_handle = self.shutdown.clone();
+            #[cfg(target_os = "linux")]
+            let peer_addrs = self
+                .config
+                .peer_credentials
+                .then_some((client_addr, local_addr));
+            let span_sampled = self.config.span_sampling >= 1.0
+                || rand::random::<f64>() < self.config.span_sampling;
+            let parent_span = self.config.parent_span.clone();
+            let events = self
+                .config
+                .events
+                .as_ref()
+                .map(|events| events.connection(client_addr));
+            let connection = {
+                let mut http_handler = self.http_handler.clone();
+                let ctx = ConnContext { client_addr };
+                let events = events.clone();
+                Arc::new(ConnectionGuard::new(
+                    async move {
+                        if let Some(events) = events {
+                            events.emit(ProxyEventKind::ConnectionClosed);
+                        }
+                        http_handler.on_connection_close(&ctx).await
+                    }
+                    .instrument(parent_span.clone()),
+                ))
+            };
             async move {
-                Ok::<_, Infallible>(service_fn(move |req| {
-                    InternalProxy {
+                // Reading the credentials reads files in /proc, so it is done off the runtime.
+                #[cfg(target_os = "linux")]
+                let peer_cred = match peer_addrs {
+                    Some((client_addr, local_addr)) => tokio::task::spawn_blocking(move || {
+                        crate::peer_cred::lookup(client_addr, local_addr)
+                    })
+                    .await
+                    .ok()
+                    .flatten(),
+                    None => None,
+                };
+
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let target_form = TargetForm::from_uri(req.uri());
+
+                    // Hyper polls connections in tasks of its own, so there is no current span to
+                    // inherit here. Everything after this, including spawned tasks, is a child of
+                    // the request span.
+                    let proxy = InternalProxy {
                         ca: Arc::clone(&ca),
                         client: client.clone(),
+                        unpooled_client: unpooled_client.clone(),
                         http_handler: http_handler.clone(),
                         websocket_handler: websocket_handler.clone(),
                         websocket_connector: websocket_connector.clone(),
+                        config: Arc::clone(&config),
                         client_addr,
-                    }
-                    .proxy(req)
+                        local_addr,
+                        client_cert: None,
+                        client_alpn: None,
+                        #[cfg(target_os = "linux")]
+                        peer_cred,
+                        span_sampled,
+                        connection: Arc::clone(&connection),
+                        drain: drain.clone(),
+                        sessions: Arc::clone(&sessions),
+                        host_limits: Arc::clone(&host_limits),
+                        coalescer: Arc::clone(&coalescer),
+                        mirror: mirror.clone(),
+                        events: events.clone(),
+                        idle: idle.clone(),
+                        shutdown: shutdown_handle.clone(),
+                    };
+                    parent_span.in_scope(|| proxy.proxy(req, target_form))
                 }))
             }
-        });
-
-        let server_builder = match self.als {
-            AddrListenerServer::Addr(addr) => Server::try_bind(&addr)?
-                .http1_preserve_header_case(true)
-                .http1_title_case_headers(true),
-            AddrListenerServer::Listener(listener) => Server::from_tcp(listener)?
-                .http1_preserve_header_case(true)
-                .http1_title_case_headers(true),
-            AddrListenerServer::Server(server) => *server,
         };
 
-        server_builder
-            .serve(make_service)
-            .with_graceful_shutdown(shutdown_signal)
-            .await
-            .map_err(Into::into)
+        let shutdown = async {
+            future::select(pin!(shutdown_signal), pin!(shutdown_handle.wait())).await;
+            drain.signal();
+        };
+
+        let incoming = match self.als {
+            AddrListenerServer::Addr(addr) => {
+                let listener = std::net::TcpListener::bind(addr).map_err(Error::Bind)?;
+                Either::Left(listen(listener, socket_buffer_sizes)?)
+            }
+            AddrListenerServer::Listener(listener) => {
+                Either::Left(listen(listener, socket_buffer_sizes)?)
+            }
+            AddrListenerServer::Server(server) => Either::Right(*server),
+        };
+
+        let result = match incoming {
+            Either::Left(mut incoming) => {
+                incoming.set_sleep_on_errors(false);
+                let incoming = AcceptErrors::new(incoming, accept_error_handler);
+                let incoming = IdleConnections::new(incoming, client_idle_timeout);
+                let make_service = Pausable {
+                    make_service: make_service_fn(move |conn: &IdleStream<AddrStream>| {
+                        make_connection(
+                            conn.get_ref().remote_addr(),
+                            conn.get_ref().local_addr(),
+                            conn.tracker(),
+                        )
+                    }),
+                    control,
+                };
+                let server_builder = Server::builder(incoming)
+                    .http1_preserve_header_case(true)
+                    .http1_title_case_headers(true);
+
+                #[cfg(feature = "http2")]
+                let server_builder =
+                    with_max_concurrent_streams(server_builder, max_concurrent_streams);
+
+                with_max_buf_size(server_builder, max_buf_size)
+                    .serve(make_service)
+                    .with_graceful_shutdown(shutdown)
+                    .await
+            }
+            Either::Right(server_builder) => {
+                let make_service = Pausable {
+                    make_service: make_service_fn(move |conn: &AddrStream| {
+                        make_connection(conn.remote_addr(), conn.local_addr(), None)
+                    }),
+                    control,
+                };
+
+                #[cfg(feature = "http2")]
+                let server_builder =
+                    with_max_concurrent_streams(server_builder, max_concurrent_streams);
+
+                with_max_buf_size(server_builder, max_buf_size)
+                    .serve(make_service)
+                    .with_graceful_shutdown(shutdown)
+                    .await
+            }
+        };
+
+        drain.wait(drain_timeout).await;
+        result.map_err(Into::into)
+    }
+}
+
+/// Create the listener for accepting connections. Buffer sizes set on the listening socket are
+/// inherited by accepted sockets.
+fn listen(
+    listener: std::net::TcpListener,
+    socket_buffer_sizes: Option<BufferSizes>,
+) -> Result<AddrIncoming, Error> {
+    if let Some(sizes) = socket_buffer_sizes {
+        sizes.apply(SockRef::from(&listener));
+    }
+
+    let listener = listener
+        .set_nonblocking(true)
+        .and_then(|()| tokio::net::TcpListener::from_std(listener))
+        .map_err(|_| Error::InvalidListener)?;
+    Ok(AddrIncoming::from_listener(listener)?)
+}
+
+fn with_max_buf_size<I>(server_builder: Builder<I>, max_buf_size: Option<usize>) -> Builder<I> {
+    match max_buf_size {
+        Some(max_buf_size) => server_builder.http1_max_buf_size(max_buf_size),
+        None => server_builder,
+    }
+}
+
+#[cfg(feature = "http2")]
+fn with_max_concurrent_streams<I>(server_builder: Builder<I>, limit: Option<u32>) -> Builder<I> {
+    match limit {
+        Some(limit) => server_builder.http2_max_concurrent_streams(limit),
+        None => server_builder,
     }
 }
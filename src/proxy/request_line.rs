@@ -0,0 +1,163 @@
+use bytes::{Bytes, BytesMut};
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Number of bytes read while looking for the end of the first request line. Longer lines are left
+/// for hyper to reject.
+const MAX_LINE_SIZE: usize = 8 * 1024;
+
+/// Response sent to clients whose first request uses an HTTP version that isn't supported.
+pub(crate) const VERSION_NOT_SUPPORTED: &[u8] =
+    b"HTTP/1.1 505 HTTP Version Not Supported\r\ncontent-length: 0\r\nconnection: close\r\n\r\n";
+
+/// How the first line of a stream of requests was classified.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum RequestLine {
+    /// An HTTP/1.0 or HTTP/1.1 request line, or the HTTP/2 connection preface.
+    Supported,
+    /// A request line for another version of HTTP, including HTTP/0.9 lines without a version.
+    UnsupportedVersion,
+    /// A line that isn't a request line.
+    Malformed,
+}
+
+impl RequestLine {
+    /// Classify a line, without its line ending.
+    pub fn classify(line: &[u8]) -> Self {
+        if line == b"PRI * HTTP/2.0" {
+            return Self::Supported;
+        }
+
+        let mut parts = line.split(|&b| b == b' ');
+        let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+            return Self::Malformed;
+        };
+
+        if method.is_empty() || !method.iter().all(|&b| is_token(b)) {
+            return Self::Malformed;
+        }
+
+        if target.is_empty() || !target.iter().all(|&b| b.is_ascii_graphic()) {
+            return Self::Malformed;
+        }
+
+        match (parts.next(), parts.next()) {
+            (None, _) => Self::UnsupportedVersion,
+            (Some(b"HTTP/1.0" | b"HTTP/1.1"), None) => Self::Supported,
+            (Some(version), None) if is_version(version) => Self::UnsupportedVersion,
+            _ => Self::Malformed,
+        }
+    }
+}
+
+/// Read from a stream until the first request line has been received, returning everything that
+/// was read and the classification of the line. Leading empty lines are skipped. The line is only
+/// classified if it ended within the first 8 KiB.
+pub(crate) async fn read<I>(stream: &mut I) -> io::Result<(Bytes, Option<RequestLine>)>
+where
+    I: AsyncRead + Unpin,
+{
+    let mut buffer = BytesMut::new();
+    let mut line_start = 0;
+
+    loop {
+        while let Some(end) = buffer[line_start..].iter().position(|&b| b == b'\n') {
+            let end = line_start + end;
+            let line = buffer[line_start..end]
+                .strip_suffix(b"\r")
+                .unwrap_or(&buffer[line_start..end]);
+
+            if line.is_empty() {
+                line_start = end + 1;
+                continue;
+            }
+
+            let classification = RequestLine::classify(line);
+            return Ok((buffer.freeze(), Some(classification)));
+        }
+
+        if buffer.len() >= MAX_LINE_SIZE || stream.read_buf(&mut buffer).await? == 0 {
+            return Ok((buffer.freeze(), None));
+        }
+    }
+}
+
+fn is_token(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+fn is_version(version: &[u8]) -> bool {
+    match version.strip_prefix(b"HTTP/") {
+        Some([major]) => major.is_ascii_digit(),
+        Some([major, b'.', minor]) => major.is_ascii_digit() && minor.is_ascii_digit(),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classifies_lines() {
+        assert_eq!(
+            RequestLine::classify(b"GET / HTTP/1.1"),
+            RequestLine::Supported
+        );
+        assert_eq!(
+            RequestLine::classify(b"POST http://example.com/ HTTP/1.0"),
+            RequestLine::Supported
+        );
+        assert_eq!(
+            RequestLine::classify(b"PRI * HTTP/2.0"),
+            RequestLine::Supported
+        );
+        assert_eq!(
+            RequestLine::classify(b"GET /"),
+            RequestLine::UnsupportedVersion
+        );
+        assert_eq!(
+            RequestLine::classify(b"GET / HTTP/2.0"),
+            RequestLine::UnsupportedVersion
+        );
+        assert_eq!(
+            RequestLine::classify(b"GET / HTTP/3"),
+            RequestLine::UnsupportedVersion
+        );
+        assert_eq!(
+            RequestLine::classify(b"GET / FOO/1.1"),
+            RequestLine::Malformed
+        );
+        assert_eq!(
+            RequestLine::classify(b"GET / HTTP/1.1 extra"),
+            RequestLine::Malformed
+        );
+        assert_eq!(RequestLine::classify(b"GET"), RequestLine::Malformed);
+        assert_eq!(
+            RequestLine::classify(b"G(T / HTTP/1.1"),
+            RequestLine::Malformed
+        );
+        assert_eq!(
+            RequestLine::classify(b"\x00\x01\x02 garbage"),
+            RequestLine::Malformed
+        );
+    }
+
+    #[tokio::test]
+    async fn reads_first_line() {
+        let mut stream: &[u8] = b"\r\nGET /\r\nHost: example.com\r\n";
+        let (read, line) = read(&mut stream).await.unwrap();
+
+        assert_eq!(read, "\r\nGET /\r\nHost: example.com\r\n");
+        assert_eq!(line, Some(RequestLine::UnsupportedVersion));
+    }
+
+    #[tokio::test]
+    async fn returns_incomplete_line() {
+        let mut stream: &[u8] = b"GET / HTTP/1.1";
+        let (read, line) = read(&mut stream).await.unwrap();
+
+        assert_eq!(read, "GET / HTTP/1.1");
+        assert_eq!(line, None);
+    }
+}
@@ -0,0 +1,154 @@
+use hyper::{http::uri::Authority, Body, Response, StatusCode};
+use std::net::{IpAddr, SocketAddr};
+use tracing::warn;
+
+/// Connecting to an authority would connect back to the proxy, which would forward the request to
+/// itself in a loop.
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) struct Loop;
+
+/// Check whether connecting to an authority would connect back to the proxy.
+///
+/// The proxy is reached at the local address of the client's connection, and at any of the
+/// configured addresses. An address with an unspecified IP, such as `0.0.0.0`, stands for every
+/// address on its port. The authority is only resolved if its port is one the proxy is reached on,
+/// in which case the address it resolved to is returned, so that it can be connected to without
+/// resolving it again.
+pub(crate) async fn resolve(
+    authority: &Authority,
+    default_port: u16,
+    connect_addr: Option<SocketAddr>,
+    local_addr: SocketAddr,
+    self_addrs: &[SocketAddr],
+) -> Result<Option<SocketAddr>, Loop> {
+    let self_addrs = || std::iter::once(&local_addr).chain(self_addrs);
+    let is_self = |addr: &SocketAddr| self_addrs().any(|self_addr| matches(*self_addr, *addr));
+
+    let port = match connect_addr {
+        Some(addr) => addr.port(),
+        None => authority.port_u16().unwrap_or(default_port),
+    };
+    if !self_addrs().any(|addr| addr.port() == port) {
+        return Ok(None);
+    }
+
+    let host = authority
+        .host()
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let addr = match connect_addr {
+        Some(addr) => addr,
+        None => match host.parse::<IpAddr>() {
+            Ok(ip) => SocketAddr::new(ip, port),
+            // Hosts that fail to resolve aren't a loop, and the connection to them fails as usual.
+            Err(_) => {
+                let addrs: Vec<_> = match tokio::net::lookup_host((host, port)).await {
+                    Ok(addrs) => addrs.collect(),
+                    Err(_) => Vec::new(),
+                };
+                if addrs.iter().any(is_self) {
+                    return Err(Loop);
+                }
+
+                return Ok(addrs.first().copied());
+            }
+        },
+    };
+
+    if is_self(&addr) {
+        Err(Loop)
+    } else {
+        Ok(None)
+    }
+}
+
+fn matches(self_addr: SocketAddr, addr: SocketAddr) -> bool {
+    let self_ip = self_addr.ip().to_canonical();
+    self_addr.port() == addr.port()
+        && (self_ip.is_unspecified() || self_ip == addr.ip().to_canonical())
+}
+
+/// The response to a request that would loop back to the proxy.
+pub(crate) fn loop_detected(authority: &Authority) -> Response<Body> {
+    warn!(
+        "Rejecting request to the proxy's own address: {}",
+        authority
+    );
+
+    Response::builder()
+        .status(StatusCode::LOOP_DETECTED)
+        .body(Body::empty())
+        .expect("Failed to build response")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const LOCAL_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080);
+
+    async fn is_loop_to(authority: &'static str, self_addrs: &[SocketAddr]) -> bool {
+        let authority = Authority::from_static(authority);
+        resolve(&authority, 80, None, LOCAL_ADDR, self_addrs).await == Err(Loop)
+    }
+
+    #[tokio::test]
+    async fn detects_local_addr() {
+        assert!(is_loop_to("127.0.0.1:8080", &[]).await);
+        assert!(is_loop_to("localhost:8080", &[]).await);
+        assert!(!is_loop_to("127.0.0.1:8081", &[]).await);
+        assert!(!is_loop_to("127.0.0.2:8080", &[]).await);
+        assert!(is_loop_to("[::ffff:127.0.0.1]:8080", &[]).await);
+    }
+
+    #[tokio::test]
+    async fn detects_configured_addrs() {
+        let self_addrs = [
+            SocketAddr::from(([10, 0, 0, 1], 3128)),
+            SocketAddr::from(([0, 0, 0, 0], 80)),
+        ];
+
+        assert!(is_loop_to("10.0.0.1:3128", &self_addrs).await);
+        assert!(!is_loop_to("10.0.0.2:3128", &self_addrs).await);
+        assert!(is_loop_to("192.168.1.1", &self_addrs).await);
+    }
+
+    #[tokio::test]
+    async fn uses_connect_addr() {
+        let authority = Authority::from_static("example.com:443");
+
+        assert_eq!(
+            resolve(&authority, 443, Some(LOCAL_ADDR), LOCAL_ADDR, &[]).await,
+            Err(Loop)
+        );
+        assert_eq!(
+            resolve(
+                &authority,
+                443,
+                Some(SocketAddr::from(([127, 0, 0, 1], 443))),
+                LOCAL_ADDR,
+                &[]
+            )
+            .await,
+            Ok(None)
+        );
+    }
+
+    #[tokio::test]
+    async fn returns_resolved_addr() {
+        let self_addrs = [SocketAddr::from(([10, 0, 0, 1], 80))];
+        let resolved = |authority| async move {
+            let authority = Authority::from_static(authority);
+            resolve(&authority, 80, None, LOCAL_ADDR, &self_addrs).await
+        };
+
+        assert_eq!(resolved("localhost:8081").await, Ok(None));
+        assert_eq!(resolved("127.0.0.1").await, Ok(None));
+        assert_eq!(resolved("localhost:8080").await, Err(Loop));
+
+        let addr = resolved("localhost").await.unwrap().unwrap();
+        assert!(addr.ip().is_loopback());
+        assert_eq!(addr.port(), 80);
+    }
+}
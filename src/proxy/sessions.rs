@@ -0,0 +1,203 @@
+use futures::{Stream, StreamExt};
+use hyper::Uri;
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio_tungstenite::tungstenite::{self, Message};
+
+/// Number of messages and bytes of their payloads received in one direction of a WebSocket
+/// session.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct MessageCounts {
+    /// Number of messages, including control frames.
+    pub messages: u64,
+    /// Total length of the payloads of the messages.
+    pub bytes: u64,
+}
+
+/// A snapshot of an active WebSocket session, obtained with
+/// [`ProxyControl::websocket_sessions`](crate::ProxyControl::websocket_sessions).
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+#[non_exhaustive]
+pub struct WebSocketSession {
+    /// Identifier of the session, which is unique for the lifetime of the proxy.
+    pub id: u64,
+    /// Address of the client.
+    pub client_addr: SocketAddr,
+    /// URI of the server.
+    pub uri: Uri,
+    /// Messages received from the client.
+    pub client_to_server: MessageCounts,
+    /// Messages received from the server.
+    pub server_to_client: MessageCounts,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    messages: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl Counters {
+    fn record(&self, msg: &Message) {
+        self.messages.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(msg.len() as u64, Ordering::Relaxed);
+    }
+
+    fn load(&self) -> MessageCounts {
+        MessageCounts {
+            messages: self.messages.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    client_addr: SocketAddr,
+    uri: Uri,
+    client_to_server: Counters,
+    server_to_client: Counters,
+}
+
+/// Direction of the messages read from a stream.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Direction {
+    ClientToServer,
+    ServerToClient,
+}
+
+/// Registry of the active WebSocket sessions of a proxy.
+#[derive(Debug, Default)]
+pub(crate) struct Sessions {
+    next_id: AtomicU64,
+    active: Mutex<HashMap<u64, Arc<Entry>>>,
+}
+
+impl Sessions {
+    /// Register a new session, which stays active until the returned guard and all of its clones
+    /// are dropped.
+    pub fn register(self: &Arc<Self>, client_addr: SocketAddr, uri: Uri) -> Arc<SessionGuard> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let entry = Arc::new(Entry {
+            client_addr,
+            uri,
+            client_to_server: Counters::default(),
+            server_to_client: Counters::default(),
+        });
+
+        self.active
+            .lock()
+            .expect("Failed to lock sessions")
+            .insert(id, Arc::clone(&entry));
+
+        Arc::new(SessionGuard {
+            sessions: Arc::clone(self),
+            id,
+            entry,
+        })
+    }
+
+    /// Get a snapshot of the active sessions, ordered by when they were opened.
+    pub fn snapshot(&self) -> Vec<WebSocketSession> {
+        let mut sessions: Vec<_> = self
+            .active
+            .lock()
+            .expect("Failed to lock sessions")
+            .iter()
+            .map(|(&id, entry)| WebSocketSession {
+                id,
+                client_addr: entry.client_addr,
+                uri: entry.uri.clone(),
+                client_to_server: entry.client_to_server.load(),
+                server_to_client: entry.server_to_client.load(),
+            })
+            .collect();
+
+        sessions.sort_by_key(|session| session.id);
+        sessions
+    }
+}
+
+/// Removes a session from the registry when dropped.
+#[derive(Debug)]
+pub(crate) struct SessionGuard {
+    sessions: Arc<Sessions>,
+    id: u64,
+    entry: Arc<Entry>,
+}
+
+impl SessionGuard {
+    /// Count the messages read from a stream. The session is kept active until the stream is
+    /// dropped.
+    pub fn count<S>(
+        self: &Arc<Self>,
+        stream: S,
+        direction: Direction,
+    ) -> impl Stream<Item = Result<Message, tungstenite::Error>> + Unpin + Send + 'static
+    where
+        S: Stream<Item = Result<Message, tungstenite::Error>> + Unpin + Send + 'static,
+    {
+        let guard = Arc::clone(self);
+
+        stream.inspect(move |msg| {
+            if let Ok(msg) = msg {
+                match direction {
+                    Direction::ClientToServer => guard.entry.client_to_server.record(msg),
+                    Direction::ServerToClient => guard.entry.server_to_client.record(msg),
+                }
+            }
+        })
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        if let Ok(mut active) = self.sessions.active.lock() {
+            active.remove(&self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+
+    #[tokio::test]
+    async fn counts_messages_until_dropped() {
+        let sessions = Arc::new(Sessions::default());
+        let guard = sessions.register(
+            "127.0.0.1:8080".parse().unwrap(),
+            Uri::from_static("ws://example.com/"),
+        );
+
+        let messages = vec![
+            Ok(Message::Text("hello".to_owned())),
+            Ok(Message::Binary(vec![0; 10])),
+        ];
+        let mut stream = guard.count(stream::iter(messages), Direction::ServerToClient);
+        drop(guard);
+
+        while stream.next().await.is_some() {}
+
+        let session = sessions.snapshot().remove(0);
+        assert_eq!(session.client_to_server, MessageCounts::default());
+        assert_eq!(
+            session.server_to_client,
+            MessageCounts {
+                messages: 2,
+                bytes: 15
+            }
+        );
+
+        drop(stream);
+        assert!(sessions.snapshot().is_empty());
+    }
+}
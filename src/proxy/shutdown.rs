@@ -0,0 +1,100 @@
+use std::{
+    hash::{Hash, Hasher},
+    pin::pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+use tokio::sync::Notify;
+
+#[derive(Debug, Default)]
+struct State {
+    triggered: AtomicBool,
+    notify: Notify,
+}
+
+/// A handle for triggering the graceful shutdown of a [`Proxy`](crate::Proxy), as if its shutdown
+/// signal had resolved.
+///
+/// This is available to handlers in [`HttpContext::shutdown`](crate::HttpContext::shutdown), for
+/// example to shut down the proxy in response to a control request, and from
+/// [`Proxy::shutdown_handle`](crate::Proxy::shutdown_handle).
+///
+/// Triggering a shutdown doesn't wait for it, so it can be called from within a handler, and
+/// calling it more than once has no further effect. The proxy stops accepting new connections, but
+/// requests that are being handled, including the one that triggered the shutdown, still have
+/// their responses sent.
+#[derive(Clone, Debug, Default)]
+pub struct ShutdownHandle(Arc<State>);
+
+impl ShutdownHandle {
+    /// Start shutting down the proxy.
+    pub fn shutdown(&self) {
+        self.0.triggered.store(true, Ordering::SeqCst);
+        self.0.notify.notify_waiters();
+    }
+
+    /// Whether a shutdown has been triggered with this handle.
+    pub fn is_shutdown(&self) -> bool {
+        self.0.triggered.load(Ordering::SeqCst)
+    }
+
+    /// Wait until a shutdown is triggered.
+    pub(crate) async fn wait(&self) {
+        let mut notified = pin!(self.0.notify.notified());
+        notified.as_mut().enable();
+
+        if !self.is_shutdown() {
+            notified.await;
+        }
+    }
+}
+
+impl PartialEq for ShutdownHandle {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl Eq for ShutdownHandle {}
+
+impl Hash for ShutdownHandle {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        Arc::as_ptr(&self.0).hash(state);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[tokio::test]
+    async fn wakes_waiter() {
+        let handle = ShutdownHandle::default();
+        let waiter = tokio::spawn({
+            let handle = handle.clone();
+            async move { handle.wait().await }
+        });
+
+        handle.clone().shutdown();
+        handle.shutdown();
+
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(handle.is_shutdown());
+    }
+
+    #[tokio::test]
+    async fn returns_if_already_shut_down() {
+        let handle = ShutdownHandle::default();
+        handle.shutdown();
+
+        tokio::time::timeout(Duration::from_secs(1), handle.wait())
+            .await
+            .unwrap();
+    }
+}
@@ -0,0 +1,225 @@
+use socket2::SockRef;
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    ops::RangeInclusive,
+};
+use tokio::net::{lookup_host, TcpSocket, TcpStream};
+use tracing::warn;
+
+/// Size of the buffers used to copy data through tunnels when the socket buffer sizes aren't set.
+const DEFAULT_COPY_BUF_SIZE: usize = 8 * 1024;
+
+/// Socket buffer sizes set with
+/// [`ProxyBuilder::with_socket_buffer_sizes`](crate::ProxyBuilder::with_socket_buffer_sizes).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) struct BufferSizes {
+    pub recv: u32,
+    pub send: u32,
+}
+
+impl BufferSizes {
+    /// Set the buffer sizes of a socket. Failures are logged, since the sizes are only a hint.
+    pub fn apply(&self, socket: SockRef<'_>) {
+        if let Err(e) = socket.set_recv_buffer_size(self.recv as usize) {
+            warn!("Failed to set socket receive buffer size: {}", e);
+        }
+
+        if let Err(e) = socket.set_send_buffer_size(self.send as usize) {
+            warn!("Failed to set socket send buffer size: {}", e);
+        }
+    }
+}
+
+/// The size of the buffers used to copy data in each direction through a tunnel. An explicitly
+/// set size takes precedence over the size of the socket receive buffer.
+pub(crate) fn copy_buf_size(size: Option<usize>, sizes: Option<BufferSizes>) -> usize {
+    size.unwrap_or_else(|| sizes.map_or(DEFAULT_COPY_BUF_SIZE, |sizes| sizes.recv as usize))
+}
+
+/// Options for the sockets connecting to the servers of tunnels.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub(crate) struct ConnectOptions {
+    pub buffer_sizes: Option<BufferSizes>,
+    pub port_range: Option<RangeInclusive<u16>>,
+}
+
+/// Connect to the server of a tunnel at the given `host:port`, setting the buffer sizes and the
+/// source port before connecting, so that the sizes are taken into account when the TCP window is
+/// negotiated.
+pub(crate) async fn connect(addr: &str, options: &ConnectOptions) -> io::Result<TcpStream> {
+    if *options == ConnectOptions::default() {
+        return TcpStream::connect(addr).await;
+    }
+
+    let mut last_err = None;
+
+    for addr in lookup_host(addr).await? {
+        let result = match &options.port_range {
+            Some(port_range) => connect_from_range(addr, options.buffer_sizes, port_range).await,
+            None => new_socket(addr, options.buffer_sizes)?.connect(addr).await,
+        };
+
+        match result {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_err = Some(e),
+        }
+    }
+
+    Err(last_err.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "could not resolve to any address",
+        )
+    }))
+}
+
+fn new_socket(addr: SocketAddr, sizes: Option<BufferSizes>) -> io::Result<TcpSocket> {
+    let socket = if addr.is_ipv4() {
+        TcpSocket::new_v4()?
+    } else {
+        TcpSocket::new_v6()?
+    };
+
+    if let Some(sizes) = sizes {
+        sizes.apply(SockRef::from(&socket));
+    }
+
+    Ok(socket)
+}
+
+/// Connect from the first source port in the range that is free, starting from a random port so
+/// that connections are spread over the range.
+///
+/// Ports are bound with `SO_REUSEADDR`, so that ports of closed connections that are still in
+/// `TIME_WAIT` can be used again, and a port can be shared by connections to different servers.
+async fn connect_from_range(
+    addr: SocketAddr,
+    sizes: Option<BufferSizes>,
+    port_range: &RangeInclusive<u16>,
+) -> io::Result<TcpStream> {
+    let start = *port_range.start();
+    let len = u32::from(*port_range.end() - start) + 1;
+    let offset = rand::random::<u32>() % len;
+    let ip = match addr {
+        SocketAddr::V4(_) => IpAddr::from(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::from(Ipv6Addr::UNSPECIFIED),
+    };
+
+    for i in 0..len {
+        // The offset is below the length of the range, so the port is within it.
+        let port = start + ((offset + i) % len) as u16;
+        let socket = new_socket(addr, sizes)?;
+        socket.set_reuseaddr(true)?;
+
+        if let Err(e) = socket.bind(SocketAddr::new(ip, port)) {
+            if e.kind() == io::ErrorKind::AddrInUse {
+                continue;
+            }
+            return Err(e);
+        }
+
+        match socket.connect(addr).await {
+            // The port is already used by a connection to the same server.
+            Err(e)
+                if e.kind() == io::ErrorKind::AddrInUse
+                    || e.kind() == io::ErrorKind::AddrNotAvailable => {}
+            result => return result,
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::AddrInUse,
+        format!(
+            "no source port available in the range {}..={}",
+            port_range.start(),
+            port_range.end()
+        ),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn connects_with_buffer_sizes() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let sizes = BufferSizes {
+            recv: 256 * 1024,
+            send: 128 * 1024,
+        };
+
+        let options = ConnectOptions {
+            buffer_sizes: Some(sizes),
+            ..ConnectOptions::default()
+        };
+
+        let stream = connect(&addr, &options).await.unwrap();
+        let socket = SockRef::from(&stream);
+
+        // Some platforms, such as Linux, double the requested sizes to allow for bookkeeping.
+        assert!(socket.recv_buffer_size().unwrap() >= 256 * 1024);
+        assert!(socket.send_buffer_size().unwrap() >= 128 * 1024);
+    }
+
+    /// A port that is free, found by binding to it and closing the socket again.
+    fn free_port() -> u16 {
+        std::net::TcpListener::bind("0.0.0.0:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port()
+    }
+
+    #[tokio::test]
+    async fn connects_from_port_range() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let port = free_port();
+        let options = ConnectOptions {
+            port_range: Some(port..=port),
+            ..ConnectOptions::default()
+        };
+
+        let stream = connect(&addr, &options).await.unwrap();
+        let (_, peer_addr) = listener.accept().await.unwrap();
+
+        assert_eq!(stream.local_addr().unwrap().port(), port);
+        assert_eq!(peer_addr.port(), port);
+    }
+
+    #[tokio::test]
+    async fn fails_if_port_range_exhausted() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let taken = TcpListener::bind("0.0.0.0:0").await.unwrap();
+        let port = taken.local_addr().unwrap().port();
+        let options = ConnectOptions {
+            port_range: Some(port..=port),
+            ..ConnectOptions::default()
+        };
+
+        let err = connect(&addr, &options).await.unwrap_err();
+
+        assert_eq!(err.kind(), io::ErrorKind::AddrInUse);
+        assert_eq!(
+            err.to_string(),
+            format!("no source port available in the range {0}..={0}", port)
+        );
+    }
+
+    #[test]
+    fn sizes_copy_buffers() {
+        let sizes = Some(BufferSizes {
+            recv: 64 * 1024,
+            send: 32 * 1024,
+        });
+
+        assert_eq!(copy_buf_size(None, None), 8 * 1024);
+        assert_eq!(copy_buf_size(None, sizes), 64 * 1024);
+        assert_eq!(copy_buf_size(Some(1024 * 1024), sizes), 1024 * 1024);
+    }
+}
@@ -0,0 +1,280 @@
+use crate::{
+    decoder::{extract_encodings, is_supported_encoding},
+    Decompress, HttpContext, HttpHandler, Pipeline, Recompress, Replace, RequestOrResponse,
+};
+use bstr::ByteSlice;
+use bytes::Bytes;
+use hyper::{
+    body::HttpBody, header::CONTENT_TYPE, http::Extensions, Body, Method, Request, Response,
+    StatusCode,
+};
+use tracing::error;
+
+const DEFAULT_MAX_SIZE: usize = 1024 * 1024;
+
+fn is_text(content_type: &[u8]) -> bool {
+    let essence = content_type
+        .split_str(";")
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    essence.starts_with(b"text/")
+        || essence.ends_with(b"+xml")
+        || essence.ends_with(b"+json")
+        || matches!(
+            &essence[..],
+            b"application/json" | b"application/javascript" | b"application/xml"
+        )
+}
+
+fn bad_gateway() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::BAD_GATEWAY)
+        .body(Body::empty())
+        .expect("Failed to build response")
+}
+
+/// An HTTP handler that finds and replaces bytes in the bodies of text responses.
+///
+/// Responses are only rewritten if their `content-type` is text, such as `text/html`,
+/// `application/json`, or `application/xml`. Their bodies are decoded and buffered in full, so
+/// matches spanning multiple chunks are replaced, before being encoded again with the original
+/// `content-encoding` and sent with an updated `content-length`. Bodies larger than the maximum
+/// size are sent decoded without being modified.
+///
+/// Replacements are applied in the order they were added, after the inner handler has handled
+/// the response, by a [`Pipeline`] of [`Decompress`], [`Replace`], and [`Recompress`]. All other
+/// methods are passed through to the inner handler.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{NoopHandler, ReplaceHandler};
+///
+/// let handler = ReplaceHandler::new(NoopHandler::default())
+///     .replace("http://", "https://")
+///     .with_max_size(512 * 1024);
+/// ```
+#[cfg_attr(docsrs, doc(cfg(feature = "decoder")))]
+#[derive(Clone, Debug)]
+pub struct ReplaceHandler<H> {
+    inner: H,
+    replace: Replace,
+    max_size: usize,
+    skip_response: bool,
+}
+
+impl<H> ReplaceHandler<H> {
+    /// Wrap a handler, without any replacements. The maximum size defaults to 1 MiB.
+    pub fn new(inner: H) -> Self {
+        Self {
+            inner,
+            replace: Replace::new(),
+            max_size: DEFAULT_MAX_SIZE,
+            skip_response: false,
+        }
+    }
+
+    /// Add a replacement of all occurrences of `find` with `replace`. Empty patterns are ignored.
+    pub fn replace(mut self, find: impl Into<Bytes>, replace: impl Into<Bytes>) -> Self {
+        self.replace = self.replace.replace(find, replace);
+        self
+    }
+
+    /// Set the maximum size in bytes of a decoded body that will be rewritten.
+    pub fn with_max_size(mut self, max_size: usize) -> Self {
+        self.max_size = max_size;
+        self
+    }
+
+    fn should_rewrite(&self, res: &Response<Body>) -> bool {
+        !self.skip_response
+            && !self.replace.is_empty()
+            && !res.body().is_end_stream()
+            && res.status() != StatusCode::NO_CONTENT
+            && res.status() != StatusCode::NOT_MODIFIED
+            && res
+                .headers()
+                .get(CONTENT_TYPE)
+                .is_some_and(|content_type| is_text(content_type.as_bytes()))
+            && extract_encodings(res.headers()).all(is_supported_encoding)
+    }
+
+    async fn rewrite(&self, res: Response<Body>) -> Result<Response<Body>, crate::Error> {
+        let pipeline = Pipeline::new()
+            .with_transform(Decompress)
+            .with_transform(self.replace.clone())
+            .with_transform(Recompress)
+            .with_max_buffer_size(self.max_size);
+
+        // The pipeline's state is kept out of the response, so that it isn't seen by the
+        // pipelines set on the proxy.
+        let (mut parts, body) = res.into_parts();
+        let body = pipeline
+            .apply(&mut parts.headers, &mut Extensions::new(), body)
+            .await?;
+
+        Ok(Response::from_parts(parts, body))
+    }
+}
+
+delegate_http_handler! {
+    impl<H: HttpHandler> HttpHandler for ReplaceHandler<H> => inner {
+        async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+            // Responses to HEAD requests have no body, but keep the length of the body they describe.
+            self.skip_response = req.method() == Method::HEAD;
+            self.inner.handle_request(ctx, req).await
+        }
+
+        async fn handle_response(&mut self, ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+            let res = self.inner.handle_response(ctx, res).await;
+
+            if !self.should_rewrite(&res) {
+                return res;
+            }
+
+            match self.rewrite(res).await {
+                Ok(res) => res,
+                Err(e) => {
+                    error!("Failed to rewrite response: {}", e);
+                    bad_gateway()
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode_response, decoder::encode_body, test_ctx, NoopHandler};
+    use futures::stream;
+    use hyper::{
+        body::to_bytes,
+        header::{HeaderValue, CONTENT_ENCODING, CONTENT_LENGTH},
+        Version,
+    };
+    use std::io;
+
+    fn chunked_response(content_type: &str, chunks: &[&'static str]) -> Response<Body> {
+        let chunks: Vec<Result<_, io::Error>> = chunks.iter().map(|&chunk| Ok(chunk)).collect();
+
+        Response::builder()
+            .version(Version::HTTP_11)
+            .header(CONTENT_TYPE, content_type)
+            .body(Body::wrap_stream(stream::iter(chunks)))
+            .unwrap()
+    }
+
+    fn handler() -> ReplaceHandler<NoopHandler> {
+        ReplaceHandler::new(NoopHandler::new())
+            .replace("hello", "goodbye")
+            .replace("", "ignored")
+    }
+
+    #[tokio::test]
+    async fn replaces_across_chunks() {
+        let res = chunked_response("text/html; charset=utf-8", &["<p>he", "l", "lo</p>"]);
+        let res = handler().handle_response(&test_ctx(), res).await;
+
+        assert_eq!(res.headers()[CONTENT_LENGTH], "14");
+        assert_eq!(
+            &to_bytes(res.into_body()).await.unwrap()[..],
+            b"<p>goodbye</p>"
+        );
+    }
+
+    #[tokio::test]
+    async fn applies_replacements_in_order() {
+        let res = chunked_response("application/json", &["hello"]);
+        let res = handler()
+            .replace("goodbye", "farewell")
+            .handle_response(&test_ctx(), res)
+            .await;
+
+        assert_eq!(&to_bytes(res.into_body()).await.unwrap()[..], b"farewell");
+    }
+
+    #[tokio::test]
+    async fn reencodes_body() {
+        let encoded = encode_body(
+            &[HeaderValue::from_static("gzip")],
+            b"hello, world".to_vec(),
+        )
+        .await;
+        let res = Response::builder()
+            .header(CONTENT_TYPE, "text/plain")
+            .header(CONTENT_ENCODING, "gzip")
+            .header(CONTENT_LENGTH, encoded.len())
+            .body(Body::from(encoded))
+            .unwrap();
+
+        let res = handler().handle_response(&test_ctx(), res).await;
+        assert_eq!(res.headers()[CONTENT_ENCODING], "gzip");
+
+        let length: usize = res.headers()[CONTENT_LENGTH]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let res = decode_response(res).unwrap();
+        let body = to_bytes(res.into_body()).await.unwrap();
+        assert_ne!(length, body.len());
+        assert_eq!(&body[..], b"goodbye, world");
+    }
+
+    #[tokio::test]
+    async fn skips_non_text_responses() {
+        let res = chunked_response("image/png", &["hello"]);
+        let res = handler().handle_response(&test_ctx(), res).await;
+
+        assert_eq!(&to_bytes(res.into_body()).await.unwrap()[..], b"hello");
+    }
+
+    #[tokio::test]
+    async fn skips_bodies_over_max_size() {
+        let res = chunked_response("text/plain", &["hel", "lo, wor", "ld"]);
+        let res = handler()
+            .with_max_size(4)
+            .handle_response(&test_ctx(), res)
+            .await;
+
+        assert!(!res.headers().contains_key(CONTENT_LENGTH));
+        assert_eq!(
+            &to_bytes(res.into_body()).await.unwrap()[..],
+            b"hello, world"
+        );
+    }
+
+    #[tokio::test]
+    async fn skips_head_responses() {
+        let mut handler = handler();
+        let req = Request::builder()
+            .method(Method::HEAD)
+            .body(Body::empty())
+            .unwrap();
+        handler.handle_request(&test_ctx(), req).await;
+
+        let res = Response::builder()
+            .header(CONTENT_TYPE, "text/plain")
+            .header(CONTENT_LENGTH, 5)
+            .body(Body::empty())
+            .unwrap();
+        let res = handler.handle_response(&test_ctx(), res).await;
+
+        assert_eq!(res.headers()[CONTENT_LENGTH], "5");
+    }
+
+    #[test]
+    fn detects_text_content_types() {
+        assert!(is_text(b"text/html"));
+        assert!(is_text(b"Text/Plain; charset=utf-8"));
+        assert!(is_text(b"application/json"));
+        assert!(is_text(b"application/ld+json"));
+        assert!(is_text(b"image/svg+xml"));
+        assert!(!is_text(b"image/png"));
+        assert!(!is_text(b"application/octet-stream"));
+    }
+}
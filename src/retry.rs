@@ -0,0 +1,109 @@
+use hyper::{Method, StatusCode};
+use std::time::Duration;
+
+/// Largest request body that is buffered so that the request can be retried. Requests with larger
+/// bodies are streamed upstream and never retried.
+pub(crate) const MAX_BODY_SIZE: usize = 1024 * 1024;
+
+/// When to retry requests whose upstream response has one of the given statuses, set with
+/// [`ProxyBuilder::with_retry_policy`](crate::ProxyBuilder::with_retry_policy).
+///
+/// Only requests with idempotent methods are retried. Their bodies are buffered so that they can
+/// be sent again, unless they are larger than 1 MiB, in which case they are streamed and never
+/// retried. The response to the last attempt is passed on, whatever its status.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{hyper::StatusCode, RetryPolicy};
+/// use std::time::Duration;
+///
+/// let policy = RetryPolicy {
+///     statuses: vec![StatusCode::SERVICE_UNAVAILABLE],
+///     max: 3,
+///     backoff: Duration::from_millis(50),
+/// };
+/// ```
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RetryPolicy {
+    /// Statuses of the responses that are retried.
+    pub statuses: Vec<StatusCode>,
+    /// Maximum number of retries after the first attempt.
+    pub max: u32,
+    /// Delay before the first retry, which doubles for each retry after it.
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    /// Retry `502 Bad Gateway`, `503 Service Unavailable`, and `504 Gateway Timeout` responses up
+    /// to twice, waiting 100 ms before the first retry.
+    fn default() -> Self {
+        Self {
+            statuses: vec![
+                StatusCode::BAD_GATEWAY,
+                StatusCode::SERVICE_UNAVAILABLE,
+                StatusCode::GATEWAY_TIMEOUT,
+            ],
+            max: 2,
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether requests with the method may be retried.
+    pub(crate) fn allows(&self, method: &Method) -> bool {
+        self.max > 0
+            && matches!(
+                *method,
+                Method::GET
+                    | Method::HEAD
+                    | Method::OPTIONS
+                    | Method::TRACE
+                    | Method::PUT
+                    | Method::DELETE
+            )
+    }
+
+    /// Whether a response with the status should be retried after the given number of retries.
+    pub(crate) fn should_retry(&self, status: StatusCode, retries: u32) -> bool {
+        retries < self.max && self.statuses.contains(&status)
+    }
+
+    /// Delay before the retry following the given number of retries.
+    pub(crate) fn delay(&self, retries: u32) -> Duration {
+        self.backoff.saturating_mul(2u32.saturating_pow(retries))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retries_idempotent_methods() {
+        let policy = RetryPolicy::default();
+        assert!(policy.allows(&Method::GET));
+        assert!(policy.allows(&Method::PUT));
+        assert!(!policy.allows(&Method::POST));
+        assert!(!policy.allows(&Method::PATCH));
+
+        let policy = RetryPolicy {
+            max: 0,
+            ..RetryPolicy::default()
+        };
+        assert!(!policy.allows(&Method::GET));
+    }
+
+    #[test]
+    fn backs_off_exponentially() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.delay(0), Duration::from_millis(100));
+        assert_eq!(policy.delay(1), Duration::from_millis(200));
+        assert_eq!(policy.delay(3), Duration::from_millis(800));
+
+        assert!(policy.should_retry(StatusCode::SERVICE_UNAVAILABLE, 1));
+        assert!(!policy.should_retry(StatusCode::SERVICE_UNAVAILABLE, 2));
+        assert!(!policy.should_retry(StatusCode::INTERNAL_SERVER_ERROR, 0));
+    }
+}
@@ -40,7 +40,7 @@ impl<T> Rewind<T> {
 
     #[allow(dead_code)]
     pub(crate) fn into_inner(self) -> (T, Bytes) {
-        (self.inner, self.pre.unwrap_or_else(Bytes::new))
+        (self.inner, self.pre.unwrap_or_default())
     }
 
     // pub(crate) fn get_mut(&mut self) -> &mut T {
@@ -0,0 +1,63 @@
+use crate::{HttpContext, HttpHandler, RequestOrResponse};
+use futures::future::poll_fn;
+use hyper::{Body, Request, Response, StatusCode};
+use std::fmt::Display;
+use tower_service::Service;
+use tracing::error;
+
+/// An HTTP handler that passes requests to a [`tower`](https://docs.rs/tower) service.
+///
+/// The response returned by the service is sent to the client, so the service is responsible for
+/// forwarding requests itself. As a [`hyper::Client`] is a service, middleware can be layered on
+/// top of a client to keep forwarding requests to the upstream server. Requests handled this way
+/// are not passed to [`HttpHandler::rewrite_uri`] or [`HttpHandler::handle_response`]. If the
+/// service fails, the error is logged and a 502 Bad Gateway is sent to the client.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{hyper::Client, ServiceHandler};
+///
+/// let handler = ServiceHandler::new(Client::new());
+/// ```
+#[cfg_attr(docsrs, doc(cfg(feature = "tower")))]
+#[derive(Clone, Debug)]
+pub struct ServiceHandler<S>(S);
+
+impl<S> ServiceHandler<S> {
+    /// Create a handler that passes requests to the given service.
+    pub fn new(service: S) -> Self {
+        Self(service)
+    }
+}
+
+#[async_trait::async_trait]
+impl<S> HttpHandler for ServiceHandler<S>
+where
+    S: Service<Request<Body>, Response = Response<Body>> + Clone + Send + Sync + 'static,
+    S::Error: Display + Send,
+    S::Future: Send,
+{
+    async fn handle_request(
+        &mut self,
+        _ctx: &HttpContext,
+        req: Request<Body>,
+    ) -> RequestOrResponse {
+        let res = match poll_fn(|cx| self.0.poll_ready(cx)).await {
+            Ok(()) => self.0.call(req).await,
+            Err(e) => Err(e),
+        };
+
+        match res {
+            Ok(res) => res.into(),
+            Err(e) => {
+                error!("Service failed to handle request: {}", e);
+                Response::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .body(Body::empty())
+                    .expect("Failed to build response")
+                    .into()
+            }
+        }
+    }
+}
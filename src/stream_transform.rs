@@ -0,0 +1,416 @@
+use crate::{BodyTransform, Error};
+use async_trait::async_trait;
+use bytes::{Buf, Bytes, BytesMut};
+use futures::{ready, Stream, StreamExt};
+use hyper::{header::HeaderMap, http::Extensions, Body};
+use std::{
+    fmt,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+const DEFAULT_MAX_CARRY: usize = 64 * 1024;
+
+/// A transform that is applied to a body chunk by chunk, as it is received, with
+/// [`TransformBody`].
+///
+/// Matches can cross chunk boundaries, so a transform only consumes as much of its input as it can
+/// transform, and the rest is carried over. The next call receives the carried bytes followed by
+/// the next chunk. Once the body ends, whatever is still carried is passed to
+/// [`StreamTransform::finish`].
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{bytes::BytesMut, StreamTransform};
+///
+/// /// Replaces `http://` with `https://`, even if it is split across chunks.
+/// struct Upgrade;
+///
+/// impl StreamTransform for Upgrade {
+///     fn transform(&mut self, input: &[u8], output: &mut BytesMut) -> usize {
+///         let mut consumed = 0;
+///
+///         while let Some(i) = input[consumed..].windows(7).position(|w| w == b"http://") {
+///             output.extend_from_slice(&input[consumed..consumed + i]);
+///             output.extend_from_slice(b"https://");
+///             consumed += i + 7;
+///         }
+///
+///         // The end of the input could be the start of a match, so it is carried over.
+///         let end = input.len().saturating_sub(6).max(consumed);
+///         output.extend_from_slice(&input[consumed..end]);
+///         end
+///     }
+/// }
+/// ```
+pub trait StreamTransform: Send + 'static {
+    /// Transform the start of `input`, appending the result to `output`, and return how many bytes
+    /// of `input` were consumed. The bytes that weren't consumed are passed again in the next call.
+    fn transform(&mut self, input: &[u8], output: &mut BytesMut) -> usize;
+
+    /// Transform the input that was carried over when the body ended. Defaults to appending it to
+    /// `output` as is.
+    fn finish(&mut self, input: &[u8], output: &mut BytesMut) {
+        output.extend_from_slice(input);
+    }
+}
+
+/// A body that applies a [`StreamTransform`] to another body as it is read.
+///
+/// Chunks are only read from the original body when this body is polled and has nothing left to
+/// yield, so a slow reader slows down reading from the original body instead of causing it to be
+/// buffered. At most one chunk and the bytes carried over by the transform are held at a time. The
+/// carried bytes are limited to 64 KiB by default, and the body fails with
+/// [`Error::CarryLimitExceeded`] if the transform carries over more than that.
+///
+/// It can be converted into a [`Body`], or added to a [`Pipeline`](crate::Pipeline) with
+/// [`Streaming`].
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{bytes::BytesMut, hyper::Body, Lines, TransformBody};
+///
+/// let body = Body::from("first\nsecond\n");
+/// let body: Body = TransformBody::new(
+///     body,
+///     Lines::new(|line: &[u8], output: &mut BytesMut| {
+///         if !line.starts_with(b"second") {
+///             output.extend_from_slice(line);
+///         }
+///     }),
+/// )
+/// .into();
+/// ```
+pub struct TransformBody<T> {
+    body: Body,
+    transform: T,
+    carry: BytesMut,
+    max_carry: usize,
+    done: bool,
+}
+
+impl<T: StreamTransform> TransformBody<T> {
+    /// Apply a transform to a body.
+    pub fn new(body: Body, transform: T) -> Self {
+        Self {
+            body,
+            transform,
+            carry: BytesMut::new(),
+            max_carry: DEFAULT_MAX_CARRY,
+            done: false,
+        }
+    }
+
+    /// Set the maximum number of bytes the transform can carry over between chunks.
+    pub fn with_max_carry(mut self, max_carry: usize) -> Self {
+        self.max_carry = max_carry;
+        self
+    }
+
+    /// Transform a chunk, following the bytes carried over from the previous one.
+    fn transform_chunk(&mut self, chunk: Bytes) -> Result<BytesMut, Error> {
+        let mut output = BytesMut::new();
+
+        if self.carry.is_empty() {
+            let consumed = self.transform.transform(&chunk, &mut output);
+            self.carry.extend_from_slice(&chunk[consumed..]);
+        } else {
+            self.carry.extend_from_slice(&chunk);
+            let consumed = self.transform.transform(&self.carry, &mut output);
+            self.carry.advance(consumed);
+        }
+
+        if self.carry.len() > self.max_carry {
+            return Err(Error::CarryLimitExceeded);
+        }
+
+        Ok(output)
+    }
+}
+
+impl<T: StreamTransform + Unpin> Stream for TransformBody<T> {
+    type Item = Result<Bytes, Error>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+
+        while !this.done {
+            match ready!(this.body.poll_next_unpin(cx)) {
+                Some(Ok(chunk)) => match this.transform_chunk(chunk) {
+                    // Chunks that are carried over in full don't yield anything, so the next one
+                    // is read straight away.
+                    Ok(output) if output.is_empty() => continue,
+                    Ok(output) => return Poll::Ready(Some(Ok(output.freeze()))),
+                    Err(e) => {
+                        this.done = true;
+                        return Poll::Ready(Some(Err(e)));
+                    }
+                },
+                Some(Err(e)) => {
+                    this.done = true;
+                    return Poll::Ready(Some(Err(e.into())));
+                }
+                None => {
+                    this.done = true;
+                    let mut output = BytesMut::new();
+                    this.transform.finish(&this.carry, &mut output);
+                    this.carry.clear();
+
+                    if !output.is_empty() {
+                        return Poll::Ready(Some(Ok(output.freeze())));
+                    }
+                }
+            }
+        }
+
+        Poll::Ready(None)
+    }
+}
+
+impl<T: StreamTransform + Unpin> From<TransformBody<T>> for Body {
+    fn from(body: TransformBody<T>) -> Self {
+        Body::wrap_stream(body)
+    }
+}
+
+impl<T> fmt::Debug for TransformBody<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TransformBody")
+            .field("carry", &self.carry.len())
+            .field("max_carry", &self.max_carry)
+            .field("done", &self.done)
+            .finish()
+    }
+}
+
+/// A streaming [`BodyTransform`] that applies a new [`StreamTransform`] to each body with
+/// [`TransformBody`], so that it can be added to a [`Pipeline`](crate::Pipeline).
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{bytes::BytesMut, Lines, Pipeline, Streaming};
+///
+/// let pipeline = Pipeline::new().with_transform(Streaming::new(|| {
+///     Lines::new(|line: &[u8], output: &mut BytesMut| {
+///         output.extend_from_slice(b"> ");
+///         output.extend_from_slice(line);
+///     })
+/// }));
+/// ```
+pub struct Streaming<F> {
+    new_transform: F,
+    max_carry: usize,
+}
+
+impl<F, T> Streaming<F>
+where
+    F: Fn() -> T + Send + Sync + 'static,
+    T: StreamTransform + Unpin,
+{
+    /// Create a transform from a function that creates the [`StreamTransform`] for each body.
+    pub fn new(new_transform: F) -> Self {
+        Self {
+            new_transform,
+            max_carry: DEFAULT_MAX_CARRY,
+        }
+    }
+
+    /// Set the maximum number of bytes each transform can carry over between chunks, see
+    /// [`TransformBody::with_max_carry`].
+    pub fn with_max_carry(mut self, max_carry: usize) -> Self {
+        self.max_carry = max_carry;
+        self
+    }
+}
+
+#[async_trait]
+impl<F, T> BodyTransform for Streaming<F>
+where
+    F: Fn() -> T + Send + Sync + 'static,
+    T: StreamTransform + Unpin,
+{
+    async fn transform(
+        &self,
+        _headers: &mut HeaderMap,
+        _extensions: &mut Extensions,
+        body: Body,
+    ) -> Result<Body, Error> {
+        Ok(TransformBody::new(body, (self.new_transform)())
+            .with_max_carry(self.max_carry)
+            .into())
+    }
+}
+
+impl<F> fmt::Debug for Streaming<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Streaming")
+            .field("max_carry", &self.max_carry)
+            .finish()
+    }
+}
+
+/// A [`StreamTransform`] that calls a function with each line of a body.
+///
+/// Lines are passed with their line ending, and the function appends what should replace them to
+/// the output. The last line of the body is passed once the body ends, even if it doesn't end with
+/// a line ending. Lines longer than the maximum carried over by [`TransformBody`] fail the body.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{bytes::BytesMut, Lines};
+///
+/// // Remove comment lines.
+/// let lines = Lines::new(|line: &[u8], output: &mut BytesMut| {
+///     if !line.starts_with(b"#") {
+///         output.extend_from_slice(line);
+///     }
+/// });
+/// ```
+pub struct Lines<F>(F);
+
+impl<F> Lines<F>
+where
+    F: FnMut(&[u8], &mut BytesMut) + Send + 'static,
+{
+    /// Create a transform that calls a function with each line.
+    pub fn new(f: F) -> Self {
+        Self(f)
+    }
+}
+
+impl<F> StreamTransform for Lines<F>
+where
+    F: FnMut(&[u8], &mut BytesMut) + Send + 'static,
+{
+    fn transform(&mut self, input: &[u8], output: &mut BytesMut) -> usize {
+        let mut consumed = 0;
+
+        while let Some(i) = input[consumed..].iter().position(|&b| b == b'\n') {
+            let end = consumed + i + 1;
+            (self.0)(&input[consumed..end], output);
+            consumed = end;
+        }
+
+        consumed
+    }
+
+    fn finish(&mut self, input: &[u8], output: &mut BytesMut) {
+        if !input.is_empty() {
+            (self.0)(input, output);
+        }
+    }
+}
+
+impl<F> fmt::Debug for Lines<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Lines").finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+    use hyper::body::to_bytes;
+    use std::{
+        convert::Infallible,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    fn chunked(chunks: &'static [&'static str]) -> Body {
+        Body::wrap_stream(stream::iter(
+            chunks.iter().copied().map(Ok::<_, Infallible>),
+        ))
+    }
+
+    fn numbered() -> Lines<impl FnMut(&[u8], &mut BytesMut)> {
+        let mut number = 0;
+        Lines::new(move |line: &[u8], output: &mut BytesMut| {
+            number += 1;
+            output.extend_from_slice(format!("{}: ", number).as_bytes());
+            output.extend_from_slice(line);
+        })
+    }
+
+    #[tokio::test]
+    async fn transforms_lines_across_chunks() {
+        let body = TransformBody::new(
+            chunked(&["fir", "st\nsec", "ond\n", "", "third"]),
+            numbered(),
+        );
+
+        assert_eq!(
+            to_bytes(Body::from(body)).await.unwrap(),
+            "1: first\n2: second\n3: third"
+        );
+    }
+
+    #[tokio::test]
+    async fn yields_lines_as_they_complete() {
+        let mut body = TransformBody::new(chunked(&["a\nb", "c\n", "d"]), numbered());
+
+        assert_eq!(body.next().await.unwrap().unwrap(), "1: a\n");
+        assert_eq!(body.next().await.unwrap().unwrap(), "2: bc\n");
+        assert_eq!(body.next().await.unwrap().unwrap(), "3: d");
+        assert!(body.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn fails_if_carry_limit_exceeded() {
+        let mut body =
+            TransformBody::new(chunked(&["0123", "4567", "89\n"]), numbered()).with_max_carry(6);
+
+        assert!(matches!(
+            body.next().await,
+            Some(Err(Error::CarryLimitExceeded))
+        ));
+        assert!(body.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn reads_only_when_polled() {
+        let read = Arc::new(AtomicUsize::new(0));
+        let source = {
+            let read = Arc::clone(&read);
+            stream::repeat_with(move || {
+                read.fetch_add(1, Ordering::SeqCst);
+                Ok::<_, Infallible>("line\n".repeat(1024))
+            })
+        };
+        let mut body = TransformBody::new(Body::wrap_stream(source), numbered());
+
+        // The original body never ends, so the transform must not try to read all of it.
+        for expected in 1..=3 {
+            body.next().await.unwrap().unwrap();
+            tokio::task::yield_now().await;
+            assert_eq!(read.load(Ordering::SeqCst), expected);
+            assert!(body.carry.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn applies_in_pipeline() {
+        let pipeline = crate::Pipeline::new().with_transform(Streaming::new(numbered));
+        let mut headers = HeaderMap::new();
+        headers.insert(hyper::header::CONTENT_LENGTH, "9".parse().unwrap());
+
+        let body = pipeline
+            .apply(
+                &mut headers,
+                &mut Extensions::new(),
+                chunked(&["a\nb", "\nc\n"]),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(to_bytes(body).await.unwrap(), "1: a\n2: b\n3: c\n");
+        assert!(!headers.contains_key(hyper::header::CONTENT_LENGTH));
+    }
+}
@@ -0,0 +1,224 @@
+use bytes::Bytes;
+use hyper::{
+    body::HttpBody,
+    header::{HeaderMap, CONTENT_LENGTH, TRANSFER_ENCODING},
+    Body, Request, Response, Version,
+};
+use tracing::error;
+
+/// How the length of a body was framed on the wire.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Framing {
+    /// The message has no body.
+    Empty,
+    /// The length of the body was set by a `content-length` header.
+    ContentLength(u64),
+    /// The body was sent with `transfer-encoding: chunked`.
+    Chunked,
+    /// The body of a response lasts until the connection is closed.
+    CloseDelimited,
+    /// The body was sent as HTTP/2 `DATA` frames.
+    Http2,
+}
+
+impl Framing {
+    /// Determine how the body of a request was framed.
+    pub fn of_request<T>(req: &Request<T>) -> Self {
+        Self::from_headers(req.version(), req.headers()).unwrap_or(Self::Empty)
+    }
+
+    /// Determine how the body of a response was framed.
+    pub fn of_response<T>(res: &Response<T>) -> Self {
+        Self::from_headers(res.version(), res.headers()).unwrap_or(Self::CloseDelimited)
+    }
+
+    fn from_headers(version: Version, headers: &HeaderMap) -> Option<Self> {
+        if version >= Version::HTTP_2 {
+            return Some(Self::Http2);
+        }
+
+        let chunked = headers
+            .get_all(TRANSFER_ENCODING)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.rsplit(','))
+            .next()
+            .is_some_and(|encoding| encoding.trim().eq_ignore_ascii_case("chunked"));
+
+        if chunked {
+            return Some(Self::Chunked);
+        }
+
+        headers
+            .get(CONTENT_LENGTH)
+            .and_then(|value| value.to_str().ok()?.parse().ok())
+            .map(Self::ContentLength)
+    }
+}
+
+/// A frame of a body, as it was received by the proxy.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum BodyFrame<'a> {
+    /// A chunk of data.
+    Data(&'a Bytes),
+    /// Trailers sent after the last chunk of data.
+    Trailers(&'a HeaderMap),
+    /// The body has ended.
+    End,
+}
+
+/// Observe the frames of a body as they are forwarded, without collapsing them.
+///
+/// Each chunk of data is passed to the callback and forwarded as its own chunk, followed by any
+/// trailers. This is lower level than reading the body as bytes, and together with [`Framing`]
+/// allows chunk boundaries to be analyzed.
+///
+/// Hyper doesn't expose everything that was on the wire. For chunked bodies, each chunk of data is
+/// never merged with another chunk, but a large chunk may be split into several chunks of data if
+/// it was received over multiple reads. Chunk extensions and the size lines are not available. For
+/// other bodies, chunks of data reflect how the body was read from the connection.
+///
+/// The body is forwarded by a spawned task, so this must be called from within a Tokio runtime.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{
+///     async_trait::async_trait,
+///     hyper::{Body, Request},
+///     tap_body, BodyFrame, Framing, HttpContext, HttpHandler, RequestOrResponse,
+/// };
+/// use tracing::info;
+///
+/// #[derive(Clone)]
+/// pub struct TapHandler;
+///
+/// #[async_trait]
+/// impl HttpHandler for TapHandler {
+///     async fn handle_request(&mut self, _ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+///         let framing = Framing::of_request(&req);
+///         let (parts, body) = req.into_parts();
+///         let body = tap_body(body, move |frame| match frame {
+///             BodyFrame::Data(data) => info!("{:?} body chunk of {} bytes", framing, data.len()),
+///             _ => (),
+///         });
+///
+///         Request::from_parts(parts, body).into()
+///     }
+/// }
+/// ```
+pub fn tap_body<F>(mut body: Body, mut on_frame: F) -> Body
+where
+    F: FnMut(BodyFrame<'_>) + Send + 'static,
+{
+    let (mut sender, tapped) = Body::channel();
+
+    tokio::spawn(async move {
+        while let Some(data) = body.data().await {
+            let data = match data {
+                Ok(data) => data,
+                Err(e) => {
+                    error!("Failed to read body: {}", e);
+                    sender.abort();
+                    return;
+                }
+            };
+
+            on_frame(BodyFrame::Data(&data));
+
+            if sender.send_data(data).await.is_err() {
+                return;
+            }
+        }
+
+        match body.trailers().await {
+            Ok(Some(trailers)) => {
+                on_frame(BodyFrame::Trailers(&trailers));
+
+                if sender.send_trailers(trailers).await.is_err() {
+                    return;
+                }
+            }
+            Ok(None) => (),
+            Err(e) => {
+                error!("Failed to read body trailers: {}", e);
+                sender.abort();
+                return;
+            }
+        }
+
+        on_frame(BodyFrame::End);
+    });
+
+    tapped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn detects_framing() {
+        let req = Request::builder()
+            .header(TRANSFER_ENCODING, "gzip, chunked")
+            .body(())
+            .unwrap();
+        assert_eq!(Framing::of_request(&req), Framing::Chunked);
+
+        let req = Request::builder()
+            .header(CONTENT_LENGTH, "5")
+            .body(())
+            .unwrap();
+        assert_eq!(Framing::of_request(&req), Framing::ContentLength(5));
+
+        let req = Request::builder().body(()).unwrap();
+        assert_eq!(Framing::of_request(&req), Framing::Empty);
+
+        let res = Response::builder().body(()).unwrap();
+        assert_eq!(Framing::of_response(&res), Framing::CloseDelimited);
+
+        let res = Response::builder()
+            .version(Version::HTTP_2)
+            .header(CONTENT_LENGTH, "5")
+            .body(())
+            .unwrap();
+        assert_eq!(Framing::of_response(&res), Framing::Http2);
+    }
+
+    #[tokio::test]
+    async fn keeps_frames() {
+        let (mut sender, body) = Body::channel();
+        let mut trailers = HeaderMap::new();
+        trailers.insert("x-checksum", "abc".parse().unwrap());
+
+        tokio::spawn(async move {
+            sender.send_data("hello, ".into()).await.unwrap();
+            sender.send_data("world".into()).await.unwrap();
+            sender.send_trailers(trailers).await.unwrap();
+        });
+
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let mut body = tap_body(body, {
+            let frames = Arc::clone(&frames);
+            move |frame| {
+                frames.lock().unwrap().push(match frame {
+                    BodyFrame::Data(data) => format!("data {:?}", data),
+                    BodyFrame::Trailers(trailers) => format!("trailers {}", trailers.len()),
+                    BodyFrame::End => "end".to_owned(),
+                })
+            }
+        });
+
+        assert_eq!(body.data().await.unwrap().unwrap(), "hello, ");
+        assert_eq!(body.data().await.unwrap().unwrap(), "world");
+        assert!(body.data().await.is_none());
+        assert_eq!(body.trailers().await.unwrap().unwrap()["x-checksum"], "abc");
+
+        assert_eq!(
+            *frames.lock().unwrap(),
+            vec!["data b\"hello, \"", "data b\"world\"", "trailers 1", "end"]
+        );
+    }
+}
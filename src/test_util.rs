@@ -0,0 +1,508 @@
+//! Utilities for testing handlers.
+
+use crate::{HttpContext, HttpHandler, RequestOrResponse};
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures::future::{self, Ready};
+use hyper::{
+    client::connect::{Connected, Connection},
+    header::HeaderMap,
+    server::conn::Http,
+    service::{service_fn, Service},
+    Body, Client, Method, Request, Response, StatusCode, Uri,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::Infallible,
+    io,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tracing::error;
+
+const BUFFER_SIZE: usize = 64 * 1024;
+const DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// A request received by a [`MockUpstream`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct RecordedRequest {
+    /// Method of the request.
+    pub method: Method,
+    /// URI of the request.
+    pub uri: Uri,
+    /// Headers of the request.
+    pub headers: HeaderMap,
+    /// Body of the request.
+    pub body: Bytes,
+}
+
+#[derive(Clone)]
+struct MockResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+#[derive(Default)]
+struct State {
+    responses: HashMap<(Method, String), MockResponse>,
+    requests: Vec<RecordedRequest>,
+}
+
+/// An in-memory upstream server that serves canned responses.
+///
+/// Responses are registered by method and path. Requests without a registered response receive a
+/// `404 Not Found`. All received requests are recorded so that tests can make assertions about
+/// them. Connections are made in memory, so no sockets are bound.
+///
+/// # Examples
+///
+/// ```rust
+/// # #[cfg(all(feature = "rcgen-ca", feature = "rustls-client"))]
+/// # {
+/// use hudsucker::{
+///     hyper::{Method, Response},
+///     test_util::MockUpstream,
+///     Proxy,
+/// };
+/// # use hudsucker::certificate_authority::RcgenAuthority;
+/// # use rustls_pemfile as pemfile;
+/// # use tokio_rustls::rustls;
+/// #
+/// # let mut private_key_bytes: &[u8] = include_bytes!("../examples/ca/hudsucker.key");
+/// # let mut ca_cert_bytes: &[u8] = include_bytes!("../examples/ca/hudsucker.cer");
+/// # let private_key = rustls::PrivateKey(
+/// #     pemfile::pkcs8_private_keys(&mut private_key_bytes)
+/// #         .expect("Failed to parse private key")
+/// #         .remove(0),
+/// # );
+/// # let ca_cert = rustls::Certificate(
+/// #     pemfile::certs(&mut ca_cert_bytes)
+/// #         .expect("Failed to parse CA certificate")
+/// #         .remove(0),
+/// # );
+/// #
+/// # let ca = RcgenAuthority::new(private_key, ca_cert, 1_000)
+/// #     .expect("Failed to create Certificate Authority");
+///
+/// let upstream = MockUpstream::new();
+/// upstream.mock(Method::GET, "/hello", Response::new("Hello, World!"));
+///
+/// // let ca = ...;
+///
+/// let proxy = Proxy::builder()
+///     .with_addr(std::net::SocketAddr::from(([127, 0, 0, 1], 0)))
+///     .with_client(upstream.client())
+///     .with_ca(ca)
+///     .build();
+/// # }
+/// ```
+#[cfg_attr(docsrs, doc(cfg(feature = "test-util")))]
+#[derive(Clone, Default)]
+pub struct MockUpstream {
+    state: Arc<Mutex<State>>,
+}
+
+impl MockUpstream {
+    /// Create a new mock upstream with no registered responses.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register the response to send for requests with the given method and path.
+    ///
+    /// Registering a response for the same method and path again replaces the previous response.
+    pub fn mock(
+        &self,
+        method: Method,
+        path: impl Into<String>,
+        response: Response<impl Into<Bytes>>,
+    ) {
+        let (parts, body) = response.into_parts();
+        let response = MockResponse {
+            status: parts.status,
+            headers: parts.headers,
+            body: body.into(),
+        };
+
+        self.state
+            .lock()
+            .expect("Failed to lock mock upstream state")
+            .responses
+            .insert((method, path.into()), response);
+    }
+
+    /// Requests received so far, in the order they were received.
+    pub fn requests(&self) -> Vec<RecordedRequest> {
+        self.state
+            .lock()
+            .expect("Failed to lock mock upstream state")
+            .requests
+            .clone()
+    }
+
+    /// Create a client that sends all requests to this mock upstream.
+    pub fn client(&self) -> Client<MockConnector> {
+        Client::builder().build(MockConnector {
+            upstream: self.clone(),
+        })
+    }
+
+    fn respond(&self, req: RecordedRequest) -> Response<Body> {
+        let mut state = self
+            .state
+            .lock()
+            .expect("Failed to lock mock upstream state");
+        let response = state
+            .responses
+            .get(&(req.method.clone(), req.uri.path().to_owned()))
+            .cloned();
+        state.requests.push(req);
+
+        match response {
+            Some(MockResponse {
+                status,
+                headers,
+                body,
+            }) => {
+                let mut res = Response::new(Body::from(body));
+                *res.status_mut() = status;
+                *res.headers_mut() = headers;
+                res
+            }
+            None => Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::empty())
+                .expect("Failed to build response"),
+        }
+    }
+
+    async fn serve(self, req: Request<Body>) -> Result<Response<Body>, hyper::Error> {
+        let (parts, body) = req.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+
+        Ok(self.respond(RecordedRequest {
+            method: parts.method,
+            uri: parts.uri,
+            headers: parts.headers,
+            body,
+        }))
+    }
+}
+
+/// A connector that connects to a [`MockUpstream`].
+///
+/// This is created with [`MockUpstream::client`].
+#[cfg_attr(docsrs, doc(cfg(feature = "test-util")))]
+#[derive(Clone)]
+pub struct MockConnector {
+    upstream: MockUpstream,
+}
+
+impl Service<Uri> for MockConnector {
+    type Response = MockStream;
+    type Error = Infallible;
+    type Future = Ready<Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _uri: Uri) -> Self::Future {
+        let (client, server) = tokio::io::duplex(BUFFER_SIZE);
+        let upstream = self.upstream.clone();
+
+        tokio::spawn(async move {
+            let service = service_fn(move |req| upstream.clone().serve(req));
+
+            if let Err(e) = Http::new().serve_connection(server, service).await {
+                error!("Mock upstream connection error: {}", e);
+            }
+        });
+
+        future::ready(Ok(MockStream(client)))
+    }
+}
+
+/// An in-memory connection to a [`MockUpstream`].
+#[cfg_attr(docsrs, doc(cfg(feature = "test-util")))]
+#[derive(Debug)]
+pub struct MockStream(DuplexStream);
+
+impl Connection for MockStream {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+impl AsyncRead for MockStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MockStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+/// A request and its response, recorded by a [`RecordingHandler`].
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct RecordedExchange {
+    /// Method of the request.
+    pub method: Method,
+    /// URI of the request.
+    pub uri: Uri,
+    /// Headers of the request.
+    pub request_headers: HeaderMap,
+    /// Body of the request, truncated to the maximum body size.
+    pub request_body: Bytes,
+    /// Status of the response.
+    pub status: StatusCode,
+    /// Headers of the response.
+    pub response_headers: HeaderMap,
+    /// Body of the response, truncated to the maximum body size.
+    pub response_body: Bytes,
+}
+
+#[derive(Clone)]
+struct PendingRequest {
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+/// A handler that records the most recent requests and responses passing through the proxy, so
+/// that tests can make assertions about them.
+///
+/// Clones of the handler share the recorded exchanges, so a clone can be passed to the proxy and
+/// queried from the test. Once the capacity is reached, the oldest exchanges are dropped. Bodies
+/// are read in full before being forwarded, and recorded up to the maximum body size. Responses
+/// that are returned by the proxy itself, such as for failed requests, aren't recorded.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::test_util::RecordingHandler;
+///
+/// let recorder = RecordingHandler::new(10).with_max_body_size(1024);
+/// // let proxy = Proxy::builder()...with_http_handler(recorder.clone()).build();
+///
+/// // After sending requests through the proxy:
+/// for exchange in recorder.exchanges() {
+///     println!("{} {} -> {}", exchange.method, exchange.uri, exchange.status);
+/// }
+/// ```
+#[cfg_attr(docsrs, doc(cfg(feature = "test-util")))]
+#[derive(Clone)]
+pub struct RecordingHandler {
+    exchanges: Arc<Mutex<VecDeque<RecordedExchange>>>,
+    capacity: usize,
+    max_body_size: usize,
+    pending: Option<PendingRequest>,
+}
+
+impl RecordingHandler {
+    /// Create a handler that keeps up to `capacity` exchanges, with bodies truncated to 64 KiB.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            exchanges: Arc::default(),
+            capacity,
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            pending: None,
+        }
+    }
+
+    /// Set the number of bytes of each body that are recorded. Defaults to 64 KiB.
+    pub fn with_max_body_size(mut self, max_body_size: usize) -> Self {
+        self.max_body_size = max_body_size;
+        self
+    }
+
+    /// The recorded exchanges, from oldest to newest.
+    pub fn exchanges(&self) -> Vec<RecordedExchange> {
+        self.lock().iter().cloned().collect()
+    }
+
+    /// The most recently recorded exchange.
+    pub fn last(&self) -> Option<RecordedExchange> {
+        self.lock().back().cloned()
+    }
+
+    /// The number of recorded exchanges.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Whether no exchanges have been recorded.
+    pub fn is_empty(&self) -> bool {
+        self.lock().is_empty()
+    }
+
+    /// Remove all recorded exchanges.
+    pub fn clear(&self) {
+        self.lock().clear();
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, VecDeque<RecordedExchange>> {
+        self.exchanges
+            .lock()
+            .expect("Failed to lock recorded exchanges")
+    }
+
+    fn truncate(&self, body: &Bytes) -> Bytes {
+        body.slice(..body.len().min(self.max_body_size))
+    }
+}
+
+#[async_trait]
+impl HttpHandler for RecordingHandler {
+    async fn handle_request(
+        &mut self,
+        _ctx: &HttpContext,
+        req: Request<Body>,
+    ) -> RequestOrResponse {
+        let (parts, body) = req.into_parts();
+        let body = match hyper::body::to_bytes(body).await {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Failed to read request body: {}", e);
+                return Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::empty())
+                    .expect("Failed to build response")
+                    .into();
+            }
+        };
+
+        self.pending = Some(PendingRequest {
+            method: parts.method.clone(),
+            uri: parts.uri.clone(),
+            headers: parts.headers.clone(),
+            body: self.truncate(&body),
+        });
+
+        Request::from_parts(parts, Body::from(body)).into()
+    }
+
+    async fn handle_response(&mut self, _ctx: &HttpContext, res: Response<Body>) -> Response<Body> {
+        let (parts, body) = res.into_parts();
+        let body = match hyper::body::to_bytes(body).await {
+            Ok(body) => body,
+            Err(e) => {
+                error!("Failed to read response body: {}", e);
+                return Response::builder()
+                    .status(StatusCode::BAD_GATEWAY)
+                    .body(Body::empty())
+                    .expect("Failed to build response");
+            }
+        };
+
+        if let Some(req) = self.pending.take() {
+            let exchange = RecordedExchange {
+                method: req.method,
+                uri: req.uri,
+                request_headers: req.headers,
+                request_body: req.body,
+                status: parts.status,
+                response_headers: parts.headers.clone(),
+                response_body: self.truncate(&body),
+            };
+
+            let mut exchanges = self.lock();
+            exchanges.push_back(exchange);
+            while exchanges.len() > self.capacity {
+                exchanges.pop_front();
+            }
+        }
+
+        Response::from_parts(parts, Body::from(body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn serves_mocked_responses() {
+        let upstream = MockUpstream::new();
+        upstream.mock(
+            Method::GET,
+            "/hello",
+            Response::builder()
+                .header("x-mock", "true")
+                .body("Hello, World!")
+                .unwrap(),
+        );
+
+        let res = upstream
+            .client()
+            .get("http://example.com/hello".parse().unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["x-mock"], "true");
+        assert_eq!(
+            hyper::body::to_bytes(res.into_body()).await.unwrap(),
+            "Hello, World!"
+        );
+    }
+
+    #[tokio::test]
+    async fn not_found_for_unmocked_requests() {
+        let upstream = MockUpstream::new();
+        upstream.mock(Method::GET, "/hello", Response::new("Hello, World!"));
+
+        let res = upstream
+            .client()
+            .get("http://example.com/missing".parse().unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn records_requests() {
+        let upstream = MockUpstream::new();
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("http://example.com/echo")
+            .header("x-test", "foo")
+            .body(Body::from("bar"))
+            .unwrap();
+
+        upstream.client().request(req).await.unwrap();
+
+        let requests = upstream.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, Method::POST);
+        assert_eq!(requests[0].uri.path(), "/echo");
+        assert_eq!(requests[0].headers["x-test"], "foo");
+        assert_eq!(requests[0].body, "bar");
+    }
+}
@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+/// A request extension that limits how long to wait for the upstream server to respond to the
+/// request.
+///
+/// Handlers can insert this into the extensions of a request in
+/// [`HttpHandler::handle_request`](crate::HttpHandler::handle_request), so that slow endpoints can
+/// be given more time than fast ones. The timeout covers connecting to the upstream server and
+/// receiving the response head, including any retries, but not reading the response body. If it
+/// elapses, the request fails with [`ProxyError::Timeout`](crate::ProxyError::Timeout), which by
+/// default responds with `504 Gateway Timeout`. Requests without it wait for as long as the client
+/// allows.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{
+///     async_trait::async_trait,
+///     hyper::{Body, Request},
+///     HttpContext, HttpHandler, RequestOrResponse, UpstreamTimeout,
+/// };
+/// use std::time::Duration;
+///
+/// #[derive(Clone)]
+/// pub struct MyHandler;
+///
+/// #[async_trait]
+/// impl HttpHandler for MyHandler {
+///     async fn handle_request(
+///         &mut self,
+///         _ctx: &HttpContext,
+///         mut req: Request<Body>,
+///     ) -> RequestOrResponse {
+///         let timeout = if req.uri().path().starts_with("/reports") {
+///             Duration::from_secs(60)
+///         } else {
+///             Duration::from_secs(5)
+///         };
+///         req.extensions_mut().insert(UpstreamTimeout(timeout));
+///
+///         req.into()
+///     }
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct UpstreamTimeout(pub Duration);
@@ -0,0 +1,199 @@
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio_rustls::rustls::AlertDescription;
+
+/// A fault injected into the TLS handshake with a client.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum TlsFault {
+    /// Present a certificate that has already expired.
+    ExpiredCertificate,
+    /// Present a certificate issued for a different host.
+    WrongHostCertificate,
+    /// Abort the handshake by sending a fatal alert in response to the client hello.
+    Alert(AlertDescription),
+}
+
+/// Injects faults into a fraction of the TLS handshakes of intercepted connections, for testing
+/// how clients cope with them.
+///
+/// Faults are chosen using a random number generator created from a seed, so the same sequence of
+/// faults is injected each time for the same seed and order of connections. Certificate faults
+/// require support from the certificate authority, see
+/// [`CertificateAuthority::gen_faulty_server_config`].
+///
+/// [`CertificateAuthority::gen_faulty_server_config`]:
+/// crate::certificate_authority::CertificateAuthority::gen_faulty_server_config
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{rustls::AlertDescription, TlsFault, TlsFaults};
+///
+/// let faults = TlsFaults::new(42)
+///     .with_fault(TlsFault::ExpiredCertificate, 0.1)
+///     .with_fault(TlsFault::Alert(AlertDescription::HandshakeFailure), 0.05);
+/// ```
+#[derive(Clone, Debug)]
+pub struct TlsFaults {
+    faults: Vec<(TlsFault, f64)>,
+    rng: Arc<Mutex<StdRng>>,
+}
+
+impl TlsFaults {
+    /// Create a new set of faults, without any faults, using the given seed.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            faults: Vec::new(),
+            rng: Arc::new(Mutex::new(StdRng::seed_from_u64(seed))),
+        }
+    }
+
+    /// Inject a fault into the given fraction of handshakes, between `0.0` and `1.0`. The
+    /// fractions of all faults should add up to at most `1.0`.
+    pub fn with_fault(mut self, fault: TlsFault, rate: f64) -> Self {
+        self.faults.push((fault, rate));
+        self
+    }
+
+    /// Choose the fault to inject into a handshake, if any.
+    pub fn choose(&self) -> Option<TlsFault> {
+        if self.faults.is_empty() {
+            return None;
+        }
+
+        let mut roll = self
+            .rng
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .gen::<f64>();
+
+        for &(fault, rate) in &self.faults {
+            if roll < rate {
+                return Some(fault);
+            }
+
+            roll -= rate;
+        }
+
+        None
+    }
+}
+
+/// A delay added before the TLS handshakes of intercepted connections, for simulating slow
+/// certificate generation or network latency.
+///
+/// The delay is either fixed, or chosen uniformly from a range using a random number generator
+/// created from a seed, so the same sequence of delays is used each time for the same seed and
+/// order of connections.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::HandshakeDelay;
+/// use std::time::Duration;
+///
+/// let fixed = HandshakeDelay::fixed(Duration::from_millis(200));
+/// let uniform = HandshakeDelay::uniform(42, Duration::from_millis(50), Duration::from_millis(500));
+/// ```
+#[derive(Clone, Debug)]
+pub struct HandshakeDelay {
+    min: Duration,
+    max: Duration,
+    rng: Arc<Mutex<StdRng>>,
+}
+
+impl HandshakeDelay {
+    /// Delay every handshake by the same duration.
+    pub fn fixed(delay: Duration) -> Self {
+        Self::uniform(0, delay, delay)
+    }
+
+    /// Delay each handshake by a duration chosen uniformly between `min` and `max`, inclusive,
+    /// using the given seed.
+    pub fn uniform(seed: u64, min: Duration, max: Duration) -> Self {
+        Self {
+            min,
+            max,
+            rng: Arc::new(Mutex::new(StdRng::seed_from_u64(seed))),
+        }
+    }
+
+    /// Choose the delay to add before a handshake.
+    pub fn sample(&self) -> Duration {
+        if self.min >= self.max {
+            return self.min;
+        }
+
+        self.rng
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .gen_range(self.min..=self.max)
+    }
+}
+
+/// Encode a fatal alert record, as sent before the handshake has encrypted anything.
+pub(crate) fn alert_record(alert: AlertDescription) -> [u8; 7] {
+    [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, alert.get_u8()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repeats_faults_for_seed() {
+        let faults = || {
+            TlsFaults::new(7)
+                .with_fault(TlsFault::ExpiredCertificate, 0.3)
+                .with_fault(TlsFault::WrongHostCertificate, 0.3)
+        };
+        let (a, b) = (faults(), faults());
+
+        let a: Vec<_> = (0..100).map(|_| a.choose()).collect();
+        let b: Vec<_> = (0..100).map(|_| b.choose()).collect();
+
+        assert_eq!(a, b);
+        assert!(a.contains(&Some(TlsFault::ExpiredCertificate)));
+        assert!(a.contains(&Some(TlsFault::WrongHostCertificate)));
+        assert!(a.contains(&None));
+    }
+
+    #[test]
+    fn respects_rates() {
+        let always = TlsFaults::new(0).with_fault(TlsFault::ExpiredCertificate, 1.0);
+        let never = TlsFaults::new(0).with_fault(TlsFault::ExpiredCertificate, 0.0);
+
+        for _ in 0..100 {
+            assert_eq!(always.choose(), Some(TlsFault::ExpiredCertificate));
+            assert_eq!(never.choose(), None);
+        }
+    }
+
+    #[test]
+    fn samples_delays_in_range() {
+        let fixed = HandshakeDelay::fixed(Duration::from_millis(100));
+        assert_eq!(fixed.sample(), Duration::from_millis(100));
+
+        let (min, max) = (Duration::from_millis(10), Duration::from_millis(20));
+        let a = HandshakeDelay::uniform(3, min, max);
+        let b = HandshakeDelay::uniform(3, min, max);
+
+        for _ in 0..100 {
+            let delay = a.sample();
+            assert!(delay >= min && delay <= max);
+            assert_eq!(delay, b.sample());
+        }
+    }
+
+    #[test]
+    fn encodes_alert() {
+        assert_eq!(
+            alert_record(AlertDescription::HandshakeFailure),
+            [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, 40]
+        );
+    }
+}
@@ -0,0 +1,412 @@
+#[cfg(feature = "decoder")]
+use crate::decoder::{decode_body, encode_body, extract_encodings, is_supported_encoding};
+use crate::{
+    body::{collect_with_limit, BodyError},
+    Error,
+};
+use async_trait::async_trait;
+use bstr::ByteSlice;
+use bytes::Bytes;
+#[cfg(feature = "decoder")]
+use hyper::header::{HeaderValue, CONTENT_ENCODING};
+use hyper::{
+    body::HttpBody,
+    header::{HeaderMap, CONTENT_LENGTH, TRANSFER_ENCODING},
+    http::Extensions,
+    Body,
+};
+use std::{fmt, sync::Arc};
+use tracing::debug;
+
+const DEFAULT_MAX_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// A step of a [`Pipeline`] that transforms the body of a request or response.
+///
+/// Transforms are either streaming, and transform the body as it is received, or buffering, and
+/// need the whole body at once. The pipeline buffers the body before each buffering transform, so
+/// they receive a body that has already been received in full.
+///
+/// The headers describe the body passed to the transform, and should be updated to describe the
+/// body it returns, except for `content-length` and `transfer-encoding`, which are set by the
+/// pipeline. The extensions of the request or response can be used to pass state to later
+/// transforms.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{
+///     async_trait::async_trait,
+///     hyper::{header::HeaderMap, http::Extensions, Body},
+///     BodyTransform, Error,
+/// };
+///
+/// struct Uppercase;
+///
+/// #[async_trait]
+/// impl BodyTransform for Uppercase {
+///     fn is_buffering(&self) -> bool {
+///         true
+///     }
+///
+///     async fn transform(
+///         &self,
+///         _headers: &mut HeaderMap,
+///         _extensions: &mut Extensions,
+///         body: Body,
+///     ) -> Result<Body, Error> {
+///         let body = hyper::body::to_bytes(body).await?;
+///         Ok(Body::from(body.to_ascii_uppercase()))
+///     }
+/// }
+/// ```
+#[async_trait]
+pub trait BodyTransform: Send + Sync + 'static {
+    /// Whether the transform needs the whole body at once. Defaults to `false`.
+    fn is_buffering(&self) -> bool {
+        false
+    }
+
+    /// Transform a body.
+    async fn transform(
+        &self,
+        headers: &mut HeaderMap,
+        extensions: &mut Extensions,
+        body: Body,
+    ) -> Result<Body, Error>;
+}
+
+/// A sequence of [`BodyTransform`]s that are applied to bodies in order, set with
+/// [`ProxyBuilder::with_request_pipeline`] and [`ProxyBuilder::with_response_pipeline`].
+///
+/// Bodies larger than the maximum buffer size aren't passed to buffering transforms, which are
+/// skipped, so the rest of the pipeline still applies. Once all transforms have been applied, the
+/// `content-length` header is set if the length of the body is known, or removed otherwise.
+///
+/// [`ProxyBuilder::with_request_pipeline`]: crate::ProxyBuilder::with_request_pipeline
+/// [`ProxyBuilder::with_response_pipeline`]: crate::ProxyBuilder::with_response_pipeline
+///
+/// # Examples
+///
+/// ```rust
+/// # #[cfg(feature = "decoder")]
+/// # {
+/// use hudsucker::{Decompress, Pipeline, Recompress, Replace};
+///
+/// let pipeline = Pipeline::new()
+///     .with_transform(Decompress)
+///     .with_transform(Replace::new().replace("http://", "https://"))
+///     .with_transform(Recompress)
+///     .with_max_buffer_size(512 * 1024);
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct Pipeline {
+    transforms: Arc<Vec<Arc<dyn BodyTransform>>>,
+    max_buffer_size: usize,
+}
+
+impl Pipeline {
+    /// Create a new pipeline, without any transforms. The maximum buffer size defaults to 1 MiB.
+    pub fn new() -> Self {
+        Self {
+            transforms: Arc::new(Vec::new()),
+            max_buffer_size: DEFAULT_MAX_BUFFER_SIZE,
+        }
+    }
+
+    /// Add a transform to the end of the pipeline.
+    pub fn with_transform(mut self, transform: impl BodyTransform) -> Self {
+        Arc::make_mut(&mut self.transforms).push(Arc::new(transform));
+        self
+    }
+
+    /// Set the maximum size in bytes of a body that is buffered for buffering transforms.
+    pub fn with_max_buffer_size(mut self, max_buffer_size: usize) -> Self {
+        self.max_buffer_size = max_buffer_size;
+        self
+    }
+
+    /// Apply the transforms to a body, updating the headers to describe the transformed body.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if one of the transforms fails, or if the body can't be read.
+    pub async fn apply(
+        &self,
+        headers: &mut HeaderMap,
+        extensions: &mut Extensions,
+        mut body: Body,
+    ) -> Result<Body, Error> {
+        if self.transforms.is_empty() {
+            return Ok(body);
+        }
+
+        for transform in self.transforms.iter() {
+            if transform.is_buffering() {
+                match collect_with_limit(body, self.max_buffer_size).await {
+                    Ok(buffered) => body = Body::from(buffered),
+                    Err(BodyError::TooLarge { body: streamed, .. }) => {
+                        debug!("Body exceeds the maximum buffer size, skipping transform");
+                        body = streamed;
+                        continue;
+                    }
+                    Err(BodyError::Network(e)) => return Err(e.into()),
+                }
+            }
+
+            body = transform.transform(headers, extensions, body).await?;
+        }
+
+        headers.remove(TRANSFER_ENCODING);
+        match body.size_hint().exact() {
+            Some(len) => headers.insert(CONTENT_LENGTH, len.into()),
+            None => headers.remove(CONTENT_LENGTH),
+        };
+
+        Ok(body)
+    }
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Debug for Pipeline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pipeline")
+            .field("transforms", &self.transforms.len())
+            .field("max_buffer_size", &self.max_buffer_size)
+            .finish()
+    }
+}
+
+/// A buffering transform that finds and replaces bytes in bodies.
+///
+/// Replacements are applied in the order they were added. Bodies should be decoded first, for
+/// example with [`Decompress`].
+#[derive(Clone, Debug, Default)]
+pub struct Replace {
+    replacements: Arc<Vec<(Bytes, Bytes)>>,
+}
+
+impl Replace {
+    /// Create a new transform, without any replacements.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a replacement of all occurrences of `find` with `replace`. Empty patterns are ignored.
+    pub fn replace(mut self, find: impl Into<Bytes>, replace: impl Into<Bytes>) -> Self {
+        let find = find.into();
+
+        if !find.is_empty() {
+            Arc::make_mut(&mut self.replacements).push((find, replace.into()));
+        }
+
+        self
+    }
+
+    #[cfg(feature = "decoder")]
+    pub(crate) fn is_empty(&self) -> bool {
+        self.replacements.is_empty()
+    }
+}
+
+#[async_trait]
+impl BodyTransform for Replace {
+    fn is_buffering(&self) -> bool {
+        true
+    }
+
+    async fn transform(
+        &self,
+        _headers: &mut HeaderMap,
+        _extensions: &mut Extensions,
+        body: Body,
+    ) -> Result<Body, Error> {
+        let mut body = hyper::body::to_bytes(body).await?.to_vec();
+
+        for (find, replace) in self.replacements.iter() {
+            body = body.replace(find, replace);
+        }
+
+        Ok(Body::from(body))
+    }
+}
+
+/// The encodings removed by [`Decompress`], which [`Recompress`] applies again.
+#[cfg(feature = "decoder")]
+#[derive(Clone, Debug)]
+struct RemovedEncodings(Vec<HeaderValue>);
+
+/// A streaming transform that decodes bodies with a supported `content-encoding`, removing the
+/// header. Bodies with unsupported encodings are left as is.
+#[cfg(feature = "decoder")]
+#[cfg_attr(docsrs, doc(cfg(feature = "decoder")))]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Decompress;
+
+#[cfg(feature = "decoder")]
+#[async_trait]
+impl BodyTransform for Decompress {
+    async fn transform(
+        &self,
+        headers: &mut HeaderMap,
+        extensions: &mut Extensions,
+        body: Body,
+    ) -> Result<Body, Error> {
+        if !headers.contains_key(CONTENT_ENCODING)
+            || !extract_encodings(headers).all(is_supported_encoding)
+        {
+            return Ok(body);
+        }
+
+        let body = decode_body(extract_encodings(headers), body)?;
+        let encodings = headers.get_all(CONTENT_ENCODING).iter().cloned().collect();
+        headers.remove(CONTENT_ENCODING);
+        extensions.insert(RemovedEncodings(encodings));
+
+        Ok(body)
+    }
+}
+
+/// A buffering transform that encodes bodies again with the encodings removed by an earlier
+/// [`Decompress`], restoring the `content-encoding` header. Bodies that weren't decoded, or that
+/// an earlier transform set a `content-encoding` for, are left as is.
+#[cfg(feature = "decoder")]
+#[cfg_attr(docsrs, doc(cfg(feature = "decoder")))]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Recompress;
+
+#[cfg(feature = "decoder")]
+#[async_trait]
+impl BodyTransform for Recompress {
+    fn is_buffering(&self) -> bool {
+        true
+    }
+
+    async fn transform(
+        &self,
+        headers: &mut HeaderMap,
+        extensions: &mut Extensions,
+        body: Body,
+    ) -> Result<Body, Error> {
+        let Some(RemovedEncodings(encodings)) = extensions.remove() else {
+            return Ok(body);
+        };
+
+        if headers.contains_key(CONTENT_ENCODING) {
+            return Ok(body);
+        }
+
+        let body = hyper::body::to_bytes(body).await?;
+        let body = encode_body(&encodings, body.to_vec()).await;
+        for value in encodings {
+            headers.append(CONTENT_ENCODING, value);
+        }
+
+        Ok(Body::from(body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::stream;
+    use hyper::body::to_bytes;
+
+    fn chunked(chunks: &'static [&'static str]) -> Body {
+        Body::wrap_stream(stream::iter(
+            chunks.iter().map(|&chunk| Ok::<_, std::io::Error>(chunk)),
+        ))
+    }
+
+    #[tokio::test]
+    async fn buffers_for_buffering_transforms() {
+        let pipeline = Pipeline::new().with_transform(Replace::new().replace("lo wo", "lo, wo"));
+        let mut headers = HeaderMap::new();
+        headers.insert(TRANSFER_ENCODING, "chunked".parse().unwrap());
+
+        let body = pipeline
+            .apply(
+                &mut headers,
+                &mut Extensions::new(),
+                chunked(&["hel", "lo w", "orld"]),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(to_bytes(body).await.unwrap(), "hello, world");
+        assert_eq!(headers[CONTENT_LENGTH], "12");
+        assert!(!headers.contains_key(TRANSFER_ENCODING));
+    }
+
+    #[tokio::test]
+    async fn skips_buffering_transforms_for_large_bodies() {
+        let pipeline = Pipeline::new()
+            .with_transform(Replace::new().replace("a", "b"))
+            .with_max_buffer_size(4);
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_LENGTH, "6".parse().unwrap());
+
+        let body = pipeline
+            .apply(
+                &mut headers,
+                &mut Extensions::new(),
+                chunked(&["aaa", "aaa"]),
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(to_bytes(body).await.unwrap(), "aaaaaa");
+        assert!(!headers.contains_key(CONTENT_LENGTH));
+    }
+
+    #[cfg(feature = "decoder")]
+    #[tokio::test]
+    async fn composes_decompress_replace_recompress() {
+        let gzip = [HeaderValue::from_static("gzip")];
+        let pipeline = Pipeline::new()
+            .with_transform(Decompress)
+            .with_transform(Replace::new().replace("http://", "https://"))
+            .with_transform(Recompress);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(CONTENT_ENCODING, gzip[0].clone());
+        let body = encode_body(&gzip, b"<a href=\"http://example.com\">".to_vec()).await;
+
+        let body = pipeline
+            .apply(&mut headers, &mut Extensions::new(), Body::from(body))
+            .await
+            .unwrap();
+        let body = to_bytes(body).await.unwrap();
+
+        assert_eq!(headers[CONTENT_ENCODING], "gzip");
+        assert_eq!(headers[CONTENT_LENGTH], body.len().to_string().as_str());
+        assert_eq!(
+            to_bytes(decode_body([&b"gzip"[..]], Body::from(body)).unwrap())
+                .await
+                .unwrap(),
+            "<a href=\"https://example.com\">"
+        );
+    }
+
+    #[cfg(feature = "decoder")]
+    #[tokio::test]
+    async fn recompress_ignores_bodies_that_were_not_decoded() {
+        let pipeline = Pipeline::new()
+            .with_transform(Decompress)
+            .with_transform(Recompress);
+        let mut headers = HeaderMap::new();
+
+        let body = pipeline
+            .apply(&mut headers, &mut Extensions::new(), Body::from("plain"))
+            .await
+            .unwrap();
+
+        assert_eq!(to_bytes(body).await.unwrap(), "plain");
+        assert!(!headers.contains_key(CONTENT_ENCODING));
+    }
+}
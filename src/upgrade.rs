@@ -0,0 +1,81 @@
+use futures::future::BoxFuture;
+use hyper::{
+    upgrade::{OnUpgrade, Upgraded},
+    Body, Request, Response,
+};
+use std::{fmt, future::Future};
+
+/// A response that upgrades the client connection to a custom protocol.
+///
+/// The response is sent to the client, after which the upgraded connection is passed to the
+/// provided handler. The response should have a status of `101 Switching Protocols`.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{
+///     async_trait::async_trait,
+///     hyper::{header, Body, Request, Response, StatusCode},
+///     HttpContext, HttpHandler, RequestOrResponse, Upgrade,
+/// };
+///
+/// #[derive(Clone)]
+/// pub struct EchoHandler;
+///
+/// #[async_trait]
+/// impl HttpHandler for EchoHandler {
+///     async fn handle_request(
+///         &mut self,
+///         _ctx: &HttpContext,
+///         mut req: Request<Body>,
+///     ) -> RequestOrResponse {
+///         if req.headers().get(header::UPGRADE) != Some(&"echo".parse().unwrap()) {
+///             return req.into();
+///         }
+///
+///         let res = Response::builder()
+///             .status(StatusCode::SWITCHING_PROTOCOLS)
+///             .header(header::CONNECTION, "upgrade")
+///             .header(header::UPGRADE, "echo")
+///             .body(Body::empty())
+///             .unwrap();
+///
+///         Upgrade::new(&mut req, res, |upgraded| async move {
+///             let (mut reader, mut writer) = tokio::io::split(upgraded);
+///             let _ = tokio::io::copy(&mut reader, &mut writer).await;
+///         })
+///         .into()
+///     }
+/// }
+/// ```
+pub struct Upgrade {
+    pub(crate) response: Response<Body>,
+    pub(crate) on_upgrade: OnUpgrade,
+    pub(crate) handler: Box<dyn FnOnce(Upgraded) -> BoxFuture<'static, ()> + Send>,
+}
+
+impl Upgrade {
+    /// Create a new upgrade for the given request.
+    ///
+    /// The handler is called with the upgraded connection once the response has been sent to the
+    /// client.
+    pub fn new<F, Fut>(req: &mut Request<Body>, response: Response<Body>, handler: F) -> Self
+    where
+        F: FnOnce(Upgraded) -> Fut + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        Self {
+            response,
+            on_upgrade: hyper::upgrade::on(req),
+            handler: Box::new(move |upgraded| Box::pin(handler(upgraded))),
+        }
+    }
+}
+
+impl fmt::Debug for Upgrade {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Upgrade")
+            .field("response", &self.response)
+            .finish_non_exhaustive()
+    }
+}
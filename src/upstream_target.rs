@@ -0,0 +1,356 @@
+use hyper::{
+    header::{HeaderValue, HOST},
+    http::uri::{Authority, Scheme},
+    service::Service,
+    Request, Uri,
+};
+use std::{
+    future::{poll_fn, Future},
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tracing::{debug, warn};
+
+tokio::task_local! {
+    /// Where the client connects while sending a request, see [`UpstreamTargetConnector`].
+    static CONNECT_TO: ConnectTo;
+}
+
+/// Where to connect for a request, carried in its extensions until it is sent. The request keeps
+/// its URI, so that the host is all that the server sees.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub(crate) enum ConnectTo {
+    /// The address set with [`UpstreamTarget::connect_addr`].
+    Addr(SocketAddr),
+    /// The first address the host was already resolved to, so that it isn't resolved again. This
+    /// is where the client would connect anyway, so connections to it are shared with other
+    /// requests to the host. The host is resolved again if connecting to it fails, so that its
+    /// other addresses are tried.
+    Resolved(SocketAddr),
+}
+
+/// Send a request with a client, making any new connection where `to` says, instead of to the
+/// host of the request, if it is given.
+///
+/// Connectors are only passed the URI of the request, so the address is set for the task while
+/// the client is polled, which is when it calls its connector.
+pub(crate) async fn connect_to<F: Future>(to: Option<ConnectTo>, fut: F) -> F::Output {
+    match to {
+        Some(to) => CONNECT_TO.scope(to, fut).await,
+        None => fut.await,
+    }
+}
+
+/// Overrides where a request is sent upstream, returned by
+/// [`HttpHandler::upstream_target`](crate::HttpHandler::upstream_target).
+///
+/// Each override can be used on its own. Overriding the host used for SNI or the address to
+/// connect to keeps the `Host` header of the request, unless it is overridden as well.
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::UpstreamTarget;
+/// use std::net::SocketAddr;
+///
+/// // Send requests to a staging server, as if they were sent to the real one.
+/// let target = UpstreamTarget {
+///     connect_addr: Some(SocketAddr::from(([10, 0, 0, 5], 443))),
+///     ..UpstreamTarget::default()
+/// };
+/// ```
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct UpstreamTarget {
+    /// The host to connect to over TLS, which is sent with SNI and checked against the
+    /// certificate of the server. It is also resolved to find the address to connect to, unless
+    /// [`UpstreamTarget::connect_addr`] is set.
+    pub host_for_sni: Option<String>,
+    /// The address to connect to, instead of resolving the host of the request.
+    ///
+    /// This is only used by clients built on an [`UpstreamTargetConnector`], such as the ones
+    /// created by [`ProxyBuilder::with_rustls_client`](crate::ProxyBuilder::with_rustls_client)
+    /// and [`ProxyBuilder::with_native_tls_client`](crate::ProxyBuilder), and by tunnels that
+    /// aren't intercepted. The built-in clients send these requests on connections of their own,
+    /// which aren't shared with other requests. Clients given with
+    /// [`ProxyBuilder::with_client`](crate::ProxyBuilder::with_client) pool connections by host,
+    /// so they should be built with pooling disabled if requests with different addresses are
+    /// sent to the same host.
+    pub connect_addr: Option<SocketAddr>,
+    /// The `Host` header to send.
+    pub host_header: Option<HeaderValue>,
+}
+
+impl UpstreamTarget {
+    /// Apply the overrides to a request that is about to be forwarded.
+    pub(crate) fn apply<T>(self, req: &mut Request<T>) {
+        let Some(authority) = req.uri().authority().cloned() else {
+            return;
+        };
+
+        if let Some(addr) = self.connect_addr {
+            req.extensions_mut().insert(ConnectTo::Addr(addr));
+        }
+
+        let rewrite = self.host_for_sni.is_some();
+        if rewrite {
+            let uri = match target_uri(req.uri(), &authority, &self) {
+                Ok(uri) => uri,
+                Err(e) => {
+                    warn!("Invalid upstream target {:?}: {}", self, e);
+                    return;
+                }
+            };
+            *req.uri_mut() = uri;
+        }
+
+        let host_header = match self.host_header {
+            Some(host_header) => Some(host_header),
+            None if rewrite => HeaderValue::from_str(&host(&authority, req.uri().scheme())).ok(),
+            None => None,
+        };
+        if let Some(host_header) = host_header {
+            req.headers_mut().insert(HOST, host_header);
+        }
+    }
+}
+
+/// The `Host` header for an authority, which leaves out the port if it is the default for the
+/// scheme, as hyper does.
+fn host(authority: &Authority, scheme: Option<&Scheme>) -> String {
+    let default_port = match scheme {
+        Some(scheme) if *scheme == Scheme::HTTPS => Some(443),
+        Some(scheme) if *scheme == Scheme::HTTP => Some(80),
+        _ => None,
+    };
+
+    match authority.port_u16() {
+        Some(port) if Some(port) != default_port => format!("{}:{}", authority.host(), port),
+        _ => authority.host().to_owned(),
+    }
+}
+
+/// Build the URI passed to the client. The host for SNI replaces the host of the URI, since TLS
+/// connectors take the server name from it.
+fn target_uri(
+    uri: &Uri,
+    authority: &Authority,
+    target: &UpstreamTarget,
+) -> Result<Uri, hyper::http::Error> {
+    let mut target_authority = target
+        .host_for_sni
+        .as_deref()
+        .unwrap_or(authority.host())
+        .to_owned();
+
+    if let Some(port) = authority.port() {
+        target_authority.push(':');
+        target_authority.push_str(port.as_str());
+    }
+
+    let mut parts = uri.clone().into_parts();
+    parts.authority = Some(Authority::try_from(target_authority)?);
+    Ok(Uri::from_parts(parts)?)
+}
+
+/// A connector that connects to the address set with [`UpstreamTarget::connect_addr`], instead of
+/// the host of the request.
+///
+/// The address is passed to the connector by the proxy while it sends the request, and isn't part
+/// of the URI of the request, so it isn't sent to the server. The proxy also passes the address it
+/// already resolved the host to when checking for loops, so that the host isn't resolved again.
+///
+/// It should wrap the connector that makes TCP connections, such as a
+/// [`hyper::client::HttpConnector`], so that a TLS connector wrapping it still uses the host of the
+/// request as the server name. This is already done by the clients created by
+/// [`ProxyBuilder::with_rustls_client`](crate::ProxyBuilder::with_rustls_client) and
+/// [`ProxyBuilder::with_native_tls_client`](crate::ProxyBuilder).
+///
+/// # Examples
+///
+/// ```rust
+/// use hudsucker::{
+///     hyper::client::{Client, HttpConnector},
+///     UpstreamTargetConnector,
+/// };
+///
+/// let client: Client<_> = Client::builder().build(UpstreamTargetConnector::new(HttpConnector::new()));
+/// ```
+#[derive(Clone, Debug)]
+pub struct UpstreamTargetConnector<C>(C);
+
+impl<C> UpstreamTargetConnector<C> {
+    /// Wrap a connector, such as a [`hyper::client::HttpConnector`].
+    pub fn new(connector: C) -> Self {
+        Self(connector)
+    }
+}
+
+impl<C> Service<Uri> for UpstreamTargetConnector<C>
+where
+    C: Service<Uri> + Clone + Send + 'static,
+    C::Response: Send,
+    C::Error: Send,
+    C::Future: Send + 'static,
+{
+    type Response = C::Response;
+    type Error = C::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<C::Response, C::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        match CONNECT_TO.try_with(|to| *to).ok() {
+            Some(ConnectTo::Addr(addr)) => Box::pin(self.0.call(with_addr(&uri, addr))),
+            Some(ConnectTo::Resolved(addr)) => {
+                let connecting = self.0.call(with_addr(&uri, addr));
+                let mut connector = self.0.clone();
+                Box::pin(async move {
+                    match connecting.await {
+                        Ok(conn) => Ok(conn),
+                        Err(_) => {
+                            debug!("Failed to connect to {}, resolving {} again", addr, uri);
+                            poll_fn(|cx| connector.poll_ready(cx)).await?;
+                            connector.call(uri).await
+                        }
+                    }
+                })
+            }
+            None => Box::pin(self.0.call(uri)),
+        }
+    }
+}
+
+/// Replace the authority of a URI with an address.
+fn with_addr(uri: &Uri, addr: SocketAddr) -> Uri {
+    let mut parts = uri.clone().into_parts();
+    parts.authority =
+        Some(Authority::try_from(addr.to_string()).expect("Failed to build authority"));
+    Uri::from_parts(parts).expect("Failed to build URI")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(target: UpstreamTarget) -> Request<()> {
+        let mut req = Request::builder()
+            .uri("https://example.com:443/path?query")
+            .body(())
+            .unwrap();
+        target.apply(&mut req);
+        req
+    }
+
+    #[test]
+    fn overrides_host_for_sni() {
+        let req = apply(UpstreamTarget {
+            host_for_sni: Some("sni.example.org".to_owned()),
+            ..UpstreamTarget::default()
+        });
+
+        assert_eq!(req.uri(), "https://sni.example.org:443/path?query");
+        assert_eq!(req.headers()[HOST], "example.com");
+    }
+
+    #[test]
+    fn overrides_connect_addr() {
+        let req = apply(UpstreamTarget {
+            connect_addr: Some(SocketAddr::from(([127, 0, 0, 1], 8443))),
+            ..UpstreamTarget::default()
+        });
+
+        assert_eq!(req.uri(), "https://example.com:443/path?query");
+        assert_eq!(
+            req.extensions().get(),
+            Some(&ConnectTo::Addr(SocketAddr::from(([127, 0, 0, 1], 8443))))
+        );
+        assert!(!req.headers().contains_key(HOST));
+    }
+
+    #[test]
+    fn overrides_host_header() {
+        let req = apply(UpstreamTarget {
+            host_header: Some(HeaderValue::from_static("other.example.com")),
+            ..UpstreamTarget::default()
+        });
+
+        assert_eq!(req.uri(), "https://example.com:443/path?query");
+        assert_eq!(req.headers()[HOST], "other.example.com");
+    }
+
+    /// A connector that yields the URI it was called with, and fails to connect to `refused`.
+    #[derive(Clone)]
+    struct UriConnector {
+        refused: Option<SocketAddr>,
+    }
+
+    impl Service<Uri> for UriConnector {
+        type Response = Uri;
+        type Error = Uri;
+        type Future = std::future::Ready<Result<Uri, Uri>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, uri: Uri) -> Self::Future {
+            match self.refused {
+                Some(addr) if uri.authority().unwrap().as_str() == addr.to_string() => {
+                    std::future::ready(Err(uri))
+                }
+                _ => std::future::ready(Ok(uri)),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn connects_to_addr() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 8443));
+        let mut connector = UpstreamTargetConnector::new(UriConnector { refused: None });
+        let uri = Uri::from_static("https://example.com/");
+
+        let connected = connect_to(Some(ConnectTo::Addr(addr)), async {
+            connector.call(uri.clone()).await
+        })
+        .await
+        .unwrap();
+        assert_eq!(connected, "https://127.0.0.1:8443/");
+
+        let connected = connect_to(None, connector.call(uri.clone())).await.unwrap();
+        assert_eq!(connected, uri);
+    }
+
+    #[tokio::test]
+    async fn resolves_again_if_resolved_addr_fails() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 443));
+        let uri = Uri::from_static("https://example.com/");
+
+        let mut connector = UpstreamTargetConnector::new(UriConnector { refused: None });
+        let connected = connect_to(Some(ConnectTo::Resolved(addr)), async {
+            connector.call(uri.clone()).await
+        })
+        .await
+        .unwrap();
+        assert_eq!(connected, "https://127.0.0.1:443/");
+
+        let mut connector = UpstreamTargetConnector::new(UriConnector {
+            refused: Some(addr),
+        });
+        let connected = connect_to(Some(ConnectTo::Resolved(addr)), async {
+            connector.call(uri.clone()).await
+        })
+        .await
+        .unwrap();
+        assert_eq!(connected, uri);
+
+        let err = connect_to(Some(ConnectTo::Addr(addr)), async {
+            connector.call(uri.clone()).await
+        })
+        .await
+        .unwrap_err();
+        assert_eq!(err, "https://127.0.0.1:443/");
+    }
+}
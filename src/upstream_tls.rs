@@ -0,0 +1,149 @@
+use std::hash::{Hash, Hasher};
+use tokio_rustls::rustls::{self, CipherSuite, ProtocolVersion};
+
+#[cfg(feature = "rustls-client")]
+pub use connector::{TlsInfoConnector, TlsInfoStream};
+
+/// Details of the TLS connection to the upstream server.
+///
+/// This is only available when the proxy is using a client built on a [`TlsInfoConnector`], such
+/// as the one created by [`ProxyBuilder::with_rustls_client`](crate::ProxyBuilder).
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct UpstreamTlsInfo {
+    /// Negotiated TLS version.
+    pub protocol_version: Option<ProtocolVersion>,
+    /// Negotiated cipher suite.
+    pub cipher_suite: Option<CipherSuite>,
+    /// Negotiated ALPN protocol.
+    pub alpn_protocol: Option<Vec<u8>>,
+    /// Certificate chain presented by the server, starting with its own certificate.
+    pub peer_certificates: Vec<rustls::Certificate>,
+}
+
+impl UpstreamTlsInfo {
+    /// Extract the details of an established client connection.
+    pub fn from_connection(conn: &rustls::ClientConnection) -> Self {
+        Self {
+            protocol_version: conn.protocol_version(),
+            cipher_suite: conn.negotiated_cipher_suite().map(|suite| suite.suite()),
+            alpn_protocol: conn.alpn_protocol().map(ToOwned::to_owned),
+            peer_certificates: conn
+                .peer_certificates()
+                .map(ToOwned::to_owned)
+                .unwrap_or_default(),
+        }
+    }
+}
+
+impl Hash for UpstreamTlsInfo {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.protocol_version.map(|v| v.get_u16()).hash(state);
+        self.cipher_suite.map(|s| s.get_u16()).hash(state);
+        self.alpn_protocol.hash(state);
+        self.peer_certificates.hash(state);
+    }
+}
+
+#[cfg(feature = "rustls-client")]
+mod connector {
+    use super::UpstreamTlsInfo;
+    use crate::connection_reuse::ConnectionUses;
+    use futures::future::{MapOk, TryFutureExt};
+    use hyper::{
+        client::connect::{Connected, Connection},
+        service::Service,
+        Uri,
+    };
+    use hyper_rustls::MaybeHttpsStream;
+    use std::{
+        io,
+        pin::Pin,
+        task::{Context, Poll},
+    };
+    use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+    /// A connector that records the details of TLS connections made by a hyper-rustls connector.
+    ///
+    /// The details are made available to handlers as [`HttpContext::upstream_tls`], along with
+    /// [`HttpContext::connection_reused`].
+    ///
+    /// [`HttpContext::upstream_tls`]: crate::HttpContext::upstream_tls
+    /// [`HttpContext::connection_reused`]: crate::HttpContext::connection_reused
+    #[cfg_attr(docsrs, doc(cfg(feature = "rustls-client")))]
+    #[derive(Clone, Debug)]
+    pub struct TlsInfoConnector<C>(C);
+
+    impl<C> TlsInfoConnector<C> {
+        /// Wrap a connector, such as a [`hyper_rustls::HttpsConnector`].
+        pub fn new(connector: C) -> Self {
+            Self(connector)
+        }
+    }
+
+    impl<C, T> Service<Uri> for TlsInfoConnector<C>
+    where
+        C: Service<Uri, Response = MaybeHttpsStream<T>>,
+    {
+        type Response = TlsInfoStream<T>;
+        type Error = C::Error;
+        type Future = MapOk<C::Future, fn(MaybeHttpsStream<T>) -> TlsInfoStream<T>>;
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            self.0.poll_ready(cx)
+        }
+
+        fn call(&mut self, uri: Uri) -> Self::Future {
+            self.0.call(uri).map_ok(TlsInfoStream)
+        }
+    }
+
+    /// A connection made by a [`TlsInfoConnector`].
+    #[cfg_attr(docsrs, doc(cfg(feature = "rustls-client")))]
+    #[derive(Debug)]
+    pub struct TlsInfoStream<T>(MaybeHttpsStream<T>);
+
+    impl<T> Connection for TlsInfoStream<T>
+    where
+        T: AsyncRead + AsyncWrite + Connection + Unpin,
+    {
+        fn connected(&self) -> Connected {
+            let connected = ConnectionUses::attach(self.0.connected());
+
+            match &self.0 {
+                MaybeHttpsStream::Https(stream) => {
+                    connected.extra(UpstreamTlsInfo::from_connection(stream.get_ref().1))
+                }
+                MaybeHttpsStream::Http(_) => connected,
+            }
+        }
+    }
+
+    impl<T: AsyncRead + AsyncWrite + Unpin> AsyncRead for TlsInfoStream<T> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_read(cx, buf)
+        }
+    }
+
+    impl<T: AsyncRead + AsyncWrite + Unpin> AsyncWrite for TlsInfoStream<T> {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.0).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.0).poll_shutdown(cx)
+        }
+    }
+}
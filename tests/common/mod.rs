@@ -2,6 +2,7 @@ use async_compression::tokio::bufread::GzipEncoder;
 use futures::{SinkExt, StreamExt};
 use hudsucker::{
     async_trait::async_trait,
+    builder::WantsClient,
     certificate_authority::CertificateAuthority,
     decode_request, decode_response,
     hyper::{
@@ -9,14 +10,15 @@ use hudsucker::{
             connect::{Connect, HttpConnector},
             Client,
         },
-        header::CONTENT_ENCODING,
+        header::{CONTENT_ENCODING, SET_COOKIE},
         server::conn::AddrStream,
         service::{make_service_fn, service_fn},
         Body, Method, Request, Response, Server, StatusCode,
     },
     rustls,
     tokio_tungstenite::tungstenite::Message,
-    HttpContext, HttpHandler, Proxy, RequestOrResponse, WebSocketContext, WebSocketHandler,
+    HttpContext, HttpHandler, Proxy, ProxyBuilder, RequestOrResponse, WebSocketContext,
+    WebSocketHandler,
 };
 use reqwest::tls::Certificate;
 use rustls_pemfile as pemfile;
@@ -29,12 +31,16 @@ use std::{
     },
 };
 use tls_listener::TlsListener;
-use tokio::sync::oneshot::Sender;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    sync::oneshot::Sender,
+};
 use tokio_native_tls::{self, native_tls};
 use tokio_util::io::ReaderStream;
 
 pub const HELLO_WORLD: &str = "Hello, World!";
 pub const WORLD: &str = "world";
+pub const COOKIES: [&str; 2] = ["a=1; Path=/", "b=2; Expires=Wed, 21 Oct 2015 07:28:00 GMT"];
 
 async fn test_server(req: Request<Body>) -> Result<Response<Body>, Infallible> {
     if hyper_tungstenite::is_upgrade_request(&req) {
@@ -65,6 +71,16 @@ async fn test_server(req: Request<Body>) -> Result<Response<Body>, Infallible> {
             ))))
             .unwrap()),
         (&Method::POST, "/echo") => Ok(Response::new(req.into_body())),
+        (&Method::GET, "/cookies") => Ok(Response::builder()
+            .header(SET_COOKIE, COOKIES[0])
+            .header(SET_COOKIE, COOKIES[1])
+            .body(Body::empty())
+            .unwrap()),
+        (&Method::GET, "/slow") => {
+            tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+            Ok(Response::new(Body::from(HELLO_WORLD)))
+        }
+        (&Method::GET, "/version") => Ok(Response::new(Body::from(format!("{:?}", req.version())))),
         _ => Ok(Response::new(Body::empty())),
     }
 }
@@ -91,7 +107,21 @@ pub fn start_http_server() -> Result<(SocketAddr, Sender<()>), Box<dyn std::erro
 pub async fn start_https_server(
     ca: impl CertificateAuthority,
 ) -> Result<(SocketAddr, Sender<()>), Box<dyn std::error::Error>> {
-    let make_svc = make_service_fn(|_| async { Ok::<_, Infallible>(service_fn(test_server)) });
+    let (addr, _, tx) = start_https_server_with_connection_counter(ca).await?;
+    Ok((addr, tx))
+}
+
+pub async fn start_https_server_with_connection_counter(
+    ca: impl CertificateAuthority,
+) -> Result<(SocketAddr, Arc<AtomicUsize>, Sender<()>), Box<dyn std::error::Error>> {
+    let connection_counter = Arc::new(AtomicUsize::new(0));
+    let make_svc = {
+        let connection_counter = Arc::clone(&connection_counter);
+        make_service_fn(move |_| {
+            connection_counter.fetch_add(1, Ordering::Relaxed);
+            async { Ok::<_, Infallible>(service_fn(test_server)) }
+        })
+    };
 
     let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))?;
     listener.set_nonblocking(true)?;
@@ -110,7 +140,7 @@ pub async fn start_https_server(
             .with_graceful_shutdown(async { rx.await.unwrap_or_default() }),
     );
 
-    Ok((addr, tx))
+    Ok((addr, connection_counter, tx))
 }
 
 pub fn http_client() -> Client<HttpConnector> {
@@ -121,7 +151,7 @@ pub fn plain_websocket_connector() -> tokio_tungstenite::Connector {
     tokio_tungstenite::Connector::Plain
 }
 
-fn rustls_client_config() -> rustls::ClientConfig {
+pub fn rustls_client_config() -> rustls::ClientConfig {
     let mut roots = rustls::RootCertStore::empty();
 
     for cert in rustls_native_certs::load_native_certs().unwrap() {
@@ -255,6 +285,28 @@ pub fn start_noop_proxy(
     Ok((addr, tx))
 }
 
+pub fn start_custom_proxy<C, CA, H, W>(
+    build: impl FnOnce(ProxyBuilder<WantsClient>) -> Proxy<C, CA, H, W>,
+) -> Result<(SocketAddr, Sender<()>), Box<dyn std::error::Error>>
+where
+    C: Connect + Clone + Send + Sync + 'static,
+    CA: CertificateAuthority,
+    H: HttpHandler,
+    W: WebSocketHandler,
+{
+    let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))?;
+    let addr = listener.local_addr()?;
+    let (tx, rx) = tokio::sync::oneshot::channel();
+
+    let proxy = build(Proxy::builder().with_listener(listener));
+
+    tokio::spawn(proxy.start(async {
+        rx.await.unwrap_or_default();
+    }));
+
+    Ok((addr, tx))
+}
+
 pub fn build_client(proxy: &str) -> reqwest::Client {
     let proxy = reqwest::Proxy::all(proxy).unwrap();
     let ca_cert = Certificate::from_pem(include_bytes!("../../examples/ca/hudsucker.cer")).unwrap();
@@ -269,6 +321,25 @@ pub fn build_client(proxy: &str) -> reqwest::Client {
         .unwrap()
 }
 
+pub async fn gzip(content: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::new();
+    GzipEncoder::new(content)
+        .read_to_end(&mut encoded)
+        .await
+        .unwrap();
+    encoded
+}
+
+pub async fn read_response_head(stream: &mut (impl AsyncRead + Unpin)) -> String {
+    let mut head = Vec::new();
+
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(stream.read_u8().await.unwrap());
+    }
+
+    String::from_utf8(head).unwrap()
+}
+
 #[derive(Clone)]
 pub struct TestHandler {
     pub request_counter: Arc<AtomicUsize>,
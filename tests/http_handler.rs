@@ -0,0 +1,2802 @@
+use hudsucker::{
+    async_trait::async_trait,
+    certificate_authority::RcgenAuthority,
+    decode_response,
+    hyper::{
+        client::HttpConnector,
+        ext::ReasonPhrase,
+        header::{self, HeaderMap, HeaderValue},
+        http::uri::{Authority, Scheme},
+        server::conn::Http,
+        service::service_fn,
+        Body, Client, Method, Request, Response, StatusCode, Uri,
+    },
+    rustls,
+    test_util::{MockUpstream, RecordingHandler},
+    BlackholeMode, ConnContext, Decompress, HostFilter, HttpContext, HttpHandler, Pipeline, Proxy,
+    ProxyEventKind, Recompress, Replace, RequestExt, RequestOrResponse, ResponseExt,
+    ResponseHeaderPolicy, RetryPolicy, ReuseInfoConnector, ServiceHandler, TlsInfoConnector,
+    Upgrade, UpstreamTarget, UpstreamTargetConnector, UpstreamTimeout,
+};
+use rustls_pemfile as pemfile;
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tower::ServiceBuilder;
+use tracing::span;
+use tracing_subscriber::{
+    layer::{self, SubscriberExt},
+    registry::LookupSpan,
+    Layer, Registry,
+};
+
+#[allow(unused)]
+mod common;
+
+fn build_ca() -> RcgenAuthority {
+    let mut private_key_bytes: &[u8] = include_bytes!("../examples/ca/hudsucker.key");
+    let mut ca_cert_bytes: &[u8] = include_bytes!("../examples/ca/hudsucker.cer");
+    let private_key = rustls::PrivateKey(
+        pemfile::pkcs8_private_keys(&mut private_key_bytes)
+            .expect("Failed to parse private key")
+            .remove(0),
+    );
+    let ca_cert = rustls::Certificate(
+        pemfile::certs(&mut ca_cert_bytes)
+            .expect("Failed to parse CA certificate")
+            .remove(0),
+    );
+
+    RcgenAuthority::new(private_key, ca_cert, 1_000)
+        .expect("Failed to create Certificate Authority")
+}
+
+#[tokio::test]
+async fn rewrite_uri() {
+    #[derive(Clone)]
+    struct RewriteHandler;
+
+    #[async_trait]
+    impl HttpHandler for RewriteHandler {
+        async fn rewrite_uri(&mut self, _ctx: &HttpContext, uri: Uri) -> Uri {
+            let mut parts = uri.into_parts();
+            parts.path_and_query = Some("/hello".parse().unwrap());
+            Uri::from_parts(parts).unwrap()
+        }
+    }
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::http_client())
+            .with_ca(build_ca())
+            .with_http_handler(RewriteHandler)
+            .build()
+    })
+    .unwrap();
+
+    let (server_addr, stop_server) = common::start_http_server().unwrap();
+    let client = common::build_client(&proxy_addr.to_string());
+
+    let res = client
+        .get(format!("http://{}/mapped/path", server_addr))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.bytes().await.unwrap(), common::HELLO_WORLD);
+
+    stop_server.send(()).unwrap();
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn custom_upgrade() {
+    #[derive(Clone)]
+    struct EchoUpgradeHandler;
+
+    #[async_trait]
+    impl HttpHandler for EchoUpgradeHandler {
+        async fn handle_request(
+            &mut self,
+            _ctx: &HttpContext,
+            mut req: Request<Body>,
+        ) -> RequestOrResponse {
+            let res = Response::builder()
+                .status(StatusCode::SWITCHING_PROTOCOLS)
+                .header(header::CONNECTION, "upgrade")
+                .header(header::UPGRADE, "echo")
+                .body(Body::empty())
+                .unwrap();
+
+            Upgrade::new(&mut req, res, |upgraded| async move {
+                let (mut reader, mut writer) = tokio::io::split(upgraded);
+                tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+            })
+            .into()
+        }
+    }
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::http_client())
+            .with_ca(build_ca())
+            .with_http_handler(EchoUpgradeHandler)
+            .build()
+    })
+    .unwrap();
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream
+        .write_all(
+            b"GET http://example.com/ HTTP/1.1\r\n\
+            Host: example.com\r\n\
+            Connection: upgrade\r\n\
+            Upgrade: echo\r\n\r\n",
+        )
+        .await
+        .unwrap();
+
+    let head = common::read_response_head(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 101"));
+
+    stream.write_all(b"ping").await.unwrap();
+
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn mock_upstream() {
+    let upstream = MockUpstream::new();
+    upstream.mock(Method::GET, "/hello", Response::new(common::HELLO_WORLD));
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(upstream.client())
+            .with_ca(build_ca())
+            .build()
+    })
+    .unwrap();
+
+    let client = common::build_client(&proxy_addr.to_string());
+
+    let res = client
+        .get("http://example.com/hello")
+        .header("x-test", "foo")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.bytes().await.unwrap(), common::HELLO_WORLD);
+
+    let requests = upstream.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].method, Method::GET);
+    assert_eq!(requests[0].uri.path(), "/hello");
+    assert_eq!(requests[0].headers["x-test"], "foo");
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn expect_continue_intercepted() {
+    let upstream = MockUpstream::new();
+    upstream.mock(Method::POST, "/upload", Response::new("uploaded"));
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(upstream.client())
+            .with_ca(build_ca())
+            .build()
+    })
+    .unwrap();
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream
+        .write_all(b"CONNECT localhost:443 HTTP/1.1\r\nHost: localhost:443\r\n\r\n")
+        .await
+        .unwrap();
+    assert!(common::read_response_head(&mut stream)
+        .await
+        .starts_with("HTTP/1.1 200"));
+
+    let mut stream = tokio_rustls::TlsConnector::from(Arc::new(common::rustls_client_config()))
+        .connect("localhost".try_into().unwrap(), stream)
+        .await
+        .unwrap();
+    stream
+        .write_all(
+            b"POST /upload HTTP/1.1\r\n\
+            Host: localhost\r\n\
+            Content-Length: 5\r\n\
+            Expect: 100-continue\r\n\r\n",
+        )
+        .await
+        .unwrap();
+
+    // The interim response is sent by the proxy, not relayed from the upstream server.
+    let head = common::read_response_head(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 100 Continue"), "{}", head);
+
+    stream.write_all(b"hello").await.unwrap();
+
+    let head = common::read_response_head(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 200 OK"), "{}", head);
+
+    let requests = upstream.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].uri.path(), "/upload");
+    assert_eq!(requests[0].headers[header::EXPECT], "100-continue");
+    assert_eq!(requests[0].body, "hello");
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn recording_handler() {
+    let upstream = MockUpstream::new();
+    upstream.mock(Method::GET, "/hello", Response::new(common::HELLO_WORLD));
+    upstream.mock(
+        Method::POST,
+        "/echo",
+        Response::builder()
+            .status(StatusCode::CREATED)
+            .header("x-test", "bar")
+            .body(common::WORLD)
+            .unwrap(),
+    );
+
+    let recorder = RecordingHandler::new(1).with_max_body_size(5);
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(upstream.client())
+            .with_ca(build_ca())
+            .with_http_handler(recorder.clone())
+            .build()
+    })
+    .unwrap();
+
+    let client = common::build_client(&proxy_addr.to_string());
+
+    let res = client.get("http://example.com/hello").send().await.unwrap();
+    assert_eq!(res.bytes().await.unwrap(), common::HELLO_WORLD);
+    assert_eq!(recorder.len(), 1);
+
+    let res = client
+        .post("http://example.com/echo")
+        .header("x-test", "foo")
+        .body("hello world")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::CREATED);
+    assert_eq!(res.bytes().await.unwrap(), common::WORLD);
+
+    let exchanges = recorder.exchanges();
+    assert_eq!(exchanges.len(), 1);
+
+    let exchange = recorder.last().unwrap();
+    assert_eq!(exchange.method, Method::POST);
+    assert_eq!(exchange.uri.path(), "/echo");
+    assert_eq!(exchange.request_headers["x-test"], "foo");
+    assert_eq!(exchange.request_body, "hello");
+    assert_eq!(exchange.status, StatusCode::CREATED);
+    assert_eq!(exchange.response_headers["x-test"], "bar");
+    assert_eq!(exchange.response_body, common::WORLD);
+    assert_eq!(upstream.requests()[1].body, "hello world");
+
+    recorder.clear();
+    assert!(recorder.is_empty());
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn not_modified() {
+    #[derive(Clone)]
+    struct DecodeHandler;
+
+    #[async_trait]
+    impl HttpHandler for DecodeHandler {
+        async fn handle_response(
+            &mut self,
+            _ctx: &HttpContext,
+            res: Response<Body>,
+        ) -> Response<Body> {
+            decode_response(res).unwrap()
+        }
+    }
+
+    let upstream = MockUpstream::new();
+    upstream.mock(
+        Method::GET,
+        "/hello",
+        Response::builder()
+            .status(StatusCode::NOT_MODIFIED)
+            .header(header::ETAG, "\"v1\"")
+            .header(header::CONTENT_ENCODING, "gzip")
+            .body("")
+            .unwrap(),
+    );
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(upstream.client())
+            .with_ca(build_ca())
+            .with_http_handler(DecodeHandler)
+            .build()
+    })
+    .unwrap();
+
+    let client = common::build_client(&proxy_addr.to_string());
+    let res = client
+        .get("http://example.com/hello")
+        .header(header::IF_NONE_MATCH, "\"v1\"")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), 304);
+    assert_eq!(res.headers()[header::ETAG], "\"v1\"");
+    assert_eq!(res.headers()[header::CONTENT_ENCODING], "gzip");
+    assert!(res.bytes().await.unwrap().is_empty());
+    assert_eq!(
+        upstream.requests()[0].headers[header::IF_NONE_MATCH],
+        "\"v1\""
+    );
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn auto_decode() {
+    #[derive(Clone)]
+    struct UppercaseHandler {
+        modify: bool,
+    }
+
+    #[async_trait]
+    impl HttpHandler for UppercaseHandler {
+        async fn handle_request(
+            &mut self,
+            _ctx: &HttpContext,
+            req: Request<Body>,
+        ) -> RequestOrResponse {
+            self.modify = req.uri().path() == "/modify";
+            let (parts, body) = req.into_parts();
+            let body = hyper::body::to_bytes(body).await.unwrap();
+            Request::from_parts(parts, Body::from(body.to_ascii_uppercase())).into()
+        }
+
+        async fn handle_response(
+            &mut self,
+            _ctx: &HttpContext,
+            res: Response<Body>,
+        ) -> Response<Body> {
+            assert!(!res.headers().contains_key(header::CONTENT_ENCODING));
+            let (parts, body) = res.into_parts();
+            let body = hyper::body::to_bytes(body).await.unwrap();
+            assert_eq!(body, common::HELLO_WORLD);
+
+            if self.modify {
+                Response::from_parts(parts, Body::from(body.to_ascii_uppercase()))
+            } else {
+                Response::from_parts(parts, Body::from(body))
+            }
+        }
+    }
+
+    let encoded = common::gzip(common::HELLO_WORLD.as_bytes()).await;
+    let upstream = MockUpstream::new();
+    for path in ["/keep", "/modify"] {
+        upstream.mock(
+            Method::POST,
+            path,
+            Response::builder()
+                .header(header::CONTENT_ENCODING, "gzip")
+                .body(encoded.clone())
+                .unwrap(),
+        );
+    }
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(upstream.client())
+            .with_ca(build_ca())
+            .with_http_handler(UppercaseHandler { modify: false })
+            .with_auto_decode(true)
+            .build()
+    })
+    .unwrap();
+
+    let client = common::build_client(&proxy_addr.to_string());
+    let res = client
+        .post("http://example.com/keep")
+        .header(header::CONTENT_ENCODING, "gzip")
+        .body(common::gzip(b"hello").await)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.headers()[header::CONTENT_ENCODING], "gzip");
+    assert_eq!(res.bytes().await.unwrap(), encoded);
+
+    let res = client
+        .post("http://example.com/modify")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.headers()[header::CONTENT_ENCODING], "gzip");
+    assert_eq!(
+        res.bytes().await.unwrap(),
+        common::gzip(common::HELLO_WORLD.to_uppercase().as_bytes()).await
+    );
+
+    let request = &upstream.requests()[0];
+    assert_eq!(request.headers[header::CONTENT_ENCODING], "gzip");
+    assert_eq!(request.body, common::gzip(b"HELLO").await);
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn body_pipelines() {
+    let upstream = MockUpstream::new();
+    upstream.mock(
+        Method::POST,
+        "/",
+        Response::builder()
+            .header(header::CONTENT_ENCODING, "gzip")
+            .body(common::gzip(b"<a href=\"http://example.com\">").await)
+            .unwrap(),
+    );
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(upstream.client())
+            .with_ca(build_ca())
+            .with_request_pipeline(
+                Pipeline::new().with_transform(Replace::new().replace("secret", "******")),
+            )
+            .with_response_pipeline(
+                Pipeline::new()
+                    .with_transform(Decompress)
+                    .with_transform(Replace::new().replace("http://", "https://"))
+                    .with_transform(Recompress),
+            )
+            .build()
+    })
+    .unwrap();
+
+    let client = common::build_client(&proxy_addr.to_string());
+    let res = client
+        .post("http://example.com/")
+        .body("password=secret")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.headers()[header::CONTENT_ENCODING], "gzip");
+    assert_eq!(
+        res.bytes().await.unwrap(),
+        common::gzip(b"<a href=\"https://example.com\">").await
+    );
+
+    let request = &upstream.requests()[0];
+    assert_eq!(request.headers[header::CONTENT_LENGTH], "15");
+    assert_eq!(request.body, "password=******");
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[cfg(feature = "metrics")]
+#[tokio::test]
+async fn prometheus_metrics() {
+    let upstream = MockUpstream::new();
+    upstream.mock(Method::GET, "/hello", Response::new(common::HELLO_WORLD));
+
+    let metrics = hudsucker::PrometheusMetrics::new();
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(upstream.client())
+            .with_ca(build_ca())
+            .with_http_handler(metrics.handler(hudsucker::NoopHandler::default()))
+            .build()
+    })
+    .unwrap();
+
+    let client = common::build_client(&proxy_addr.to_string());
+    for _ in 0..3 {
+        let res = client.get("http://example.com/hello").send().await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    let res = client
+        .get("http://hudsucker.internal/metrics")
+        .send()
+        .await
+        .unwrap();
+    assert!(res.headers()[header::CONTENT_TYPE]
+        .to_str()
+        .unwrap()
+        .starts_with("text/plain"));
+
+    let rendered = res.text().await.unwrap();
+    for expected in [
+        "hudsucker_requests_total{method=\"GET\"} 3",
+        "hudsucker_responses_total{status=\"200\"} 3",
+        "hudsucker_errors_total 0",
+        "hudsucker_request_duration_seconds_count 3",
+    ] {
+        assert!(rendered.contains(expected), "missing {}", expected);
+    }
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn should_process() {
+    #[derive(Clone)]
+    struct ApiHandler;
+
+    #[async_trait]
+    impl HttpHandler for ApiHandler {
+        fn should_process(&self, _ctx: &HttpContext, req: &Request<Body>) -> bool {
+            !req.uri().path().starts_with("/static/")
+        }
+
+        async fn handle_request(
+            &mut self,
+            _ctx: &HttpContext,
+            mut req: Request<Body>,
+        ) -> RequestOrResponse {
+            req.headers_mut()
+                .insert("x-processed", HeaderValue::from_static("true"));
+            req.into()
+        }
+
+        async fn handle_response(
+            &mut self,
+            _ctx: &HttpContext,
+            mut res: Response<Body>,
+        ) -> Response<Body> {
+            res.headers_mut()
+                .insert("x-processed", HeaderValue::from_static("true"));
+            res
+        }
+    }
+
+    let upstream = MockUpstream::new();
+    for path in ["/api/user", "/static/app.js"] {
+        upstream.mock(Method::POST, path, Response::new("secret"));
+    }
+
+    // The pipelines only apply to requests that are processed.
+    let pipeline = || Pipeline::new().with_transform(Replace::new().replace("secret", "******"));
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(upstream.client())
+            .with_ca(build_ca())
+            .with_http_handler(ApiHandler)
+            .with_request_pipeline(pipeline())
+            .with_response_pipeline(pipeline())
+            .build()
+    })
+    .unwrap();
+
+    let client = common::build_client(&proxy_addr.to_string());
+    for path in ["/api/user", "/static/app.js"] {
+        let res = client
+            .post(format!("http://example.com{}", path))
+            .body("secret")
+            .send()
+            .await
+            .unwrap();
+
+        let processed = path == "/api/user";
+        assert_eq!(res.headers().contains_key("x-processed"), processed);
+        assert_eq!(
+            res.text().await.unwrap(),
+            if processed { "******" } else { "secret" }
+        );
+    }
+
+    let requests = upstream.requests();
+    assert!(requests[0].headers.contains_key("x-processed"));
+    assert_eq!(requests[0].body, "******");
+    assert!(!requests[1].headers.contains_key("x-processed"));
+    assert_eq!(requests[1].body, "secret");
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn response_header_policy() {
+    let upstream = MockUpstream::new();
+    upstream.mock(
+        Method::GET,
+        "/hello",
+        Response::builder()
+            .header(header::SERVER, "nginx/1.25.0")
+            .header("x-powered-by", "Express")
+            .header(header::VIA, "1.1 cache")
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body("")
+            .unwrap(),
+    );
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(upstream.client())
+            .with_ca(build_ca())
+            .with_response_header_policy(ResponseHeaderPolicy::anonymize())
+            .build()
+    })
+    .unwrap();
+
+    let client = common::build_client(&proxy_addr.to_string());
+    let res = client.get("http://example.com/hello").send().await.unwrap();
+
+    assert!(!res.headers().contains_key(header::SERVER));
+    assert!(!res.headers().contains_key("x-powered-by"));
+    assert!(!res.headers().contains_key(header::VIA));
+    assert_eq!(res.headers()[header::CONTENT_TYPE], "text/plain");
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn peer_credentials() {
+    #[derive(Clone)]
+    struct PeerCredHandler(mpsc::UnboundedSender<HttpContext>);
+
+    #[async_trait]
+    impl HttpHandler for PeerCredHandler {
+        async fn handle_request(
+            &mut self,
+            ctx: &HttpContext,
+            req: Request<Body>,
+        ) -> RequestOrResponse {
+            self.0.send(ctx.clone()).unwrap();
+            req.into()
+        }
+    }
+
+    let upstream = MockUpstream::new();
+    upstream.mock(Method::GET, "/hello", Response::new(""));
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(upstream.client())
+            .with_ca(build_ca())
+            .with_http_handler(PeerCredHandler(tx))
+            .with_peer_credentials(true)
+            .build()
+    })
+    .unwrap();
+
+    let client = common::build_client(&proxy_addr.to_string());
+    let res = client.get("http://example.com/hello").send().await.unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+
+    let peer_cred = rx.recv().await.unwrap().peer_cred.unwrap();
+    // SAFETY: getuid is always successful.
+    assert_eq!(peer_cred.uid, unsafe { libc::getuid() });
+    let pid = tokio::task::spawn_blocking(move || peer_cred.pid())
+        .await
+        .unwrap();
+    assert_eq!(pid, Some(std::process::id()));
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn shutdown_from_handler() {
+    #[derive(Clone)]
+    struct AdminHandler;
+
+    #[async_trait]
+    impl HttpHandler for AdminHandler {
+        async fn handle_request(
+            &mut self,
+            ctx: &HttpContext,
+            req: Request<Body>,
+        ) -> RequestOrResponse {
+            if req.method() == Method::POST && req.uri().path() == "/admin/shutdown" {
+                ctx.shutdown.shutdown();
+                return Response::new(Body::from("shutting down")).into();
+            }
+
+            req.into()
+        }
+    }
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let proxy_addr = listener.local_addr().unwrap();
+    let proxy = Proxy::builder()
+        .with_listener(listener)
+        .with_client(MockUpstream::new().client())
+        .with_ca(build_ca())
+        .with_http_handler(AdminHandler)
+        .build();
+    let shutdown_handle = proxy.shutdown_handle();
+    let proxy = tokio::spawn(proxy.start(futures::future::pending()));
+
+    let client = common::build_client(&proxy_addr.to_string());
+    let res = client
+        .post("http://example.com/admin/shutdown")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.text().await.unwrap(), "shutting down");
+    assert!(shutdown_handle.is_shutdown());
+
+    tokio::time::timeout(Duration::from_secs(5), proxy)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn pause_and_resume() {
+    let upstream = MockUpstream::new();
+    upstream.mock(Method::GET, "/hello", Response::new(""));
+
+    let mut control = None;
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        let proxy = builder
+            .with_client(upstream.client())
+            .with_ca(build_ca())
+            .build();
+        control = Some(proxy.control());
+        proxy
+    })
+    .unwrap();
+    let control = control.unwrap();
+
+    let request = b"GET http://example.com/hello HTTP/1.1\r\nHost: example.com\r\n\r\n";
+
+    let mut existing = TcpStream::connect(proxy_addr).await.unwrap();
+    existing.write_all(request).await.unwrap();
+    let head = common::read_response_head(&mut existing).await;
+    assert!(head.starts_with("HTTP/1.1 200"));
+
+    control.pause();
+    assert!(control.is_paused());
+
+    let mut new = TcpStream::connect(proxy_addr).await.unwrap();
+    new.write_all(request).await.unwrap();
+    assert!(tokio::time::timeout(
+        Duration::from_millis(200),
+        common::read_response_head(&mut new)
+    )
+    .await
+    .is_err());
+
+    existing.write_all(request).await.unwrap();
+    let head = common::read_response_head(&mut existing).await;
+    assert!(head.starts_with("HTTP/1.1 200"));
+
+    control.resume();
+    let head = tokio::time::timeout(Duration::from_secs(5), common::read_response_head(&mut new))
+        .await
+        .unwrap();
+    assert!(head.starts_with("HTTP/1.1 200"));
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn trace_propagation() {
+    let upstream = MockUpstream::new();
+    upstream.mock(Method::GET, "/hello", Response::new(common::HELLO_WORLD));
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(upstream.client())
+            .with_ca(build_ca())
+            .with_trace_propagation(true)
+            .build()
+    })
+    .unwrap();
+
+    let client = common::build_client(&proxy_addr.to_string());
+    let parent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    client
+        .get("http://example.com/hello")
+        .header("traceparent", parent)
+        .send()
+        .await
+        .unwrap();
+    client.get("http://example.com/hello").send().await.unwrap();
+
+    let requests = upstream.requests();
+    assert_eq!(requests.len(), 2);
+
+    let child = requests[0].headers["traceparent"].to_str().unwrap();
+    assert_ne!(child, parent);
+    assert!(child.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+    assert!(child.ends_with("-01"));
+
+    let root = requests[1].headers["traceparent"].to_str().unwrap();
+    assert_eq!(root.len(), parent.len());
+    assert!(!root.starts_with("00-4bf92f3577b34da6a3ce929d0e0e4736-"));
+
+    stop_proxy.send(()).unwrap();
+}
+
+/// Records each span created as `parent > name`, or just `name` for spans without a parent.
+#[derive(Clone, Default)]
+struct SpanParents(Arc<std::sync::Mutex<Vec<String>>>);
+
+impl<S> Layer<S> for SpanParents
+where
+    S: tracing::Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, _: &span::Attributes<'_>, id: &span::Id, ctx: layer::Context<'_, S>) {
+        let span = ctx.span(id).unwrap();
+        let name = match span.parent() {
+            Some(parent) => format!("{} > {}", parent.name(), span.name()),
+            None => span.name().to_owned(),
+        };
+        self.0.lock().unwrap().push(name);
+    }
+}
+
+#[tokio::test]
+async fn parent_span() {
+    let parents = SpanParents::default();
+    let _guard = tracing::subscriber::set_default(Registry::default().with(parents.clone()));
+
+    let upstream = MockUpstream::new();
+    upstream.mock(Method::GET, "/hello", Response::new(common::HELLO_WORLD));
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(upstream.client())
+            .with_ca(build_ca())
+            .with_parent_span(tracing::info_span!("app"))
+            .build()
+    })
+    .unwrap();
+
+    let client = common::build_client(&proxy_addr.to_string());
+    let res = client.get("http://example.com/hello").send().await.unwrap();
+    assert_eq!(res.status(), 200);
+
+    let parents = parents.0.lock().unwrap();
+    assert!(parents.iter().any(|span| span == "app > proxy"));
+    assert!(parents.iter().any(|span| span == "proxy > handle_request"));
+    assert!(!parents.iter().any(|span| span == "proxy"));
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn max_forwards() {
+    let upstream = MockUpstream::new();
+    upstream.mock(Method::OPTIONS, "/", Response::new(""));
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(upstream.client())
+            .with_ca(build_ca())
+            .build()
+    })
+    .unwrap();
+
+    let client = common::build_client(&proxy_addr.to_string());
+
+    let res = client
+        .request(Method::TRACE, "http://example.com/trace")
+        .header(header::MAX_FORWARDS, "0")
+        .header("x-test", "trace")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers()[header::CONTENT_TYPE], "message/http");
+    let echoed = res.text().await.unwrap();
+    assert!(echoed.starts_with("TRACE http://example.com/trace HTTP/1.1\r\n"));
+    assert!(echoed.contains("\r\nx-test: trace\r\n"));
+
+    let res = client
+        .request(Method::OPTIONS, "http://example.com/")
+        .header(header::MAX_FORWARDS, "2")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+
+    let requests = upstream.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].method, Method::OPTIONS);
+    assert_eq!(requests[0].headers[header::MAX_FORWARDS], "1");
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn extension_methods() {
+    let purge = Method::from_bytes(b"PURGE").unwrap();
+    let upstream = MockUpstream::new();
+    upstream.mock(purge.clone(), "/cached", Response::new("purged"));
+    upstream.mock(Method::PATCH, "/resource", Response::new("patched"));
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(upstream.client())
+            .with_ca(build_ca())
+            .build()
+    })
+    .unwrap();
+
+    let client = common::build_client(&proxy_addr.to_string());
+
+    let res = client
+        .request(purge.clone(), "http://example.com/cached")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.text().await.unwrap(), "purged");
+
+    let res = client
+        .patch("http://example.com/resource")
+        .body("{\"a\":1}")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.text().await.unwrap(), "patched");
+
+    let requests = upstream.requests();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0].method, purge);
+    assert_eq!(requests[1].method, Method::PATCH);
+    assert_eq!(requests[1].body, "{\"a\":1}");
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn request_target_form() {
+    #[derive(Clone)]
+    struct TargetFormHandler;
+
+    #[async_trait]
+    impl HttpHandler for TargetFormHandler {
+        async fn handle_request(
+            &mut self,
+            ctx: &HttpContext,
+            _req: Request<Body>,
+        ) -> RequestOrResponse {
+            Response::new(Body::from(format!("{:?}", ctx.request_target_form))).into()
+        }
+    }
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::http_client())
+            .with_ca(build_ca())
+            .with_http_handler(TargetFormHandler)
+            .build()
+    })
+    .unwrap();
+
+    let client = common::build_client(&proxy_addr.to_string());
+    let res = client.get("http://example.com/hello").send().await.unwrap();
+    assert_eq!(res.text().await.unwrap(), "Absolute");
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream
+        .write_all(
+            b"GET /hello HTTP/1.1\r\n\
+            Host: example.com\r\n\
+            Connection: close\r\n\r\n",
+        )
+        .await
+        .unwrap();
+
+    let mut res = String::new();
+    stream.read_to_string(&mut res).await.unwrap();
+    assert!(res.ends_with("\r\n\r\nOrigin"));
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn reason_phrase() {
+    #[derive(Clone)]
+    struct ReasonPhraseHandler;
+
+    #[async_trait]
+    impl HttpHandler for ReasonPhraseHandler {
+        async fn handle_request(
+            &mut self,
+            _ctx: &HttpContext,
+            _req: Request<Body>,
+        ) -> RequestOrResponse {
+            let mut res = Response::new(Body::empty());
+            res.set_reason_phrase("Totally Fine");
+            res.into()
+        }
+    }
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::http_client())
+            .with_ca(build_ca())
+            .with_http_handler(ReasonPhraseHandler)
+            .build()
+    })
+    .unwrap();
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream
+        .write_all(b"GET http://example.com/ HTTP/1.1\r\nHost: example.com\r\n\r\n")
+        .await
+        .unwrap();
+
+    let head = common::read_response_head(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 200 Totally Fine\r\n"));
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn tower_service() {
+    let service = ServiceBuilder::new()
+        .map_request(|mut req: Request<Body>| {
+            req.set_header("x-layer", "applied");
+            req
+        })
+        .service_fn(|req: Request<Body>| async move {
+            let layer = req.header_str("x-layer").unwrap_or_default().to_owned();
+            Ok::<_, Infallible>(Response::new(Body::from(layer)))
+        });
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::http_client())
+            .with_ca(build_ca())
+            .with_http_handler(ServiceHandler::new(service))
+            .build()
+    })
+    .unwrap();
+
+    let client = common::build_client(&proxy_addr.to_string());
+    let res = client.get("http://example.com/").send().await.unwrap();
+    assert_eq!(res.text().await.unwrap(), "applied");
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn on_connection_close() {
+    #[derive(Clone)]
+    struct CloseHandler(mpsc::UnboundedSender<ConnContext>);
+
+    #[async_trait]
+    impl HttpHandler for CloseHandler {
+        async fn handle_request(
+            &mut self,
+            _ctx: &HttpContext,
+            mut req: Request<Body>,
+        ) -> RequestOrResponse {
+            let res = Response::builder()
+                .status(StatusCode::SWITCHING_PROTOCOLS)
+                .header(header::CONNECTION, "upgrade")
+                .header(header::UPGRADE, "echo")
+                .body(Body::empty())
+                .unwrap();
+
+            Upgrade::new(&mut req, res, |upgraded| async move {
+                let (mut reader, mut writer) = tokio::io::split(upgraded);
+                let _ = tokio::io::copy(&mut reader, &mut writer).await;
+            })
+            .into()
+        }
+
+        async fn on_connection_close(&mut self, ctx: &ConnContext) {
+            self.0.send(ctx.clone()).unwrap();
+        }
+    }
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::http_client())
+            .with_ca(build_ca())
+            .with_http_handler(CloseHandler(tx))
+            .build()
+    })
+    .unwrap();
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream
+        .write_all(
+            b"GET http://example.com/ HTTP/1.1\r\n\
+            Host: example.com\r\n\
+            Connection: upgrade\r\n\
+            Upgrade: echo\r\n\r\n",
+        )
+        .await
+        .unwrap();
+
+    let head = common::read_response_head(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 101"));
+
+    stream.write_all(b"ping").await.unwrap();
+    let mut buf = [0; 4];
+    stream.read_exact(&mut buf).await.unwrap();
+
+    let client_addr = stream.local_addr().unwrap();
+    assert!(rx.try_recv().is_err());
+
+    drop(stream);
+
+    let ctx = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(ctx.client_addr, client_addr);
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn should_sniff() {
+    #[derive(Clone)]
+    struct NoSniffHandler;
+
+    #[async_trait]
+    impl HttpHandler for NoSniffHandler {
+        fn should_sniff(&self, _ctx: &HttpContext, _authority: &Authority) -> bool {
+            false
+        }
+    }
+
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = server.accept().await.unwrap();
+        stream.write_all(b"220 ready\r\n").await.unwrap();
+    });
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::http_client())
+            .with_ca(build_ca())
+            .with_http_handler(NoSniffHandler)
+            .build()
+    })
+    .unwrap();
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream
+        .write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", server_addr).as_bytes())
+        .await
+        .unwrap();
+
+    let head = common::read_response_head(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 200"));
+
+    let mut banner = String::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_string(&mut banner))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(banner, "220 ready\r\n");
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn connect_scheme() {
+    #[derive(Clone)]
+    struct ForceHttpsHandler(mpsc::UnboundedSender<Uri>);
+
+    #[async_trait]
+    impl HttpHandler for ForceHttpsHandler {
+        fn connect_scheme(&self, _ctx: &HttpContext, _authority: &Authority) -> Option<Scheme> {
+            Some(Scheme::HTTPS)
+        }
+
+        async fn handle_request(
+            &mut self,
+            _ctx: &HttpContext,
+            req: Request<Body>,
+        ) -> RequestOrResponse {
+            if req.method() == Method::CONNECT {
+                return req.into();
+            }
+
+            self.0.send(req.uri().clone()).unwrap();
+            Response::new(Body::empty()).into()
+        }
+    }
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::http_client())
+            .with_ca(build_ca())
+            .with_http_handler(ForceHttpsHandler(tx))
+            .build()
+    })
+    .unwrap();
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream
+        .write_all(b"CONNECT example.com:8443 HTTP/1.1\r\nHost: example.com:8443\r\n\r\n")
+        .await
+        .unwrap();
+
+    let head = common::read_response_head(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 200"));
+
+    stream
+        .write_all(b"GET /hello HTTP/1.1\r\nHost: example.com:8443\r\n\r\n")
+        .await
+        .unwrap();
+
+    let head = common::read_response_head(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 200"));
+
+    let uri = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(uri, "https://example.com:8443/hello");
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn blackhole() {
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let accept = tokio::spawn(async move {
+        let (mut stream, _) = server.accept().await.unwrap();
+        stream.write_all(b"220 ready\r\n").await.unwrap();
+    });
+
+    for mode in [BlackholeMode::Close, BlackholeMode::Reset] {
+        let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+            builder
+                .with_client(common::http_client())
+                .with_ca(build_ca())
+                .with_blackhole(HostFilter::new().with_host("127.0.0.1"), mode)
+                .build()
+        })
+        .unwrap();
+
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+        stream
+            .write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", server_addr).as_bytes())
+            .await
+            .unwrap();
+
+        let head = common::read_response_head(&mut stream).await;
+        assert!(head.starts_with("HTTP/1.1 200"));
+
+        let mut rest = Vec::new();
+        let read = tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest))
+            .await
+            .unwrap();
+        match mode {
+            BlackholeMode::Reset => {
+                assert_eq!(
+                    read.unwrap_err().kind(),
+                    std::io::ErrorKind::ConnectionReset
+                )
+            }
+            _ => assert_eq!(read.unwrap(), 0),
+        }
+        assert!(rest.is_empty());
+
+        stop_proxy.send(()).unwrap();
+    }
+
+    assert!(!accept.is_finished());
+}
+
+#[tokio::test]
+async fn socket_buffer_sizes() {
+    #[derive(Clone)]
+    struct NoSniffHandler;
+
+    #[async_trait]
+    impl HttpHandler for NoSniffHandler {
+        fn should_sniff(&self, _ctx: &HttpContext, _authority: &Authority) -> bool {
+            false
+        }
+    }
+
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = server.accept().await.unwrap();
+        let (mut read, mut write) = stream.split();
+        tokio::io::copy(&mut read, &mut write).await.unwrap();
+    });
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::http_client())
+            .with_ca(build_ca())
+            .with_http_handler(NoSniffHandler)
+            .with_socket_buffer_sizes(256 * 1024, 256 * 1024)
+            .build()
+    })
+    .unwrap();
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream
+        .write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", server_addr).as_bytes())
+        .await
+        .unwrap();
+
+    let head = common::read_response_head(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 200"));
+
+    let data = vec![7; 1024 * 1024];
+    let (mut read, mut write) = stream.into_split();
+    let sent = data.clone();
+    tokio::spawn(async move {
+        write.write_all(&sent).await.unwrap();
+        write.shutdown().await.unwrap();
+    });
+
+    let mut echoed = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), read.read_to_end(&mut echoed))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(echoed, data);
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn tunnel_buffer_size() {
+    #[derive(Clone)]
+    struct NoSniffHandler;
+
+    #[async_trait]
+    impl HttpHandler for NoSniffHandler {
+        fn should_sniff(&self, _ctx: &HttpContext, _authority: &Authority) -> bool {
+            false
+        }
+    }
+
+    let data: Vec<u8> = (0..32 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server.local_addr().unwrap();
+    tokio::spawn({
+        let data = data.clone();
+        async move {
+            let (mut stream, _) = server.accept().await.unwrap();
+            stream.write_all(&data).await.unwrap();
+            stream.shutdown().await.unwrap();
+        }
+    });
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::http_client())
+            .with_ca(build_ca())
+            .with_http_handler(NoSniffHandler)
+            .with_tunnel_buffer_size(256 * 1024)
+            .build()
+    })
+    .unwrap();
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream
+        .write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", server_addr).as_bytes())
+        .await
+        .unwrap();
+
+    let head = common::read_response_head(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 200"));
+
+    let mut received = Vec::new();
+    tokio::time::timeout(Duration::from_secs(10), stream.read_to_end(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert!(received == data, "Received {} bytes", received.len());
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn outbound_port_range() {
+    #[derive(Clone)]
+    struct NoSniffHandler;
+
+    #[async_trait]
+    impl HttpHandler for NoSniffHandler {
+        fn should_sniff(&self, _ctx: &HttpContext, _authority: &Authority) -> bool {
+            false
+        }
+    }
+
+    let start = std::net::TcpListener::bind("0.0.0.0:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let range = start..=start.saturating_add(15);
+
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, client_addr) = server.accept().await.unwrap();
+        stream
+            .write_all(client_addr.port().to_string().as_bytes())
+            .await
+            .unwrap();
+        stream.shutdown().await.unwrap();
+    });
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::http_client())
+            .with_ca(build_ca())
+            .with_http_handler(NoSniffHandler)
+            .with_outbound_port_range(range.clone())
+            .build()
+    })
+    .unwrap();
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream
+        .write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", server_addr).as_bytes())
+        .await
+        .unwrap();
+
+    let head = common::read_response_head(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 200"));
+
+    let mut port = String::new();
+    stream.read_to_string(&mut port).await.unwrap();
+    let port: u16 = port.parse().unwrap();
+    assert!(range.contains(&port), "{} is not in {:?}", port, range);
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn connect_response() {
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        let mut headers = HeaderMap::new();
+        headers.insert("proxy-agent", HeaderValue::from_static("hudsucker"));
+
+        builder
+            .with_client(common::http_client())
+            .with_ca(build_ca())
+            .with_connect_headers(headers)
+            .with_connect_reason_phrase(ReasonPhrase::from_static(b"Connection Established"))
+            .build()
+    })
+    .unwrap();
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream
+        .write_all(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n")
+        .await
+        .unwrap();
+
+    let head = common::read_response_head(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 200 Connection Established\r\n"));
+    assert!(head.contains("\r\nProxy-Agent: hudsucker\r\n"));
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn sniff_timeout() {
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server.local_addr().unwrap();
+    tokio::spawn(async move {
+        let (mut stream, _) = server.accept().await.unwrap();
+        stream.write_all(b"220 ready\r\n").await.unwrap();
+    });
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::http_client())
+            .with_ca(build_ca())
+            .with_sniff_timeout(Duration::from_millis(100))
+            .build()
+    })
+    .unwrap();
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream
+        .write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", server_addr).as_bytes())
+        .await
+        .unwrap();
+
+    let head = common::read_response_head(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 200"));
+
+    let mut banner = String::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_string(&mut banner))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(banner, "220 ready\r\n");
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn unsupported_request_lines() {
+    let upstream = MockUpstream::new();
+    upstream.mock(Method::GET, "/", Response::new(common::HELLO_WORLD));
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(upstream.client())
+            .with_ca(build_ca())
+            .build()
+    })
+    .unwrap();
+
+    let cases: [(&[u8], &str); 4] = [
+        (
+            b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n",
+            "HTTP/1.1 200",
+        ),
+        (b"GET /\r\n", "HTTP/1.1 505"),
+        (
+            b"GET / HTTP/2.0\r\nHost: example.com\r\n\r\n",
+            "HTTP/1.1 505",
+        ),
+        (b"GET / FOO/1.1\r\nHost: example.com\r\n\r\n", ""),
+    ];
+
+    for (request, expected) in cases {
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+        stream
+            .write_all(b"CONNECT example.com:80 HTTP/1.1\r\nHost: example.com:80\r\n\r\n")
+            .await
+            .unwrap();
+
+        let head = common::read_response_head(&mut stream).await;
+        assert!(head.starts_with("HTTP/1.1 200"));
+
+        stream.write_all(request).await.unwrap();
+        if expected.is_empty() {
+            let mut response = Vec::new();
+            tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut response))
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(response.is_empty());
+        } else {
+            let head = common::read_response_head(&mut stream).await;
+            assert!(head.starts_with(expected), "{head}");
+        }
+    }
+
+    assert_eq!(upstream.requests().len(), 1);
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn per_host_connection_limit() {
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    let max_in_flight = Arc::new(AtomicUsize::new(0));
+
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server.local_addr().unwrap();
+    tokio::spawn({
+        let in_flight = Arc::clone(&in_flight);
+        let max_in_flight = Arc::clone(&max_in_flight);
+        async move {
+            loop {
+                let (stream, _) = server.accept().await.unwrap();
+                let in_flight = Arc::clone(&in_flight);
+                let max_in_flight = Arc::clone(&max_in_flight);
+                let service = service_fn(move |_| {
+                    let in_flight = Arc::clone(&in_flight);
+                    let max_in_flight = Arc::clone(&max_in_flight);
+                    async move {
+                        let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                        max_in_flight.fetch_max(current, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(200)).await;
+                        in_flight.fetch_sub(1, Ordering::SeqCst);
+                        Ok::<_, Infallible>(Response::new(Body::from(common::HELLO_WORLD)))
+                    }
+                });
+                tokio::spawn(Http::new().serve_connection(stream, service));
+            }
+        }
+    });
+
+    let mut control = None;
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        let proxy = builder
+            .with_client(common::http_client())
+            .with_ca(build_ca())
+            .with_per_host_connection_limit(2)
+            .build();
+        control = Some(proxy.control());
+        proxy
+    })
+    .unwrap();
+    let control = control.unwrap();
+
+    let client = common::build_client(&proxy_addr.to_string());
+    let requests: Vec<_> = (0..5)
+        .map(|_| {
+            let client = client.clone();
+            tokio::spawn(async move {
+                let res = client
+                    .get(format!("http://{}/", server_addr))
+                    .send()
+                    .await
+                    .unwrap();
+                res.bytes().await.unwrap()
+            })
+        })
+        .collect();
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(
+        control.host_connections(),
+        HashMap::from([(server_addr.to_string().parse().unwrap(), 2)])
+    );
+
+    for request in requests {
+        assert_eq!(request.await.unwrap(), common::HELLO_WORLD);
+    }
+
+    assert_eq!(max_in_flight.load(Ordering::SeqCst), 2);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(control.host_connections().is_empty());
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn retry_policy() {
+    let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server.local_addr().unwrap();
+    tokio::spawn({
+        let requests = Arc::clone(&requests);
+        async move {
+            loop {
+                let (stream, _) = server.accept().await.unwrap();
+                let requests = Arc::clone(&requests);
+                let service = service_fn(move |req: Request<Body>| {
+                    let requests = Arc::clone(&requests);
+                    async move {
+                        let path = req.uri().path().to_owned();
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+
+                        // The first request to each path fails.
+                        let mut requests = requests.lock().unwrap();
+                        let status = if requests.iter().any(|(seen, _)| *seen == path) {
+                            StatusCode::OK
+                        } else {
+                            StatusCode::SERVICE_UNAVAILABLE
+                        };
+                        requests.push((path, body));
+
+                        Ok::<_, Infallible>(
+                            Response::builder()
+                                .status(status)
+                                .body(Body::empty())
+                                .unwrap(),
+                        )
+                    }
+                });
+                tokio::spawn(Http::new().serve_connection(stream, service));
+            }
+        }
+    });
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::http_client())
+            .with_ca(build_ca())
+            .with_retry_policy(RetryPolicy {
+                statuses: vec![StatusCode::SERVICE_UNAVAILABLE],
+                max: 2,
+                backoff: Duration::from_millis(10),
+            })
+            .build()
+    })
+    .unwrap();
+
+    let client = common::build_client(&proxy_addr.to_string());
+
+    let res = client
+        .put(format!("http://{}/put", server_addr))
+        .body("body")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+
+    let res = client
+        .post(format!("http://{}/post", server_addr))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 503);
+
+    let requests = requests.lock().unwrap();
+    assert_eq!(
+        *requests,
+        [
+            ("/put".to_owned(), "body".into()),
+            ("/put".to_owned(), "body".into()),
+            ("/post".to_owned(), "".into()),
+        ]
+    );
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn mirror() {
+    let upstream = MockUpstream::new();
+    upstream.mock(Method::GET, "/hello", Response::new(common::HELLO_WORLD));
+    upstream.mock(
+        Method::POST,
+        "/echo",
+        Response::builder().header("x-a", "a").body("done").unwrap(),
+    );
+
+    let (tx, mut rx) = mpsc::channel(8);
+    let mut control = None;
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        let proxy = builder
+            .with_client(upstream.client())
+            .with_ca(build_ca())
+            .with_mirror(tx)
+            .build();
+        control = Some(proxy.control());
+        proxy
+    })
+    .unwrap();
+    let control = control.unwrap();
+
+    let client = common::build_client(&proxy_addr.to_string());
+    let res = client.get("http://example.com/hello").send().await.unwrap();
+    assert_eq!(res.text().await.unwrap(), common::HELLO_WORLD);
+    let res = client
+        .post("http://example.com/echo")
+        .body("request")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.text().await.unwrap(), "done");
+
+    let mut records = Vec::new();
+    for _ in 0..2 {
+        let record = tokio::time::timeout(Duration::from_secs(1), rx.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        records.push(record);
+    }
+
+    assert_eq!(records[0].method, Method::GET);
+    assert_eq!(records[0].uri, "http://example.com/hello");
+    assert_eq!(records[0].status, Some(StatusCode::OK));
+    assert_eq!(records[0].response_body, common::HELLO_WORLD);
+
+    assert_eq!(records[1].method, Method::POST);
+    assert_eq!(records[1].request_body, "request");
+    assert!(!records[1].request_body_truncated);
+    assert_eq!(records[1].response_headers["x-a"], "a");
+    assert_eq!(records[1].response_body, "done");
+    assert!(!records[1].response_body_truncated);
+
+    assert_eq!(control.mirror_records_dropped(), 0);
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn event_channel() {
+    let upstream = MockUpstream::new();
+    upstream.mock(Method::GET, "/hello", Response::new(common::HELLO_WORLD));
+
+    let mut events = None;
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        let (builder, receiver) = builder
+            .with_client(upstream.client())
+            .with_ca(build_ca())
+            .with_event_channel(16);
+        events = Some(receiver);
+        builder.build()
+    })
+    .unwrap();
+    let mut events = events.unwrap();
+
+    let client = common::build_client(&proxy_addr.to_string());
+    let res = client.get("http://example.com/hello").send().await.unwrap();
+    assert_eq!(res.text().await.unwrap(), common::HELLO_WORLD);
+    drop(client);
+
+    let mut received = Vec::new();
+    loop {
+        let event = tokio::time::timeout(Duration::from_secs(1), events.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        let closed = event.kind == ProxyEventKind::ConnectionClosed;
+        received.push(event);
+        if closed {
+            break;
+        }
+    }
+
+    assert_eq!(received.len(), 4);
+    assert!(matches!(
+        received[0].kind,
+        ProxyEventKind::ConnectionAccepted { .. }
+    ));
+    assert_eq!(
+        received[1].kind,
+        ProxyEventKind::RequestStarted {
+            request_id: 0,
+            method: Method::GET,
+            uri: Uri::from_static("http://example.com/hello"),
+        }
+    );
+    assert_eq!(
+        received[2].kind,
+        ProxyEventKind::ResponseCompleted {
+            request_id: 0,
+            status: StatusCode::OK,
+        }
+    );
+    assert!(received
+        .iter()
+        .all(|event| event.connection_id == received[0].connection_id));
+    assert!(received
+        .windows(2)
+        .all(|events| events[0].timestamp <= events[1].timestamp));
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn client_idle_timeout() {
+    let upstream = MockUpstream::new();
+    upstream.mock(Method::GET, "/hello", Response::new(common::HELLO_WORLD));
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(upstream.client())
+            .with_ca(build_ca())
+            .with_client_idle_timeout(Duration::from_millis(200))
+            .build()
+    })
+    .unwrap();
+
+    for connect in [false, true] {
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+        let target = if connect {
+            stream
+                .write_all(b"CONNECT example.com:80 HTTP/1.1\r\nHost: example.com:80\r\n\r\n")
+                .await
+                .unwrap();
+            assert!(common::read_response_head(&mut stream)
+                .await
+                .starts_with("HTTP/1.1 200"));
+            "/hello"
+        } else {
+            "http://example.com/hello"
+        };
+
+        let request = format!("GET {} HTTP/1.1\r\nHost: example.com\r\n\r\n", target);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        assert!(common::read_response_head(&mut stream)
+            .await
+            .starts_with("HTTP/1.1 200"));
+        let mut body = [0; common::HELLO_WORLD.len()];
+        stream.read_exact(&mut body).await.unwrap();
+
+        let idle = tokio::time::Instant::now();
+        let mut rest = Vec::new();
+        tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(rest.is_empty());
+        assert!(idle.elapsed() >= Duration::from_millis(150));
+    }
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn header_limits() {
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::http_client())
+            .with_ca(build_ca())
+            .with_max_headers(2)
+            .with_max_header_bytes(100)
+            .build()
+    })
+    .unwrap();
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream
+        .write_all(
+            b"GET http://127.0.0.1:1/ HTTP/1.1\r\nHost: 127.0.0.1:1\r\nx-a: a\r\nx-b: b\r\n\r\n",
+        )
+        .await
+        .unwrap();
+    let head = common::read_response_head(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 431"));
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream
+        .write_all(
+            format!(
+                "GET http://127.0.0.1:1/ HTTP/1.1\r\nHost: 127.0.0.1:1\r\nx-a: {}\r\n\r\n",
+                "a".repeat(100)
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    let head = common::read_response_head(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 431"));
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[cfg(feature = "http2")]
+#[tokio::test]
+async fn max_concurrent_streams() {
+    #[derive(Clone, Default)]
+    struct SlowHandler {
+        in_flight: Arc<AtomicUsize>,
+        max_in_flight: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl HttpHandler for SlowHandler {
+        async fn handle_request(
+            &mut self,
+            _ctx: &HttpContext,
+            _req: Request<Body>,
+        ) -> RequestOrResponse {
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+
+            Response::new(Body::empty()).into()
+        }
+    }
+
+    let handler = SlowHandler::default();
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::http_client())
+            .with_ca(build_ca())
+            .with_http_handler(handler.clone())
+            .with_max_concurrent_streams(2)
+            .build()
+    })
+    .unwrap();
+
+    let client = reqwest::Client::builder()
+        .proxy(reqwest::Proxy::all(proxy_addr.to_string()).unwrap())
+        .http2_prior_knowledge()
+        .build()
+        .unwrap();
+
+    // The first request waits for the settings of the proxy to be received.
+    let res = client.get("http://example.com/").send().await.unwrap();
+    assert_eq!(res.status(), 200);
+
+    let requests = (0..6).map(|_| client.get("http://example.com/").send());
+    for res in futures::future::join_all(requests).await {
+        assert_eq!(res.unwrap().status(), 200);
+    }
+    assert_eq!(handler.max_in_flight.load(Ordering::SeqCst), 2);
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn response_header_limit() {
+    #[derive(Clone)]
+    struct SourceHandler;
+
+    #[async_trait]
+    impl HttpHandler for SourceHandler {
+        async fn handle_error(&mut self, _ctx: &HttpContext, err: hyper::Error) -> Response<Body> {
+            let too_large = std::error::Error::source(&err)
+                .and_then(|source| source.downcast_ref::<hudsucker::Error>())
+                .is_some_and(|source| matches!(source, hudsucker::Error::ResponseHeadersTooLarge));
+            assert!(too_large);
+
+            Response::builder()
+                .status(StatusCode::BAD_GATEWAY)
+                .body(Body::empty())
+                .unwrap()
+        }
+    }
+
+    let upstream = MockUpstream::new();
+    upstream.mock(
+        Method::GET,
+        "/small",
+        Response::builder().header("x-a", "a").body("").unwrap(),
+    );
+    upstream.mock(
+        Method::GET,
+        "/large",
+        Response::builder()
+            .header("x-a", "a".repeat(16 * 1024))
+            .body("secret")
+            .unwrap(),
+    );
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(upstream.client())
+            .with_ca(build_ca())
+            .with_http_handler(SourceHandler)
+            .with_max_response_header_bytes(1024)
+            .build()
+    })
+    .unwrap();
+
+    let client = common::build_client(&proxy_addr.to_string());
+
+    let res = client.get("http://example.com/small").send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers()["x-a"], "a");
+
+    let res = client.get("http://example.com/large").send().await.unwrap();
+    assert_eq!(res.status(), 502);
+    assert!(!res.headers().contains_key("x-a"));
+    assert_eq!(res.text().await.unwrap(), "");
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn smuggling_protection() {
+    async fn send_ambiguous_request(smuggling_protection: bool) -> String {
+        let upstream = MockUpstream::new();
+        upstream.mock(Method::POST, "/", Response::new(""));
+
+        let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+            builder
+                .with_client(upstream.client())
+                .with_ca(build_ca())
+                .with_smuggling_protection(smuggling_protection)
+                .build()
+        })
+        .unwrap();
+
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+        stream
+            .write_all(
+                b"POST http://example.com/ HTTP/1.1\r\n\
+                Host: example.com\r\n\
+                Content-Length: 5\r\n\
+                Transfer-Encoding: chunked\r\n\r\n\
+                0\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let head = common::read_response_head(&mut stream).await;
+        stop_proxy.send(()).unwrap();
+        head
+    }
+
+    assert!(send_ambiguous_request(true)
+        .await
+        .starts_with("HTTP/1.1 400"));
+    assert!(send_ambiguous_request(false)
+        .await
+        .starts_with("HTTP/1.1 200"));
+}
+
+#[tokio::test]
+async fn upstream_tls() {
+    #[derive(Clone)]
+    struct TlsInfoHandler;
+
+    #[async_trait]
+    impl HttpHandler for TlsInfoHandler {
+        async fn handle_response(
+            &mut self,
+            ctx: &HttpContext,
+            mut res: Response<Body>,
+        ) -> Response<Body> {
+            let tls = ctx.upstream_tls.as_ref().unwrap();
+            let headers = res.headers_mut();
+            headers.insert(
+                "x-tls-version",
+                format!("{:?}", tls.protocol_version.unwrap())
+                    .parse()
+                    .unwrap(),
+            );
+            headers.insert("x-peer-certificates", tls.peer_certificates.len().into());
+            res
+        }
+    }
+
+    let https = hyper_rustls::HttpsConnectorBuilder::new()
+        .with_tls_config(common::rustls_client_config())
+        .https_or_http()
+        .enable_http1()
+        .build();
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(Client::builder().build(TlsInfoConnector::new(https)))
+            .with_ca(build_ca())
+            .with_http_handler(TlsInfoHandler)
+            .build()
+    })
+    .unwrap();
+
+    let (server_addr, stop_server) = common::start_https_server(build_ca()).await.unwrap();
+    let client = common::build_client(&proxy_addr.to_string());
+
+    let res = client
+        .get(format!("https://localhost:{}/hello", server_addr.port()))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers()["x-tls-version"], "TLSv1_3");
+    assert_eq!(res.headers()["x-peer-certificates"], "1");
+
+    stop_server.send(()).unwrap();
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn request_coalescing() {
+    let hits = Arc::new(AtomicUsize::new(0));
+
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server.local_addr().unwrap();
+    tokio::spawn({
+        let hits = Arc::clone(&hits);
+        async move {
+            loop {
+                let (stream, _) = server.accept().await.unwrap();
+                let hits = Arc::clone(&hits);
+                let service = service_fn(move |_| {
+                    let hits = Arc::clone(&hits);
+                    async move {
+                        hits.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(200)).await;
+                        Ok::<_, Infallible>(Response::new(Body::from(common::HELLO_WORLD)))
+                    }
+                });
+                tokio::spawn(Http::new().serve_connection(stream, service));
+            }
+        }
+    });
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::http_client())
+            .with_ca(build_ca())
+            .with_request_coalescing(true)
+            .build()
+    })
+    .unwrap();
+
+    let client = common::build_client(&proxy_addr.to_string());
+    let get = || {
+        let client = client.clone();
+        tokio::spawn(async move {
+            let res = client
+                .get(format!("http://{}/", server_addr))
+                .send()
+                .await
+                .unwrap();
+            res.bytes().await.unwrap()
+        })
+    };
+
+    let requests: Vec<_> = (0..5).map(|_| get()).collect();
+    for request in requests {
+        assert_eq!(request.await.unwrap(), common::HELLO_WORLD);
+    }
+    assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+    // Responses aren't kept once they have been shared.
+    assert_eq!(get().await.unwrap(), common::HELLO_WORLD);
+    assert_eq!(hits.load(Ordering::SeqCst), 2);
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn connection_reused() {
+    #[derive(Clone)]
+    struct ReuseHandler;
+
+    #[async_trait]
+    impl HttpHandler for ReuseHandler {
+        async fn handle_response(
+            &mut self,
+            ctx: &HttpContext,
+            mut res: Response<Body>,
+        ) -> Response<Body> {
+            let reused = format!("{:?}", ctx.connection_reused);
+            res.headers_mut()
+                .insert("x-connection-reused", reused.parse().unwrap());
+            res
+        }
+    }
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(Client::builder().build(ReuseInfoConnector::new(HttpConnector::new())))
+            .with_ca(build_ca())
+            .with_http_handler(ReuseHandler)
+            .build()
+    })
+    .unwrap();
+
+    let (server_addr, stop_server) = common::start_http_server().unwrap();
+    let client = common::build_client(&proxy_addr.to_string());
+
+    for expected in ["Some(false)", "Some(true)"] {
+        let res = client
+            .get(format!("http://{}/hello", server_addr))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(res.headers()["x-connection-reused"], expected);
+        assert_eq!(res.bytes().await.unwrap(), common::HELLO_WORLD);
+    }
+
+    stop_server.send(()).unwrap();
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn rewrite_method() {
+    #[derive(Clone, Default)]
+    struct MethodHandler {
+        head: bool,
+    }
+
+    #[async_trait]
+    impl HttpHandler for MethodHandler {
+        async fn handle_request(
+            &mut self,
+            _ctx: &HttpContext,
+            mut req: Request<Body>,
+        ) -> RequestOrResponse {
+            // Send HEAD requests upstream as GET requests, and GET requests as HEAD requests.
+            self.head = req.method() == Method::HEAD;
+            *req.method_mut() = if self.head { Method::GET } else { Method::HEAD };
+            req.into()
+        }
+
+        async fn handle_response(
+            &mut self,
+            _ctx: &HttpContext,
+            mut res: Response<Body>,
+        ) -> Response<Body> {
+            if self.head {
+                let (parts, _) = res.into_parts();
+                Response::from_parts(parts, Body::empty())
+            } else {
+                let has_length = res.headers().contains_key(header::CONTENT_LENGTH);
+                res.headers_mut()
+                    .insert("x-has-length", has_length.to_string().parse().unwrap());
+                res
+            }
+        }
+    }
+
+    let upstream = MockUpstream::new();
+    upstream.mock(Method::GET, "/hello", Response::new(common::HELLO_WORLD));
+    upstream.mock(
+        Method::HEAD,
+        "/hello",
+        Response::builder()
+            .header(header::CONTENT_LENGTH, common::HELLO_WORLD.len())
+            .body(common::HELLO_WORLD)
+            .unwrap(),
+    );
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(upstream.client())
+            .with_ca(build_ca())
+            .with_http_handler(MethodHandler::default())
+            .build()
+    })
+    .unwrap();
+
+    let client = common::build_client(&proxy_addr.to_string());
+
+    let res = client
+        .head("http://example.com/hello")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(
+        res.headers()[header::CONTENT_LENGTH],
+        common::HELLO_WORLD.len().to_string()
+    );
+    assert_eq!(res.bytes().await.unwrap(), "");
+
+    let res = client.get("http://example.com/hello").send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers()["x-has-length"], "false");
+    assert_eq!(res.bytes().await.unwrap(), "");
+
+    let methods: Vec<_> = upstream
+        .requests()
+        .into_iter()
+        .map(|req| req.method)
+        .collect();
+    assert_eq!(methods, [Method::GET, Method::HEAD]);
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn upstream_timeout() {
+    #[derive(Clone)]
+    struct TimeoutHandler;
+
+    #[async_trait]
+    impl HttpHandler for TimeoutHandler {
+        async fn handle_request(
+            &mut self,
+            _ctx: &HttpContext,
+            mut req: Request<Body>,
+        ) -> RequestOrResponse {
+            let timeout = match req.uri().query() {
+                Some("short") => Duration::from_millis(50),
+                _ => Duration::from_secs(5),
+            };
+            req.extensions_mut().insert(UpstreamTimeout(timeout));
+            req.into()
+        }
+    }
+
+    let (server_addr, stop_server) = common::start_http_server().unwrap();
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::http_client())
+            .with_ca(build_ca())
+            .with_http_handler(TimeoutHandler)
+            .build()
+    })
+    .unwrap();
+
+    let client = common::build_client(&proxy_addr.to_string());
+
+    let res = client
+        .get(format!("http://{}/slow?short", server_addr))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::GATEWAY_TIMEOUT);
+
+    let res = client
+        .get(format!("http://{}/slow?long", server_addr))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), StatusCode::OK);
+    assert_eq!(res.text().await.unwrap(), common::HELLO_WORLD);
+
+    stop_server.send(()).unwrap();
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn user_agent_override() {
+    let upstream = MockUpstream::new();
+    upstream.mock(Method::GET, "/hello", Response::new(common::HELLO_WORLD));
+
+    let (default_addr, stop_default) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(upstream.client())
+            .with_ca(build_ca())
+            .build()
+    })
+    .unwrap();
+    let (override_addr, stop_override) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(upstream.client())
+            .with_ca(build_ca())
+            .with_user_agent_override(HeaderValue::from_static("bot/1.0"))
+            .build()
+    })
+    .unwrap();
+
+    for proxy_addr in [default_addr, override_addr] {
+        let res = common::build_client(&proxy_addr.to_string())
+            .get("http://example.com/hello")
+            .header(header::USER_AGENT, "curl/8.0")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+    }
+
+    let requests = upstream.requests();
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[0].headers[header::USER_AGENT], "curl/8.0");
+    assert_eq!(requests[1].headers[header::USER_AGENT], "bot/1.0");
+
+    stop_default.send(()).unwrap();
+    stop_override.send(()).unwrap();
+}
+
+const H2C_UPGRADE_REQUEST: &str = "Connection: Upgrade, HTTP2-Settings\r\n\
+                                   Upgrade: h2c\r\n\
+                                   HTTP2-Settings: AAMAAABkAARAAAAAAAIAAAAA\r\n\r\n";
+
+#[tokio::test]
+async fn strips_h2c_upgrade() {
+    let upstream = MockUpstream::new();
+    upstream.mock(Method::GET, "/hello", Response::new(common::HELLO_WORLD));
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(upstream.client())
+            .with_ca(build_ca())
+            .build()
+    })
+    .unwrap();
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream
+        .write_all(
+            format!(
+                "GET http://example.com/hello HTTP/1.1\r\nHost: example.com\r\n{}",
+                H2C_UPGRADE_REQUEST
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+
+    let head = common::read_response_head(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 200 OK\r\n"), "{}", head);
+
+    let requests = upstream.requests();
+    assert_eq!(requests.len(), 1);
+    assert_eq!(requests[0].headers.get(header::UPGRADE), None);
+    assert_eq!(requests[0].headers.get(header::CONNECTION), None);
+    assert_eq!(requests[0].headers.get("http2-settings"), None);
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn tunnels_h2c_upgrade() {
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = server.accept().await.unwrap();
+            let service = service_fn(|mut req: Request<Body>| async move {
+                assert_eq!(req.headers()[header::UPGRADE], "h2c");
+                assert!(req.headers().contains_key("http2-settings"));
+
+                // Echo whatever is sent over the upgraded connection.
+                tokio::spawn(async move {
+                    let upgraded = hyper::upgrade::on(&mut req).await.unwrap();
+                    let (mut reader, mut writer) = tokio::io::split(upgraded);
+                    let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                });
+
+                Ok::<_, Infallible>(
+                    Response::builder()
+                        .status(StatusCode::SWITCHING_PROTOCOLS)
+                        .header(header::CONNECTION, "Upgrade")
+                        .header(header::UPGRADE, "h2c")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+            });
+            tokio::spawn(
+                Http::new()
+                    .serve_connection(stream, service)
+                    .with_upgrades(),
+            );
+        }
+    });
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::http_client())
+            .with_ca(build_ca())
+            .with_h2c_upgrade(true)
+            .build()
+    })
+    .unwrap();
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream
+        .write_all(
+            format!(
+                "GET http://{0}/ HTTP/1.1\r\nHost: {0}\r\n{1}",
+                server_addr, H2C_UPGRADE_REQUEST
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+
+    let head = common::read_response_head(&mut stream).await;
+    assert!(
+        head.starts_with("HTTP/1.1 101 Switching Protocols\r\n"),
+        "{}",
+        head
+    );
+
+    stream.write_all(b"ping").await.unwrap();
+    let mut echoed = [0; 4];
+    tokio::time::timeout(Duration::from_secs(1), stream.read_exact(&mut echoed))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&echoed, b"ping");
+
+    stop_proxy.send(()).unwrap();
+}
+
+/// Start a server that responds with the `Host` header of each request.
+async fn start_host_echo_server() -> std::net::SocketAddr {
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (stream, _) = server.accept().await.unwrap();
+            let service = service_fn(|req: Request<Body>| async move {
+                let host = req.headers()[header::HOST].clone();
+                Ok::<_, Infallible>(Response::new(Body::from(host.as_bytes().to_vec())))
+            });
+            tokio::spawn(Http::new().serve_connection(stream, service));
+        }
+    });
+    server_addr
+}
+
+#[derive(Clone)]
+struct TargetHandler(UpstreamTarget);
+
+#[async_trait]
+impl HttpHandler for TargetHandler {
+    fn should_sniff(&self, _ctx: &HttpContext, _authority: &Authority) -> bool {
+        false
+    }
+
+    fn upstream_target(&self, _ctx: &HttpContext, _req: &Request<Body>) -> Option<UpstreamTarget> {
+        Some(self.0.clone())
+    }
+}
+
+#[tokio::test]
+async fn upstream_target_connect_addr() {
+    let server_addr = start_host_echo_server().await;
+    let target = UpstreamTarget {
+        connect_addr: Some(server_addr),
+        ..UpstreamTarget::default()
+    };
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(
+                Client::builder().build(UpstreamTargetConnector::new(HttpConnector::new())),
+            )
+            .with_ca(build_ca())
+            .with_http_handler(TargetHandler(target))
+            .build()
+    })
+    .unwrap();
+
+    let client = common::build_client(&proxy_addr.to_string());
+    let res = client
+        .get("http://example.invalid:8080/")
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.text().await.unwrap(), "example.invalid:8080");
+
+    // Tunnels that aren't intercepted connect to the address as well.
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream
+        .write_all(b"CONNECT example.invalid:80 HTTP/1.1\r\nHost: example.invalid:80\r\n\r\n")
+        .await
+        .unwrap();
+
+    let head = common::read_response_head(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 200"));
+
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: tunneled.example\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut res = String::new();
+    stream.read_to_string(&mut res).await.unwrap();
+    assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+    assert!(res.ends_with("\r\n\r\ntunneled.example"));
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn upstream_target_host_header() {
+    let server_addr = start_host_echo_server().await;
+    let target = UpstreamTarget {
+        host_header: Some(HeaderValue::from_static("other.example")),
+        ..UpstreamTarget::default()
+    };
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::http_client())
+            .with_ca(build_ca())
+            .with_http_handler(TargetHandler(target))
+            .build()
+    })
+    .unwrap();
+
+    let client = common::build_client(&proxy_addr.to_string());
+    let res = client
+        .get(format!("http://{}/", server_addr))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.text().await.unwrap(), "other.example");
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn detects_loop_to_proxy() {
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::http_client())
+            .with_ca(build_ca())
+            .with_self_addrs([SocketAddr::from(([192, 0, 2, 1], 3128))])
+            .build()
+    })
+    .unwrap();
+
+    for authority in [proxy_addr.to_string(), "192.0.2.1:3128".to_owned()] {
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+        stream
+            .write_all(format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n\r\n", authority).as_bytes())
+            .await
+            .unwrap();
+
+        let head = common::read_response_head(&mut stream).await;
+        assert!(head.starts_with("HTTP/1.1 508"), "{}", head);
+    }
+
+    let client = common::build_client(&proxy_addr.to_string());
+    let res = client
+        .get(format!("http://{}/", proxy_addr))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), 508);
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[derive(Clone)]
+struct ExtensionsHandler;
+
+#[derive(Clone)]
+struct RequestPath(String);
+
+#[async_trait]
+impl HttpHandler for ExtensionsHandler {
+    async fn handle_request(&mut self, ctx: &HttpContext, req: Request<Body>) -> RequestOrResponse {
+        ctx.extensions.update(|count: &mut usize| *count += 1);
+        ctx.extensions
+            .insert(RequestPath(req.uri().path().to_owned()));
+        req.into()
+    }
+
+    async fn handle_response(
+        &mut self,
+        ctx: &HttpContext,
+        mut res: Response<Body>,
+    ) -> Response<Body> {
+        let RequestPath(path) = ctx.extensions.get().unwrap();
+        let count = ctx.extensions.get::<usize>().unwrap();
+        res.headers_mut()
+            .insert("x-request-path", HeaderValue::from_str(&path).unwrap());
+        res.headers_mut()
+            .insert("x-request-count", HeaderValue::from(count));
+        res
+    }
+}
+
+#[tokio::test]
+async fn exchange_extensions() {
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::http_client())
+            .with_ca(build_ca())
+            .with_http_handler(ExtensionsHandler)
+            .build()
+    })
+    .unwrap();
+    let (server_addr, stop_server) = common::start_http_server().unwrap();
+    let client = common::build_client(&proxy_addr.to_string());
+
+    // Each exchange has a store of its own, even on the same connection.
+    for path in ["/hello", "/hello/again"] {
+        let res = client
+            .get(format!("http://{}{}", server_addr, path))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(res.headers()["x-request-path"], path);
+        assert_eq!(res.headers()["x-request-count"], "1");
+    }
+
+    stop_server.send(()).unwrap();
+    stop_proxy.send(()).unwrap();
+}
@@ -4,6 +4,7 @@ use hudsucker::{
 };
 use std::sync::atomic::Ordering;
 
+#[allow(unused)]
 mod common;
 
 fn build_ca() -> OpensslAuthority {
@@ -16,6 +17,33 @@ fn build_ca() -> OpensslAuthority {
     OpensslAuthority::new(private_key, ca_cert, MessageDigest::sha256(), 1_000)
 }
 
+#[tokio::test]
+async fn http() {
+    let (proxy_addr, handler, stop_proxy) = common::start_proxy(
+        build_ca(),
+        common::http_client(),
+        common::plain_websocket_connector(),
+    )
+    .unwrap();
+
+    let (server_addr, stop_server) = common::start_http_server().unwrap();
+    let client = common::build_client(&proxy_addr.to_string());
+
+    let res = client
+        .get(format!("http://{}/hello", server_addr))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), 200);
+    assert_eq!(handler.request_counter.load(Ordering::Relaxed), 1);
+    assert_eq!(handler.response_counter.load(Ordering::Relaxed), 1);
+    assert_eq!(res.bytes().await.unwrap(), common::HELLO_WORLD);
+
+    stop_server.send(()).unwrap();
+    stop_proxy.send(()).unwrap();
+}
+
 #[tokio::test]
 async fn https_rustls() {
     let (proxy_addr, handler, stop_proxy) = common::start_proxy(
@@ -1,7 +1,25 @@
-use hudsucker::{certificate_authority::RcgenAuthority, rustls};
+use hudsucker::{
+    async_trait::async_trait,
+    certificate_authority::{
+        generate_ca_to_disk, CaBuilder, CertificateAuthority, ClientCertMode, RcgenAuthority,
+    },
+    hyper::{http::uri::Authority, Body, Method, Request, Response, Version},
+    rcgen, rustls, DecryptedStream, HandshakeDelay, HttpContext, HttpHandler, InterceptPolicy,
+    InterceptPolicyHandle, ProxyError, ProxyEventKind, RequestOrResponse, TlsFault, TlsFaults,
+    UpstreamTarget,
+};
 use rustls_pemfile as pemfile;
-use std::sync::atomic::Ordering;
+use std::{
+    sync::{atomic::Ordering, Arc},
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
 
+#[allow(unused)]
 mod common;
 
 fn build_ca() -> RcgenAuthority {
@@ -22,6 +40,33 @@ fn build_ca() -> RcgenAuthority {
         .expect("Failed to create Certificate Authority")
 }
 
+#[tokio::test]
+async fn http() {
+    let (proxy_addr, handler, stop_proxy) = common::start_proxy(
+        build_ca(),
+        common::http_client(),
+        common::plain_websocket_connector(),
+    )
+    .unwrap();
+
+    let (server_addr, stop_server) = common::start_http_server().unwrap();
+    let client = common::build_client(&proxy_addr.to_string());
+
+    let res = client
+        .get(format!("http://{}/hello", server_addr))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), 200);
+    assert_eq!(handler.request_counter.load(Ordering::Relaxed), 1);
+    assert_eq!(handler.response_counter.load(Ordering::Relaxed), 1);
+    assert_eq!(res.bytes().await.unwrap(), common::HELLO_WORLD);
+
+    stop_server.send(()).unwrap();
+    stop_proxy.send(()).unwrap();
+}
+
 #[tokio::test]
 async fn https_rustls() {
     let (proxy_addr, handler, stop_proxy) = common::start_proxy(
@@ -48,6 +93,1039 @@ async fn https_rustls() {
     stop_proxy.send(()).unwrap();
 }
 
+#[tokio::test]
+async fn generated_ca() {
+    let ca = CaBuilder::new().build().unwrap();
+    let ca_cert = reqwest::Certificate::from_pem(ca.ca_cert_pem().as_bytes()).unwrap();
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::rustls_client())
+            .with_ca(ca)
+            .build()
+    })
+    .unwrap();
+
+    let (server_addr, stop_server) = common::start_https_server(build_ca()).await.unwrap();
+    let client = reqwest::Client::builder()
+        .proxy(reqwest::Proxy::all(proxy_addr.to_string()).unwrap())
+        .tls_built_in_root_certs(false)
+        .add_root_certificate(ca_cert)
+        .build()
+        .unwrap();
+
+    let res = client
+        .get(format!("https://localhost:{}/hello", server_addr.port()))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), 200);
+
+    stop_server.send(()).unwrap();
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn ca_cert_endpoint() {
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::rustls_client())
+            .with_ca(build_ca())
+            .with_ca_cert_endpoint(true)
+            .build()
+    })
+    .unwrap();
+
+    let client = common::build_client(&proxy_addr.to_string());
+    let ca_cert_bytes: &[u8] = include_bytes!("../examples/ca/hudsucker.cer");
+    let ca_cert = pemfile::certs(&mut &ca_cert_bytes[..]).unwrap().remove(0);
+
+    let res = client
+        .get("http://hudsucker.internal/cert")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.headers()["content-type"], "application/x-pem-file");
+    let pem = res.bytes().await.unwrap();
+    assert_eq!(
+        pemfile::certs(&mut &pem[..]).unwrap(),
+        vec![ca_cert.clone()]
+    );
+
+    let res = client
+        .get("http://hudsucker.internal/cert")
+        .header("accept", "application/x-x509-ca-cert")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.bytes().await.unwrap(), ca_cert);
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn https_non_default_port() {
+    #[derive(Clone)]
+    struct AuthorityHandler(mpsc::UnboundedSender<String>);
+
+    #[async_trait]
+    impl HttpHandler for AuthorityHandler {
+        async fn handle_request(
+            &mut self,
+            _ctx: &HttpContext,
+            req: Request<Body>,
+        ) -> RequestOrResponse {
+            if req.method() != Method::CONNECT {
+                let authority = req.uri().authority().unwrap().to_string();
+                self.0.send(authority).unwrap();
+            }
+
+            req.into()
+        }
+    }
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::rustls_client())
+            .with_ca(build_ca())
+            .with_http_handler(AuthorityHandler(tx))
+            .build()
+    })
+    .unwrap();
+
+    let (server_addr, stop_server) = common::start_https_server(build_ca()).await.unwrap();
+    assert_ne!(server_addr.port(), 443);
+
+    let client = common::build_client(&proxy_addr.to_string());
+    let res = client
+        .get(format!("https://localhost:{}/hello", server_addr.port()))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), 200);
+    assert_eq!(
+        rx.recv().await.unwrap(),
+        format!("localhost:{}", server_addr.port())
+    );
+
+    stop_server.send(()).unwrap();
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn upstream_root_store() {
+    async fn request_with_roots(root_store: rustls::RootCertStore) -> reqwest::StatusCode {
+        let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+            builder
+                .with_upstream_root_store(root_store)
+                .with_ca(build_ca())
+                .build()
+        })
+        .unwrap();
+
+        let (server_addr, stop_server) = common::start_https_server(build_ca()).await.unwrap();
+        let client = common::build_client(&proxy_addr.to_string());
+        let res = client
+            .get(format!("https://localhost:{}/hello", server_addr.port()))
+            .send()
+            .await
+            .unwrap();
+
+        // The server stops by itself if the handshake fails.
+        stop_server.send(()).unwrap_or_default();
+        stop_proxy.send(()).unwrap();
+        res.status()
+    }
+
+    let mut ca_cert_bytes: &[u8] = include_bytes!("../examples/ca/hudsucker.cer");
+    let mut root_store = rustls::RootCertStore::empty();
+    root_store.add_parsable_certificates(&pemfile::certs(&mut ca_cert_bytes).unwrap());
+
+    assert_eq!(request_with_roots(root_store).await, 200);
+    assert_eq!(
+        request_with_roots(rustls::RootCertStore::empty()).await,
+        502
+    );
+}
+
+#[tokio::test]
+async fn upstream_tls_error() {
+    #[derive(Clone)]
+    struct ErrorHandler(mpsc::UnboundedSender<ProxyError>);
+
+    #[async_trait]
+    impl HttpHandler for ErrorHandler {
+        async fn handle_proxy_error(
+            &mut self,
+            _ctx: &HttpContext,
+            err: ProxyError,
+        ) -> Response<Body> {
+            let res = Response::builder()
+                .status(err.status())
+                .body(Body::empty())
+                .unwrap();
+            self.0.send(err).unwrap();
+            res
+        }
+    }
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_upstream_root_store(rustls::RootCertStore::empty())
+            .with_ca(build_ca())
+            .with_http_handler(ErrorHandler(tx))
+            .build()
+    })
+    .unwrap();
+
+    let (server_addr, stop_server) = common::start_https_server(build_ca()).await.unwrap();
+    let client = common::build_client(&proxy_addr.to_string());
+    let res = client
+        .get(format!("https://localhost:{}/hello", server_addr.port()))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), 502);
+    assert!(matches!(
+        rx.recv().await.unwrap(),
+        ProxyError::Tls { alert: None, .. }
+    ));
+
+    // The server stops by itself if the handshake fails.
+    stop_server.send(()).unwrap_or_default();
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn cert_served_events() {
+    let mut events = None;
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        let (builder, receiver) = builder
+            .with_client(common::rustls_client())
+            .with_ca(build_ca())
+            .with_event_channel(16);
+        events = Some(receiver);
+        builder.build()
+    })
+    .unwrap();
+    let mut events = events.unwrap();
+
+    let (server_addr, stop_server) = common::start_https_server(build_ca()).await.unwrap();
+    let authority = format!("localhost:{}", server_addr.port());
+
+    let mut served = Vec::new();
+    for _ in 0..2 {
+        // Each client opens its own connection, so that a certificate is served to both.
+        let res = common::build_client(&proxy_addr.to_string())
+            .get(format!("https://{}/hello", authority))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+
+        loop {
+            let event = tokio::time::timeout(Duration::from_secs(1), events.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            if let ProxyEventKind::CertServed { .. } = event.kind {
+                served.push(event.kind);
+                break;
+            }
+        }
+    }
+
+    match &served[..] {
+        [ProxyEventKind::CertServed {
+            authority: first_authority,
+            from_cache: false,
+            gen_duration: Some(_),
+            key_algo: rustls::SignatureAlgorithm::RSA,
+        }, ProxyEventKind::CertServed {
+            authority: second_authority,
+            from_cache: true,
+            gen_duration: None,
+            key_algo: rustls::SignatureAlgorithm::RSA,
+        }] => {
+            assert_eq!(first_authority, &authority);
+            assert_eq!(second_authority, &authority);
+        }
+        served => panic!("Unexpected events: {:?}", served),
+    }
+
+    stop_server.send(()).unwrap();
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn upstream_target_host_for_sni() {
+    #[derive(Clone)]
+    struct SniHandler;
+
+    #[async_trait]
+    impl HttpHandler for SniHandler {
+        fn upstream_target(
+            &self,
+            _ctx: &HttpContext,
+            req: &Request<Body>,
+        ) -> Option<UpstreamTarget> {
+            // The server only has a certificate for localhost.
+            (req.method() != Method::CONNECT).then(|| UpstreamTarget {
+                host_for_sni: Some("localhost".to_owned()),
+                ..UpstreamTarget::default()
+            })
+        }
+    }
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::rustls_client())
+            .with_ca(build_ca())
+            .with_http_handler(SniHandler)
+            .build()
+    })
+    .unwrap();
+
+    let (server_addr, stop_server) = common::start_https_server(build_ca()).await.unwrap();
+    let client = common::build_client(&proxy_addr.to_string());
+
+    let res = client
+        .get(format!(
+            "https://example.invalid:{}/hello",
+            server_addr.port()
+        ))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.text().await.unwrap(), common::HELLO_WORLD);
+
+    stop_server.send(()).unwrap();
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn generated_ca_on_disk() {
+    let dir = std::env::temp_dir().join(format!("hudsucker-ca-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let generated = generate_ca_to_disk(
+        &dir,
+        Duration::from_secs(24 * 60 * 60),
+        &rcgen::PKCS_ECDSA_P384_SHA384,
+    )
+    .unwrap();
+    let ca = CaBuilder::new().build_to_disk(&dir).unwrap();
+    assert_eq!(ca.ca_cert_der(), generated.ca_cert_der());
+
+    let ca_cert = std::fs::read(dir.join("ca.cer")).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::rustls_client())
+            .with_ca(ca)
+            .build()
+    })
+    .unwrap();
+
+    let (server_addr, stop_server) = common::start_https_server(build_ca()).await.unwrap();
+    let client = reqwest::Client::builder()
+        .proxy(reqwest::Proxy::all(proxy_addr.to_string()).unwrap())
+        .tls_built_in_root_certs(false)
+        .add_root_certificate(reqwest::Certificate::from_pem(&ca_cert).unwrap())
+        .build()
+        .unwrap();
+
+    let res = client
+        .get(format!("https://localhost:{}/hello", server_addr.port()))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), 200);
+
+    stop_server.send(()).unwrap();
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn min_tls_version() {
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::rustls_client())
+            .with_ca(build_ca().with_min_tls_version(&rustls::version::TLS13))
+            .build()
+    })
+    .unwrap();
+
+    let (server_addr, stop_server) = common::start_https_server(build_ca()).await.unwrap();
+    let url = format!("https://localhost:{}/hello", server_addr.port());
+    let tls12_client = reqwest::Client::builder()
+        .proxy(reqwest::Proxy::all(proxy_addr.to_string()).unwrap())
+        .add_root_certificate(
+            reqwest::Certificate::from_pem(include_bytes!("../examples/ca/hudsucker.cer")).unwrap(),
+        )
+        .max_tls_version(reqwest::tls::Version::TLS_1_2)
+        .build()
+        .unwrap();
+
+    assert!(tls12_client.get(&url).send().await.is_err());
+
+    let res = common::build_client(&proxy_addr.to_string())
+        .get(&url)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+
+    stop_server.send(()).unwrap();
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn request_client_cert() {
+    #[derive(Clone)]
+    struct ClientCertHandler(mpsc::UnboundedSender<Option<Vec<rustls::Certificate>>>);
+
+    #[async_trait]
+    impl HttpHandler for ClientCertHandler {
+        async fn handle_request(
+            &mut self,
+            ctx: &HttpContext,
+            req: Request<Body>,
+        ) -> RequestOrResponse {
+            self.0.send(ctx.client_cert.clone()).unwrap();
+            req.into()
+        }
+    }
+
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::rustls_client())
+            .with_ca(build_ca().with_request_client_cert(ClientCertMode::Optional))
+            .with_http_handler(ClientCertHandler(tx))
+            .build()
+    })
+    .unwrap();
+
+    let (server_addr, stop_server) = common::start_https_server(build_ca()).await.unwrap();
+    let url = format!("https://localhost:{}/hello", server_addr.port());
+
+    let client_cert = rcgen::generate_simple_self_signed(vec!["client".to_owned()]).unwrap();
+    let client_cert_der = rustls::Certificate(client_cert.serialize_der().unwrap());
+
+    let mut roots = rustls::RootCertStore::empty();
+    let mut ca_cert_bytes: &[u8] = include_bytes!("../examples/ca/hudsucker.cer");
+    roots
+        .add(&rustls::Certificate(
+            pemfile::certs(&mut ca_cert_bytes).unwrap().remove(0),
+        ))
+        .unwrap();
+    let config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_client_auth_cert(
+            vec![client_cert_der.clone()],
+            rustls::PrivateKey(client_cert.serialize_private_key_der()),
+        )
+        .unwrap();
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream
+        .write_all(
+            format!(
+                "CONNECT localhost:{0} HTTP/1.1\r\nHost: localhost:{0}\r\n\r\n",
+                server_addr.port()
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    assert!(common::read_response_head(&mut stream)
+        .await
+        .starts_with("HTTP/1.1 200"));
+
+    let mut stream = tokio_rustls::TlsConnector::from(Arc::new(config))
+        .connect("localhost".try_into().unwrap(), stream)
+        .await
+        .unwrap();
+    stream
+        .write_all(
+            format!(
+                "GET /hello HTTP/1.1\r\nHost: localhost:{}\r\n\r\n",
+                server_addr.port()
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    assert!(common::read_response_head(&mut stream)
+        .await
+        .starts_with("HTTP/1.1 200"));
+
+    // The CONNECT request is handled before the TLS handshake.
+    assert_eq!(rx.recv().await.unwrap(), None);
+    assert_eq!(rx.recv().await.unwrap(), Some(vec![client_cert_der]));
+
+    let res = common::build_client(&proxy_addr.to_string())
+        .get(&url)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+
+    assert_eq!(rx.recv().await.unwrap(), None);
+    assert_eq!(rx.recv().await.unwrap(), None);
+
+    stop_server.send(()).unwrap();
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn decrypted_stream() {
+    #[derive(Clone)]
+    struct ForwardHandler;
+
+    #[async_trait]
+    impl HttpHandler for ForwardHandler {
+        async fn handle_decrypted_stream(
+            &mut self,
+            _ctx: &HttpContext,
+            stream: DecryptedStream,
+            authority: Authority,
+        ) {
+            let config = Arc::new(common::rustls_client_config());
+            stream.forward(&authority, config).await.unwrap();
+        }
+    }
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::rustls_client())
+            .with_ca(build_ca())
+            .with_http_handler(ForwardHandler)
+            .build()
+    })
+    .unwrap();
+
+    // A TLS server that echoes everything it receives.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = listener.local_addr().unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(
+        build_ca()
+            .gen_server_config(&"localhost".parse().unwrap())
+            .await,
+    );
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let stream = acceptor.accept(stream).await.unwrap();
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+    });
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream
+        .write_all(
+            format!(
+                "CONNECT localhost:{0} HTTP/1.1\r\nHost: localhost:{0}\r\n\r\n",
+                server_addr.port()
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+    assert!(common::read_response_head(&mut stream)
+        .await
+        .starts_with("HTTP/1.1 200"));
+
+    let mut stream = tokio_rustls::TlsConnector::from(Arc::new(common::rustls_client_config()))
+        .connect("localhost".try_into().unwrap(), stream)
+        .await
+        .unwrap();
+
+    stream.write_all(b"\x00\x01ping").await.unwrap();
+    let mut buf = [0; 6];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"\x00\x01ping");
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn decrypted_stream_default() {
+    #[derive(Clone)]
+    struct TargetHandler(std::net::SocketAddr);
+
+    #[async_trait]
+    impl HttpHandler for TargetHandler {
+        fn upstream_target(
+            &self,
+            _ctx: &HttpContext,
+            _req: &Request<Body>,
+        ) -> Option<UpstreamTarget> {
+            Some(UpstreamTarget {
+                connect_addr: Some(self.0),
+                ..UpstreamTarget::default()
+            })
+        }
+    }
+
+    // A TLS server that echoes everything it receives, with a certificate that is only trusted
+    // through the upstream root store.
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = listener.local_addr().unwrap();
+    let acceptor = tokio_rustls::TlsAcceptor::from(
+        build_ca()
+            .gen_server_config(&"localhost".parse().unwrap())
+            .await,
+    );
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let stream = acceptor.accept(stream).await.unwrap();
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+    });
+
+    let mut ca_cert_bytes: &[u8] = include_bytes!("../examples/ca/hudsucker.cer");
+    let mut root_store = rustls::RootCertStore::empty();
+    root_store.add_parsable_certificates(&pemfile::certs(&mut ca_cert_bytes).unwrap());
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_upstream_root_store(root_store)
+            .with_ca(build_ca())
+            .with_http_handler(TargetHandler(server_addr))
+            .build()
+    })
+    .unwrap();
+
+    // The port of the authority is closed, so the stream only reaches the server through the
+    // address to connect to.
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream
+        .write_all(b"CONNECT localhost:1 HTTP/1.1\r\nHost: localhost:1\r\n\r\n")
+        .await
+        .unwrap();
+    assert!(common::read_response_head(&mut stream)
+        .await
+        .starts_with("HTTP/1.1 200"));
+
+    let mut stream = tokio_rustls::TlsConnector::from(Arc::new(common::rustls_client_config()))
+        .connect("localhost".try_into().unwrap(), stream)
+        .await
+        .unwrap();
+
+    stream.write_all(b"\x00\x01ping").await.unwrap();
+    let mut buf = [0; 6];
+    stream.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"\x00\x01ping");
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[cfg(feature = "http2")]
+#[tokio::test]
+async fn upstream_target_connect_addr_h2() {
+    use hudsucker::hyper::{server::conn::Http, service::service_fn};
+    use std::{convert::Infallible, net::SocketAddr};
+
+    #[derive(Clone)]
+    struct TargetHandler(SocketAddr);
+
+    #[async_trait]
+    impl HttpHandler for TargetHandler {
+        fn upstream_target(
+            &self,
+            _ctx: &HttpContext,
+            req: &Request<Body>,
+        ) -> Option<UpstreamTarget> {
+            (req.uri().path() == "/other").then(|| UpstreamTarget {
+                connect_addr: Some(self.0),
+                ..UpstreamTarget::default()
+            })
+        }
+    }
+
+    // A TLS server that responds with its name, and the version and authority of the request.
+    async fn start_server(name: &'static str) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let acceptor = tokio_rustls::TlsAcceptor::from(
+            build_ca()
+                .gen_server_config(&"localhost".parse().unwrap())
+                .await,
+        );
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let stream = acceptor.accept(stream).await.unwrap();
+                let service = service_fn(move |req: Request<Body>| async move {
+                    let authority = req.uri().authority().map(ToString::to_string);
+                    let body = format!(
+                        "{} {:?} {}",
+                        name,
+                        req.version(),
+                        authority.unwrap_or_default()
+                    );
+                    Ok::<_, Infallible>(Response::new(Body::from(body)))
+                });
+                tokio::spawn(Http::new().serve_connection(stream, service));
+            }
+        });
+        addr
+    }
+
+    let origin_addr = start_server("origin").await;
+    let other_addr = start_server("other").await;
+
+    let mut ca_cert_bytes: &[u8] = include_bytes!("../examples/ca/hudsucker.cer");
+    let mut root_store = rustls::RootCertStore::empty();
+    root_store.add_parsable_certificates(&pemfile::certs(&mut ca_cert_bytes).unwrap());
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_upstream_root_store(root_store)
+            .with_ca(build_ca())
+            .with_http_handler(TargetHandler(other_addr))
+            .build()
+    })
+    .unwrap();
+
+    // The server only sees the authority of the request, and the connection to the origin isn't
+    // reused for the request to the other address.
+    let client = common::build_client(&proxy_addr.to_string());
+    let authority = format!("localhost:{}", origin_addr.port());
+    for (path, name) in [("/", "origin"), ("/other", "other"), ("/", "origin")] {
+        let res = client
+            .get(format!("https://{}{}", authority, path))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        assert_eq!(
+            res.text().await.unwrap(),
+            format!("{} HTTP/2.0 {}", name, authority)
+        );
+    }
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn handshake_delay() {
+    async fn handshake_duration(proxy_addr: std::net::SocketAddr) -> Duration {
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+        stream
+            .write_all(b"CONNECT localhost:443 HTTP/1.1\r\nHost: localhost:443\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(common::read_response_head(&mut stream)
+            .await
+            .starts_with("HTTP/1.1 200"));
+
+        let start = Instant::now();
+        tokio_rustls::TlsConnector::from(Arc::new(common::rustls_client_config()))
+            .connect("localhost".try_into().unwrap(), stream)
+            .await
+            .unwrap();
+        start.elapsed()
+    }
+
+    let delay = Duration::from_millis(300);
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::rustls_client())
+            .with_ca(build_ca())
+            .with_handshake_delay(HandshakeDelay::fixed(delay))
+            .build()
+    })
+    .unwrap();
+
+    assert!(handshake_duration(proxy_addr).await >= delay);
+    stop_proxy.send(()).unwrap();
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::rustls_client())
+            .with_ca(build_ca())
+            .build()
+    })
+    .unwrap();
+
+    assert!(handshake_duration(proxy_addr).await < delay);
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn tls_faults() {
+    async fn request_with_fault(fault: TlsFault) -> reqwest::Result<reqwest::Response> {
+        let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+            builder
+                .with_client(common::rustls_client())
+                .with_ca(build_ca())
+                .with_tls_faults(TlsFaults::new(0).with_fault(fault, 1.0))
+                .build()
+        })
+        .unwrap();
+
+        let (server_addr, stop_server) = common::start_https_server(build_ca()).await.unwrap();
+        let client = common::build_client(&proxy_addr.to_string());
+        let res = client
+            .get(format!("https://localhost:{}/hello", server_addr.port()))
+            .send()
+            .await;
+
+        stop_server.send(()).unwrap_or_default();
+        stop_proxy.send(()).unwrap();
+        res
+    }
+
+    assert!(request_with_fault(TlsFault::ExpiredCertificate)
+        .await
+        .is_err());
+    assert!(request_with_fault(TlsFault::WrongHostCertificate)
+        .await
+        .is_err());
+    assert!(
+        request_with_fault(TlsFault::Alert(rustls::AlertDescription::HandshakeFailure))
+            .await
+            .is_err()
+    );
+}
+
+#[cfg(feature = "http2")]
+#[tokio::test]
+async fn upstream_version() {
+    #[derive(Clone)]
+    struct VersionHandler;
+
+    #[async_trait]
+    impl HttpHandler for VersionHandler {
+        fn upstream_version(&self, _ctx: &HttpContext, _req: &Request<Body>) -> Version {
+            Version::HTTP_2
+        }
+    }
+
+    let mut ca_cert_bytes: &[u8] = include_bytes!("../examples/ca/hudsucker.cer");
+    let mut root_store = rustls::RootCertStore::empty();
+    root_store.add_parsable_certificates(&pemfile::certs(&mut ca_cert_bytes).unwrap());
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_upstream_root_store(root_store)
+            .with_ca(build_ca())
+            .with_http_handler(VersionHandler)
+            .build()
+    })
+    .unwrap();
+
+    let (server_addr, stop_server) = common::start_https_server(build_ca()).await.unwrap();
+    let client = common::build_client(&proxy_addr.to_string());
+    let res = client
+        .get(format!("https://localhost:{}/version", server_addr.port()))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.text().await.unwrap(), "HTTP/2.0");
+
+    stop_server.send(()).unwrap();
+    stop_proxy.send(()).unwrap();
+}
+
+#[cfg(feature = "http2")]
+#[tokio::test]
+async fn alpn_router() {
+    use hudsucker::AlpnRouter;
+
+    #[derive(Clone)]
+    struct NamedHandler(&'static str);
+
+    #[async_trait]
+    impl HttpHandler for NamedHandler {
+        async fn handle_request(
+            &mut self,
+            _ctx: &HttpContext,
+            req: Request<Body>,
+        ) -> RequestOrResponse {
+            if req.method() == Method::CONNECT {
+                return req.into();
+            }
+
+            Response::builder()
+                .header("x-handler", self.0)
+                .body(Body::empty())
+                .unwrap()
+                .into()
+        }
+    }
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::rustls_client())
+            .with_ca(build_ca())
+            .with_http_handler(
+                AlpnRouter::new(NamedHandler("default")).route("h2", NamedHandler("h2")),
+            )
+            .build()
+    })
+    .unwrap();
+
+    async fn connect(
+        proxy_addr: std::net::SocketAddr,
+        alpn: &[u8],
+    ) -> tokio_rustls::client::TlsStream<TcpStream> {
+        let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+        stream
+            .write_all(b"CONNECT localhost:443 HTTP/1.1\r\nHost: localhost:443\r\n\r\n")
+            .await
+            .unwrap();
+        assert!(common::read_response_head(&mut stream)
+            .await
+            .starts_with("HTTP/1.1 200"));
+
+        let mut config = common::rustls_client_config();
+        config.alpn_protocols = vec![alpn.to_vec()];
+        let stream = tokio_rustls::TlsConnector::from(Arc::new(config))
+            .connect("localhost".try_into().unwrap(), stream)
+            .await
+            .unwrap();
+        assert_eq!(stream.get_ref().1.alpn_protocol(), Some(alpn));
+        stream
+    }
+
+    let mut stream = connect(proxy_addr, b"http/1.1").await;
+    stream
+        .write_all(b"GET / HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let head = common::read_response_head(&mut stream).await;
+    assert!(
+        head.to_lowercase().contains("x-handler: default"),
+        "{}",
+        head
+    );
+
+    let stream = connect(proxy_addr, b"h2").await;
+    let (mut sender, conn) = hudsucker::hyper::client::conn::Builder::new()
+        .http2_only(true)
+        .handshake::<_, Body>(stream)
+        .await
+        .unwrap();
+    tokio::spawn(conn);
+    let res = sender
+        .send_request(
+            Request::get("https://localhost/")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(res.headers()["x-handler"], "h2");
+
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn keylog() {
+    let path = std::env::temp_dir().join(format!("hudsucker-keylog-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let mut ca_cert_bytes: &[u8] = include_bytes!("../examples/ca/hudsucker.cer");
+    let mut root_store = rustls::RootCertStore::empty();
+    root_store.add_parsable_certificates(&pemfile::certs(&mut ca_cert_bytes).unwrap());
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_keylog(&path)
+            .unwrap()
+            .with_upstream_root_store(root_store)
+            .with_ca(build_ca())
+            .build()
+    })
+    .unwrap();
+
+    let (server_addr, stop_server) = common::start_https_server(build_ca()).await.unwrap();
+    let client = common::build_client(&proxy_addr.to_string());
+    let res = client
+        .get(format!("https://localhost:{}/hello", server_addr.port()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 200);
+
+    let keylog = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    // Secrets are logged for both the client and the upstream connection.
+    let mut client_randoms = keylog
+        .lines()
+        .map(|line| line.split(' ').nth(1).unwrap())
+        .collect::<Vec<_>>();
+    client_randoms.sort_unstable();
+    client_randoms.dedup();
+    assert_eq!(client_randoms.len(), 2, "{}", keylog);
+
+    stop_server.send(()).unwrap();
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn separate_set_cookie_headers() {
+    #[derive(Clone)]
+    struct VersionHandler(Version);
+
+    #[async_trait]
+    impl HttpHandler for VersionHandler {
+        fn upstream_version(&self, _ctx: &HttpContext, _req: &Request<Body>) -> Version {
+            self.0
+        }
+    }
+
+    let mut versions = vec![Version::HTTP_11];
+    if cfg!(feature = "http2") {
+        versions.push(Version::HTTP_2);
+    }
+
+    for version in versions {
+        let mut ca_cert_bytes: &[u8] = include_bytes!("../examples/ca/hudsucker.cer");
+        let mut root_store = rustls::RootCertStore::empty();
+        root_store.add_parsable_certificates(&pemfile::certs(&mut ca_cert_bytes).unwrap());
+
+        let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+            builder
+                .with_upstream_root_store(root_store)
+                .with_ca(build_ca())
+                .with_http_handler(VersionHandler(version))
+                .build()
+        })
+        .unwrap();
+
+        let (server_addr, stop_server) = common::start_https_server(build_ca()).await.unwrap();
+        let client = common::build_client(&proxy_addr.to_string());
+        let res = client
+            .get(format!("https://localhost:{}/cookies", server_addr.port()))
+            .send()
+            .await
+            .unwrap();
+
+        let cookies: Vec<_> = res.headers().get_all("set-cookie").iter().collect();
+        assert_eq!(cookies, common::COOKIES, "{:?}", version);
+
+        stop_server.send(()).unwrap();
+        stop_proxy.send(()).unwrap();
+    }
+}
+
 #[tokio::test]
 async fn https_native_tls() {
     let (proxy_addr, handler, stop_proxy) = common::start_proxy(
@@ -74,6 +1152,132 @@ async fn https_native_tls() {
     stop_proxy.send(()).unwrap();
 }
 
+#[tokio::test]
+async fn reuses_upstream_connections() {
+    let (proxy_addr, handler, stop_proxy) = common::start_proxy(
+        build_ca(),
+        common::rustls_client(),
+        common::rustls_websocket_connector(),
+    )
+    .unwrap();
+
+    let (server_addr, connection_counter, stop_server) =
+        common::start_https_server_with_connection_counter(build_ca())
+            .await
+            .unwrap();
+    let client = common::build_client(&proxy_addr.to_string());
+
+    for _ in 0..2 {
+        let res = client
+            .get(format!("https://localhost:{}/hello", server_addr.port()))
+            .send()
+            .await
+            .unwrap();
+
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.bytes().await.unwrap(), common::HELLO_WORLD);
+    }
+
+    // Both requests are sent over a single CONNECT tunnel.
+    assert_eq!(handler.request_counter.load(Ordering::Relaxed), 3);
+    assert_eq!(connection_counter.load(Ordering::Relaxed), 1);
+
+    stop_server.send(()).unwrap();
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn expect_continue() {
+    let (proxy_addr, stop_proxy) = common::start_noop_proxy(build_ca()).unwrap();
+    let (server_addr, stop_server) = common::start_http_server().unwrap();
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream
+        .write_all(
+            format!(
+                "POST http://{0}/echo HTTP/1.1\r\n\
+                Host: {0}\r\n\
+                Content-Length: 5\r\n\
+                Expect: 100-continue\r\n\r\n",
+                server_addr
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+
+    // The interim response is sent by the proxy, not relayed from the upstream server.
+    let head = common::read_response_head(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 100 Continue"));
+
+    stream.write_all(b"hello").await.unwrap();
+
+    let head = common::read_response_head(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 200 OK"));
+
+    let mut body = [0; 5];
+    stream.read_exact(&mut body).await.unwrap();
+    assert_eq!(&body, b"hello");
+
+    stop_server.send(()).unwrap();
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn closes_upstream_when_client_disconnects() {
+    let server = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = server.local_addr().unwrap();
+    let (closed_tx, closed_rx) = tokio::sync::oneshot::channel();
+
+    tokio::spawn(async move {
+        let (mut stream, _) = server.accept().await.unwrap();
+        common::read_response_head(&mut stream).await;
+        stream
+            .write_all(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n")
+            .await
+            .unwrap();
+
+        while stream.write_all(b"5\r\nhello\r\n").await.is_ok() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        closed_tx.send(()).unwrap();
+    });
+
+    let (proxy_addr, _, stop_proxy) = common::start_proxy(
+        build_ca(),
+        common::http_client(),
+        common::plain_websocket_connector(),
+    )
+    .unwrap();
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    stream
+        .write_all(
+            format!(
+                "GET http://{0}/stream HTTP/1.1\r\nHost: {0}\r\n\r\n",
+                server_addr
+            )
+            .as_bytes(),
+        )
+        .await
+        .unwrap();
+
+    let head = common::read_response_head(&mut stream).await;
+    assert!(head.starts_with("HTTP/1.1 200"));
+
+    let mut buf = [0; 10];
+    stream.read_exact(&mut buf).await.unwrap();
+    drop(stream);
+
+    tokio::time::timeout(Duration::from_secs(5), closed_rx)
+        .await
+        .expect("Upstream connection was not closed")
+        .unwrap();
+
+    stop_proxy.send(()).unwrap();
+}
+
 #[tokio::test]
 async fn without_intercept() {
     let (proxy_addr, handler, stop_proxy) = common::start_proxy_without_intercept(
@@ -125,6 +1329,33 @@ async fn decodes_response() {
     stop_proxy.send(()).unwrap();
 }
 
+#[tokio::test]
+async fn decodes_request() {
+    let (proxy_addr, _, stop_proxy) = common::start_proxy(
+        build_ca(),
+        common::native_tls_client(),
+        common::native_tls_websocket_connector(),
+    )
+    .unwrap();
+
+    let (server_addr, stop_server) = common::start_http_server().unwrap();
+    let client = common::build_client(&proxy_addr.to_string());
+
+    let res = client
+        .post(format!("http://{}/echo", server_addr))
+        .header("content-encoding", "gzip")
+        .body(common::gzip(common::HELLO_WORLD.as_bytes()).await)
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(res.status(), 200);
+    assert_eq!(res.bytes().await.unwrap(), common::HELLO_WORLD);
+
+    stop_server.send(()).unwrap();
+    stop_proxy.send(()).unwrap();
+}
+
 #[tokio::test]
 async fn noop() {
     let (proxy_addr, stop_proxy) = common::start_noop_proxy(build_ca()).unwrap();
@@ -143,3 +1374,65 @@ async fn noop() {
     stop_server.send(()).unwrap();
     stop_proxy.send(()).unwrap();
 }
+
+#[tokio::test]
+async fn intercept_policy_handle() {
+    #[derive(Clone)]
+    struct PolicyHandler(InterceptPolicyHandle, mpsc::UnboundedSender<Method>);
+
+    #[async_trait]
+    impl HttpHandler for PolicyHandler {
+        async fn should_intercept(&mut self, _ctx: &HttpContext, req: &Request<Body>) -> bool {
+            self.0.decide_request(req).intercept
+        }
+
+        async fn handle_request(
+            &mut self,
+            _ctx: &HttpContext,
+            req: Request<Body>,
+        ) -> RequestOrResponse {
+            self.1.send(req.method().clone()).unwrap();
+            req.into()
+        }
+    }
+
+    let policy = InterceptPolicyHandle::new(InterceptPolicy::deny_by_default());
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let handler = PolicyHandler(policy.clone(), tx);
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::rustls_client())
+            .with_ca(build_ca())
+            .with_http_handler(handler)
+            .build()
+    })
+    .unwrap();
+
+    let (server_addr, stop_server) = common::start_https_server(build_ca()).await.unwrap();
+    let url = format!("https://localhost:{}/hello", server_addr.port());
+
+    let res = common::build_client(&proxy_addr.to_string())
+        .get(&url)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.bytes().await.unwrap(), common::HELLO_WORLD);
+    assert_eq!(rx.recv().await.unwrap(), Method::CONNECT);
+    assert!(rx.try_recv().is_err());
+
+    policy.update(|policy| policy.clone().allow("localhost", "testing"));
+
+    // A new client opens a new tunnel, which uses the updated policy.
+    let res = common::build_client(&proxy_addr.to_string())
+        .get(&url)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.bytes().await.unwrap(), common::HELLO_WORLD);
+    assert_eq!(rx.recv().await.unwrap(), Method::CONNECT);
+    assert_eq!(rx.recv().await.unwrap(), Method::GET);
+
+    stop_server.send(()).unwrap();
+    stop_proxy.send(()).unwrap();
+}
@@ -1,11 +1,27 @@
 use async_http_proxy::http_connect_tokio;
 use futures::{SinkExt, StreamExt};
 use hudsucker::{
-    certificate_authority::RcgenAuthority, rustls, tokio_tungstenite::tungstenite::Message,
+    async_trait::async_trait,
+    certificate_authority::RcgenAuthority,
+    hyper::{
+        header::{HeaderValue, SEC_WEBSOCKET_PROTOCOL},
+        Body, Method, Request,
+    },
+    rustls,
+    tokio_tungstenite::tungstenite::{
+        client::IntoClientRequest, protocol::frame::coding::CloseCode, Message,
+    },
+    HttpContext, HttpHandler, LoggingWebSocketHandler, RequestOrResponse, WebSocketHandler,
 };
 use rustls_pemfile as pemfile;
-use std::sync::atomic::Ordering;
-use tokio::net::TcpStream;
+use std::{
+    sync::{atomic::Ordering, Arc, Mutex},
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::oneshot,
+};
 
 #[allow(unused)]
 mod common;
@@ -63,6 +79,261 @@ async fn http() {
     stop_proxy.send(()).unwrap();
 }
 
+#[tokio::test]
+async fn closes_on_shutdown() {
+    let (proxy_addr, _, stop_proxy) = common::start_proxy(
+        build_ca(),
+        common::native_tls_client(),
+        common::native_tls_websocket_connector(),
+    )
+    .unwrap();
+
+    let (server_addr, stop_server) = common::start_http_server().unwrap();
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    http_connect_tokio(
+        &mut stream,
+        &server_addr.ip().to_string(),
+        server_addr.port(),
+    )
+    .await
+    .unwrap();
+
+    let (mut ws, _) = tokio_tungstenite::client_async(format!("ws://{}", server_addr), stream)
+        .await
+        .unwrap();
+
+    ws.send(Message::Text("hello".to_owned())).await.unwrap();
+    ws.next().await.unwrap().unwrap();
+
+    stop_proxy.send(()).unwrap();
+
+    match ws.next().await.unwrap().unwrap() {
+        Message::Close(Some(frame)) => assert_eq!(frame.code, CloseCode::Away),
+        msg => panic!("Expected a close frame, got {:?}", msg),
+    }
+
+    stop_server.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn logging_handler() {
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::native_tls_client())
+            .with_ca(build_ca())
+            .with_websocket_handler(LoggingWebSocketHandler::new())
+            .build()
+    })
+    .unwrap();
+
+    let (server_addr, stop_server) = common::start_http_server().unwrap();
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    http_connect_tokio(
+        &mut stream,
+        &server_addr.ip().to_string(),
+        server_addr.port(),
+    )
+    .await
+    .unwrap();
+
+    let (mut ws, _) = tokio_tungstenite::client_async(format!("ws://{}", server_addr), stream)
+        .await
+        .unwrap();
+
+    ws.send(Message::Text("hello".to_owned())).await.unwrap();
+
+    let msg = ws.next().await.unwrap().unwrap();
+
+    assert_eq!(msg.to_string(), common::WORLD);
+
+    stop_server.send(()).unwrap();
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn session_counts() {
+    let mut control = None;
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        let proxy = builder
+            .with_client(common::native_tls_client())
+            .with_ca(build_ca())
+            .build();
+        control = Some(proxy.control());
+        proxy
+    })
+    .unwrap();
+    let control = control.unwrap();
+
+    let (server_addr, stop_server) = common::start_http_server().unwrap();
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    http_connect_tokio(
+        &mut stream,
+        &server_addr.ip().to_string(),
+        server_addr.port(),
+    )
+    .await
+    .unwrap();
+
+    let (mut ws, _) = tokio_tungstenite::client_async(format!("ws://{}", server_addr), stream)
+        .await
+        .unwrap();
+
+    for _ in 0..3 {
+        ws.send(Message::Text("hi".to_owned())).await.unwrap();
+        assert_eq!(ws.next().await.unwrap().unwrap().to_string(), common::WORLD);
+    }
+
+    let sessions = control.websocket_sessions();
+    assert_eq!(sessions.len(), 1);
+    assert_eq!(
+        sessions[0].uri.to_string(),
+        format!("ws://{}/", server_addr)
+    );
+    assert_eq!(sessions[0].client_to_server.messages, 3);
+    assert_eq!(sessions[0].client_to_server.bytes, 6);
+    assert_eq!(sessions[0].server_to_client.messages, 3);
+    assert_eq!(sessions[0].server_to_client.bytes, 15);
+
+    ws.close(None).await.unwrap();
+    while ws.next().await.is_some() {}
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while !control.websocket_sessions().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .unwrap();
+
+    stop_server.send(()).unwrap();
+    stop_proxy.send(()).unwrap();
+}
+
+#[tokio::test]
+async fn is_websocket_upgrade() {
+    #[derive(Clone, Default)]
+    struct UpgradeHandler(Arc<Mutex<Vec<(Method, bool)>>>);
+
+    #[async_trait]
+    impl HttpHandler for UpgradeHandler {
+        async fn handle_request(
+            &mut self,
+            ctx: &HttpContext,
+            req: Request<Body>,
+        ) -> RequestOrResponse {
+            self.0
+                .lock()
+                .unwrap()
+                .push((req.method().clone(), ctx.is_websocket_upgrade));
+            req.into()
+        }
+    }
+
+    let handler = UpgradeHandler::default();
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::native_tls_client())
+            .with_ca(build_ca())
+            .with_http_handler(handler.clone())
+            .build()
+    })
+    .unwrap();
+
+    let (server_addr, stop_server) = common::start_http_server().unwrap();
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    http_connect_tokio(
+        &mut stream,
+        &server_addr.ip().to_string(),
+        server_addr.port(),
+    )
+    .await
+    .unwrap();
+
+    let (mut ws, _) = tokio_tungstenite::client_async(format!("ws://{}", server_addr), stream)
+        .await
+        .unwrap();
+
+    ws.send(Message::Text("hello".to_owned())).await.unwrap();
+    ws.next().await.unwrap().unwrap();
+
+    assert_eq!(
+        *handler.0.lock().unwrap(),
+        [(Method::CONNECT, false), (Method::GET, true)]
+    );
+
+    stop_server.send(()).unwrap();
+    stop_proxy.send(()).unwrap();
+}
+
+// The callback's error type is set by tungstenite.
+#[allow(clippy::result_large_err)]
+#[tokio::test]
+async fn rewrite_handshake_request() {
+    #[derive(Clone)]
+    struct ProtocolHandler;
+
+    #[async_trait]
+    impl WebSocketHandler for ProtocolHandler {
+        fn rewrite_handshake_request(&self, req: &mut Request<()>) {
+            req.headers_mut()
+                .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("v2"));
+        }
+    }
+
+    let (proxy_addr, stop_proxy) = common::start_custom_proxy(|builder| {
+        builder
+            .with_client(common::native_tls_client())
+            .with_ca(build_ca())
+            .with_websocket_handler(ProtocolHandler)
+            .build()
+    })
+    .unwrap();
+
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let server_addr = listener.local_addr().unwrap();
+    let (protocol_tx, protocol_rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let mut ws = tokio_tungstenite::accept_hdr_async(stream, |req: &Request<()>, res| {
+            protocol_tx
+                .send(req.headers().get(SEC_WEBSOCKET_PROTOCOL).cloned())
+                .unwrap();
+            Ok(res)
+        })
+        .await
+        .unwrap();
+
+        ws.close(None).await.unwrap();
+    });
+
+    let mut stream = TcpStream::connect(proxy_addr).await.unwrap();
+    http_connect_tokio(
+        &mut stream,
+        &server_addr.ip().to_string(),
+        server_addr.port(),
+    )
+    .await
+    .unwrap();
+
+    let mut req = format!("ws://{}", server_addr)
+        .into_client_request()
+        .unwrap();
+    req.headers_mut()
+        .insert(SEC_WEBSOCKET_PROTOCOL, HeaderValue::from_static("v1"));
+    let (_ws, _) = tokio_tungstenite::client_async(req, stream).await.unwrap();
+
+    assert_eq!(
+        protocol_rx.await.unwrap(),
+        Some(HeaderValue::from_static("v2"))
+    );
+
+    stop_proxy.send(()).unwrap();
+}
+
 #[tokio::test]
 async fn https_rustls() {
     let (proxy_addr, handler, stop_proxy) = common::start_proxy(